@@ -7,7 +7,7 @@ use anyhow::Result;
 use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
 use console::style;
 
-use crate::get_did_secret;
+use crate::{agents::state_management::SharedState, get_did_secret, secrets::is_secret_missing};
 
 /// Retrieves secrets for a DID from the keyring
 pub fn get_secrets(did: &str) -> Result<Vec<Secret>> {
@@ -18,7 +18,7 @@ pub fn get_secrets(did: &str) -> Result<Vec<Secret>> {
                 "{}",
                 style(format!("ERROR: Couldn't get secret for {}: {}", did, e)).red()
             );
-            return Err(e.into());
+            return Err(e);
         }
     };
 
@@ -58,3 +58,81 @@ pub fn get_secrets(did: &str) -> Result<Vec<Secret>> {
         }
     }
 }
+
+/// Retrieves the secrets of every model's agent DIDs, returning (model name, secrets) for each model that can
+/// be started
+/// Models with missing secrets are skipped so they don't stop the other models from starting
+pub async fn get_model_secrets(config: &SharedState) -> Vec<(String, Vec<Secret>)> {
+    let mut startable = Vec::new();
+    for (model_name, model) in config.models.lock().await.iter() {
+        let dids = model.lock().await.dids.clone();
+        match dids
+            .iter()
+            .map(|did| get_secrets(&did.did))
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(model_secrets) => {
+                startable.push((
+                    model_name.to_string(),
+                    model_secrets.into_iter().flatten().collect(),
+                ));
+            }
+            Err(e) if is_secret_missing(&e) => println!(
+                "{}",
+                style(format!(
+                    "ERROR: Model ({}) won't be started, its secret is missing from the secret store ({}). Restore it with --import-secrets, or remove the model with /remove-model on the concierge and add it again with /add-model",
+                    model_name, e
+                ))
+                .red()
+            ),
+            Err(e) => println!(
+                "{}",
+                style(format!(
+                    "ERROR: Model ({}) is misconfigured and won't be started: {}",
+                    model_name, e
+                ))
+                .red()
+            ),
+        }
+    }
+    startable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DIDMethods, create_did,
+        test_support::{test_model, use_memory_secret_store},
+    };
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn a_model_missing_its_secret_doesnt_stop_the_others() {
+        use_memory_secret_store();
+        let config = SharedState::default();
+        for (model_name, did) in [
+            (
+                "with-secret",
+                create_did(&DIDMethods::Key, "", &[]).unwrap(),
+            ),
+            ("missing-secret", "did:example:no-secret".to_string()),
+        ] {
+            let mut model = test_model(&[]);
+            model.dids[0].did = did;
+            config
+                .models
+                .lock()
+                .await
+                .insert(model_name.to_string(), Arc::new(Mutex::new(model)));
+        }
+
+        let startable = get_model_secrets(&config).await;
+
+        assert_eq!(startable.len(), 1);
+        let (model_name, secrets) = &startable[0];
+        assert_eq!(model_name, "with-secret");
+        assert_eq!(secrets.len(), 1);
+    }
+}
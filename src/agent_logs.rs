@@ -0,0 +1,152 @@
+/*!
+ * Separate log files for each agent
+ *
+ * Model agents and the concierge run inside a span named `agent` with a `name` field. Events within an
+ * agent span are appended to `<name>.log` in the log directory, events outside any agent span are ignored.
+ * Where agent spans are nested, the innermost agent gets the event.
+ */
+
+use anyhow::{Context as _, Result};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id},
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+/// Name of the spans that route events to an agent's log file
+const AGENT_SPAN: &str = "agent";
+
+/// Tracing layer writing the events of each agent to its own file
+pub struct AgentLogs {
+    dir: PathBuf,
+    /// Open log files by agent name
+    files: Mutex<HashMap<String, File>>,
+}
+
+/// Agent name recorded on an agent span
+struct AgentName(String);
+
+impl AgentLogs {
+    /// Creates the log directory if needed
+    pub fn new(dir: &str) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Couldn't create log directory ({})", dir))?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Appends a line to the agent's log file, opening it the first time the agent logs
+    fn write(&self, agent: &str, line: &str) {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if !files.contains_key(agent) {
+            match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(log_file_name(agent)))
+            {
+                Ok(file) => {
+                    files.insert(agent.to_string(), file);
+                }
+                // Logging must never stop the agent, the event is only lost
+                Err(_) => return,
+            }
+        }
+        if let Some(file) = files.get_mut(agent) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+/// Log file for an agent, characters that aren't safe in file names (e.g. the : in llama3.2:latest) become _
+fn log_file_name(agent: &str) -> String {
+    let name = agent
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("{}.log", name)
+}
+
+impl<S> Layer<S> for AgentLogs
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != AGENT_SPAN {
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.name, ctx.span(id)) {
+            span.extensions_mut().insert(AgentName(name));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(agent) = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .into_iter()
+                .find_map(|span| span.extensions().get::<AgentName>().map(|n| n.0.clone()))
+        }) else {
+            return;
+        };
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        self.write(
+            &agent,
+            &format!(
+                "{} {:>5} {}: {}{}",
+                chrono::Utc::now().to_rfc3339(),
+                metadata.level(),
+                metadata.target(),
+                visitor.message,
+                visitor.fields
+            ),
+        );
+    }
+}
+
+/// Collects the message, the agent name and any other fields of a span or event
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    name: Option<String>,
+    fields: String,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            "name" => self.name = Some(value.to_string()),
+            name => self.fields.push_str(&format!(" {}={}", name, value)),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            // Display values (%name) are recorded as Debug of their Display output
+            "name" => self.name = Some(format!("{:?}", value)),
+            name => self.fields.push_str(&format!(" {}={:?}", name, value)),
+        }
+    }
+}
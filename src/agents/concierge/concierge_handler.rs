@@ -5,37 +5,57 @@
  */
 
 use crate::{
+    DIDMethods,
+    activate::get_secrets,
     agents::{
-        model::{ModelAction, ModelAgent},
-        state_management::{ChannelState, ChatChannelState, SharedState, SharedStateRef},
+        model::{ModelAction, ModelAgent, PromptQueues},
+        state_management::{
+            ChannelState, ChatChannelState, DIDCommAgent, OllamaModel, SharedState, SharedStateRef,
+            now_secs,
+        },
+    },
+    chat_messages::{
+        ChatMessage, NOT_PERMITTED_RESPONSE, PromptKind, check_connection_setup, check_prompt,
+        generate_greeting, handle_prompt, is_expired, send_message, stop_generation,
     },
-    chat_messages::send_message,
     didcomm_messages::{
-        clear_messages::{clear_inbound_messages, clear_outbound_messages},
         handle_presence,
-        oob_connection::send_connection_response,
+        oob_connection::{INVITATION_EXPIRY, create_invitation, send_connection_response},
+        websocket::{
+            ProfileEvent, ReconnectSchedule, activate_profile, new_profile, reconnect_profile,
+            renew_profile,
+        },
     },
+    secrets::is_store_unavailable,
     termination::{Interrupted, Terminator},
 };
-use affinidi_messaging_didcomm::{Message, UnpackMetadata};
-use affinidi_messaging_sdk::{ATM, profiles::ATMProfile};
-use anyhow::Result;
-use console::style;
+use affinidi_messaging_sdk::{ATM, messages::Folder, profiles::ATMProfile};
+use affinidi_tdk::secrets_resolver::SecretsResolver;
+use anyhow::{Context, Result, bail};
 use sha256::digest;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fs, path::Path, sync::Arc, time::Duration};
 use tokio::{
     select,
     sync::{
-        broadcast,
+        Mutex, broadcast,
         mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
     },
+    task::JoinHandle,
+    time::Instant,
 };
-use tracing::{info, warn};
+use tracing::{Instrument, error, info, warn};
 
 /// Concierge Messages that can be sent to/from Concierge Task
 pub enum ConciergeMessage {
     Exit,
-    StartModel { model_name: String },
+    StartModel {
+        model_name: String,
+    },
+    /// Apply a reloaded and validated configuration
+    Reload {
+        config: Box<SharedState>,
+    },
 }
 
 /// Concierge Task
@@ -46,11 +66,48 @@ pub struct Concierge {
     to_concierge_channel: UnboundedReceiver<ConciergeMessage>,
     /// Shared State
     shared_state: SharedStateRef,
+    /// Configuration file the state is loaded from and saved to
+    config_file: String,
 }
 
 struct Model {
     // profile: Arc<Profile>,
     tx_channel: UnboundedSender<ModelAction>,
+    /// Task running the model agent
+    handle: JoinHandle<()>,
+    /// Current status as reported by the model agent
+    status: ModelStatus,
+    /// Number of times the model agent has been restarted after failing
+    restarts: u32,
+}
+
+/// Time a model agent has to stop after being told to exit, before its task is aborted
+const MODEL_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ollama service used for models added at runtime when no other model is configured
+const DEFAULT_OLLAMA_HOST: &str = "http://localhost";
+const DEFAULT_OLLAMA_PORT: u16 = 11434;
+
+/// Channels unused for this many days are removed by /gc unless another age is given
+const DEFAULT_CHANNEL_MAX_AGE_DAYS: u64 = 30;
+
+/// Time each model is given to send a broadcast to its channels
+const BROADCAST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the mediator is checked for messages waiting for unloaded models
+const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time each profile's mediator inbox is checked for before the check gives up
+const WAKE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Status of a model agent as seen by the concierge
+#[derive(Clone, Debug)]
+enum ModelStatus {
+    Running,
+    Idle,
+    /// Stopped after being idle, started again when a message arrives for it
+    Unloaded,
+    Failed(String),
 }
 
 impl Concierge {
@@ -59,6 +116,7 @@ impl Concierge {
     pub fn new(
         atm: ATM,
         config: Arc<SharedState>,
+        config_file: &str,
         to_concierge: UnboundedReceiver<ConciergeMessage>,
     ) -> (Self, UnboundedReceiver<ConciergeMessage>) {
         let (_, from_concierge) = mpsc::unbounded_channel::<ConciergeMessage>();
@@ -67,46 +125,849 @@ impl Concierge {
                 atm,
                 to_concierge_channel: to_concierge,
                 shared_state: config,
+                config_file: config_file.to_string(),
             },
             from_concierge,
         )
     }
 
+    /// Starts the agent for a model
+    /// `clear_queued` - delete messages queued on the mediator for the model's DIDs before it starts
+    async fn start_model(
+        &self,
+        model_name: &str,
+        profiles: Vec<ATMProfile>,
+        to_concierge: &UnboundedSender<ModelAction>,
+        clear_queued: bool,
+    ) -> Result<Model> {
+        let model = {
+            let lock = self.shared_state.models.lock().await;
+            let Some(model) = lock.get(model_name) else {
+                return Err(anyhow::anyhow!("Model not found: {}", model_name));
+            };
+            model.clone()
+        };
+        info!("Starting Model: {:?}", model_name);
+
+        // Channel to communicate with the model
+        let (to_model, from_concierge) = mpsc::unbounded_channel::<ModelAction>();
+        let model_agent = ModelAgent::new(
+            self.atm.clone(),
+            model,
+            from_concierge,
+            to_concierge.clone(),
+            self.shared_state.clone(),
+        );
+        info!("Model Agent new: {}", model_name);
+        let handle = model_agent.start(profiles, clear_queued).await?;
+
+        info!("After run(): {}", model_name);
+        Ok(Model {
+            tx_channel: to_model,
+            handle,
+            status: ModelStatus::Running,
+            restarts: 0,
+        })
+    }
+
+    /// Handles a command sent to the concierge
+    /// Returns the response to send to the remote party, or an error if the secret store is unavailable
+    async fn handle_command(
+        &self,
+        profile: &Arc<ATMProfile>,
+        text: &str,
+        from_did: &str,
+        models: &mut HashMap<String, Model>,
+        model_profiles: &mut HashMap<String, Vec<ATMProfile>>,
+        to_concierge: &UnboundedSender<ModelAction>,
+    ) -> Result<String> {
+        let agent = self.shared_state.concierge.lock().await.agent.clone();
+        let text = text.trim();
+        let (command, argument) = match text.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command.to_lowercase(), argument.trim()),
+            None => (text.to_lowercase(), ""),
+        };
+
+        let result = match command.as_str() {
+            "/help" => Ok(agent.render_commands(
+                r#"Help:
+          /help - Display this help message
+          /status - Display the status of the concierge and running models
+          /list-models - List the configured models
+          /add-model <name> [http://host:port] - Add and start an Ollama model
+          /remove-model <name> - Stop and remove a model
+          /reload <name> - Apply a model's settings from the configuration file without restarting it
+          /set-name <model> <name> - Change the name shown to new connections of a model's agents
+          /set-greeting <model> <text> - Change the greeting sent to new connections of a model's agents
+          /gc [days] - Remove channels that haven't been used for this many days (default 30)
+          /broadcast <text> - Send a message to every connected client
+          /export <did> [json|md] - Export the conversation with a DID (or its hash) to a file
+          /invite [model|did] - Create an invitation to connect to the concierge, a model (its DIDs in turn) or an agent DID
+          /stop - Stop the default model's response that is being generated
+        "#,
+            )),
+            "/status" => Ok(self.status(models).await),
+            "/list-models" => Ok(self.list_models(models).await),
+            "/add-model" => {
+                self.add_model(argument, models, model_profiles, to_concierge)
+                    .await
+            }
+            "/remove-model" => self.remove_model(argument, models, model_profiles).await,
+            "/reload" => self.reload_model(argument, models).await,
+            "/set-name" => self.set_name(argument).await,
+            "/set-greeting" => self.set_greeting(argument).await,
+            "/gc" => self.prune_channels(argument).await,
+            "/broadcast" => self.broadcast(profile, argument, models).await,
+            "/export" => self.export_channel(argument).await,
+            "/invite" => self.invite(profile, argument).await,
+            "/stop" => Ok(stop_generation(&self.shared_state.concierge, from_did).await),
+            _ => Ok(agent.render_unknown_command(&format!(
+                "{}{}",
+                agent.command_prefix(),
+                &text[1..]
+            ))),
+        };
+
+        // Nothing works without the secret store, so that is left for the caller to shut down on
+        match result {
+            Err(e) if is_store_unavailable(&e) => Err(e),
+            result => Ok(result.unwrap_or_else(|e| format!("ERROR: {}", e))),
+        }
+    }
+
+    /// Model that answers chat messages sent to the concierge, if it is set and exists
+    async fn default_model(&self) -> Option<String> {
+        let default_model = self
+            .shared_state
+            .concierge
+            .lock()
+            .await
+            .default_model
+            .clone()?;
+        if self
+            .shared_state
+            .models
+            .lock()
+            .await
+            .contains_key(&default_model)
+        {
+            Some(default_model)
+        } else {
+            warn!("Default model ({}) doesn't exist", default_model);
+            None
+        }
+    }
+
+    /// Model that writes greetings (name, model) and the system prompt it is given, if greeting_model is set
+    /// and exists
+    async fn greeting_model(&self) -> Option<(String, Arc<Mutex<OllamaModel>>, String)> {
+        let greeting_model = {
+            self.shared_state
+                .concierge
+                .lock()
+                .await
+                .greeting_model
+                .clone()?
+        };
+        let (model, mut names) = {
+            let models = self.shared_state.models.lock().await;
+            (
+                models.get(&greeting_model).cloned(),
+                models.keys().cloned().collect::<Vec<String>>(),
+            )
+        };
+        names.sort();
+        let system_prompt = {
+            self.shared_state
+                .concierge
+                .lock()
+                .await
+                .render_greeting_prompt(&names)
+        };
+        match model {
+            Some(model) => Some((greeting_model, model, system_prompt)),
+            None => {
+                warn!("Greeting model ({}) doesn't exist", greeting_model);
+                None
+            }
+        }
+    }
+
+    /// Creates an OOB invitation for the concierge, the next of a model's agent DIDs or an agent DID
+    /// argument: [model|did]
+    async fn invite(&self, profile: &Arc<ATMProfile>, argument: &str) -> Result<String> {
+        let profile = if argument.is_empty() {
+            profile.clone()
+        } else {
+            let model = { self.shared_state.models.lock().await.get(argument).cloned() };
+            let did = match model {
+                // Connections are spread across the model's DIDs
+                Some(model) => match self.shared_state.next_agent(&*model.lock().await) {
+                    Some(agent) => agent.did,
+                    None => bail!("model ({}) has no agent DIDs", argument),
+                },
+                None => argument.to_string(),
+            };
+            // Only agents that are running have a profile
+            let profiles = self.atm.get_profiles();
+            let profile = profiles.read().await.find_by_did(&did);
+            match profile {
+                Some(profile) => profile,
+                None => bail!(
+                    "no running agent for ({})\nUse /invite [model|did]",
+                    argument
+                ),
+            }
+        };
+
+        let url = create_invitation(&self.atm, &profile).await?;
+        Ok(format!(
+            "Invitation to connect to ({}), valid for {} hours:\n{}",
+            profile.inner.did,
+            INVITATION_EXPIRY.as_secs() / 3600,
+            url
+        ))
+    }
+
+    /// Reports the concierge DID and whether each model agent task is alive
+    async fn status(&self, models: &HashMap<String, Model>) -> String {
+        let concierge_did = { self.shared_state.concierge.lock().await.agent.did.clone() };
+
+        let mut names = models.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let models = if names.is_empty() {
+            "  No models are running".to_string()
+        } else {
+            names
+                .iter()
+                .filter_map(|name| models.get(name).map(|model| (name, model)))
+                .map(|(name, model)| {
+                    format!(
+                        "  {}: task {}, {:?}, restarts ({})",
+                        name,
+                        if model.handle.is_finished() {
+                            "exited"
+                        } else {
+                            "alive"
+                        },
+                        model.status,
+                        model.restarts
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+
+        format!(
+            "Status:\nConcierge DID: {}\nMediator DIDs: {}\nModels:\n{}",
+            concierge_did,
+            self.shared_state.mediator_dids.join(", "),
+            models
+        )
+    }
+
+    /// Lists the configured models and the status of their agents
+    async fn list_models(&self, models: &HashMap<String, Model>) -> String {
+        // (name, alias)
+        let mut names = Vec::new();
+        {
+            let configured = { self.shared_state.models.lock().await.clone() };
+            for (name, model) in configured {
+                names.push((name, model.lock().await.alias.clone()));
+            }
+        }
+        if names.is_empty() {
+            return "No models are configured".to_string();
+        }
+        names.sort();
+
+        let models = names
+            .iter()
+            .map(|(name, alias)| {
+                let status = match models.get(name).map(|model| &model.status) {
+                    Some(ModelStatus::Running) => "running".to_string(),
+                    Some(ModelStatus::Idle) => "idle".to_string(),
+                    Some(ModelStatus::Unloaded) => "unloaded".to_string(),
+                    Some(ModelStatus::Failed(error)) => format!("failed: {}", error),
+                    None => "stopped".to_string(),
+                };
+                match alias {
+                    Some(alias) => format!("  {} [{}] ({})", name, alias, status),
+                    None => format!("  {} ({})", name, status),
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        format!("Models:\n{}", models)
+    }
+
+    /// Adds a new model to the configuration and starts its agent
+    /// argument: <name> [http://host:port]
+    async fn add_model(
+        &self,
+        argument: &str,
+        models: &mut HashMap<String, Model>,
+        model_profiles: &mut HashMap<String, Vec<ATMProfile>>,
+        to_concierge: &UnboundedSender<ModelAction>,
+    ) -> Result<String> {
+        let mut parts = argument.split_whitespace();
+        let Some(model_name) = parts.next() else {
+            bail!("missing model name\nUse /add-model <name> [http://host:port]");
+        };
+
+        // Use the address given, otherwise the Ollama service of an existing model
+        let (ollama_host, ollama_port, ollama_api_key, ollama_fallback_hosts) = match parts.next() {
+            Some(address) => {
+                let Some((host, port)) = address.rsplit_once(':') else {
+                    bail!(
+                        "invalid address ({}), must look similar to http://localhost:11434",
+                        address
+                    );
+                };
+                (host.to_string(), port.parse::<u16>()?, None, Vec::new())
+            }
+            None => {
+                let existing = {
+                    let lock = self.shared_state.models.lock().await;
+                    lock.values().next().cloned()
+                };
+                match existing {
+                    Some(model) => {
+                        let lock = model.lock().await;
+                        (
+                            lock.ollama_host.clone(),
+                            lock.ollama_port,
+                            lock.ollama_api_key.clone(),
+                            lock.ollama_fallback_hosts.clone(),
+                        )
+                    }
+                    None => (
+                        DEFAULT_OLLAMA_HOST.to_string(),
+                        DEFAULT_OLLAMA_PORT,
+                        None,
+                        Vec::new(),
+                    ),
+                }
+            }
+        };
+
+        if self
+            .shared_state
+            .models
+            .lock()
+            .await
+            .contains_key(model_name)
+        {
+            bail!("model ({}) already exists", model_name);
+        }
+
+        // New DIDs use the same method as the concierge
+        let did_method = {
+            let lock = self.shared_state.concierge.lock().await;
+            DIDMethods::from_did(&lock.agent.did)
+        };
+
+        let mut model = OllamaModel::new(
+            ollama_host,
+            ollama_port,
+            self.shared_state.service_mediator_did(),
+            &self.shared_state.routing_keys,
+            model_name,
+            &did_method,
+        )?;
+        model.ollama_api_key = ollama_api_key;
+        model.ollama_fallback_hosts = ollama_fallback_hosts;
+
+        let profiles = self.create_profiles(&model.dids).await?;
+        let model_did = model
+            .dids
+            .first()
+            .map(|did| did.did.clone())
+            .unwrap_or_default();
+        self.shared_state.add_model(model_name, model).await;
+        self.shared_state.save(&self.config_file).await?;
+
+        let model = self
+            .start_model(model_name, profiles.clone(), to_concierge, true)
+            .await?;
+        models.insert(model_name.to_string(), model);
+        model_profiles.insert(model_name.to_string(), profiles);
+
+        Ok(format!("Model ({}) added\nDID: {}", model_name, model_did))
+    }
+
+    /// Stops the agent for a model and removes it from the configuration
+    async fn remove_model(
+        &self,
+        model_name: &str,
+        models: &mut HashMap<String, Model>,
+        model_profiles: &mut HashMap<String, Vec<ATMProfile>>,
+    ) -> Result<String> {
+        if model_name.is_empty() {
+            bail!("missing model name\nUse /remove-model <name>");
+        }
+        if !self
+            .shared_state
+            .models
+            .lock()
+            .await
+            .contains_key(model_name)
+        {
+            bail!("unknown model: {}", model_name);
+        }
+
+        self.stop_model(model_name, models, model_profiles).await;
+
+        // Also deletes the DID secrets
+        self.shared_state.remove_model(model_name).await;
+        self.shared_state.save(&self.config_file).await?;
+
+        Ok(format!("Model ({}) removed", model_name))
+    }
+
+    /// Creates the ATM profiles for a model's DIDs, loading their secrets into the resolver
+    async fn create_profiles(&self, dids: &[DIDCommAgent]) -> Result<Vec<ATMProfile>> {
+        let mut profiles = Vec::new();
+        for did in dids {
+            self.atm
+                .get_tdk()
+                .secrets_resolver
+                .insert_vec(&get_secrets(&did.did)?)
+                .await;
+            profiles.push(
+                new_profile(
+                    &self.atm,
+                    &did.name,
+                    &did.did,
+                    &self.shared_state.mediator_dids,
+                )
+                .await?,
+            );
+        }
+        Ok(profiles)
+    }
+
+    /// Stops the agent for a model and removes its profiles, the model stays configured
+    async fn stop_model(
+        &self,
+        model_name: &str,
+        models: &mut HashMap<String, Model>,
+        model_profiles: &mut HashMap<String, Vec<ATMProfile>>,
+    ) {
+        if let Some(model) = models.remove(model_name) {
+            let _ = model.tx_channel.send(ModelAction::Exit);
+            info!("Send exit action to model: {}", model_name);
+            join_model(
+                model_name,
+                model.handle,
+                Instant::now() + MODEL_STOP_TIMEOUT,
+            )
+            .await;
+        }
+        // Closes the profiles' websockets to the mediator
+        for profile in model_profiles.remove(model_name).unwrap_or_default() {
+            let _ = self.atm.profile_remove(&profile.inner.alias).await;
+        }
+    }
+
+    /// Re-reads a model's settings from the configuration file and applies them to its running agent
+    /// Its websockets and channels are kept, so models whose DIDs changed need a full reload
+    /// argument: <name>
+    async fn reload_model(
+        &self,
+        model_name: &str,
+        models: &HashMap<String, Model>,
+    ) -> Result<String> {
+        if model_name.is_empty() {
+            bail!("missing model name\nUse /reload <name>");
+        }
+        let Some(model) = models.get(model_name) else {
+            bail!("model ({}) isn't running", model_name);
+        };
+
+        let config = SharedState::load(&self.config_file)?;
+        let new_model = { config.models.lock().await.get(model_name).cloned() };
+        let Some(new_model) = new_model else {
+            bail!(
+                "model ({}) isn't in the configuration file ({})",
+                model_name,
+                self.config_file
+            );
+        };
+        let new_model = new_model.lock().await.clone();
+
+        let current = {
+            self.shared_state
+                .models
+                .lock()
+                .await
+                .get(model_name)
+                .cloned()
+        };
+        if let Some(current) = current
+            && !current
+                .lock()
+                .await
+                .dids
+                .iter()
+                .map(|agent| &agent.did)
+                .eq(new_model.dids.iter().map(|agent| &agent.did))
+        {
+            bail!(
+                "the DIDs of model ({}) have changed, the whole configuration needs reloading",
+                model_name
+            );
+        }
+
+        model
+            .tx_channel
+            .send(ModelAction::Reload(Box::new(new_model)))
+            .map_err(|_| anyhow::anyhow!("model ({}) has stopped", model_name))?;
+        info!("Sent reload action to model: {}", model_name);
+
+        Ok(format!("Model ({}) reloaded", model_name))
+    }
+
+    /// Changes the name of a model's agents, used on the vCard sent to new connections
+    /// argument: <model> <name>
+    async fn set_name(&self, argument: &str) -> Result<String> {
+        let Some((model_name, name)) = argument
+            .split_once(char::is_whitespace)
+            .map(|(model_name, name)| (model_name, name.trim()))
+            .filter(|(_, name)| !name.is_empty())
+        else {
+            bail!("missing model or name\nUse /set-name <model> <name>");
+        };
+
+        self.update_agents(model_name, |agent| agent.name = name.to_string())
+            .await?;
+        Ok(format!("Model ({}) renamed to {}", model_name, name))
+    }
+
+    /// Changes the greeting of a model's agents, sent to new connections
+    /// argument: <model> <text>
+    async fn set_greeting(&self, argument: &str) -> Result<String> {
+        let Some((model_name, greeting)) = argument
+            .split_once(char::is_whitespace)
+            .map(|(model_name, greeting)| (model_name, greeting.trim()))
+            .filter(|(_, greeting)| !greeting.is_empty())
+        else {
+            bail!("missing model or greeting\nUse /set-greeting <model> <text>");
+        };
+
+        self.update_agents(model_name, |agent| agent.greeting = greeting.to_string())
+            .await?;
+        Ok(format!("Model ({}) greeting changed", model_name))
+    }
+
+    /// Applies a change to every agent DID of a model and saves the configuration
+    /// The running agent shares the model's state, so new connections pick up the change straight away
+    async fn update_agents(
+        &self,
+        model_name: &str,
+        update: impl Fn(&mut DIDCommAgent),
+    ) -> Result<()> {
+        let model = {
+            self.shared_state
+                .models
+                .lock()
+                .await
+                .get(model_name)
+                .cloned()
+        };
+        let Some(model) = model else {
+            bail!("unknown model: {}", model_name);
+        };
+        model.lock().await.dids.iter_mut().for_each(update);
+
+        self.shared_state.save(&self.config_file).await
+    }
+
+    /// Renews the profiles of a model that is being restarted, profiles that can't be renewed are skipped
+    async fn renew_profiles(&self, profiles: &[ATMProfile]) -> Vec<ATMProfile> {
+        let mut renewed_profiles = Vec::new();
+        for profile in profiles {
+            match renew_profile(&self.atm, profile).await {
+                Ok(profile) => renewed_profiles.push(profile),
+                Err(e) => warn!("Couldn't renew profile ({}): {}", profile.inner.did, e),
+            }
+        }
+        renewed_profiles
+    }
+
+    /// Whether messages are waiting on the mediator for any of the profiles
+    /// The profiles of unloaded models have been removed from ATM (profile_remove), which only closes their
+    /// websockets. list_messages uses the profile it is given over the mediator's REST API, authenticating with
+    /// the profile's own tokens and the DID secrets still in the TDK secrets resolver, so it works for them.
+    /// ATM retries authentication until it succeeds, so each check is time limited to keep the concierge
+    /// responsive while the mediator is unreachable
+    async fn has_waiting_messages(&self, profiles: &[ATMProfile]) -> bool {
+        for profile in profiles {
+            let listed = tokio::time::timeout(
+                WAKE_CHECK_TIMEOUT,
+                self.atm
+                    .list_messages(&Arc::new(profile.clone()), Folder::Inbox),
+            )
+            .await;
+            match listed {
+                Ok(Ok(messages)) if !messages.is_empty() => return true,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!(
+                    "Couldn't check messages waiting for ({}): {}",
+                    profile.inner.did, e
+                ),
+                Err(_) => warn!(
+                    "Timed out checking messages waiting for ({})",
+                    profile.inner.did
+                ),
+            }
+        }
+        false
+    }
+
+    /// Applies a reloaded configuration, stopping and starting agents for removed and added models
+    async fn reload(
+        &self,
+        config: SharedState,
+        models: &mut HashMap<String, Model>,
+        model_profiles: &mut HashMap<String, Vec<ATMProfile>>,
+        to_concierge: &UnboundedSender<ModelAction>,
+    ) {
+        let changes = self.shared_state.apply_config(config).await;
+
+        for model_name in &changes.removed {
+            self.stop_model(model_name, models, model_profiles).await;
+        }
+
+        for model_name in &changes.added {
+            let dids = {
+                let lock = self.shared_state.models.lock().await;
+                match lock.get(model_name) {
+                    Some(model) => model.lock().await.dids.clone(),
+                    None => continue,
+                }
+            };
+            let profiles = match self.create_profiles(&dids).await {
+                Ok(profiles) => profiles,
+                Err(e) => {
+                    warn!(
+                        "Model ({}) is misconfigured and won't be started: {}",
+                        model_name, e
+                    );
+                    continue;
+                }
+            };
+            match self
+                .start_model(model_name, profiles.clone(), to_concierge, true)
+                .await
+            {
+                Ok(model) => {
+                    models.insert(model_name.clone(), model);
+                    model_profiles.insert(model_name.clone(), profiles);
+                }
+                Err(e) => warn!("Couldn't start model ({}): {}", model_name, e),
+            }
+        }
+
+        info!(
+            "Configuration reloaded: models added ({}), removed ({})",
+            changes.added.len(),
+            changes.removed.len()
+        );
+    }
+
+    /// Sends the text to every channel of the concierge and the running models
+    async fn broadcast(
+        &self,
+        profile: &Arc<ATMProfile>,
+        text: &str,
+        models: &HashMap<String, Model>,
+    ) -> Result<String> {
+        if text.is_empty() {
+            bail!("missing text\nUse /broadcast <text>");
+        }
+
+        // Cloned so the lock isn't held while sending
+        let remote_dids = {
+            let lock = self.shared_state.concierge.lock().await;
+            lock.channel_state
+                .values()
+                .map(|state| state.remote_did.clone())
+                .collect::<Vec<String>>()
+        };
+        let mut sent = 0;
+        for remote_did in remote_dids {
+            match send_message(
+                &self.atm,
+                profile,
+                text,
+                &remote_did,
+                &self.shared_state.concierge,
+            )
+            .await
+            {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("Couldn't broadcast to ({}): {}", remote_did, e),
+            }
+        }
+
+        let mut replies = Vec::new();
+        for (model_name, model) in models {
+            let (tx, rx) = oneshot::channel();
+            if model
+                .tx_channel
+                .send(ModelAction::Broadcast {
+                    text: text.to_string(),
+                    sent: tx,
+                })
+                .is_ok()
+            {
+                replies.push((model_name, rx));
+            }
+        }
+        for (model_name, rx) in replies {
+            match tokio::time::timeout(BROADCAST_TIMEOUT, rx).await {
+                Ok(Ok(count)) => sent += count,
+                _ => warn!("Model ({}) didn't report its broadcast", model_name),
+            }
+        }
+
+        info!("Broadcast sent to ({}) channels", sent);
+        Ok(format!("Broadcast sent to ({}) channels", sent))
+    }
+
+    /// Removes channels that haven't been used for the given number of days
+    async fn prune_channels(&self, days: &str) -> Result<String> {
+        let days = if days.is_empty() {
+            DEFAULT_CHANNEL_MAX_AGE_DAYS
+        } else {
+            days.parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("invalid number of days: {}", days))?
+        };
+
+        let pruned = self
+            .shared_state
+            .prune_channels(Duration::from_secs(days * 24 * 60 * 60))
+            .await;
+        info!("Pruned ({}) channels unused for ({}) days", pruned, days);
+
+        Ok(format!(
+            "Removed ({}) channels unused for ({}) days",
+            pruned, days
+        ))
+    }
+
+    /// Exports the conversation with a remote DID (or its SHA256 hash) to a new file in the export directory
+    /// Only the file name is chosen here, so the remote party can't write outside the export directory
+    async fn export_channel(&self, argument: &str) -> Result<String> {
+        let mut parts = argument.split_whitespace();
+        let Some(did) = parts.next() else {
+            bail!("missing DID\nUse /export <did> [json|md]");
+        };
+        let extension = match parts.next().map(|f| f.to_lowercase()) {
+            None => "json".to_string(),
+            Some(format) if format == "json" || format == "md" => format,
+            Some(format) => bail!("unknown export format ({}), must be json or md", format),
+        };
+        // The hash becomes part of the file name, so anything else is rejected
+        let did_hash = if did.starts_with("did:") {
+            digest(did)
+        } else if did.len() == 64 && did.chars().all(|c| c.is_ascii_hexdigit()) {
+            did.to_lowercase()
+        } else {
+            bail!("invalid DID ({}), must be a DID or its SHA256 hash", did);
+        };
+
+        let export_dir = Path::new(self.shared_state.export_dir());
+        fs::create_dir_all(export_dir).context(format!(
+            "Couldn't create export directory ({})",
+            export_dir.display()
+        ))?;
+        let path = export_dir.join(format!(
+            "{}-{}.{}",
+            did_hash,
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+            extension
+        ));
+
+        let exported = self.shared_state.export_channel(&did_hash, &path).await?;
+        info!("Exported channel ({}) to {}", did_hash, path.display());
+
+        Ok(format!(
+            "Exported ({}) messages to {}",
+            exported,
+            path.display()
+        ))
+    }
+
     /// Run the Concierge Task
     pub async fn run(
         mut self,
         concierge_profile: ATMProfile,
-        model_profiles: HashMap<String, Vec<ATMProfile>>,
+        mut model_profiles: HashMap<String, Vec<ATMProfile>>,
         mut terminator: Terminator,
         mut interrupt_rx: broadcast::Receiver<Interrupted>,
     ) -> Result<Interrupted> {
-        let profile = self.atm.profile_add(&concierge_profile, false).await?;
-        let _ = clear_inbound_messages(&self.atm, &profile).await;
-        let _ = clear_outbound_messages(&self.atm, &profile).await;
-
         // Start live streaming
-        self.atm.profile_enable_websocket(&profile).await?;
+        let (events_tx, mut events_rx) = mpsc::channel::<ProfileEvent>(32);
+        let mut profile = activate_profile(
+            &self.atm,
+            &concierge_profile,
+            &self.shared_state.mediator_dids,
+            Some(self.shared_state.fetch_settings()),
+            events_tx.clone(),
+        )
+        .await?;
 
         info!("Concierge Task Started");
-        let (direct_tx, mut direct_rx) = mpsc::channel::<Box<(Message, UnpackMetadata)>>(32);
-
-        profile.enable_direct_channel(direct_tx).await?;
+        self.shared_state.readiness.set_concierge(true);
 
         let mut models: HashMap<String, Model> = HashMap::new();
         // Channels used to communicate from models to the concierge
         let (to_concierge_from_models, mut from_models_to_concierge) =
             mpsc::unbounded_channel::<ModelAction>();
 
-        let didcomm_agent = {
+        let mut didcomm_agent = {
             let lock = self.shared_state.concierge.lock().await;
             lock.agent.clone()
         };
 
         let concierge_state = self.shared_state.concierge.clone();
+        let mut reconnects = ReconnectSchedule::default();
+        let mut wake_check = tokio::time::interval(WAKE_CHECK_INTERVAL);
+        // Prompts answered by the default model wait for earlier prompts from the same DID
+        let mut prompt_queues = PromptQueues::default();
         let result = loop {
             select! {
-                Some(action) = from_models_to_concierge.recv() => {
-                        warn!("NOT IMPLEMENTED: {:#?}", action);
+                Some(action) = from_models_to_concierge.recv() => match apply_model_report(&mut models, action, self.shared_state.model_restart_limit()) {
+                    Some(ReportFollowUp::Restart { model_name, attempt }) => {
+                        let Some(profiles) = model_profiles.get(&model_name) else {
+                            warn!("No model_profiles found for {}", model_name);
+                            continue;
+                        };
+                        let renewed_profiles = self.renew_profiles(profiles).await;
+
+                        info!("Restarting Model ({}): attempt ({})", model_name, attempt);
+                        match self.start_model(&model_name, renewed_profiles, &to_concierge_from_models, true).await {
+                            Ok(mut model) => {
+                                model.restarts = attempt;
+                                models.insert(model_name, model);
+                            }
+                            Err(e) => {
+                                warn!("Couldn't restart model ({}): {}", model_name, e);
+                                if let Some(model) = models.get_mut(&model_name) {
+                                    model.status = ModelStatus::Failed(e.to_string());
+                                }
+                            }
+                        }
+                    }
+                    Some(ReportFollowUp::CloseProfiles { model_name }) => {
+                        // Closes the websockets, messages wait on the mediator until the model is woken
+                        for profile in model_profiles.get(&model_name).into_iter().flatten() {
+                            let _ = self.atm.profile_remove(&profile.inner.alias).await;
+                        }
+                    }
+                    None => {}
                 },
                 Some(action) = self.to_concierge_channel.recv() => match action {
                 ConciergeMessage::Exit => {
@@ -116,32 +977,80 @@ impl Concierge {
                     break Interrupted::UserInt;
                 },
                 ConciergeMessage::StartModel { model_name } => {
-                    let model = {
-                        let lock = self.shared_state.models.lock().await;
-                        let Some(model) = lock.get(&model_name) else {
-                            warn!("Model not found: {}", model_name);
-                            continue;
-                        };
-                        model.clone()
-                    };
-                    info!("Starting Model: {:?}", model_name);
                     match model_profiles.get(&model_name) {
                         Some(model_profiles) => {
-                            // Channel to communicate with the model
-                            let (to_model, from_concierge) = mpsc::unbounded_channel::<ModelAction>();
-                            let model_agent = ModelAgent::new(self.atm.clone(), model.clone(), from_concierge, to_concierge_from_models.clone());
-                            info!("Model Agent new: {}", &model_name);
-                            model_agent.start(model_profiles.to_owned()).await?;
-
-                            info!("After run(): {}", &model_name);
-                            models.insert(model_name.clone(), Model {  tx_channel: to_model});
+                            match self.start_model(&model_name, model_profiles.to_owned(), &to_concierge_from_models, true).await {
+                                Ok(model) => {
+                                    models.insert(model_name.clone(), model);
+                                }
+                                Err(e) => warn!("Couldn't start model ({}): {}", model_name, e),
+                            }
                         },
                         None => println!("No model_profiles found for {model_name}.")
                     }
                 }
+                ConciergeMessage::Reload { config } => {
+                    self.reload(*config, &mut models, &mut model_profiles, &to_concierge_from_models).await;
+                    didcomm_agent = self.shared_state.concierge.lock().await.agent.clone();
+                }
             },
-                Some(boxed_data) = direct_rx.recv() => {
-                        let (message, meta) = *boxed_data;
+                _ = wake_check.tick() => {
+                    let unloaded = models
+                        .iter()
+                        .filter(|(_, model)| matches!(model.status, ModelStatus::Unloaded))
+                        .map(|(model_name, _)| model_name.clone())
+                        .collect::<Vec<String>>();
+                    for model_name in unloaded {
+                        let Some(profiles) = model_profiles.get(&model_name) else {
+                            continue;
+                        };
+                        if !self.has_waiting_messages(profiles).await {
+                            continue;
+                        }
+                        info!("Waking Model ({}), messages are waiting for it", model_name);
+                        let renewed_profiles = self.renew_profiles(profiles).await;
+                        // The waiting messages are answered rather than cleared
+                        match self.start_model(&model_name, renewed_profiles, &to_concierge_from_models, false).await {
+                            Ok(model) => {
+                                models.insert(model_name, model);
+                            }
+                            Err(e) => {
+                                warn!("Couldn't wake model ({}): {}", model_name, e);
+                                if let Some(model) = models.get_mut(&model_name) {
+                                    model.status = ModelStatus::Failed(e.to_string());
+                                }
+                            }
+                        }
+                    }
+                },
+                _ = tokio::time::sleep_until(reconnects.next_attempt()), if !reconnects.is_empty() => {
+                    match reconnect_profile(&self.atm, &profile, &self.shared_state.mediator_dids, events_tx.clone()).await {
+                        Ok(new_profile) => {
+                            info!("Concierge Reconnected: {}", new_profile.inner.did);
+                            reconnects.succeeded(&new_profile.inner.did);
+                            self.shared_state.readiness.set_concierge(true);
+                            profile = new_profile;
+                        }
+                        Err(e) => {
+                            let attempts = reconnects.failed(&profile.inner.did, &e);
+                            if attempts >= self.shared_state.mediator_reconnect_limit() {
+                                error!("Concierge couldn't reconnect to the mediator after ({}) attempts, exiting", attempts);
+                                let _ = terminator.terminate(Interrupted::SystemError);
+                                break Interrupted::SystemError;
+                            }
+                        }
+                    }
+                },
+                Some(event) = events_rx.recv() => {
+                        let (message, meta) = match event {
+                            ProfileEvent::Message(boxed_data) => *boxed_data,
+                            ProfileEvent::Disconnected(did) => {
+                                warn!("Concierge websocket disconnected: {}. Reconnecting...", did);
+                                self.shared_state.readiness.set_concierge(false);
+                                reconnects.schedule(&did);
+                                continue;
+                            }
+                        };
                         let _ = self.atm.delete_message_background(&profile, &meta.sha256_hash).await;
 
                         let Some(from_did) = message.from.clone() else {
@@ -160,6 +1069,9 @@ impl Concierge {
                                 };
                                 concierge_state.insert_channel_state(&from_did_hash, remote_state);
                             }
+                            if let Some(state) = concierge_state.get_channel_state_mut(&from_did_hash) {
+                                state.touch();
+                            }
                         }
 
                         info!("Concierge Received Message: message.type_ = {:#?}", message.type_);
@@ -169,18 +1081,19 @@ impl Concierge {
                                 "{}: Received Connection Setup Request: from({:#?})",
                                 profile.inner.alias, message.from
                             );
-                            let new_did = send_connection_response(&self.atm, &profile, &message, &didcomm_agent).await?;
+                            // A malformed request is rejected without stopping the concierge
+                            if check_connection_setup(&self.atm, &profile, &message, &from_did, &self.shared_state).await.is_err() {
+                                continue;
+                            }
+                            let new_did = match send_connection_response(&self.atm, &profile, &message, &didcomm_agent).await {
+                                Ok(new_did) => new_did,
+                                Err(e) => {
+                                    warn!("Couldn't respond to connection setup from ({}): {}", from_did, e);
+                                    continue;
+                                }
+                            };
                             {
                                 let mut lock = concierge_state.lock().await;
-                                let Some(from_did) = &message.from else {
-                                    println!("{}", style("No 'from' field in message").red());
-                                    println!(
-                                        "{}",
-                                        style("How would one respond to an anonymous message?").red()
-                                    );
-                                    return Err(anyhow::anyhow!("No 'from' field in message"));
-                                };
-                                let from_did_hash = digest(from_did);
                                 lock.remove_channel_state(&from_did_hash);
                                 let new_did_hash = digest(&new_did);
                                 lock.insert_channel_state(
@@ -188,18 +1101,30 @@ impl Concierge {
                                     ChatChannelState {
                                         remote_did: new_did.clone(),
                                         remote_did_hash: new_did_hash.clone(),
+                                        last_seen: now_secs(),
                                         ..Default::default()
                                     },
                                 );
                             }
-                            let _ = send_message(
-                                &self.atm,
-                                &profile,
-                                &didcomm_agent.greeting,
-                                &new_did,
-                                &concierge_state,
-                            )
-                            .await;
+                            // A generated greeting can take a while, so it is sent in the background
+                            let atm = self.atm.clone();
+                            let profile = profile.clone();
+                            let concierge_state = concierge_state.clone();
+                            let greeting_model = self.greeting_model().await;
+                            let shared_state = self.shared_state.clone();
+                            let static_greeting = didcomm_agent.render_greeting("concierge");
+                            tokio::spawn(async move {
+                                let greeting = match greeting_model {
+                                    Some((model_name, model, system_prompt)) => generate_greeting(&model, &system_prompt, &shared_state)
+                                        .await
+                                        .unwrap_or_else(|e| {
+                                            warn!("Greeting model ({}) couldn't write a greeting, sending the static greeting: {}", model_name, e);
+                                            static_greeting
+                                        }),
+                                    None => static_greeting,
+                                };
+                                let _ = send_message(&atm, &profile, &greeting, &new_did, &concierge_state).await;
+                            }.in_current_span());
                         } else if message.type_ ==  "https://affinidi.com/atm/client-actions/chat-presence" {
                             // Send a presence response back
                             let _ = handle_presence(&self.atm, &profile, &from_did).await;
@@ -209,6 +1134,64 @@ impl Concierge {
                             // Ignore chat activity messages
                         } else if message.type_ ==  "https://didcomm.org/messagepickup/3.0/status" {
                             // Ignore DIDComm status messages
+                        } else if !didcomm_agent.is_allowed(&from_did) {
+                            warn!("DID ({}) is not permitted to chat with the concierge", from_did);
+                            let _ = send_message(
+                                &self.atm,
+                                &profile,
+                                NOT_PERMITTED_RESPONSE,
+                                &from_did,
+                                &concierge_state,
+                            )
+                            .await;
+                        } else if let Some(text) = serde_json::from_value::<ChatMessage>(message.body.clone())
+                            .ok()
+                            .map(|chat_message| chat_message.text)
+                            .and_then(|text| didcomm_agent.parse_command(&text))
+                        {
+                            match self
+                                .handle_command(&profile, &text, &from_did, &mut models, &mut model_profiles, &to_concierge_from_models)
+                                .await
+                            {
+                                Ok(response) => {
+                                    let _ = send_message(&self.atm, &profile, &response, &from_did, &concierge_state).await;
+                                }
+                                Err(e) => {
+                                    error!("Secret store is unavailable, exiting: {:#}", e);
+                                    let _ = send_message(&self.atm, &profile, &format!("ERROR: {}", e), &from_did, &concierge_state).await;
+                                    let _ = terminator.terminate(Interrupted::SystemError);
+                                    break Interrupted::SystemError;
+                                }
+                            }
+                        } else if let (Some(default_model), Ok(chat_message)) = (
+                            self.default_model().await,
+                            serde_json::from_value::<ChatMessage>(message.body.clone()),
+                        ) {
+                            if is_expired(&message) {
+                                warn!("Dropping expired message ({}) from DID ({})", message.id, from_did);
+                                continue;
+                            }
+                            // Answered in the background so the concierge keeps handling commands (e.g. /stop)
+                            // Prompts from a DID are answered in the order they arrive
+                            let mut turn = prompt_queues.queue(&from_did);
+                            let mut chat_message = ChatMessage {
+                                thid: Some(message.thid.clone().unwrap_or_else(|| message.id.clone())),
+                                ..chat_message
+                            };
+                            let atm = self.atm.clone();
+                            let profile = profile.clone();
+                            let concierge_state = concierge_state.clone();
+                            let shared_state = self.shared_state.clone();
+                            tokio::spawn(async move {
+                                turn.wait().await;
+                                // The same checks as a prompt sent to the default model itself, using its limits
+                                if !check_prompt(&atm, &profile, &concierge_state, &message, &from_did, &mut chat_message.text, &shared_state).await {
+                                    return;
+                                }
+                                if let Err(e) = handle_prompt(&atm, &profile, &chat_message, &concierge_state, &from_did, &shared_state, PromptKind::New).await {
+                                    warn!("Default model ({}) couldn't answer: {}", default_model, e);
+                                }
+                            }.in_current_span());
                         } else {
                             info!("Concierge Received Message: {:#?}", message);
                             let _ = send_message(
@@ -228,15 +1211,202 @@ impl Concierge {
             }
         };
 
-        // Clean up the models
+        self.shared_state.readiness.set_concierge(false);
+
+        // Give the models a chance to finish in-flight responses before stopping them
+        let grace_period = self.shared_state.shutdown_grace_period();
+        for (model_name, model) in &models {
+            if let ModelStatus::Failed(error) = &model.status {
+                warn!("Model ({}) had failed: {}", model_name, error);
+            }
+            let _ = model.tx_channel.send(ModelAction::Drain { grace_period });
+            info!("Send drain action to model: {}", model_name);
+        }
+        // Allow time for the going offline notices to be sent after the grace period
+        let deadline = Instant::now() + grace_period + Duration::from_secs(5);
         for (model_name, model) in models {
-            let _ = model.tx_channel.send(ModelAction::Exit);
-            info!("Send exit action to model: {}", model_name);
+            join_model(&model_name, model.handle, deadline).await;
+        }
+
+        // Closes the websockets to the mediator
+        for profile in model_profiles.into_values().flatten() {
+            let _ = self.atm.profile_remove(&profile.inner.alias).await;
         }
+        let _ = self.atm.profile_remove(&profile.inner.alias).await;
 
         // Save the config to disk
-        self.shared_state.save("config.json").await?;
+        self.shared_state.save(&self.config_file).await?;
 
         Ok(result)
     }
 }
+
+/// What the concierge still has to do after a model's report has updated the model's status
+#[derive(Debug, PartialEq)]
+enum ReportFollowUp {
+    /// Start the failed model again, `attempt` is the number of times it has now been restarted
+    Restart { model_name: String, attempt: u32 },
+    /// Close the websockets of the unloaded model
+    CloseProfiles { model_name: String },
+}
+
+/// Updates the status of the model that sent the report
+/// A failed model is restarted until it has failed more than `restart_limit` times, then it is left failed
+fn apply_model_report(
+    models: &mut HashMap<String, Model>,
+    action: ModelAction,
+    restart_limit: u32,
+) -> Option<ReportFollowUp> {
+    match action {
+        ModelAction::ReportError { model_name, error } => {
+            warn!("Model ({}) reported an error: {}", model_name, error);
+            let restarts = models
+                .get(&model_name)
+                .map(|m| m.restarts)
+                .unwrap_or_default();
+            if restarts >= restart_limit {
+                warn!(
+                    "Model ({}) has failed ({}) times, not restarting",
+                    model_name,
+                    restarts + 1
+                );
+                if let Some(model) = models.get_mut(&model_name) {
+                    model.status = ModelStatus::Failed(error);
+                }
+                return None;
+            }
+            Some(ReportFollowUp::Restart {
+                model_name,
+                attempt: restarts + 1,
+            })
+        }
+        ModelAction::ReportIdle { model_name } => {
+            info!("Model ({}) is idle", model_name);
+            if let Some(model) = models.get_mut(&model_name) {
+                model.status = ModelStatus::Idle;
+            }
+            None
+        }
+        ModelAction::ReportActive { model_name } => {
+            if let Some(model) = models.get_mut(&model_name) {
+                model.status = ModelStatus::Running;
+            }
+            None
+        }
+        ModelAction::ReportUnloaded { model_name } => {
+            info!("Model ({}) unloaded after being idle", model_name);
+            if let Some(model) = models.get_mut(&model_name) {
+                model.status = ModelStatus::Unloaded;
+            }
+            Some(ReportFollowUp::CloseProfiles { model_name })
+        }
+        ModelAction::Exit
+        | ModelAction::Drain { .. }
+        | ModelAction::Reload(_)
+        | ModelAction::Broadcast { .. } => {
+            warn!(
+                "Concierge received unexpected {:?} action from a model",
+                action
+            );
+            None
+        }
+    }
+}
+
+/// Waits for a model agent task to finish, aborting it if it is still running at the deadline
+async fn join_model(model_name: &str, mut handle: JoinHandle<()>, deadline: Instant) {
+    if tokio::time::timeout_at(deadline, &mut handle).await.is_ok() {
+        info!("Model ({}) stopped", model_name);
+        return;
+    }
+    warn!(
+        "Model ({}) didn't stop in time, aborting its task",
+        model_name
+    );
+    handle.abort();
+    let _ = handle.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::REMOTE_DID;
+
+    /// A model agent that is running, as started by the concierge
+    fn running_model(restarts: u32) -> Model {
+        let (tx_channel, _) = mpsc::unbounded_channel();
+        Model {
+            tx_channel,
+            handle: tokio::spawn(async {}),
+            status: ModelStatus::Running,
+            restarts,
+        }
+    }
+
+    /// Receives the report of a fake model agent that has failed
+    async fn report_failure(model_name: &str) -> ModelAction {
+        let (to_concierge, mut from_models) = mpsc::unbounded_channel();
+        let model_name = model_name.to_string();
+        tokio::spawn(async move {
+            let _ = to_concierge.send(ModelAction::ReportError {
+                model_name,
+                error: "mediator unavailable".to_string(),
+            });
+        });
+        from_models.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_model_is_restarted() {
+        let mut models = HashMap::from([("model".to_string(), running_model(1))]);
+
+        let follow_up = apply_model_report(&mut models, report_failure("model").await, 3);
+
+        assert_eq!(
+            follow_up,
+            Some(ReportFollowUp::Restart {
+                model_name: "model".to_string(),
+                attempt: 2
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn model_is_left_failed_after_the_restart_limit() {
+        let mut models = HashMap::from([("model".to_string(), running_model(3))]);
+
+        let follow_up = apply_model_report(&mut models, report_failure("model").await, 3);
+
+        assert_eq!(follow_up, None);
+        assert!(matches!(
+            &models["model"].status,
+            ModelStatus::Failed(error) if error == "mediator unavailable"
+        ));
+    }
+
+    #[tokio::test]
+    async fn stop_ends_the_default_models_response_on_the_channel() {
+        let shared_state = SharedState::default();
+        let generation = Arc::new(tokio::sync::Notify::new());
+        shared_state.concierge.lock().await.insert_channel_state(
+            &digest(REMOTE_DID),
+            ChatChannelState {
+                remote_did: REMOTE_DID.to_string(),
+                generation: Some(generation.clone()),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            stop_generation(&shared_state.concierge, REMOTE_DID).await,
+            "Stopped"
+        );
+        tokio::time::timeout(Duration::from_secs(1), generation.notified())
+            .await
+            .unwrap();
+        assert_eq!(
+            stop_generation(&shared_state.concierge, REMOTE_DID).await,
+            "Nothing to stop"
+        );
+    }
+}
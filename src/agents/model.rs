@@ -4,34 +4,66 @@
  * Allows for interaction with a AI model via DIDComm messages
  */
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use crate::{
-    agents::state_management::ChatChannelState,
-    chat_messages::handle_message,
-    didcomm_messages::clear_messages::{clear_inbound_messages, clear_outbound_messages},
+    agents::state_management::{ChatChannelState, SharedStateRef},
+    chat_messages::{handle_message, is_prompt, send_message},
+    didcomm_messages::{
+        MessageTransport,
+        websocket::{ProfileEvent, ReconnectSchedule, activate_profile, reconnect_profile},
+    },
     termination::Interrupted,
 };
-use affinidi_messaging_didcomm::{Message, UnpackMetadata};
+use affinidi_messaging_didcomm::Message;
 use affinidi_messaging_sdk::{ATM, profiles::ATMProfile};
-use anyhow::Result;
+use anyhow::{Result, bail};
+use console::style;
+use ollama_rs::generation::{completion::request::GenerationRequest, parameters::KeepAlive};
 use sha256::digest;
 use tokio::{
     select,
     sync::{
         Mutex,
         mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
     },
-    task::JoinHandle,
+    task::{JoinHandle, JoinSet},
+    time::Instant,
 };
-use tracing::{info, warn};
+use tracing::{Instrument, error, info, info_span, warn};
 
 use super::state_management::OllamaModel;
 
+/// How long a model can go without receiving a message before it reports itself as idle
+const IDLE_REPORT_AFTER: Duration = Duration::from_secs(300);
+
+/// Notice sent to recently active channels when the model shuts down
+const GOING_OFFLINE_NOTICE: &str =
+    "I'm going offline for a little while. Messages sent now will be answered when I'm back.";
+
 /// Model Actions that can be sent to/from Model Task
 #[derive(Debug)]
 pub enum ModelAction {
+    /// Concierge -> Model: Stop the model agent
     Exit,
+    /// Concierge -> Model: Stop accepting prompts, finish in-flight responses within the grace period, then stop
+    Drain { grace_period: Duration },
+    /// Model -> Concierge: The model agent failed and has stopped
+    ReportError { model_name: String, error: String },
+    /// Model -> Concierge: The model agent hasn't received any messages recently
+    ReportIdle { model_name: String },
+    /// Model -> Concierge: The model agent is receiving messages again after being idle
+    ReportActive { model_name: String },
+    /// Model -> Concierge: The model agent has stopped after idle_unload_secs without messages
+    ReportUnloaded { model_name: String },
+    /// Concierge -> Model: Apply reloaded settings, the model's channel state is kept
+    Reload(Box<OllamaModel>),
+    /// Concierge -> Model: Send the text to every channel, replying with the number of channels it was sent to
+    Broadcast {
+        text: String,
+        sent: oneshot::Sender<usize>,
+    },
 }
 
 /// Model Agent
@@ -44,6 +76,8 @@ pub struct ModelAgent {
     to_model_channel: UnboundedReceiver<ModelAction>,
     /// Model info
     model: Arc<Mutex<OllamaModel>>,
+    /// Shared State
+    shared_state: SharedStateRef,
 }
 
 impl ModelAgent {
@@ -54,49 +88,73 @@ impl ModelAgent {
         model: Arc<Mutex<OllamaModel>>,
         to_model_channel: UnboundedReceiver<ModelAction>,
         to_concierge_channel: UnboundedSender<ModelAction>,
+        shared_state: SharedStateRef,
     ) -> Self {
         Self {
             atm,
             concierge_tx: to_concierge_channel,
             to_model_channel,
             model,
+            shared_state,
         }
     }
 
-    pub async fn start(self, profiles: Vec<ATMProfile>) -> Result<JoinHandle<()>> {
+    /// Starts the model agent task
+    /// Refuses to start if the model is missing from the Ollama service
+    /// `clear_queued` - delete messages queued on the mediator, a model woken from idle answers them instead
+    pub async fn start(
+        self,
+        profiles: Vec<ATMProfile>,
+        clear_queued: bool,
+    ) -> Result<JoinHandle<()>> {
+        let model_name = { self.model.lock().await.name.clone() };
+        check_model_available(&*self.model.lock().await).await?;
+
+        let concierge_tx = self.concierge_tx.clone();
         let agent = ModelAgent {
             atm: self.atm.clone(),
             concierge_tx: self.concierge_tx.clone(),
             to_model_channel: self.to_model_channel,
             model: self.model.clone(),
+            shared_state: self.shared_state.clone(),
         };
 
-        let handle = tokio::spawn(async move {
-            let _ = agent.run(profiles).await;
-        });
+        // Not a child of the concierge's span, the model logs to its own file
+        let span = info_span!(parent: None, "agent", name = %model_name);
+        let handle = tokio::spawn(
+            async move {
+                if let Err(e) = agent.run(profiles, clear_queued).await {
+                    error!("Model ({}) failed: {}", model_name, e);
+                    let _ = concierge_tx.send(ModelAction::ReportError {
+                        model_name,
+                        error: e.to_string(),
+                    });
+                }
+            }
+            .instrument(span),
+        );
 
         Ok(handle)
     }
 
     /// Run the Model Agent
-    async fn run(mut self, profiles: Vec<ATMProfile>) -> Result<Interrupted> {
+    async fn run(mut self, profiles: Vec<ATMProfile>, clear_queued: bool) -> Result<Interrupted> {
         let model_name = { self.model.lock().await.name.clone() };
-        let (direct_tx, mut direct_rx) = mpsc::channel::<Box<(Message, UnpackMetadata)>>(32);
+        let (events_tx, mut events_rx) = mpsc::channel::<ProfileEvent>(32);
 
         info!("Model ({}) starting...", model_name);
         let mut activated_profiles: HashMap<String, Arc<ATMProfile>> = HashMap::new();
         for profile in profiles {
-            let model_profile = self.atm.profile_add(&profile, false).await?;
-            activated_profiles.insert(profile.inner.did.clone(), model_profile.clone());
-
-            let _ = clear_inbound_messages(&self.atm, &model_profile).await;
-            let _ = clear_outbound_messages(&self.atm, &model_profile).await;
-
             // Start live streaming
-            self.atm.profile_enable_websocket(&model_profile).await?;
-            model_profile
-                .enable_direct_channel(direct_tx.clone())
-                .await?;
+            let model_profile = activate_profile(
+                &self.atm,
+                &profile,
+                &self.shared_state.mediator_dids,
+                clear_queued.then(|| self.shared_state.fetch_settings()),
+                events_tx.clone(),
+            )
+            .await?;
+            activated_profiles.insert(profile.inner.did.clone(), model_profile);
             info!(
                 "Model ({}) Profile Activated: {}",
                 model_name, profile.inner.did
@@ -104,39 +162,128 @@ impl ModelAgent {
         }
 
         info!("Model ({}) Started", model_name);
+        self.shared_state.readiness.set_model(&model_name, true);
 
+        let mut reconnects = ReconnectSchedule::default();
+        let mut last_activity = Instant::now();
+        let mut idle = false;
+        let mut idle_check = tokio::time::interval(IDLE_REPORT_AFTER / 10);
+        // Messages being handled, and when each remote DID (and the profile it used) was last active
+        let mut tasks = JoinSet::new();
+        let mut channels: HashMap<String, (Arc<ATMProfile>, Instant)> = HashMap::new();
+        let mut prompt_queues = PromptQueues::default();
         let result = loop {
             select! {
                 Some(action) = self.to_model_channel.recv() => match action {
-                ModelAction::Exit => {
-                    info!("Model Exiting...");
+                    ModelAction::Exit => {
+                        info!("Model Exiting...");
+
+                        break Interrupted::UserInt;
+                    },
+                    ModelAction::Drain { grace_period } => {
+                        self.drain(&model_name, tasks, &channels, grace_period).await;
 
-                    break Interrupted::UserInt;
+                        break Interrupted::UserInt;
+                    },
+                    ModelAction::Reload(new_model) => {
+                        let mut model = self.model.lock().await;
+                        let channel_state = std::mem::take(&mut model.channel_state);
+                        *model = OllamaModel { channel_state, ..*new_model };
+                        info!("Model ({}) reloaded", model_name);
+                    },
+                    ModelAction::Broadcast { text, sent } => {
+                        // Sent from its own task so that messages are still handled while broadcasting
+                        let atm = self.atm.clone();
+                        let model = self.model.clone();
+                        let profiles = activated_profiles.clone();
+                        tasks.spawn(async move {
+                            let _ = sent.send(broadcast(&atm, &model, &profiles, &text).await);
+                        }.in_current_span());
+                    },
+                    _ => warn!("Model ({}) received unexpected action: {:?}", model_name, action),
                 },
-            },
-                Some(boxed_data) = direct_rx.recv() => {
-                        let (message, meta) = *boxed_data;
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {},
+                _ = idle_check.tick() => {
+                    if !idle && last_activity.elapsed() >= IDLE_REPORT_AFTER {
+                        idle = true;
+                        let _ = self.concierge_tx.send(ModelAction::ReportIdle { model_name: model_name.clone() });
+                    }
+                    let idle_unload = { self.model.lock().await.idle_unload() };
+                    if let Some(idle_unload) = idle_unload.filter(|after| tasks.is_empty() && last_activity.elapsed() >= *after) {
+                        info!("Model ({}) unloading after {}s without messages", model_name, idle_unload.as_secs());
+                        let model = { self.model.lock().await.clone() };
+                        unload_from_backend(&model).await;
+                        let _ = self.concierge_tx.send(ModelAction::ReportUnloaded { model_name: model_name.clone() });
+
+                        break Interrupted::UserInt;
+                    }
+                },
+                _ = tokio::time::sleep_until(reconnects.next_attempt()), if !reconnects.is_empty() => {
+                    for did in reconnects.due() {
+                        let Some(profile) = activated_profiles.get(&did).cloned() else {
+                            reconnects.succeeded(&did);
+                            continue;
+                        };
+                        match reconnect_profile(&self.atm, &profile, &self.shared_state.mediator_dids, events_tx.clone()).await {
+                            Ok(profile) => {
+                                info!("Model ({}) Profile Reconnected: {}", model_name, did);
+                                activated_profiles.insert(did.clone(), profile);
+                                reconnects.succeeded(&did);
+                            }
+                            Err(e) => {
+                                reconnects.failed(&did, &e);
+                            }
+                        }
+                    }
+                    if reconnects.is_empty() {
+                        self.shared_state.readiness.set_model(&model_name, true);
+                    }
+                },
+                Some(event) = events_rx.recv() => {
+                        let (message, meta) = match event {
+                            ProfileEvent::Message(boxed_data) => *boxed_data,
+                            ProfileEvent::Disconnected(did) => {
+                                warn!("Model ({}) websocket disconnected: {}. Reconnecting...", model_name, did);
+                                self.shared_state.readiness.set_model(&model_name, false);
+                                reconnects.schedule(&did);
+                                continue;
+                            }
+                        };
+
+                        last_activity = Instant::now();
+                        if idle {
+                            idle = false;
+                            let _ = self.concierge_tx.send(ModelAction::ReportActive { model_name: model_name.clone() });
+                        }
 
                         let Some(from_did) = message.from.clone() else {
                             warn!("Received anonymous message, can't reply. Ignoring...");
                             continue;
                         };
                         let from_did_hash = digest(&from_did);
+                        let Some(to_did) = recipient_did(&message) else {
+                            warn!("Received message ({}) with no 'to' DID. Ignoring...", message.id);
+                            continue;
+                        };
 
-                        let model_name = {
+                        let (model_name, prompt) = {
                             let mut model = self.model.lock().await;
-                            if model.channel_state.get_mut(&from_did_hash).is_none() {
+                            match model.channel_state.get_mut(&from_did_hash) {
+                                Some(state) => state.agent_did = Some(to_did.clone()),
+                                None => {
                                     let remote_state = ChatChannelState {
                                         remote_did_hash: from_did_hash.clone(),
                                         remote_did: from_did.clone(),
+                                        agent_did: Some(to_did.clone()),
                                         ..Default::default()
                                     };
                                     model.channel_state.insert(from_did_hash.clone(), remote_state);
+                                }
                             }
-                            model.name.clone()
+                            let agent = model.dids.iter().find(|agent| agent.did == to_did).cloned().unwrap_or_default();
+                            (model.name.clone(), is_prompt(&message, &agent, model.respond_to_reactions))
                         };
 
-                        let to_did = message.to.as_ref().unwrap().first().unwrap().clone();
                         let profile = match activated_profiles.get(&to_did) {
                             Some(profile) => profile,
                             None => {
@@ -145,14 +292,249 @@ impl ModelAgent {
                             }
                         };
 
-                       let _ = handle_message(&self.atm,  profile, &self.model, &model_name, &message).await;
-                       let _ = self.atm.delete_message_background(profile, &meta.sha256_hash).await;
+                        channels.insert(from_did.clone(), (profile.clone(), Instant::now()));
+
+                        // Handled in its own task so that commands (e.g. /stop) are processed while generating
+                        // Prompts wait for earlier prompts on the channel, so they are answered in order
+                        let mut turn = prompt.then(|| prompt_queues.queue(&from_did));
+                        let atm = self.atm.clone();
+                        let profile = profile.clone();
+                        let model = self.model.clone();
+                        let shared_state = self.shared_state.clone();
+                        tasks.spawn(async move {
+                            if let Some(turn) = &mut turn {
+                                turn.wait().await;
+                            }
+                            let _ = handle_message(&atm, &profile, &model, &model_name, &message, &shared_state).await;
+                            let _ = atm.delete_message_background(&profile, &meta.sha256_hash).await;
+                        }.in_current_span());
                 },
             }
         };
 
         info!("{}: Exiting Model Agent", model_name);
+        self.shared_state.readiness.set_model(&model_name, false);
 
         Ok(result)
     }
+
+    /// Waits for in-flight messages to be handled, then tells recently active channels the model is going offline
+    /// No new messages are handled while draining, they remain on the mediator until the model is restarted
+    async fn drain(
+        &self,
+        model_name: &str,
+        mut tasks: JoinSet<()>,
+        channels: &HashMap<String, (Arc<ATMProfile>, Instant)>,
+        grace_period: Duration,
+    ) {
+        info!(
+            "Model ({}) draining ({}) in-flight messages...",
+            model_name,
+            tasks.len()
+        );
+        self.shared_state.readiness.set_model(model_name, false);
+
+        let drained = tokio::time::timeout(grace_period, async {
+            while tasks.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Model ({}) didn't finish ({}) in-flight messages within {}s, abandoning them",
+                model_name,
+                tasks.len(),
+                grace_period.as_secs()
+            );
+            tasks.abort_all();
+        }
+
+        for (remote_did, (profile, last_active)) in channels {
+            if last_active.elapsed() < IDLE_REPORT_AFTER {
+                let _ = send_message(
+                    &self.atm,
+                    profile,
+                    GOING_OFFLINE_NOTICE,
+                    remote_did,
+                    &self.model,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Asks Ollama to release the model's memory, other backends manage their memory themselves
+async fn unload_from_backend(model: &OllamaModel) {
+    if !model.backend.is_ollama() {
+        return;
+    }
+    let request =
+        GenerationRequest::new(model.name.clone(), "").keep_alive(KeepAlive::UnloadOnCompletion);
+    if let Err(e) = model.ollama_client().generate(request).await {
+        warn!("Model ({}): Couldn't unload from Ollama: {}", model.name, e);
+    }
+}
+
+/// Checks that the model exists in the Ollama service it is configured to use
+/// If Ollama can't be reached the check is skipped, requests are retried once the agent is running
+/// Models using other backends aren't checked
+async fn check_model_available(model: &OllamaModel) -> Result<()> {
+    if !model.backend.is_ollama() {
+        return Ok(());
+    }
+    let ollama = model.ollama_client();
+    let local_models = match ollama.list_local_models().await {
+        Ok(local_models) => local_models,
+        Err(e) => {
+            warn!(
+                "Model ({}): Couldn't list models from Ollama ({}:{}): {}",
+                model.name, model.ollama_host, model.ollama_port, e
+            );
+            return Ok(());
+        }
+    };
+
+    // Ollama adds the ":latest" tag when a model is pulled without one
+    if local_models
+        .iter()
+        .any(|m| m.name == model.name || m.name == format!("{}:latest", model.name))
+    {
+        return Ok(());
+    }
+
+    let available = local_models
+        .iter()
+        .map(|m| m.name.clone())
+        .collect::<Vec<String>>()
+        .join(", ");
+    println!(
+        "{}",
+        style(format!(
+            "ERROR: Model ({}) doesn't exist in Ollama ({}:{}). Available models: {}",
+            model.name, model.ollama_host, model.ollama_port, available
+        ))
+        .red()
+    );
+    bail!(
+        "Model ({}) doesn't exist in Ollama ({}:{}). Available models: {}",
+        model.name,
+        model.ollama_host,
+        model.ollama_port,
+        available
+    )
+}
+
+/// Answers prompts on each channel one at a time, in the order they arrive
+#[derive(Default)]
+pub(crate) struct PromptQueues {
+    /// Closed when the last prompt queued from each remote DID has been handled
+    last: HashMap<String, oneshot::Receiver<()>>,
+}
+
+impl PromptQueues {
+    /// Queues a prompt from the remote DID, its turn comes once the prompts queued before it have been handled
+    pub(crate) fn queue(&mut self, remote_did: &str) -> PromptTurn {
+        let (done, done_rx) = oneshot::channel();
+        PromptTurn {
+            previous: self.last.insert(remote_did.to_string(), done_rx),
+            _done: done,
+        }
+    }
+}
+
+/// A queued prompt, the next prompt on the channel waits until this is dropped
+pub(crate) struct PromptTurn {
+    previous: Option<oneshot::Receiver<()>>,
+    _done: oneshot::Sender<()>,
+}
+
+impl PromptTurn {
+    /// Waits until the prompts queued before this one have been handled
+    pub(crate) async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // Closed without a value when the previous prompt's turn is dropped
+            let _ = previous.await;
+        }
+    }
+}
+
+/// Agent DID a received message was sent to, the first of its recipients
+fn recipient_did(message: &Message) -> Option<String> {
+    message.to.as_ref()?.first().cloned()
+}
+
+/// Sends the text to every channel of the model
+/// Channels are sent from the profile of the agent DID they chat with, or any of the model's profiles if it isn't known
+/// Returns the number of channels the text was sent to, channels that fail (e.g. stale DIDs) are skipped
+async fn broadcast(
+    atm: &dyn MessageTransport,
+    model: &Arc<Mutex<OllamaModel>>,
+    profiles: &HashMap<String, Arc<ATMProfile>>,
+    text: &str,
+) -> usize {
+    let channels = {
+        let lock = model.lock().await;
+        lock.channel_state
+            .values()
+            .map(|state| (state.remote_did.clone(), state.agent_did.clone()))
+            .collect::<Vec<(String, Option<String>)>>()
+    };
+
+    let mut sent = 0;
+    for (remote_did, agent_did) in channels {
+        let Some(profile) = agent_did
+            .and_then(|did| profiles.get(&did))
+            .or_else(|| profiles.values().next())
+        else {
+            break;
+        };
+        match send_message(atm, profile, text, &remote_did, model).await {
+            Ok(_) => sent += 1,
+            Err(e) => warn!("Couldn't broadcast to ({}): {}", remote_did, e),
+        }
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AGENT_DID, message_from_remote};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn prompts_on_a_channel_wait_for_earlier_prompts() {
+        let mut queues = PromptQueues::default();
+        let first = queues.queue("did:example:a");
+        let mut second = queues.queue("did:example:a");
+        let mut other_channel = queues.queue("did:example:b");
+
+        // Prompts on other channels don't wait
+        other_channel.wait().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), second.wait())
+                .await
+                .is_err()
+        );
+
+        drop(first);
+        second.wait().await;
+    }
+
+    #[test]
+    fn recipient_did_is_the_first_to_did() {
+        let message = message_from_remote("https://example.com/test", json!({}));
+
+        assert_eq!(recipient_did(&message).as_deref(), Some(AGENT_DID));
+    }
+
+    #[test]
+    fn message_without_a_recipient_has_no_recipient_did() {
+        let mut message = message_from_remote("https://example.com/test", json!({}));
+        message.to = None;
+        assert_eq!(recipient_did(&message), None);
+
+        message.to = Some(Vec::new());
+        assert_eq!(recipient_did(&message), None);
+    }
 }
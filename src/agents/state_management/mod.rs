@@ -2,29 +2,225 @@
  * All things to do with state management
  */
 
-use crate::{DIDMethods, create_did, delete_did_secret};
-use anyhow::{Context, Result};
+use crate::{
+    DIDMethods,
+    audit_log::{AuditLog, DEFAULT_AUDIT_LOG_MAX_BYTES},
+    backends::{self, GenerationStats},
+    create_did, delete_did_secret,
+    didcomm_messages::clear_messages::{DEFAULT_FETCH_BATCH_LIMIT, DeletePolicy, FetchSettings},
+    encryption::{decrypt, encrypt},
+    health::Readiness,
+    message_handlers::MessageHandlers,
+    secrets::SecretsConfig,
+    termination::Interrupted,
+    write_atomic,
+};
+use anyhow::{Context, Result, bail};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use ollama_rs::{
+    Ollama,
+    generation::{
+        options::GenerationOptions,
+        parameters::{KeepAlive, TimeUnit},
+    },
+};
+use reqwest::header::HeaderValue;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, sync::Arc};
-use tokio::sync::Mutex as TokioMutex;
+use sha256::digest;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::Write,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    select,
+    sync::{Mutex as TokioMutex, Notify, OwnedSemaphorePermit, Semaphore, broadcast},
+};
+use tracing::warn;
 
 #[derive(Default)]
 pub struct SharedState {
     /// Ollama models that have been configured
     pub models: Arc<TokioMutex<HashMap<String, Arc<TokioMutex<OllamaModel>>>>>,
-    /// Mediator DID for DIDComm
-    pub mediator_did: String,
+    /// Mediator DIDs for DIDComm in priority order, the first is the primary mediator
+    pub mediator_dids: Vec<String>,
+    /// Mediator DID advertised as the service endpoint of new did:peer DIDs, the primary mediator if not set
+    /// For topologies where messages are delivered through a different mediator than the agents connect to
+    pub service_mediator_did: Option<String>,
+    /// Routing keys added to the mediator service of new did:peer DIDs
+    pub routing_keys: Vec<String>,
     pub concierge: Arc<TokioMutex<ConciergeState>>,
+    /// Backend used to store DID secrets
+    pub secrets: SecretsConfig,
+    /// Number of times a failed model agent is restarted before giving up
+    pub model_restart_limit: Option<u32>,
+    /// Number of failed attempts to reconnect the concierge to the mediator before the bridge exits
+    pub mediator_reconnect_limit: Option<u32>,
+    /// Port to serve Prometheus metrics on, disabled if not set
+    pub metrics_port: Option<u16>,
+    /// Port to serve the health check endpoints on, disabled if not set
+    pub health_port: Option<u16>,
+    /// Time models are given to finish in-flight responses when shutting down (seconds)
+    pub shutdown_grace_period_secs: Option<u64>,
+    /// Maximum number of responses generated at once on each backend host, unlimited if not set
+    /// Shared by all models pointing at the same host
+    pub max_concurrent_generations: Option<usize>,
+    /// Time a prompt waits for a free generation slot before the remote party is told the host is busy (seconds)
+    pub generation_queue_timeout_secs: Option<u64>,
+    /// Directory /export writes conversation transcripts to, defaults to "exports"
+    pub export_dir: Option<String>,
+    /// What happens to messages queued on the mediator when agents connect, they are deleted if not set
+    pub fetch_delete_policy: Option<DeletePolicy>,
+    /// Number of queued messages fetched in each batch when agents connect, defaults to 10
+    pub fetch_batch_limit: Option<usize>,
+    /// JSON lines file each prompt and its response is appended to, nothing is recorded if not set
+    /// See the audit_log module for the privacy implications
+    pub audit_log_path: Option<String>,
+    /// Size the audit log grows to before it is rotated (bytes), defaults to 10MB
+    pub audit_log_max_bytes: Option<u64>,
+    /// Writer for the audit log, started by the binary when audit_log_path is set, not persisted
+    pub audit_log: Option<AuditLog>,
+    /// Delete policy set on the command line (--fetch-delete-policy), used instead of fetch_delete_policy, not persisted
+    pub fetch_delete_policy_override: Option<DeletePolicy>,
+    /// Generation slots in use on each backend host, not persisted
+    pub generation_slots: GenerationSlots,
+    /// Readiness of the running agents, not persisted
+    pub readiness: Readiness,
+    /// Number of new connections each model has presented an agent DID for, not persisted
+    pub agent_rotation: std::sync::Mutex<HashMap<String, u64>>,
+    /// Handlers for messages received by model agents, not persisted
+    /// Register custom handlers before the state is shared with the agents
+    pub message_handlers: MessageHandlers,
+    /// Digest of the configuration file contents last saved, not persisted
+    pub last_saved: std::sync::Mutex<Option<String>>,
+    /// Held while the configuration is saved, so the periodic persist, agent updates and shutdown don't interleave
+    pub save_lock: TokioMutex<()>,
+}
+
+/// Default number of times a failed model agent is restarted
+const DEFAULT_MODEL_RESTART_LIMIT: u32 = 3;
+
+/// Default number of failed mediator reconnect attempts before the bridge exits
+/// Attempts back off to one a minute, so this is roughly half an hour without the mediator
+const DEFAULT_MEDIATOR_RECONNECT_LIMIT: u32 = 30;
+
+/// Default time models are given to finish in-flight responses when shutting down
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How often channel sequence numbers are checked for changes and saved
+const SEQUENCE_PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Passphrase used to encrypt the configuration file, saved unencrypted if not set
+const CONFIG_PASSPHRASE_ENV: &str = "DIDCOMM_AI_BRIDGE_CONFIG_PASSPHRASE";
+
+/// Start of an encrypted configuration file, files without it are read as plain JSON
+const ENCRYPTED_CONFIG_HEADER: &[u8] = b"DIDCOMM-AI-BRIDGE-ENCRYPTED-CONFIG:1\n";
+
+/// Default prefix that marks a chat message as a command
+const DEFAULT_COMMAND_PREFIX: &str = "/";
+
+/// Default system prompt the concierge's greeting model is given
+const DEFAULT_GREETING_PROMPT: &str = "You are {agent_name}, the concierge of a private AI chat service. Write a short, friendly greeting for someone who has just connected. Mention that they can chat with these AI models: {models}, and that /help lists the commands. Reply with the greeting only.";
+
+/// Default reply to an unknown command
+const DEFAULT_UNKNOWN_COMMAND_RESPONSE: &str =
+    "ERROR: unknown command: {command}\nUse {prefix}help to show commands";
+
+/// Temperature Ollama uses when none is set
+pub const DEFAULT_TEMPERATURE: f32 = 0.8;
+
+/// Default share of new connections an agent DID is presented for
+const DEFAULT_AGENT_WEIGHT: u32 = 1;
+
+/// Default directory conversation transcripts are exported to
+const DEFAULT_EXPORT_DIR: &str = "exports";
+
+/// Default time a prompt waits for a free generation slot
+const DEFAULT_GENERATION_QUEUE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits the number of concurrent generations on each backend host
+/// Hosts are keyed by their address (host:port or base URL)
+#[derive(Default)]
+pub struct GenerationSlots {
+    hosts: std::sync::Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl GenerationSlots {
+    /// Semaphore for the host, created with `limit` permits the first time the host is seen
+    fn semaphore(&self, host: &str, limit: usize) -> Arc<Semaphore> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone()
+    }
 }
 
 pub type SharedStateRef = Arc<SharedState>;
 
 /// Holding struct that eases conversion between JSON file and turning into shared state
-#[derive(Default, Deserialize, Serialize)]
+/// Field descriptions are included in the JSON schema printed by --config-schema
+#[derive(Default, Deserialize, Serialize, JsonSchema)]
+#[schemars(description = "didcomm-ai-bridge configuration file (config.json)")]
 pub struct Config {
+    /// Ollama models that have been configured, keyed by model name
     pub models: HashMap<String, OllamaModel>,
-    pub mediator_did: String,
+    /// Mediator DIDs for DIDComm in priority order, the first is the primary mediator
+    pub mediator_dids: Vec<String>,
+    /// Mediator DID advertised as the service endpoint of new did:peer DIDs, the primary mediator if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_mediator_did: Option<String>,
+    /// Routing keys added to the mediator service of new did:peer DIDs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routing_keys: Vec<String>,
     pub concierge: ConciergeState,
+    /// Backend used to store DID secrets
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    /// Number of times a failed model agent is restarted before giving up (default 3)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_restart_limit: Option<u32>,
+    /// Number of failed attempts to reconnect the concierge to the mediator before the bridge exits (default 30)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mediator_reconnect_limit: Option<u32>,
+    /// Port to serve Prometheus metrics on, disabled if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_port: Option<u16>,
+    /// Port to serve the health check endpoints on, disabled if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_port: Option<u16>,
+    /// Time models are given to finish in-flight responses when shutting down (seconds, default 30)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shutdown_grace_period_secs: Option<u64>,
+    /// Maximum number of responses generated at once on each backend host, unlimited if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_generations: Option<usize>,
+    /// Time a prompt waits for a free generation slot before the remote party is told the host is busy (seconds, default 60)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation_queue_timeout_secs: Option<u64>,
+    /// Directory /export writes conversation transcripts to, defaults to "exports"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub export_dir: Option<String>,
+    /// What happens to messages queued on the mediator when agents connect, they are deleted if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_delete_policy: Option<DeletePolicy>,
+    /// Number of queued messages fetched in each batch when agents connect, defaults to 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetch_batch_limit: Option<usize>,
+    /// JSON lines file each prompt and its response is appended to, nothing is recorded if not set
+    /// The file holds every conversation unencrypted, restrict access to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log_path: Option<String>,
+    /// Size the audit log grows to before it is rotated (bytes, default 10MB), 5 rotated files are kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit_log_max_bytes: Option<u64>,
 }
 
 impl Config {
@@ -37,59 +233,745 @@ impl Config {
 
         SharedState {
             models: Arc::new(TokioMutex::new(models)),
-            mediator_did: self.mediator_did,
+            mediator_dids: self.mediator_dids,
+            service_mediator_did: self.service_mediator_did,
+            routing_keys: self.routing_keys,
             concierge: Arc::new(TokioMutex::new(self.concierge)),
+            secrets: self.secrets,
+            model_restart_limit: self.model_restart_limit,
+            mediator_reconnect_limit: self.mediator_reconnect_limit,
+            metrics_port: self.metrics_port,
+            health_port: self.health_port,
+            shutdown_grace_period_secs: self.shutdown_grace_period_secs,
+            max_concurrent_generations: self.max_concurrent_generations,
+            generation_queue_timeout_secs: self.generation_queue_timeout_secs,
+            export_dir: self.export_dir,
+            fetch_delete_policy: self.fetch_delete_policy,
+            fetch_batch_limit: self.fetch_batch_limit,
+            audit_log_path: self.audit_log_path,
+            audit_log_max_bytes: self.audit_log_max_bytes,
+            audit_log: None,
+            fetch_delete_policy_override: None,
+            generation_slots: GenerationSlots::default(),
+            readiness: Readiness::default(),
+            agent_rotation: std::sync::Mutex::default(),
+            message_handlers: MessageHandlers::default(),
+            last_saved: std::sync::Mutex::default(),
+            save_lock: TokioMutex::default(),
         }
     }
 }
 
+/// Author of a turn within a conversation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub enum Role {
+    User,
+    Assistant,
+    /// Tools the model asked to call, the text is the calls as JSON
+    ToolCalls,
+    /// Result of a tool call, returned by the remote party
+    Tool,
+}
+
 // Common state for all Chat Channels
-#[derive(Clone, Default, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct ChatChannelState {
     /// DID of the remote party
     pub remote_did: String,
     /// SHA256 hash of the remote DID
     pub remote_did_hash: String,
+    /// Agent DID the remote party chats with, not set for channels from older configurations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_did: Option<String>,
     /// activitySeqNo - used to show when the agent is thinking/typing
     pub activity_seq_no: u64,
     /// seqNo - used to track the order of messages when sent
     pub seq_no: u64,
+    /// Conversation history that is replayed to the model as context
+    #[serde(default)]
+    pub history: Vec<(Role, String)>,
+    /// Model selected via /model, prompts are routed to this model instead of the agent's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_model: Option<String>,
+    /// Send the model's thinking (reasoning) tokens to the remote party
+    #[serde(default)]
+    pub show_thinking: bool,
+    /// Remote party chats using DIDComm basic messages, responses are sent as basic messages
+    #[serde(default)]
+    pub basic_message: bool,
+    /// Remote party's client can edit messages (supportsEdits in its chat messages)
+    /// Responses are streamed by editing a single message instead of sending each part as a new message
+    #[serde(default)]
+    pub supports_edits: bool,
+    /// Last prompt sent to the model, replayed by /regenerate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_prompt: Option<String>,
+    /// Base64 encoded images attached to the last prompt, replayed with it by /regenerate
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub last_prompt_images: Vec<String>,
+    /// Thread the last prompt was answered in, the regenerated response is sent in the same thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_prompt_thid: Option<String>,
+    /// Responses are requested as JSON (/json), the model's json_format is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_format: Option<bool>,
+    /// Temperature set with /temp, the model's options are used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Stop sequences added with /stop-seq, used with the model's stop sequences
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    /// Language code (e.g. fr, pt-BR) the model is asked to respond in, set with /lang or by the client's locale
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Number of chat messages received on this channel
+    #[serde(default)]
+    pub messages_processed: u64,
+    /// When a message was last received on this channel (seconds since the UNIX epoch)
+    /// Channels from older configurations are treated as seen when the configuration was loaded
+    #[serde(default = "now_secs")]
+    pub last_seen: u64,
+    /// Statistics of the last response on this channel, reported by /last, not persisted
+    #[serde(skip)]
+    pub last_generation: Option<GenerationStats>,
+    /// Limits the rate of prompts from the remote party, not persisted
+    #[serde(skip)]
+    pub rate_limiter: RateLimiter,
+    /// Set while a response is being generated, notified to cancel the generation
+    #[serde(skip)]
+    pub generation: Option<Arc<Notify>>,
+    /// Id of the message being edited while a response is streamed, not persisted
+    #[serde(skip)]
+    pub streaming_message_id: Option<String>,
+    /// Thread of the prompt being answered, replies are sent in it, not persisted
+    #[serde(skip)]
+    pub reply_thid: Option<String>,
+    /// Tool calls sent to the remote party that are waiting for their results, not persisted
+    #[serde(skip)]
+    pub pending_tool_calls: Vec<ToolCallRequest>,
+}
+
+/// Tool a model can ask the remote party to run (function calling)
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct ToolDefinition {
+    pub name: String,
+    /// Tells the model what the tool does and when to call it
+    #[serde(default)]
+    pub description: String,
+    /// JSON schema of the tool's arguments
+    #[serde(default = "default_tool_parameters")]
+    pub parameters: serde_json::Value,
+}
+
+fn default_tool_parameters() -> serde_json::Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// Tool call sent to the remote party, the id is echoed back with its result
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ToolCallRequest {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
 }
 
-#[derive(Clone, Default, Deserialize, Serialize)]
+/// Token bucket limiting how many prompts a remote party can send per minute
+/// The bucket holds up to a minute's worth of tokens and refills continuously
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket
+    /// Returns false if the bucket is empty and the prompt should be throttled
+    pub fn try_acquire(&mut self, per_minute: u32) -> bool {
+        let capacity = per_minute as f64;
+        let now = Instant::now();
+        self.tokens = match self.last_refill {
+            Some(last_refill) => {
+                let refill = now.duration_since(last_refill).as_secs_f64() * capacity / 60.0;
+                (self.tokens + refill).min(capacity)
+            }
+            None => capacity,
+        };
+        self.last_refill = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Last prompt of a channel, as it was received
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LastPrompt {
+    pub text: String,
+    /// Base64 encoded images attached to the prompt
+    pub images: Vec<String>,
+    /// Thread the prompt was answered in
+    pub thid: Option<String>,
+}
+
+impl ChatChannelState {
+    /// Records the prompt being answered, so /regenerate can replay it
+    pub fn set_last_prompt(&mut self, text: &str, images: &[String], thid: Option<&str>) {
+        self.last_prompt = Some(text.to_string());
+        self.last_prompt_images = images.to_vec();
+        self.last_prompt_thid = thid.map(str::to_string);
+    }
+
+    /// Forgets the last prompt, there is nothing to regenerate until the next prompt
+    pub fn clear_last_prompt(&mut self) {
+        self.last_prompt = None;
+        self.last_prompt_images.clear();
+        self.last_prompt_thid = None;
+    }
+
+    /// Removes the last prompt and any response to it from the conversation history
+    /// Returns the prompt, or None if there is no prompt to regenerate
+    pub fn rewind_last_prompt(&mut self) -> Option<LastPrompt> {
+        let prompt = LastPrompt {
+            text: self.last_prompt.take()?,
+            images: std::mem::take(&mut self.last_prompt_images),
+            thid: self.last_prompt_thid.take(),
+        };
+        if let Some(position) = self
+            .history
+            .iter()
+            .rposition(|(role, _)| *role == Role::User)
+        {
+            self.history.truncate(position);
+        }
+        Some(prompt)
+    }
+
+    /// Records that a message was received on this channel
+    pub fn touch(&mut self) {
+        self.last_seen = now_secs();
+    }
+
+    /// Appends a turn to the conversation history
+    /// Oldest turns are dropped once the history exceeds `max_history` entries, the new turn is always kept
+    pub fn push_history(&mut self, role: Role, text: &str, max_history: usize) {
+        self.history.push((role, text.to_string()));
+        let max_history = max_history.max(1);
+        if self.history.len() > max_history {
+            let excess = self.history.len() - max_history;
+            self.history.drain(..excess);
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct ConciergeState {
     /// Concierge Agent DID for DIDComm
     pub agent: DIDCommAgent,
 
     /// Remote Channels State
     pub channel_state: HashMap<String, ChatChannelState>,
+
+    /// Model that answers chat messages sent to the concierge, a canned response is sent if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_model: Option<String>,
+
+    /// Model that writes the greeting sent to new connections
+    /// The agent's static greeting is sent if not set, or if the model can't generate one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub greeting_model: Option<String>,
+
+    /// System prompt the greeting model is given, {models}, {agent_name} and {date} are filled in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub greeting_prompt: Option<String>,
+}
+
+impl ConciergeState {
+    /// System prompt for generating a greeting, with the placeholders filled in
+    /// `models` - names of the configured models
+    pub fn render_greeting_prompt(&self, models: &[String]) -> String {
+        expand_template(
+            self.greeting_prompt
+                .as_deref()
+                .unwrap_or(DEFAULT_GREETING_PROMPT),
+            &[
+                ("models", &models.join(", ")),
+                ("agent_name", &self.agent.name),
+                ("date", &chrono::Local::now().format("%Y-%m-%d").to_string()),
+            ],
+        )
+    }
 }
 
 /// DIDCommAgent represents an agent that can communicate using DIDComm
 /// A model may have many listening agents
-#[derive(Clone, Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct DIDCommAgent {
     pub did: String,
     pub name: String,
+    /// Sent when a connection is made, {model_name}, {agent_name} and {date} are filled in
     pub greeting: String,
+    /// Avatar sent as the vCard photo, a file path or a base64 data URI (data:image/png;base64,...)
     pub image: String,
     pub x_meetingplace_contact_attributes: u8,
     pub x_meetingplace_verification_id: Option<String>,
+    /// Remote DIDs (or their SHA256 hash) permitted to chat with this agent
+    /// If not set, any DID may chat with this agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_dids: Option<Vec<String>>,
+    /// Surname presented on the vCard sent to connecting clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcard_surname: Option<String>,
+    /// Work email presented on the vCard sent to connecting clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcard_email: Option<String>,
+    /// Cell phone number presented on the vCard sent to connecting clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcard_tel: Option<String>,
+    /// Prefix that marks a message as a command (default "/"), commands are disabled if empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_prefix: Option<String>,
+    /// Sent in reply to an unknown command, {command} and {prefix} are filled in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_command_response: Option<String>,
+    /// Share of new connections this DID is presented for, relative to the model's other DIDs (default 1)
+    /// A DID with weight 0 is never presented but still accepts connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// Gzip attachments (e.g. the vCard) when it makes them smaller (default false)
+    /// Only enable for clients that decompress attachments with a `+gzip` media type suffix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress_attachments: Option<bool>,
+}
+
+impl DIDCommAgent {
+    /// Prefix that marks a message as a command, empty if commands are disabled
+    pub fn command_prefix(&self) -> &str {
+        self.command_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_COMMAND_PREFIX)
+    }
+
+    /// If the text is a command, returns it with the prefix replaced by "/" (e.g. "!help" => "/help")
+    pub fn parse_command(&self, text: &str) -> Option<String> {
+        let prefix = self.command_prefix();
+        if prefix.is_empty() {
+            return None;
+        }
+        text.trim()
+            .strip_prefix(prefix)
+            .map(|command| format!("/{}", command))
+    }
+
+    /// Replaces the "/" of each command in the text with the command prefix
+    pub fn render_commands(&self, text: &str) -> String {
+        text.replace(
+            "\n          /",
+            &format!("\n          {}", self.command_prefix()),
+        )
+    }
+
+    /// Reply to an unknown command with the {command} and {prefix} placeholders filled in
+    pub fn render_unknown_command(&self, command: &str) -> String {
+        expand_template(
+            self.unknown_command_response
+                .as_deref()
+                .unwrap_or(DEFAULT_UNKNOWN_COMMAND_RESPONSE),
+            &[("command", command), ("prefix", self.command_prefix())],
+        )
+    }
+
+    /// Share of new connections this DID is presented for
+    pub fn weight(&self) -> u32 {
+        self.weight.unwrap_or(DEFAULT_AGENT_WEIGHT)
+    }
+
+    /// Whether attachments sent by this agent are gzip compressed
+    pub fn compress_attachments(&self) -> bool {
+        self.compress_attachments.unwrap_or_default()
+    }
+
+    /// Checks whether the remote DID is permitted to chat with this agent
+    pub fn is_allowed(&self, remote_did: &str) -> bool {
+        match &self.allowed_dids {
+            Some(allowed_dids) => {
+                let remote_did_hash = digest(remote_did);
+                allowed_dids
+                    .iter()
+                    .any(|did| did == remote_did || *did == remote_did_hash)
+            }
+            None => true,
+        }
+    }
+
+    /// Reads the avatar image, decoding it if it is a data URI
+    pub fn image_data(&self) -> Result<Vec<u8>> {
+        let Some(data_uri) = self.image.strip_prefix("data:") else {
+            return fs::read(&self.image).context(format!("couldn't read image ({})", self.image));
+        };
+        let Some((_, data)) = data_uri
+            .split_once(',')
+            .filter(|(media_type, _)| media_type.ends_with(";base64"))
+        else {
+            bail!("image data URI must be base64 encoded (data:<media type>;base64,<data>)");
+        };
+        BASE64_STANDARD
+            .decode(data.trim())
+            .or_else(|_| BASE64_URL_SAFE_NO_PAD.decode(data.trim()))
+            .context("couldn't decode the image data URI")
+    }
+
+    /// Greeting with the {model_name}, {agent_name} and {date} placeholders filled in
+    pub fn render_greeting(&self, model_name: &str) -> String {
+        expand_template(
+            &self.greeting,
+            &[
+                ("model_name", model_name),
+                ("agent_name", &self.name),
+                ("date", &chrono::Local::now().format("%Y-%m-%d").to_string()),
+            ],
+        )
+    }
+}
+
+/// Replaces `{name}` placeholders in a template with their values
+/// Unknown placeholders and unmatched braces are left as they are
+pub fn expand_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                expanded.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
 }
 
 /// OllamaModel represents a model within the Ollama Service
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct OllamaModel {
     /// Name of the model in Ollama
     pub name: String,
-    /// Hostname of the Ollama service for this model
+    /// Friendly name clients can use instead of the model name (e.g. /model deepseek)
+    /// Agents named after the model are presented with this name on their vCard
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Address of the Ollama service for this model, including the scheme (http:// or https://)
     pub ollama_host: String,
     /// Port of the Ollama service for this model
     pub ollama_port: u16,
+    /// Sent to Ollama as a bearer token, for Ollama services behind an authenticating proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ollama_api_key: Option<String>,
+    /// Further Ollama services (e.g. http://ollama2:11434) tried in order when the ones before can't be reached
+    /// They use the same API key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ollama_fallback_hosts: Vec<String>,
+    /// Service used to generate responses, defaults to the Ollama service above
+    #[serde(default, skip_serializing_if = "Backend::is_ollama")]
+    pub backend: Backend,
     /// DIDs for sending messages to this model
     pub dids: Vec<DIDCommAgent>,
     /// ChannelState for this model
     pub channel_state: HashMap<String, ChatChannelState>,
+    /// Maximum number of turns (prompts and responses) kept as conversation history
+    #[serde(default = "default_max_history")]
+    pub max_history: usize,
+    /// Generation options passed to Ollama, uses Ollama defaults if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+    /// Model accepts image attachments (vision models such as llava)
+    #[serde(default)]
+    pub supports_images: bool,
+    /// Model can generate embeddings (embedding models such as nomic-embed-text)
+    #[serde(default)]
+    pub supports_embeddings: bool,
+    /// Maximum time streamed tokens are buffered before being sent (milliseconds)
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    /// Maximum number of buffered characters before they are sent
+    #[serde(default = "default_flush_chars")]
+    pub flush_chars: usize,
+    /// Time between typing indicators sent while a response is generated (milliseconds)
+    #[serde(default = "default_typing_interval_ms")]
+    pub typing_interval_ms: u64,
+    /// Time between presence updates sent while a response is generated (milliseconds)
+    #[serde(default = "default_presence_interval_ms")]
+    pub presence_interval_ms: u64,
+    /// Responses longer than this (characters) are sent as a text file attachment once the limit is reached,
+    /// if not set responses are always sent as chat messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment_threshold_chars: Option<usize>,
+    /// Number of times a failed request to Ollama is retried
+    #[serde(default = "default_ollama_retries")]
+    pub ollama_retries: u32,
+    /// Delay before the first retry, doubled on each further attempt (milliseconds)
+    #[serde(default = "default_ollama_retry_backoff_ms")]
+    pub ollama_retry_backoff_ms: u64,
+    /// Maximum prompts per minute from each remote party, unlimited if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_per_minute: Option<u32>,
+    /// How long Ollama keeps the model loaded after a reply ("-1" forever, "0" unload, or e.g. "30s", "5m", "1h")
+    /// If not set, the Ollama default is used (5 minutes unless OLLAMA_KEEP_ALIVE is set on the Ollama host)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+    /// System prompt (persona) sent to the model before the conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Prompts older than this (based on the message created_time) are not answered (seconds)
+    /// If not set, prompts are answered regardless of their age
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_message_age_secs: Option<u64>,
+    /// Tell the sender when their prompt was too old to be answered
+    #[serde(default)]
+    pub notify_stale_messages: bool,
+    /// Sent when a prompt has to wait for a free generation slot, a default is used if not set
+    /// Set to an empty string to wait without telling the remote party
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued_response: Option<String>,
+    /// Sent when no generation slot became free in time, a default is used if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub busy_response: Option<String>,
+    /// Ask the model to respond with JSON, chat channels can override this with /json
+    #[serde(default)]
+    pub json_format: bool,
+    /// Longest prompt passed to the model (characters), longer prompts are rejected
+    /// If not set, prompts of any length are passed to the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_prompt_chars: Option<usize>,
+    /// Cut prompts longer than max_prompt_chars down to size, telling the remote party, instead of rejecting them
+    #[serde(default)]
+    pub truncate_long_prompts: bool,
+    /// Answer emoji reactions to the model's messages, otherwise reactions are only acknowledged
+    #[serde(default)]
+    pub respond_to_reactions: bool,
+    /// Stop the model's agent after this long without messages (seconds), disabled if not set
+    /// It is started again once a message for one of its DIDs is waiting on the mediator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_unload_secs: Option<u64>,
+    /// Tools offered to the model, calls are sent to the remote party to run (Ollama backend only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+}
+
+/// Current time in seconds since the UNIX epoch
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn default_max_history() -> usize {
+    20
+}
+
+fn default_flush_interval_ms() -> u64 {
+    2000
+}
+
+fn default_flush_chars() -> usize {
+    500
+}
+
+fn default_typing_interval_ms() -> u64 {
+    3000
+}
+
+fn default_presence_interval_ms() -> u64 {
+    15000
+}
+
+fn default_ollama_retries() -> u32 {
+    3
+}
+
+fn default_ollama_retry_backoff_ms() -> u64 {
+    1000
+}
+
+/// Service used by a model to generate responses
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Backend {
+    /// Ollama chat API, using the model's ollama_host and ollama_port
+    #[default]
+    Ollama,
+    /// OpenAI compatible `/chat/completions` API (vLLM, LM Studio, ...)
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible {
+        /// Base URL of the API including any version prefix, e.g. http://localhost:8000/v1
+        base_url: String,
+        /// Sent as a bearer token if set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        api_key: Option<String>,
+    },
+    /// Streams fixed tokens without a model server, for testing the bridge
+    Scripted {
+        /// Tokens of every response, the last prompt is echoed back if empty
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tokens: Vec<String>,
+    },
+}
+
+impl Backend {
+    pub fn is_ollama(&self) -> bool {
+        matches!(self, Backend::Ollama)
+    }
+}
+
+/// Parses a keep_alive duration: "-1" (forever), "0" (unload after each reply) or a number with an s/m/h unit
+pub fn parse_keep_alive(keep_alive: &str) -> Result<KeepAlive> {
+    let keep_alive = keep_alive.trim();
+    match keep_alive {
+        "-1" => return Ok(KeepAlive::Indefinitely),
+        "0" => return Ok(KeepAlive::UnloadOnCompletion),
+        _ => {}
+    }
+
+    let invalid = || {
+        anyhow::anyhow!(
+            "keep_alive ({}) must be -1, 0 or a duration such as 30s, 5m or 1h",
+            keep_alive
+        )
+    };
+    let unit_index = keep_alive
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (time, unit) = keep_alive.split_at(unit_index);
+    let time = time.parse::<u64>().map_err(|_| invalid())?;
+    match unit {
+        "s" => Ok(KeepAlive::Until {
+            time,
+            unit: TimeUnit::Seconds,
+        }),
+        "m" => Ok(KeepAlive::Until {
+            time,
+            unit: TimeUnit::Minutes,
+        }),
+        // Ollama doesn't accept the "hr" unit that ollama_rs uses for hours
+        "h" => Ok(KeepAlive::Until {
+            time: time.saturating_mul(60),
+            unit: TimeUnit::Minutes,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Tunable generation options for an Ollama model
+/// Any option that isn't set falls back to the Ollama default
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct OllamaOptions {
+    /// Creativity of the model (0.0 - 2.0)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability (0.0 - 1.0)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Limits sampling to the top K tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    /// Size of the context window in tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u64>,
+    /// Penalty applied to repeated tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    /// Generation stops when the model produces any of these sequences, they aren't included in the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+}
+
+impl OllamaOptions {
+    /// Raises the temperature for a more varied response, starting from the Ollama default if not set
+    pub fn raise_temperature(&mut self) {
+        let temperature = self.temperature.unwrap_or(DEFAULT_TEMPERATURE) + 0.2;
+        self.temperature = Some(temperature.min(2.0));
+    }
+
+    /// Checks that each option that is set is within a valid range
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+            bail!("temperature ({}) must be between 0.0 and 2.0", temperature);
+        }
+        if let Some(top_p) = self.top_p.filter(|p| !(0.0..=1.0).contains(p)) {
+            bail!("top_p ({}) must be between 0.0 and 1.0", top_p);
+        }
+        if self.top_k == Some(0) {
+            bail!("top_k must be greater than 0");
+        }
+        if self.num_ctx == Some(0) {
+            bail!("num_ctx must be greater than 0");
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty.filter(|p| *p <= 0.0) {
+            bail!(
+                "repeat_penalty ({}) must be greater than 0.0",
+                repeat_penalty
+            );
+        }
+        if self
+            .stop
+            .iter()
+            .flatten()
+            .any(|sequence| sequence.is_empty())
+        {
+            bail!("stop sequences must not be empty");
+        }
+
+        Ok(())
+    }
+
+    /// Converts to the ollama_rs GenerationOptions
+    pub fn to_generation_options(&self) -> GenerationOptions {
+        let mut options = GenerationOptions::default();
+        if let Some(temperature) = self.temperature {
+            options = options.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            options = options.top_p(top_p);
+        }
+        if let Some(top_k) = self.top_k {
+            options = options.top_k(top_k);
+        }
+        if let Some(num_ctx) = self.num_ctx {
+            options = options.num_ctx(num_ctx);
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            options = options.repeat_penalty(repeat_penalty);
+        }
+        if let Some(stop) = self.stop.clone().filter(|stop| !stop.is_empty()) {
+            options = options.stop(stop);
+        }
+        options
+    }
+}
+
+/// Only the name is shown, the model holds API keys and conversations
+impl std::fmt::Debug for OllamaModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OllamaModel")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
 }
 
 impl OllamaModel {
@@ -97,40 +979,310 @@ impl OllamaModel {
         ollama_host: String,
         ollama_port: u16,
         mediator_did: &str,
+        routing_keys: &[String],
         model_name: &str,
         did_method: &DIDMethods,
     ) -> Result<Self> {
         Ok(Self {
             name: model_name.into(),
+            alias: None,
             ollama_host,
             ollama_port,
+            ollama_api_key: None,
+            ollama_fallback_hosts: Vec::new(),
+            backend: Backend::default(),
             dids: vec![DIDCommAgent {
-                did: create_did(did_method, mediator_did)?,
+                did: create_did(did_method, mediator_did, routing_keys)?,
                 greeting: "Standard Greeting".into(),
                 image: "deepseek.png".into(),
                 name: model_name.into(),
                 x_meetingplace_contact_attributes: 8,
                 x_meetingplace_verification_id: None,
+                allowed_dids: None,
+                vcard_surname: None,
+                vcard_email: None,
+                vcard_tel: None,
+                command_prefix: None,
+                unknown_command_response: None,
+                weight: None,
+                compress_attachments: None,
             }],
             channel_state: HashMap::new(),
+            max_history: default_max_history(),
+            options: None,
+            supports_images: false,
+            supports_embeddings: false,
+            flush_interval_ms: default_flush_interval_ms(),
+            flush_chars: default_flush_chars(),
+            typing_interval_ms: default_typing_interval_ms(),
+            presence_interval_ms: default_presence_interval_ms(),
+            attachment_threshold_chars: None,
+            ollama_retries: default_ollama_retries(),
+            ollama_retry_backoff_ms: default_ollama_retry_backoff_ms(),
+            rate_limit_per_minute: None,
+            keep_alive: None,
+            system_prompt: None,
+            max_message_age_secs: None,
+            notify_stale_messages: false,
+            queued_response: None,
+            busy_response: None,
+            json_format: false,
+            max_prompt_chars: None,
+            truncate_long_prompts: false,
+            respond_to_reactions: false,
+            idle_unload_secs: None,
+            tools: Vec::new(),
         })
     }
+
+    /// Address of the service generating responses, for display
+    pub fn backend_address(&self) -> String {
+        match &self.backend {
+            Backend::Ollama => format!("{}:{}", self.ollama_host, self.ollama_port),
+            Backend::OpenAiCompatible { base_url, .. } => base_url.clone(),
+            Backend::Scripted { .. } => "scripted".to_string(),
+        }
+    }
+
+    /// Time without messages after which the model's agent is stopped, if idle unloading is enabled
+    pub fn idle_unload(&self) -> Option<Duration> {
+        self.idle_unload_secs.map(Duration::from_secs)
+    }
+
+    /// Name presented to clients, the alias if one is set
+    pub fn display_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Client for the model's Ollama service
+    pub fn ollama_client(&self) -> Ollama {
+        backends::ollama::client(
+            &self.ollama_host,
+            self.ollama_port,
+            self.ollama_api_key.as_deref(),
+        )
+    }
+
+    /// Clients for the model's Ollama services with their addresses, in the order they are tried
+    pub fn ollama_clients(&self) -> Vec<(String, Ollama)> {
+        let api_key = self.ollama_api_key.as_deref();
+        let mut clients = vec![(
+            format!("{}:{}", self.ollama_host, self.ollama_port),
+            self.ollama_client(),
+        )];
+        // Validated when the config is loaded
+        clients.extend(self.ollama_fallback_hosts.iter().filter_map(|address| {
+            let (host, port) = backends::ollama::parse_address(address).ok()?;
+            Some((
+                address.clone(),
+                backends::ollama::client(&host, port, api_key),
+            ))
+        }));
+        clients
+    }
+
+    /// Checks the model configuration is valid
+    pub fn validate(&self) -> Result<()> {
+        if self.dids.is_empty() {
+            bail!("at least one DID must be configured in dids");
+        }
+        if self.backend.is_ollama()
+            && !self.ollama_host.starts_with("http://")
+            && !self.ollama_host.starts_with("https://")
+        {
+            bail!(
+                "ollama_host ({}) must start with http:// or https://",
+                self.ollama_host
+            );
+        }
+        for address in &self.ollama_fallback_hosts {
+            backends::ollama::parse_address(address)
+                .with_context(|| format!("invalid ollama_fallback_hosts address ({})", address))?;
+        }
+        if self
+            .ollama_api_key
+            .as_deref()
+            .is_some_and(|api_key| HeaderValue::from_str(api_key).is_err())
+        {
+            bail!("ollama_api_key contains characters that can't be sent in an HTTP header");
+        }
+        if let Some(options) = &self.options {
+            options.validate()?;
+        }
+        if self.max_history == 0 {
+            bail!("max_history must be greater than 0");
+        }
+        if self.flush_interval_ms == 0 {
+            bail!("flush_interval_ms must be greater than 0");
+        }
+        if self.flush_chars == 0 {
+            bail!("flush_chars must be greater than 0");
+        }
+        if self.typing_interval_ms == 0 {
+            bail!("typing_interval_ms must be greater than 0");
+        }
+        if self.presence_interval_ms == 0 {
+            bail!("presence_interval_ms must be greater than 0");
+        }
+        if self.attachment_threshold_chars == Some(0) {
+            bail!("attachment_threshold_chars must be greater than 0");
+        }
+        if self.rate_limit_per_minute == Some(0) {
+            bail!("rate_limit_per_minute must be greater than 0");
+        }
+        if !self.tools.is_empty() && !self.backend.is_ollama() {
+            bail!("tools are only supported with the Ollama backend");
+        }
+        let mut tool_names = HashSet::new();
+        for tool in &self.tools {
+            if tool.name.trim().is_empty() {
+                bail!("tool names must not be empty");
+            }
+            if !tool_names.insert(tool.name.as_str()) {
+                bail!("tool ({}) is defined more than once", tool.name);
+            }
+            if !tool.parameters.is_object() {
+                bail!(
+                    "parameters of tool ({}) must be a JSON schema object",
+                    tool.name
+                );
+            }
+        }
+        if self.idle_unload_secs == Some(0) {
+            bail!("idle_unload_secs must be greater than 0");
+        }
+        if self.max_prompt_chars == Some(0) {
+            bail!("max_prompt_chars must be greater than 0");
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            parse_keep_alive(keep_alive)?;
+        }
+        if let Backend::OpenAiCompatible { base_url, .. } = &self.backend
+            && !base_url.starts_with("http://")
+            && !base_url.starts_with("https://")
+        {
+            bail!("backend base_url ({}) must be a http(s) URL", base_url);
+        }
+        if self.max_message_age_secs == Some(0) {
+            bail!("max_message_age_secs must be greater than 0");
+        }
+
+        Ok(())
+    }
 }
 
 impl SharedState {
     pub fn load(config_file: &str) -> Result<Self> {
-        let contents = fs::read_to_string(config_file).context(format!(
+        let contents = fs::read(config_file).context(format!(
             "Couldn't open configuration file ({})",
             config_file
         ))?;
+        let contents = match contents.strip_prefix(ENCRYPTED_CONFIG_HEADER) {
+            Some(encrypted) => {
+                let passphrase = env::var(CONFIG_PASSPHRASE_ENV).context(format!(
+                    "Configuration file ({}) is encrypted, {} must be set",
+                    config_file, CONFIG_PASSPHRASE_ENV
+                ))?;
+                decrypt(&passphrase, encrypted).context(format!(
+                    "Couldn't decrypt configuration file ({})",
+                    config_file
+                ))?
+            }
+            None => contents,
+        };
+        let contents = String::from_utf8(contents).context(format!(
+            "Configuration file ({}) isn't valid UTF-8",
+            config_file
+        ))?;
+
+        let mut config: serde_json::Value = serde_json::from_str(&contents)
+            .map_err(anyhow::Error::msg)
+            .context(format!(
+                "Parse error on configuration file ({})",
+                config_file
+            ))?;
+        if migrate_legacy_mediator(&mut config) {
+            warn!(
+                "Configuration file ({}) uses the legacy single mediator format, it will be upgraded when saved",
+                config_file
+            );
+        }
+        if migrate_legacy_models(&mut config) {
+            warn!(
+                "Configuration file ({}) uses the legacy single DID model format, it will be upgraded when saved",
+                config_file
+            );
+        }
 
-        let config: Config = serde_json::from_str(&contents)
+        let config: Config = serde_json::from_value(config)
             .map_err(anyhow::Error::msg)
             .context(format!(
                 "Parse error on configuration file ({})",
                 config_file
             ))?;
 
+        if config.mediator_dids.is_empty() {
+            bail!(
+                "Configuration file ({}) has no mediator_dids configured",
+                config_file
+            );
+        }
+
+        if let Some(did) = config
+            .service_mediator_did
+            .as_ref()
+            .filter(|did| !did.starts_with("did:"))
+        {
+            bail!(
+                "Configuration file ({}) has an invalid service_mediator_did ({}), it must be a DID",
+                config_file,
+                did
+            );
+        }
+
+        if config.fetch_batch_limit == Some(0) {
+            bail!(
+                "Configuration file ({}) has fetch_batch_limit set to 0, it must be greater than 0",
+                config_file
+            );
+        }
+
+        if config.audit_log_max_bytes == Some(0) {
+            bail!(
+                "Configuration file ({}) has audit_log_max_bytes set to 0, it must be greater than 0",
+                config_file
+            );
+        }
+
+        if config.max_concurrent_generations == Some(0) {
+            bail!(
+                "Configuration file ({}) has max_concurrent_generations set to 0, no responses could be generated",
+                config_file
+            );
+        }
+
+        for (name, model) in &config.models {
+            model
+                .validate()
+                .context(format!("Invalid configuration for model ({})", name))?;
+        }
+        validate_aliases(&config.models).context(format!(
+            "Configuration file ({}) has invalid model aliases",
+            config_file
+        ))?;
+
+        // A broken avatar doesn't stop an agent working, it is only left off the vCard
+        for agent in std::iter::once(&config.concierge.agent)
+            .chain(config.models.values().flat_map(|model| &model.dids))
+        {
+            if let Err(e) = agent.image_data() {
+                warn!(
+                    "Agent ({}): vCards will be sent without a photo: {:#}",
+                    agent.name, e
+                );
+            }
+        }
+
         Ok(config.from_config())
     }
 
@@ -144,23 +1296,366 @@ impl SharedState {
 
         Ok(Config {
             models: new_models,
-            mediator_did: self.mediator_did.clone(),
+            mediator_dids: self.mediator_dids.clone(),
+            service_mediator_did: self.service_mediator_did.clone(),
+            routing_keys: self.routing_keys.clone(),
             concierge: self.concierge.lock().await.clone(),
+            secrets: self.secrets.clone(),
+            model_restart_limit: self.model_restart_limit,
+            mediator_reconnect_limit: self.mediator_reconnect_limit,
+            metrics_port: self.metrics_port,
+            health_port: self.health_port,
+            shutdown_grace_period_secs: self.shutdown_grace_period_secs,
+            max_concurrent_generations: self.max_concurrent_generations,
+            generation_queue_timeout_secs: self.generation_queue_timeout_secs,
+            export_dir: self.export_dir.clone(),
+            fetch_delete_policy: self.fetch_delete_policy,
+            fetch_batch_limit: self.fetch_batch_limit,
+            audit_log_path: self.audit_log_path.clone(),
+            audit_log_max_bytes: self.audit_log_max_bytes,
         })
     }
 
+    /// Primary mediator DID, agents connect to it first
+    pub fn mediator_did(&self) -> &str {
+        self.mediator_dids
+            .first()
+            .map(|did| did.as_str())
+            .unwrap_or_default()
+    }
+
+    /// How messages queued on the mediator are cleared when agents connect
+    pub fn fetch_settings(&self) -> FetchSettings {
+        FetchSettings {
+            delete_policy: self
+                .fetch_delete_policy_override
+                .or(self.fetch_delete_policy)
+                .unwrap_or_default(),
+            batch_limit: self.fetch_batch_limit.unwrap_or(DEFAULT_FETCH_BATCH_LIMIT),
+        }
+    }
+
+    /// Size the audit log grows to before it is rotated
+    pub fn audit_log_max_bytes(&self) -> u64 {
+        self.audit_log_max_bytes
+            .unwrap_or(DEFAULT_AUDIT_LOG_MAX_BYTES)
+    }
+
+    /// Mediator DID used as the service endpoint for new DIDs, the primary mediator unless service_mediator_did is set
+    pub fn service_mediator_did(&self) -> &str {
+        self.service_mediator_did
+            .as_deref()
+            .unwrap_or_else(|| self.mediator_did())
+    }
+
+    /// All agent DIDs in the configuration (concierge and models)
+    pub async fn dids(&self) -> Vec<String> {
+        let mut dids = vec![self.concierge.lock().await.agent.did.clone()];
+        let models = { self.models.lock().await.clone() };
+        for model in models.values() {
+            dids.extend(
+                model
+                    .lock()
+                    .await
+                    .dids
+                    .iter()
+                    .map(|agent| agent.did.clone()),
+            );
+        }
+        dids
+    }
+
+    /// Number of times a failed model agent is restarted before giving up
+    pub fn model_restart_limit(&self) -> u32 {
+        self.model_restart_limit
+            .unwrap_or(DEFAULT_MODEL_RESTART_LIMIT)
+    }
+
+    /// Number of failed attempts to reconnect the concierge to the mediator before the bridge exits
+    pub fn mediator_reconnect_limit(&self) -> u32 {
+        self.mediator_reconnect_limit
+            .unwrap_or(DEFAULT_MEDIATOR_RECONNECT_LIMIT)
+    }
+
+    /// Waits for a free generation slot on the backend host
+    /// `queued` is run if every slot is in use, before waiting (e.g. to tell the remote party they are queued)
+    /// Returns None if generations aren't limited, the slot is released when the permit is dropped
+    /// Errors if no slot became free within the queue timeout
+    pub async fn acquire_generation_slot(
+        &self,
+        host: &str,
+        queued: impl Future<Output = ()>,
+    ) -> Result<Option<OwnedSemaphorePermit>> {
+        let Some(limit) = self.max_concurrent_generations else {
+            return Ok(None);
+        };
+        let timeout = self
+            .generation_queue_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_GENERATION_QUEUE_TIMEOUT);
+
+        let semaphore = self.generation_slots.semaphore(host, limit);
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        queued.await;
+
+        match tokio::time::timeout(timeout, semaphore.acquire_owned()).await {
+            Ok(permit) => Ok(Some(permit?)),
+            Err(_) => bail!(
+                "no generation slot free on ({}) after {}s",
+                host,
+                timeout.as_secs()
+            ),
+        }
+    }
+
+    /// Removes channels (concierge and models) that haven't received a message within `max_age`
+    /// Channels with a response being generated are kept
+    /// Returns the number of channels removed
+    pub async fn prune_channels(&self, max_age: Duration) -> usize {
+        let cutoff = now_secs().saturating_sub(max_age.as_secs());
+        let keep =
+            |state: &ChatChannelState| state.last_seen >= cutoff || state.generation.is_some();
+
+        let mut pruned = 0;
+        {
+            let mut concierge = self.concierge.lock().await;
+            let before = concierge.channel_state.len();
+            concierge.channel_state.retain(|_, state| keep(state));
+            pruned += before - concierge.channel_state.len();
+        }
+
+        let models = { self.models.lock().await.clone() };
+        for model in models.values() {
+            let mut model = model.lock().await;
+            let before = model.channel_state.len();
+            model.channel_state.retain(|_, state| keep(state));
+            pruned += before - model.channel_state.len();
+        }
+
+        pruned
+    }
+
+    /// Agent to present for a new connection to the model
+    /// Rotates through the model's DIDs, each presented for its weight's share of connections in turn
+    /// (e.g. weights 2 and 1 present A, A, B, A, A, B, ...)
+    pub fn next_agent(&self, model: &OllamaModel) -> Option<DIDCommAgent> {
+        let total = model
+            .dids
+            .iter()
+            .map(|agent| agent.weight() as u64)
+            .sum::<u64>();
+        if total == 0 {
+            return model.dids.first().cloned();
+        }
+
+        let mut position = {
+            let mut rotation = self
+                .agent_rotation
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            let cursor = rotation.entry(model.name.clone()).or_default();
+            let position = *cursor % total;
+            *cursor = cursor.wrapping_add(1);
+            position
+        };
+        model
+            .dids
+            .iter()
+            .find(|agent| {
+                let weight = agent.weight() as u64;
+                if position < weight {
+                    return true;
+                }
+                position -= weight;
+                false
+            })
+            .cloned()
+    }
+
+    /// Directory conversation transcripts are exported to
+    pub fn export_dir(&self) -> &str {
+        self.export_dir.as_deref().unwrap_or(DEFAULT_EXPORT_DIR)
+    }
+
+    /// Writes the conversation history of a channel, with its metadata, to a new file
+    /// The format is taken from the extension: JSON (.json) or Markdown (.md)
+    /// Existing files are never overwritten
+    ///
+    /// The transcript holds everything the remote party said to the model and their DID, treat it as
+    /// personal data: store it securely and delete it when it is no longer needed
+    /// Returns the number of history entries exported
+    pub async fn export_channel(&self, did_hash: &str, path: &Path) -> Result<usize> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => ExportFormat::Json,
+            Some("md") => ExportFormat::Markdown,
+            _ => bail!(
+                "export file ({}) must end with .json or .md",
+                path.display()
+            ),
+        };
+
+        let mut found = None;
+        let models = { self.models.lock().await.clone() };
+        for (model_name, model) in models {
+            if let Some(state) = model.lock().await.channel_state.get(did_hash) {
+                found = Some((model_name, state.clone()));
+                break;
+            }
+        }
+        let Some((model_name, state)) = found else {
+            bail!("no chat channel found for ({})", did_hash);
+        };
+
+        let contents = match format {
+            ExportFormat::Json => {
+                serde_json::to_string_pretty(&ChannelExport::new(&model_name, &state))?
+            }
+            ExportFormat::Markdown => ChannelExport::new(&model_name, &state).to_markdown(),
+        };
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context(format!("Couldn't create export file ({})", path.display()))?;
+        file.write_all(contents.as_bytes())
+            .context(format!("Couldn't write export file ({})", path.display()))?;
+
+        Ok(state.history.len())
+    }
+
+    /// Time models are given to finish in-flight responses when shutting down
+    pub fn shutdown_grace_period(&self) -> Duration {
+        self.shutdown_grace_period_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD)
+    }
+
     /// Save the configuration to the specified file
+    /// Encrypting and writing the file happens on a blocking thread so the agents aren't held up.
+    /// The file is replaced atomically, and saves run one at a time so a stale snapshot never overwrites a newer one.
     pub async fn save(&self, config_file: &str) -> Result<()> {
+        let _saving = self.save_lock.lock().await;
         let contents = serde_json::to_string_pretty(&self.to_config().await?)
             .context("Couldn't serialize configuration")?;
-        fs::write(config_file, contents).context(format!(
-            "Couldn't write configuration file ({})",
-            config_file
-        ))
+        let path = config_file.to_string();
+        let contents = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let contents = match env::var(CONFIG_PASSPHRASE_ENV) {
+                Ok(passphrase) => [
+                    ENCRYPTED_CONFIG_HEADER,
+                    &encrypt(&passphrase, contents.as_bytes())?,
+                ]
+                .concat(),
+                Err(_) => contents.into_bytes(),
+            };
+            write_atomic(Path::new(&path), &contents, None)
+                .context(format!("Couldn't write configuration file ({})", path))?;
+            Ok(contents)
+        })
+        .await??;
+
+        if let Ok(mut last_saved) = self.last_saved.lock() {
+            *last_saved = Some(digest(contents.as_slice()));
+        }
+        Ok(())
+    }
+
+    /// True if the contents are what this process last saved, so changes to the file can be told apart from our own writes
+    pub fn is_own_write(&self, contents: &[u8]) -> bool {
+        self.last_saved
+            .lock()
+            .is_ok_and(|last_saved| last_saved.as_deref() == Some(digest(contents).as_str()))
+    }
+
+    /// Changes whenever a message is sent on any channel, or channels are added or removed
+    async fn sequence_fingerprint(&self) -> u64 {
+        let fingerprint = |channels: &HashMap<String, ChatChannelState>| {
+            channels.values().fold(channels.len() as u64, |sum, state| {
+                sum.wrapping_add(state.seq_no)
+                    .wrapping_add(state.activity_seq_no)
+            })
+        };
+
+        let mut sum = fingerprint(&self.concierge.lock().await.channel_state);
+        let models = { self.models.lock().await.clone() };
+        for model in models.values() {
+            sum = sum.wrapping_add(fingerprint(&model.lock().await.channel_state));
+        }
+        sum
+    }
+
+    /// Applies a reloaded configuration without disturbing live connections
+    /// Agent settings (greetings, system prompts, options etc) are updated in place, channel state is kept.
+    /// Models whose DIDs changed are both removed and added so that their agents are restarted.
+    /// Settings that are only read at startup (mediators, secrets, ports etc) need a restart to take effect.
+    pub async fn apply_config(&self, config: SharedState) -> ConfigChanges {
+        if let (Ok(current), Ok(new)) = (self.to_config().await, config.to_config().await)
+            && startup_settings(current) != startup_settings(new)
+        {
+            warn!("Configuration changes outside of models and the concierge agent need a restart");
+        }
+
+        {
+            let new_agent = config.concierge.lock().await.agent.clone();
+            let mut concierge = self.concierge.lock().await;
+            if concierge.agent.did != new_agent.did {
+                warn!(
+                    "Changing the concierge DID needs a restart, keeping ({})",
+                    concierge.agent.did
+                );
+            }
+            concierge.agent = DIDCommAgent {
+                did: concierge.agent.did.clone(),
+                ..new_agent
+            };
+        }
+
+        let new_models = { config.models.lock().await.clone() };
+        let mut models = self.models.lock().await;
+        let mut changes = ConfigChanges::default();
+
+        let removed = models
+            .keys()
+            .filter(|name| !new_models.contains_key(*name))
+            .cloned()
+            .collect::<Vec<String>>();
+        for name in removed {
+            // Secrets are kept, the model may only be disabled for now
+            models.remove(&name);
+            changes.removed.push(name);
+        }
+
+        for (name, new_model) in new_models {
+            let mut new_model = new_model.lock().await.clone();
+            match models.get(&name) {
+                Some(model) => {
+                    let mut model = model.lock().await;
+                    new_model.channel_state = std::mem::take(&mut model.channel_state);
+                    let dids_changed = !model
+                        .dids
+                        .iter()
+                        .map(|agent| &agent.did)
+                        .eq(new_model.dids.iter().map(|agent| &agent.did));
+                    *model = new_model;
+                    if dids_changed {
+                        changes.removed.push(name.clone());
+                        changes.added.push(name);
+                    }
+                }
+                None => {
+                    models.insert(name.clone(), Arc::new(TokioMutex::new(new_model)));
+                    changes.added.push(name);
+                }
+            }
+        }
+
+        changes
     }
 
     /// Add a Ollama model to the shared state
-    pub async fn add_model(&mut self, name: &str, model: OllamaModel) {
+    pub async fn add_model(&self, name: &str, model: OllamaModel) {
         self.models
             .lock()
             .await
@@ -168,7 +1663,7 @@ impl SharedState {
     }
 
     /// Remove a Ollama model from the shared state
-    pub async fn remove_model(&mut self, model_name: &str) {
+    pub async fn remove_model(&self, model_name: &str) {
         if let Some(model) = self.models.lock().await.remove(model_name) {
             // Clean up secret keys
             let lock = model.lock().await;
@@ -179,6 +1674,224 @@ impl SharedState {
     }
 }
 
+/// Saves the configuration whenever channel sequence numbers change, checking every few seconds
+/// Clients use the sequence numbers to order messages, so they must keep increasing if the bridge crashes
+pub async fn persist_sequence_numbers(
+    shared_state: SharedStateRef,
+    config_file: String,
+    mut interrupt_rx: broadcast::Receiver<Interrupted>,
+) {
+    let mut interval = tokio::time::interval(SEQUENCE_PERSIST_INTERVAL);
+    let mut persisted = shared_state.sequence_fingerprint().await;
+    loop {
+        select! {
+            _ = interval.tick() => {
+                let fingerprint = shared_state.sequence_fingerprint().await;
+                if fingerprint == persisted {
+                    continue;
+                }
+                match shared_state.save(&config_file).await {
+                    Ok(_) => persisted = fingerprint,
+                    Err(e) => warn!("Couldn't persist sequence numbers: {:#}", e),
+                }
+            }
+            _ = interrupt_rx.recv() => break,
+        }
+    }
+}
+
+/// Models added and removed by a reloaded configuration
+#[derive(Default)]
+pub struct ConfigChanges {
+    /// Models whose agents need to be started
+    pub added: Vec<String>,
+    /// Models whose agents need to be stopped
+    pub removed: Vec<String>,
+}
+
+/// Settings of a configuration that are only read at startup, for comparison
+fn startup_settings(mut config: Config) -> serde_json::Value {
+    config.models.clear();
+    config.concierge = ConciergeState::default();
+    serde_json::to_value(config).unwrap_or_default()
+}
+
+/// Checks each alias identifies a single model: aliases must be unique (ignoring case) and can't be another
+/// model's name
+fn validate_aliases(models: &HashMap<String, OllamaModel>) -> Result<()> {
+    let mut aliases: HashMap<String, &str> = HashMap::new();
+    for (name, model) in models {
+        let Some(alias) = &model.alias else {
+            continue;
+        };
+        if alias.trim().is_empty() {
+            bail!("model ({}) has an empty alias", name);
+        }
+        if let Some(other) = models
+            .keys()
+            .find(|other| *other != name && *other == alias)
+        {
+            bail!(
+                "alias ({}) of model ({}) is the name of model ({})",
+                alias,
+                name,
+                other
+            );
+        }
+        if let Some(other) = aliases.insert(alias.to_lowercase(), name) {
+            bail!(
+                "alias ({}) is used by models ({}) and ({})",
+                alias,
+                other,
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// File format of a channel export
+enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+/// Transcript of a chat channel written by /export
+#[derive(Serialize)]
+struct ChannelExport<'a> {
+    model: &'a str,
+    remote_did: &'a str,
+    remote_did_hash: &'a str,
+    agent_did: Option<&'a str>,
+    seq_no: u64,
+    activity_seq_no: u64,
+    messages_processed: u64,
+    /// RFC 3339 timestamps
+    last_seen: String,
+    exported_at: String,
+    history: Vec<ExportedTurn<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportedTurn<'a> {
+    role: Role,
+    text: &'a str,
+}
+
+impl<'a> ChannelExport<'a> {
+    fn new(model: &'a str, state: &'a ChatChannelState) -> Self {
+        let timestamp = |secs: u64| {
+            chrono::DateTime::from_timestamp(secs as i64, 0)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default()
+        };
+        Self {
+            model,
+            remote_did: &state.remote_did,
+            remote_did_hash: &state.remote_did_hash,
+            agent_did: state.agent_did.as_deref(),
+            seq_no: state.seq_no,
+            activity_seq_no: state.activity_seq_no,
+            messages_processed: state.messages_processed,
+            last_seen: timestamp(state.last_seen),
+            exported_at: timestamp(now_secs()),
+            history: state
+                .history
+                .iter()
+                .map(|(role, text)| ExportedTurn { role: *role, text })
+                .collect(),
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut markdown = format!(
+            "# Conversation with {}\n\n- Model: {}\n- Remote DID hash: {}\n- Agent DID: {}\n- seq_no: {}\n- activity_seq_no: {}\n- Messages processed: {}\n- Last seen: {}\n- Exported at: {}\n",
+            self.remote_did,
+            self.model,
+            self.remote_did_hash,
+            self.agent_did.unwrap_or("unknown"),
+            self.seq_no,
+            self.activity_seq_no,
+            self.messages_processed,
+            self.last_seen,
+            self.exported_at
+        );
+        for turn in &self.history {
+            let author = match turn.role {
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+                Role::ToolCalls => "Tool calls",
+                Role::Tool => "Tool result",
+            };
+            markdown.push_str(&format!("\n## {}\n\n{}\n", author, turn.text));
+        }
+        markdown
+    }
+}
+
+/// Converts the legacy single `mediator_did` field into the `mediator_dids` list
+/// Returns true if the mediator was migrated
+fn migrate_legacy_mediator(config: &mut serde_json::Value) -> bool {
+    let Some(config) = config
+        .as_object_mut()
+        .filter(|c| !c.contains_key("mediator_dids"))
+    else {
+        return false;
+    };
+    let Some(mediator_did) = config.remove("mediator_did") else {
+        return false;
+    };
+
+    config.insert("mediator_dids".into(), serde_json::json!([mediator_did]));
+    true
+}
+
+/// Converts models using the legacy single `did` field into the `dids` list of agents
+/// The agent is given the same defaults as a newly created model
+/// Returns true if any model was migrated
+fn migrate_legacy_models(config: &mut serde_json::Value) -> bool {
+    let Some(models) = config
+        .get_mut("models")
+        .and_then(|models| models.as_object_mut())
+    else {
+        return false;
+    };
+
+    let mut migrated = false;
+    for (name, model) in models.iter_mut() {
+        let Some(model) = model.as_object_mut().filter(|m| !m.contains_key("dids")) else {
+            continue;
+        };
+        let Some(did) = model.remove("did") else {
+            continue;
+        };
+
+        let agent = DIDCommAgent {
+            did: did.as_str().unwrap_or_default().to_string(),
+            name: name.clone(),
+            greeting: "Standard Greeting".into(),
+            image: "deepseek.png".into(),
+            x_meetingplace_contact_attributes: 8,
+            x_meetingplace_verification_id: None,
+            allowed_dids: None,
+            vcard_surname: None,
+            vcard_email: None,
+            vcard_tel: None,
+            command_prefix: None,
+            unknown_command_response: None,
+            weight: None,
+            compress_attachments: None,
+        };
+        model.insert("dids".into(), serde_json::json!([agent]));
+        model
+            .entry("channel_state")
+            .or_insert_with(|| serde_json::json!({}));
+        migrated = true;
+    }
+
+    migrated
+}
+
 /// Common way of getting ChatChannelState from OllamaModel or ConciergeState
 pub trait ChannelState {
     /// Get a reference to the ChatChannelState
@@ -197,6 +1910,34 @@ pub trait ChannelState {
     fn get_model(&self) -> Option<&OllamaModel> {
         None
     }
+    /// Get a mutable reference to the ChatChannelState, recreating it if it is missing
+    /// Channels can be removed while a response is being sent (e.g. a connection reset), so a warning is
+    /// logged and the message is still sent rather than the agent failing mid-response
+    fn channel_state_or_recreate(
+        &mut self,
+        remote_did: &str,
+        agent_did: &str,
+    ) -> &mut ChatChannelState {
+        let did_hash = digest(remote_did);
+        if self.get_channel_state(&did_hash).is_none() {
+            warn!(
+                "Channel state for ({}) is missing, recreating it",
+                remote_did
+            );
+            self.insert_channel_state(
+                &did_hash,
+                ChatChannelState {
+                    remote_did: remote_did.to_string(),
+                    remote_did_hash: did_hash.clone(),
+                    agent_did: Some(agent_did.to_string()),
+                    last_seen: now_secs(),
+                    ..Default::default()
+                },
+            );
+        }
+        self.get_channel_state_mut(&did_hash)
+            .expect("channel state was inserted above")
+    }
 }
 
 impl ChannelState for OllamaModel {
@@ -246,3 +1987,199 @@ impl ChannelState for ConciergeState {
         self.channel_state.insert(did_hash.into(), state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AGENT_DID, MODEL_NAME, file_names, temp_dir, test_model};
+
+    #[test]
+    fn history_keeps_the_latest_turns() {
+        let mut state = ChatChannelState::default();
+        for turn in ["one", "two", "three"] {
+            state.push_history(Role::User, turn, 2);
+        }
+
+        assert_eq!(
+            state.history,
+            vec![
+                (Role::User, "two".to_string()),
+                (Role::User, "three".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn history_always_keeps_the_current_turn() {
+        let mut state = ChatChannelState::default();
+        state.push_history(Role::User, "one", 0);
+        state.push_history(Role::User, "two", 0);
+
+        assert_eq!(state.history, vec![(Role::User, "two".to_string())]);
+    }
+
+    #[test]
+    fn history_round_trips_through_the_channel_state() {
+        let mut state = ChatChannelState::default();
+        state.push_history(Role::User, "hi", 20);
+        state.push_history(Role::Assistant, "hello", 20);
+
+        let restored: ChatChannelState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(restored.history, state.history);
+    }
+
+    #[test]
+    fn validate_rejects_no_history() {
+        let mut model = test_model(&[]);
+        assert!(model.validate().is_ok());
+
+        model.max_history = 0;
+        assert!(model.validate().is_err());
+    }
+
+    #[test]
+    fn expand_template_leaves_unknown_placeholders() {
+        let values = [("model_name", "llama3.2"), ("agent_name", "Llama")];
+
+        assert_eq!(
+            expand_template("Hi, I'm {agent_name} ({model_name})", &values),
+            "Hi, I'm Llama (llama3.2)"
+        );
+        assert_eq!(
+            expand_template("{foo} {agent_name} {", &values),
+            "{foo} Llama {"
+        );
+        assert_eq!(expand_template("{}{model_name", &values), "{}{model_name");
+    }
+
+    #[test]
+    fn rewind_last_prompt_removes_the_prompt_and_its_response() {
+        let mut state = ChatChannelState {
+            history: vec![
+                (Role::User, "first".into()),
+                (Role::Assistant, "first answer".into()),
+                (Role::User, "second".into()),
+                (Role::Assistant, "second answer".into()),
+            ],
+            ..Default::default()
+        };
+        state.set_last_prompt("second", &["image".to_string()], Some("thread"));
+
+        assert_eq!(
+            state.rewind_last_prompt(),
+            Some(LastPrompt {
+                text: "second".to_string(),
+                images: vec!["image".to_string()],
+                thid: Some("thread".to_string()),
+            })
+        );
+        assert_eq!(
+            state.history,
+            vec![
+                (Role::User, "first".to_string()),
+                (Role::Assistant, "first answer".to_string())
+            ]
+        );
+        assert!(state.last_prompt.is_none());
+        assert!(state.last_prompt_images.is_empty());
+        assert!(state.last_prompt_thid.is_none());
+    }
+
+    #[test]
+    fn rewind_last_prompt_without_a_prompt_keeps_the_history() {
+        let mut state = ChatChannelState {
+            history: vec![(Role::User, "first".into())],
+            ..Default::default()
+        };
+
+        assert!(state.rewind_last_prompt().is_none());
+        assert_eq!(state.history.len(), 1);
+    }
+
+    #[test]
+    fn raise_temperature_starts_from_the_default_and_is_capped() {
+        let mut options = OllamaOptions::default();
+        options.raise_temperature();
+        assert_eq!(options.temperature, Some(DEFAULT_TEMPERATURE + 0.2));
+
+        options.temperature = Some(1.9);
+        options.raise_temperature();
+        assert_eq!(options.temperature, Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn legacy_config_is_upgraded_when_loaded() {
+        const MEDIATOR_DID: &str = "did:example:mediator";
+        let state = SharedState {
+            mediator_dids: vec![MEDIATOR_DID.to_string()],
+            ..Default::default()
+        };
+        state.models.lock().await.insert(
+            MODEL_NAME.to_string(),
+            Arc::new(TokioMutex::new(test_model(&[]))),
+        );
+        let dir = temp_dir();
+        let path = dir.join("config.json");
+        let path = path.to_str().unwrap();
+        state.save(path).await.unwrap();
+
+        // The legacy format has a single mediator, and a single DID for each model
+        let mut config: serde_json::Value =
+            serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        let legacy = config.as_object_mut().unwrap();
+        legacy.remove("mediator_dids");
+        legacy.insert("mediator_did".into(), MEDIATOR_DID.into());
+        let model = config["models"][MODEL_NAME].as_object_mut().unwrap();
+        model.remove("dids");
+        model.insert("did".into(), AGENT_DID.into());
+        fs::write(path, config.to_string()).unwrap();
+
+        let loaded = SharedState::load(path).unwrap();
+
+        assert_eq!(loaded.mediator_dids, vec![MEDIATOR_DID.to_string()]);
+        let models = loaded.models.lock().await;
+        let model = models[MODEL_NAME].lock().await;
+        assert_eq!(model.dids.len(), 1);
+        assert_eq!(model.dids[0].did, AGENT_DID);
+        assert_eq!(model.dids[0].name, MODEL_NAME);
+    }
+
+    #[test]
+    fn current_config_is_not_migrated() {
+        let mut config = serde_json::json!({
+            "mediator_dids": ["did:example:mediator"],
+            "models": { "model": { "dids": [] } },
+        });
+        let unchanged = config.clone();
+
+        assert!(!migrate_legacy_mediator(&mut config));
+        assert!(!migrate_legacy_models(&mut config));
+        assert_eq!(config, unchanged);
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves_leave_a_complete_config() {
+        let state = Arc::new(SharedState {
+            mediator_dids: vec!["did:example:mediator".to_string()],
+            ..Default::default()
+        });
+        state.models.lock().await.insert(
+            MODEL_NAME.to_string(),
+            Arc::new(TokioMutex::new(test_model(&[]))),
+        );
+        let dir = temp_dir();
+        let path = dir.join("config.json");
+        let path = path.to_str().unwrap();
+
+        let saves = (0..8).map(|_| state.save(path));
+        for saved in futures::future::join_all(saves).await {
+            saved.unwrap();
+        }
+
+        let loaded = SharedState::load(path).unwrap();
+        assert!(loaded.models.lock().await.contains_key(MODEL_NAME));
+        assert!(state.is_own_write(&fs::read(path).unwrap()));
+        assert_eq!(file_names(&dir), vec!["config.json"]);
+    }
+}
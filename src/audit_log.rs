@@ -0,0 +1,120 @@
+/*!
+ * Append-only audit log of prompts and responses
+ *
+ * Opt-in by setting `audit_log_path` in the configuration. Each answered prompt is appended to the file as a
+ * JSON line holding the time, agent DID, remote DID hash, model, prompt and response.
+ *
+ * Records are written by a background task, so generating a response never waits on the disk. Once the file
+ * reaches `audit_log_max_bytes` it is renamed to `<path>.1` (older files move up to `<path>.5`, the oldest is
+ * deleted) and a new file is started. Records still queued when the bridge exits are lost.
+ *
+ * Privacy: the audit log holds the full text of every conversation, unencrypted, and the remote DID hash links
+ * conversations from the same party together. Restrict access to the file, decide how long rotated files are
+ * kept, and make sure users know their conversations are recorded where the law requires it.
+ */
+
+use crate::agents::state_management::ToolCallRequest;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tracing::warn;
+
+/// Default size the audit log grows to before it is rotated (10MB)
+pub const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated audit log files kept
+const AUDIT_LOG_KEPT_FILES: u32 = 5;
+
+/// A prompt and the response to it
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    /// RFC 3339 time the response finished
+    pub timestamp: String,
+    pub agent_did: String,
+    /// SHA256 hash of the remote DID
+    pub remote_did_hash: String,
+    pub model: String,
+    /// Why the prompt was sent: new, regenerate or tool_results
+    pub kind: String,
+    /// Empty for tool results, which continue the last response
+    pub prompt: String,
+    /// Text sent to the remote party, without any reasoning
+    pub response: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallRequest>,
+}
+
+/// Handle to the background task writing the audit log
+#[derive(Clone)]
+pub struct AuditLog {
+    records: UnboundedSender<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Opens the audit log and starts its writer, must be called from within the Tokio runtime
+    /// `max_bytes` - size the file grows to before it is rotated
+    pub fn start(path: &str, max_bytes: u64) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open(&path).context(format!("Couldn't open audit log ({})", path.display()))?;
+        let (records, receiver) = unbounded_channel();
+        // Writes are blocking, so they are kept off the async worker threads
+        tokio::task::spawn_blocking(move || write_records(receiver, path, file, max_bytes));
+        Ok(Self { records })
+    }
+
+    /// Queues a record to be written, never waits for the write
+    pub fn record(&self, record: AuditRecord) {
+        if self.records.send(record).is_err() {
+            warn!("Audit log writer has stopped, a record was lost");
+        }
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Appends each record as a JSON line, rotating the file when it reaches `max_bytes`
+fn write_records(
+    mut receiver: UnboundedReceiver<AuditRecord>,
+    path: PathBuf,
+    mut file: File,
+    max_bytes: u64,
+) {
+    while let Some(record) = receiver.blocking_recv() {
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Couldn't serialize audit record: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Couldn't write to audit log ({}): {}", path.display(), e);
+            continue;
+        }
+
+        if file.metadata().is_ok_and(|m| m.len() >= max_bytes) {
+            match rotate(&path) {
+                Ok(new_file) => file = new_file,
+                Err(e) => warn!("Couldn't rotate audit log ({}): {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Moves `<path>` to `<path>.1`, shifting older files up and deleting the oldest, then opens a new file
+fn rotate(path: &Path) -> std::io::Result<File> {
+    let rotated = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    let _ = fs::remove_file(rotated(AUDIT_LOG_KEPT_FILES));
+    for n in (1..AUDIT_LOG_KEPT_FILES).rev() {
+        let _ = fs::rename(rotated(n), rotated(n + 1));
+    }
+    fs::rename(path, rotated(1))?;
+    open(path)
+}
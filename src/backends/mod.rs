@@ -0,0 +1,132 @@
+/*!
+ * Services that generate responses for a model
+ *
+ * Every backend streams the response as text tokens, so batching, typing indicators and /stop in
+ * `handle_prompt` behave the same whichever service is used.
+ */
+
+use crate::agents::state_management::{Backend, OllamaModel, OllamaOptions};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use ollama_rs::generation::chat::ChatMessage;
+use std::{fmt, pin::Pin, time::Duration};
+use tokio_stream::Stream;
+
+pub mod ollama;
+pub mod openai_compatible;
+pub mod scripted;
+
+/// Text tokens of a response as they are generated
+pub type TokenStream = Pin<Box<dyn Stream<Item = Result<String>> + Send>>;
+
+/// Statistics of a completed response
+#[derive(Clone, Debug)]
+pub struct GenerationStats {
+    pub model_name: String,
+    /// Number of tokens in the response, if the backend reports it
+    pub eval_count: Option<u64>,
+    /// Time spent generating the response tokens, if the backend reports it
+    pub eval_duration: Option<Duration>,
+    /// Time spent on the whole request, including loading the model and evaluating the prompt
+    pub total_duration: Duration,
+}
+
+/// A tool the model asked to call
+#[derive(Clone, Debug)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// Why a backend couldn't generate a response
+/// Backends return these wrapped in `anyhow::Error` so the remote party can be told what went wrong
+#[derive(Debug)]
+pub enum BackendError {
+    /// The model isn't available on the backend (e.g. it hasn't been pulled)
+    ModelNotFound(String),
+    /// The backend doesn't have enough memory to load the model
+    OutOfMemory(String),
+    /// The backend couldn't be reached
+    ConnectionRefused(String),
+    /// Any other failure
+    Other(String),
+}
+
+impl BackendError {
+    /// Classifies an error message returned by the backend
+    pub fn from_message(message: String) -> Self {
+        let lower = message.to_lowercase();
+        if lower.contains("not found") || lower.contains("does not exist") {
+            BackendError::ModelNotFound(message)
+        } else if lower.contains("memory") {
+            BackendError::OutOfMemory(message)
+        } else {
+            BackendError::Other(message)
+        }
+    }
+
+    /// Classifies a failed HTTP request to the backend
+    pub fn from_request(error: &reqwest::Error) -> Self {
+        if error.is_connect() {
+            BackendError::ConnectionRefused(error.to_string())
+        } else {
+            BackendError::Other(error.to_string())
+        }
+    }
+
+    /// Retrying won't help if the model doesn't exist
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, BackendError::ModelNotFound(_))
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::ModelNotFound(e) => write!(f, "model not found: {}", e),
+            BackendError::OutOfMemory(e) => write!(f, "out of memory: {}", e),
+            BackendError::ConnectionRefused(e) => write!(f, "connection refused: {}", e),
+            BackendError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A service that generates chat responses
+pub trait ChatBackend: Send + Sync {
+    /// Starts generating a response to the conversation, using the backend's defaults for options that aren't set
+    /// If `json` is set the model is constrained to respond with a JSON document
+    fn generate_stream<'a>(
+        &'a self,
+        messages: Vec<ChatMessage>,
+        options: Option<&'a OllamaOptions>,
+        json: bool,
+    ) -> BoxFuture<'a, Result<TokenStream>>;
+
+    /// Called once a response is complete
+    fn finish(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Statistics of the last completed response, if the backend reports them
+    fn stats(&self) -> Option<GenerationStats> {
+        None
+    }
+
+    /// Tools the model asked to call in the last completed response
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        Vec::new()
+    }
+}
+
+/// Creates the backend configured for a model
+pub fn for_model(model: &OllamaModel) -> Box<dyn ChatBackend> {
+    match &model.backend {
+        Backend::Ollama => Box::new(ollama::OllamaBackend::new(model)),
+        Backend::OpenAiCompatible { base_url, api_key } => Box::new(
+            openai_compatible::OpenAiCompatibleBackend::new(model, base_url, api_key.as_deref()),
+        ),
+        Backend::Scripted { tokens } => Box::new(scripted::ScriptedBackend::new(tokens)),
+    }
+}
@@ -0,0 +1,368 @@
+/*!
+ * Ollama chat API backend
+ *
+ * Responses are streamed, except when the model has tools: ollama_rs can only send tools defined in code, so
+ * those requests are sent directly and the response, with any tool calls, arrives in one piece.
+ */
+
+use super::{BackendError, ChatBackend, GenerationStats, TokenStream, ToolCall};
+use crate::agents::state_management::{OllamaModel, OllamaOptions, parse_keep_alive};
+use anyhow::{Result, anyhow};
+use futures::{
+    future::{self, BoxFuture},
+    stream,
+};
+use ollama_rs::{
+    Ollama,
+    error::OllamaError,
+    generation::{
+        chat::{
+            ChatMessage, ChatMessageFinalResponseData, ChatMessageResponse,
+            ChatMessageResponseStream, request::ChatMessageRequest,
+        },
+        completion::request::GenerationRequest,
+        parameters::{FormatType, KeepAlive},
+        tools::ToolCall as OllamaToolCall,
+    },
+};
+use regex::Regex;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+/// Time a host that couldn't be reached is skipped for, while other hosts of the model are tried first
+const HOST_DOWN_PERIOD: Duration = Duration::from_secs(30);
+
+/// Ollama hosts that couldn't be reached and when they were last tried, shared by every model
+static HOSTS_DOWN: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub struct OllamaBackend {
+    /// Ollama services in the order they are tried, with their addresses
+    clients: Vec<(String, Ollama)>,
+    /// Client that served the last response, finish requests are sent to the same host
+    active: Mutex<usize>,
+    model_name: String,
+    keep_alive: Option<KeepAlive>,
+    /// Taken from the final chunk of the last response
+    stats: Arc<Mutex<Option<GenerationStats>>>,
+    /// Tools offered to the model, in the format of Ollama's chat API
+    tools: Vec<Value>,
+    /// Sends the requests with tools, authenticated like the Ollama clients
+    http: reqwest::Client,
+    /// Tools the model asked to call in the last response
+    tool_calls: Mutex<Vec<ToolCall>>,
+}
+
+impl OllamaBackend {
+    pub fn new(model: &OllamaModel) -> Self {
+        Self {
+            clients: model.ollama_clients(),
+            active: Mutex::new(0),
+            model_name: model.name.clone(),
+            // Validated when the config is loaded
+            keep_alive: model
+                .keep_alive
+                .as_deref()
+                .and_then(|k| parse_keep_alive(k).ok()),
+            stats: Arc::new(Mutex::new(None)),
+            tools: model
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.name,
+                            "description": tool.description,
+                            "parameters": tool.parameters,
+                        },
+                    })
+                })
+                .collect(),
+            http: http_client(model.ollama_api_key.as_deref()),
+            tool_calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Streams the chat response from the first host that can be reached
+    async fn send_request(&self, request: ChatMessageRequest) -> Result<ChatMessageResponseStream> {
+        self.with_failover(|ollama| {
+            let request = request.clone();
+            async move {
+                ollama
+                    .send_chat_messages_stream(request)
+                    .await
+                    .map_err(classify)
+            }
+        })
+        .await
+    }
+
+    /// Sends the chat request with the model's tools, the response isn't streamed
+    async fn send_tool_request(&self, request: ChatMessageRequest) -> Result<ChatMessageResponse> {
+        let mut body = serde_json::to_value(&request)?;
+        body["tools"] = json!(self.tools);
+        body["stream"] = json!(false);
+
+        self.with_failover(|ollama| {
+            let request = self
+                .http
+                .post(format!("{}api/chat", ollama.url_str()))
+                .json(&body);
+            async move {
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| BackendError::from_request(&e))?;
+                if !response.status().is_success() {
+                    let text = response.text().await.unwrap_or_default();
+                    return Err(classify(OllamaError::Other(text)));
+                }
+                response
+                    .json::<ChatMessageResponse>()
+                    .await
+                    .map_err(|e| BackendError::Other(e.to_string()))
+            }
+        })
+        .await
+    }
+
+    /// Sends a request to the first host that can be reached
+    /// Hosts that recently couldn't be reached are tried last
+    async fn with_failover<T, F, Fut>(&self, send: F) -> Result<T>
+    where
+        F: Fn(Ollama) -> Fut,
+        Fut: Future<Output = Result<T, BackendError>>,
+    {
+        let order = {
+            let down = HOSTS_DOWN.lock().unwrap_or_else(|e| e.into_inner());
+            let mut order = (0..self.clients.len()).collect::<Vec<_>>();
+            order.sort_by_key(|index| {
+                down.get(&self.clients[*index].0)
+                    .is_some_and(|at| at.elapsed() < HOST_DOWN_PERIOD)
+            });
+            order
+        };
+
+        let mut last_error = None;
+        for index in order {
+            let (address, ollama) = &self.clients[index];
+            match send(ollama.clone()).await {
+                Ok(response) => {
+                    HOSTS_DOWN
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(address);
+                    *self.active.lock().unwrap_or_else(|e| e.into_inner()) = index;
+                    return Ok(response);
+                }
+                Err(error) => {
+                    if !matches!(error, BackendError::ConnectionRefused(_)) {
+                        return Err(error.into());
+                    }
+                    warn!(
+                        "Model ({}): Ollama ({}) is unreachable: {}",
+                        self.model_name, address, error
+                    );
+                    HOSTS_DOWN
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(address.clone(), Instant::now());
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| BackendError::Other("no Ollama hosts configured".to_string()))
+            .into())
+    }
+}
+
+impl ChatBackend for OllamaBackend {
+    fn generate_stream<'a>(
+        &'a self,
+        messages: Vec<ChatMessage>,
+        options: Option<&'a OllamaOptions>,
+        json: bool,
+    ) -> BoxFuture<'a, Result<TokenStream>> {
+        Box::pin(async move {
+            let mut request = ChatMessageRequest::new(self.model_name.clone(), messages);
+            if let Some(options) = options {
+                request = request.options(options.to_generation_options());
+            }
+            if json {
+                request = request.format(FormatType::Json);
+            }
+            self.tool_calls
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clear();
+
+            if !self.tools.is_empty() {
+                let response = self.send_tool_request(request).await?;
+                if let Some(data) = &response.final_data {
+                    *self.stats.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some(generation_stats(&self.model_name, data));
+                }
+                *self.tool_calls.lock().unwrap_or_else(|e| e.into_inner()) = response
+                    .message
+                    .tool_calls
+                    .iter()
+                    .filter_map(tool_call)
+                    .collect();
+                let tokens: TokenStream =
+                    Box::pin(stream::once(future::ready(Ok(response.message.content))));
+                return Ok(tokens);
+            }
+
+            let stream = self.send_request(request).await?;
+            let model_name = self.model_name.clone();
+            let stats = self.stats.clone();
+            let tokens: TokenStream = Box::pin(stream.map(move |response| {
+                response
+                    .map(|response| {
+                        if let Some(data) = &response.final_data {
+                            *stats.lock().unwrap_or_else(|e| e.into_inner()) =
+                                Some(generation_stats(&model_name, data));
+                        }
+                        response.message.content
+                    })
+                    .map_err(|_| {
+                        anyhow!(BackendError::Other(
+                            "Ollama response stream failed".to_string()
+                        ))
+                    })
+            }));
+            Ok(tokens)
+        })
+    }
+
+    fn stats(&self) -> Option<GenerationStats> {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// ollama_rs chat requests can't carry keep_alive, an empty generate request applies it instead
+    fn finish(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let Some(keep_alive) = self.keep_alive.clone() else {
+                return;
+            };
+            let request =
+                GenerationRequest::new(self.model_name.clone(), "").keep_alive(keep_alive);
+            let active = *self.active.lock().unwrap_or_else(|e| e.into_inner());
+            let Some((_, ollama)) = self.clients.get(active) else {
+                return;
+            };
+            if let Err(e) = ollama.generate(request).await {
+                warn!(
+                    "Model ({}): Couldn't apply keep_alive: {}",
+                    self.model_name, e
+                );
+            }
+        })
+    }
+}
+
+fn generation_stats(model_name: &str, data: &ChatMessageFinalResponseData) -> GenerationStats {
+    GenerationStats {
+        model_name: model_name.to_string(),
+        eval_count: Some(data.eval_count.into()),
+        eval_duration: Some(Duration::from_nanos(data.eval_duration)),
+        total_duration: Duration::from_nanos(data.total_duration),
+    }
+}
+
+/// Reads a tool call from a response, ollama_rs doesn't expose the called function's fields
+fn tool_call(call: &OllamaToolCall) -> Option<ToolCall> {
+    let function = serde_json::to_value(&call.function).ok()?;
+    Some(ToolCall {
+        name: function["name"].as_str()?.to_string(),
+        arguments: function["arguments"].clone(),
+    })
+}
+
+/// Splits an Ollama address (e.g. http://localhost:11434) into the host (including the scheme) and port
+pub fn parse_address(ollama_address: &str) -> Result<(String, u16)> {
+    let Some((scheme, _)) = ollama_address.split_once("://") else {
+        return Err(anyhow!(
+            "This is not a valid address; must start with http:// or https:// (e.g. http://localhost:11434)"
+        ));
+    };
+    if scheme != "http" && scheme != "https" {
+        return Err(anyhow!(
+            "Unsupported scheme ({}); must start with http:// or https://",
+            scheme
+        ));
+    }
+
+    let ollama_address_re = Regex::new(r"^(https?:\/\/[^:/]+):(\d+)$").unwrap();
+    match ollama_address_re.captures(ollama_address) {
+        None => Err(anyhow!(
+            "This is not a valid address; must look similar to http://localhost:11434 or https://ollama.example.com:443"
+        )),
+        Some(caps) => {
+            let port = caps.get(2).unwrap().as_str().parse::<u16>()?;
+            Ok((caps.get(1).unwrap().as_str().to_string(), port))
+        }
+    }
+}
+
+/// Creates an Ollama client, the host includes the scheme (http:// or https://)
+/// If an API key is set it is sent as a bearer token with every request
+pub fn client(host: &str, port: u16, api_key: Option<&str>) -> Ollama {
+    match api_key {
+        Some(api_key) => Ollama::new_with_client(host, port, http_client(Some(api_key))),
+        None => Ollama::new(host, port),
+    }
+}
+
+/// HTTP client for requests to Ollama, sending the API key as a bearer token if one is set
+fn http_client(api_key: Option<&str>) -> reqwest::Client {
+    let Some(api_key) = api_key else {
+        return reqwest::Client::new();
+    };
+
+    let mut headers = HeaderMap::new();
+    match HeaderValue::from_str(&format!("Bearer {}", api_key)) {
+        Ok(mut value) => {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        // Validated when the config is loaded
+        Err(_) => warn!("Ollama API key isn't a valid header value, it won't be sent"),
+    }
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Classifies an Ollama error, error responses carry the reason as JSON (e.g. {"error": "model not found"})
+fn classify(error: OllamaError) -> BackendError {
+    match error {
+        OllamaError::ReqwestError(e) => BackendError::from_request(&e),
+        OllamaError::InternalError(e) => BackendError::from_message(e.message),
+        OllamaError::Other(body) => BackendError::from_message(
+            serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|value| value["error"].as_str().map(|e| e.to_string()))
+                .unwrap_or(body),
+        ),
+        e => BackendError::Other(e.to_string()),
+    }
+}
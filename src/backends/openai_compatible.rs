@@ -0,0 +1,209 @@
+/*!
+ * OpenAI compatible chat completions backend (vLLM, LM Studio, ...)
+ *
+ * Responses are streamed as server-sent events, each carrying a `choices[0].delta.content` token.
+ */
+
+use super::{BackendError, ChatBackend, TokenStream};
+use crate::agents::state_management::{OllamaModel, OllamaOptions};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream};
+use ollama_rs::generation::chat::{ChatMessage, MessageRole};
+use serde_json::{Value, json};
+use tokio_stream::{Stream, StreamExt};
+
+pub struct OpenAiCompatibleBackend {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    model_name: String,
+}
+
+impl OpenAiCompatibleBackend {
+    pub fn new(model: &OllamaModel, base_url: &str, api_key: Option<&str>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!("{}/chat/completions", base_url.trim_end_matches('/')),
+            api_key: api_key.map(|k| k.to_string()),
+            model_name: model.name.clone(),
+        }
+    }
+
+    /// Builds the request body
+    /// Only the options with an equivalent in the OpenAI API (temperature, top_p) and the widely supported top_k
+    /// extension are sent
+    fn request_body(
+        &self,
+        messages: &[ChatMessage],
+        options: Option<&OllamaOptions>,
+        json: bool,
+    ) -> Value {
+        let mut body = json!({
+            "model": self.model_name,
+            "stream": true,
+            "messages": messages.iter().map(to_openai_message).collect::<Vec<Value>>(),
+        });
+        if let Some(options) = options {
+            if let Some(temperature) = options.temperature {
+                body["temperature"] = json!(temperature);
+            }
+            if let Some(top_p) = options.top_p {
+                body["top_p"] = json!(top_p);
+            }
+            if let Some(top_k) = options.top_k {
+                body["top_k"] = json!(top_k);
+            }
+            if let Some(stop) = options.stop.as_ref().filter(|stop| !stop.is_empty()) {
+                body["stop"] = json!(stop);
+            }
+        }
+        if json {
+            body["response_format"] = json!({ "type": "json_object" });
+        }
+        body
+    }
+}
+
+impl ChatBackend for OpenAiCompatibleBackend {
+    fn generate_stream<'a>(
+        &'a self,
+        messages: Vec<ChatMessage>,
+        options: Option<&'a OllamaOptions>,
+        json: bool,
+    ) -> BoxFuture<'a, Result<TokenStream>> {
+        Box::pin(async move {
+            let mut request = self
+                .client
+                .post(&self.url)
+                .json(&self.request_body(&messages, options, json));
+            if let Some(api_key) = &self.api_key {
+                request = request.bearer_auth(api_key);
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(|e| BackendError::from_request(&e))?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                let message = format!("{} returned {}: {}", self.url, status, text);
+                return Err(if status == reqwest::StatusCode::NOT_FOUND {
+                    BackendError::ModelNotFound(message)
+                } else {
+                    BackendError::from_message(message)
+                }
+                .into());
+            }
+
+            Ok(event_tokens(Box::pin(response.bytes_stream())))
+        })
+    }
+}
+
+/// Converts a chat message to the OpenAI format
+/// Images are sent as data URLs alongside the text
+fn to_openai_message(message: &ChatMessage) -> Value {
+    let role = match message.role {
+        MessageRole::User => "user",
+        MessageRole::Assistant => "assistant",
+        MessageRole::System => "system",
+        MessageRole::Tool => "tool",
+    };
+
+    match message.images.as_ref().filter(|images| !images.is_empty()) {
+        Some(images) => {
+            let mut content = vec![json!({ "type": "text", "text": message.content })];
+            content.extend(images.iter().map(|image| {
+                let data = image.to_base64();
+                json!({
+                    "type": "image_url",
+                    "image_url": { "url": format!("data:{};base64,{}", image_media_type(data), data) },
+                })
+            }));
+            json!({ "role": role, "content": content })
+        }
+        None => json!({ "role": role, "content": message.content }),
+    }
+}
+
+/// Media type of a base64 encoded image, only PNG and JPEG images are passed to models
+fn image_media_type(data: &str) -> &'static str {
+    if data.starts_with("iVBOR") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Parsed server-sent event line
+enum Event {
+    Token(String),
+    Done,
+    Error(String),
+    Skip,
+}
+
+fn parse_event(line: &str) -> Event {
+    let Some(data) = line.strip_prefix("data:").map(|d| d.trim()) else {
+        // Blank lines, comments and other fields
+        return Event::Skip;
+    };
+    if data == "[DONE]" {
+        return Event::Done;
+    }
+
+    match serde_json::from_str::<Value>(data) {
+        Ok(value) => {
+            if let Some(error) = value.get("error") {
+                return Event::Error(error.to_string());
+            }
+            match value["choices"][0]["delta"]["content"].as_str() {
+                Some(token) if !token.is_empty() => Event::Token(token.to_string()),
+                _ => Event::Skip,
+            }
+        }
+        Err(e) => Event::Error(format!("invalid event ({}): {}", data, e)),
+    }
+}
+
+/// Turns the response body into a stream of tokens
+fn event_tokens<S, B>(body: S) -> TokenStream
+where
+    S: Stream<Item = reqwest::Result<B>> + Send + Unpin + 'static,
+    B: AsRef<[u8]>,
+{
+    // (body, unprocessed bytes, finished)
+    let tokens = stream::unfold(
+        (body, Vec::new(), false),
+        |(mut body, mut buffer, finished)| async move {
+            if finished {
+                return None;
+            }
+            loop {
+                // Events are processed a line at a time, lines can span chunks
+                if let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<u8>>();
+                    match parse_event(String::from_utf8_lossy(&line).trim()) {
+                        Event::Token(token) => return Some((Ok(token), (body, buffer, false))),
+                        Event::Done => return None,
+                        Event::Error(e) => {
+                            return Some((
+                                Err(BackendError::from_message(e).into()),
+                                (body, buffer, true),
+                            ));
+                        }
+                        Event::Skip => continue,
+                    }
+                }
+
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(chunk.as_ref()),
+                    Some(Err(e)) => return Some((Err(e.into()), (body, buffer, true))),
+                    None => return None,
+                }
+            }
+        },
+    );
+    Box::pin(tokens)
+}
@@ -0,0 +1,85 @@
+/*!
+ * Scripted backend that needs no model server
+ *
+ * Each response streams the configured tokens, or echoes the last prompt word by word if there are none.
+ * Used to exercise the DIDComm side of the bridge (mediator, clients, commands) without Ollama.
+ */
+
+use super::{ChatBackend, TokenStream};
+use crate::agents::state_management::OllamaOptions;
+use anyhow::Result;
+use futures::{future::BoxFuture, stream};
+use ollama_rs::generation::chat::ChatMessage;
+
+pub struct ScriptedBackend {
+    tokens: Vec<String>,
+}
+
+impl ScriptedBackend {
+    pub fn new(tokens: &[String]) -> Self {
+        Self {
+            tokens: tokens.to_vec(),
+        }
+    }
+}
+
+impl ChatBackend for ScriptedBackend {
+    fn generate_stream<'a>(
+        &'a self,
+        messages: Vec<ChatMessage>,
+        _options: Option<&'a OllamaOptions>,
+        _json: bool,
+    ) -> BoxFuture<'a, Result<TokenStream>> {
+        Box::pin(async move {
+            let tokens = if self.tokens.is_empty() {
+                messages
+                    .last()
+                    .map(|message| {
+                        message
+                            .content
+                            .split_inclusive(' ')
+                            .map(|word| word.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            } else {
+                self.tokens.clone()
+            };
+            let tokens: TokenStream = Box::pin(stream::iter(tokens.into_iter().map(Ok)));
+            Ok(tokens)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    async fn response(backend: &ScriptedBackend, prompt: &str) -> Vec<String> {
+        backend
+            .generate_stream(vec![ChatMessage::user(prompt.to_string())], None, false)
+            .await
+            .unwrap()
+            .map(|token| token.unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn streams_the_configured_tokens() {
+        let backend = ScriptedBackend::new(&["Hello ".to_string(), "there".to_string()]);
+
+        assert_eq!(response(&backend, "hi").await, vec!["Hello ", "there"]);
+    }
+
+    #[tokio::test]
+    async fn echoes_the_prompt_without_tokens() {
+        let backend = ScriptedBackend::new(&[]);
+
+        assert_eq!(
+            response(&backend, "say it back").await,
+            vec!["say ", "it ", "back"]
+        );
+    }
+}
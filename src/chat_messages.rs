@@ -2,38 +2,240 @@
  * Processing of chat messages
  */
 
-use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_didcomm::{Attachment, AttachmentData, Message};
 use affinidi_messaging_sdk::{
-    ATM, messages::known::MessageType, profiles::ATMProfile,
+    messages::known::MessageType, profiles::ATMProfile,
     protocols::message_pickup::MessagePickupStatusReply,
 };
 use anyhow::Result;
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
 use console::style;
-use ollama_rs::{Ollama, generation::completion::request::GenerationRequest};
+use futures::future::BoxFuture;
+use ollama_rs::{
+    Ollama,
+    generation::{
+        chat::ChatMessage as OllamaChatMessage,
+        embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest},
+        images::Image,
+        parameters::KeepAlive,
+        tools::ToolCall as OllamaToolCall,
+    },
+};
 use serde::{Deserialize, Serialize};
 use sha256::digest;
-use std::{
-    str::FromStr,
-    sync::Arc,
-    time::{Duration, SystemTime},
-};
+use std::{str::FromStr, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncWriteExt, stdout},
     select,
-    sync::Mutex,
+    sync::{Mutex, Notify},
     time::Instant,
 };
 use tokio_stream::StreamExt;
 use tracing::{error, info, warn};
 
 use crate::{
-    agents::state_management::{ChannelState, ChatChannelState},
-    didcomm_messages::{handle_presence, oob_connection::send_connection_response},
+    agents::state_management::{
+        ChannelState, ChatChannelState, DEFAULT_TEMPERATURE, DIDCommAgent, OllamaModel,
+        OllamaOptions, Role, SharedStateRef, ToolCallRequest, now_secs, parse_keep_alive,
+    },
+    audit_log::AuditRecord,
+    backends::{self, BackendError, ChatBackend, GenerationStats, TokenStream},
+    didcomm_messages::{
+        MessageTransport, compression, deliver, handle_presence,
+        oob_connection::{channel_did, send_connection_response},
+    },
+    message_handlers::{MessageContext, MessageHandler},
+    metrics,
 };
 
 #[derive(Deserialize, Serialize)]
-struct ChatMessage {
+pub(crate) struct ChatMessage {
     pub text: String,
+    /// Base64 encoded images attached to the message
+    #[serde(skip)]
+    pub images: Vec<String>,
+    /// Id of the chat message the prompt was received in, a chat-processed receipt is sent once it is answered
+    #[serde(skip)]
+    pub message_id: Option<String>,
+    /// Thread the response is sent in, the thid of the message the prompt was received in or its id
+    #[serde(skip)]
+    pub thid: Option<String>,
+}
+
+/// Id, thread, sequence number and tag of a chat message being sent
+struct ChatMessageHeader<'a> {
+    id: &'a str,
+    thid: Option<&'a str>,
+    seq_no: u64,
+    /// Sent as the message's `tag`, so clients can render it differently (e.g. reasoning collapsed)
+    tag: Option<&'a str>,
+}
+
+/// Tag of chat messages holding the model's reasoning (its <think> section)
+/// Clients that don't understand the tag show it as an ordinary message
+const REASONING_TAG: &str = "reasoning";
+
+/// Receipt sent when a message is received
+const CHAT_DELIVERED_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-delivered";
+
+/// Receipt sent once a prompt has been answered
+const CHAT_PROCESSED_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-processed";
+
+/// Largest image attachment that will be passed to a model (10MB)
+const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Response sent to remote DIDs that aren't on an agent's allowlist
+pub(crate) const NOT_PERMITTED_RESPONSE: &str =
+    "Sorry, you aren't permitted to chat with this agent.";
+
+/// Response sent when Ollama can't be reached or fails while generating
+const MODEL_UNAVAILABLE_RESPONSE: &str =
+    "Sorry, the model is temporarily unavailable. Please try again shortly.";
+
+/// Response sent when the model isn't installed on the backend
+const MODEL_NOT_FOUND_RESPONSE: &str =
+    "Sorry, this model isn't installed on the AI service. Please let the operator know.";
+
+/// Response sent when the backend doesn't have enough memory to run the model
+const OUT_OF_MEMORY_RESPONSE: &str = "Sorry, the AI service doesn't have enough memory to run this model right now. Please try again later, or use /model to switch to a smaller model.";
+
+/// Response sent when the backend can't be reached
+const CONNECTION_REFUSED_RESPONSE: &str =
+    "Sorry, I can't reach the AI service right now. Please try again shortly.";
+
+/// Response sent when a remote party exceeds the model's rate limit
+const THROTTLED_RESPONSE: &str =
+    "You're sending prompts too quickly, please wait a moment before trying again.";
+
+/// Response sent when a prompt has to wait for a free generation slot on the backend host
+const QUEUED_RESPONSE: &str = "I'm busy answering other people right now, you're in the queue and I'll answer as soon as I can.";
+
+/// Response sent when no generation slot on the backend host became free in time
+const BUSY_RESPONSE: &str =
+    "Sorry, I'm busy answering other people right now. Please try again in a little while.";
+
+/// Response sent when a prompt is older than the model's max_message_age_secs
+const STALE_RESPONSE: &str =
+    "Sorry, your message arrived too late to be answered. Please send it again.";
+
+/// Sent before a JSON mode response that still isn't valid JSON after a retry
+const INVALID_JSON_WARNING: &str = "Warning: this response isn't valid JSON";
+
+/// Time a JSON mode response has to be generated again when the first response isn't valid JSON
+const JSON_RETRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a greeting has to be generated before the static greeting is sent instead
+const GREETING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a typing indicator stays valid after the next one is due, so it doesn't expire between indicators
+const TYPING_EXPIRY_MARGIN: Duration = Duration::from_secs(7);
+
+/// DIDComm basic message type, used by generic DIDComm wallets to chat
+const BASIC_MESSAGE_TYPE: &str = "https://didcomm.org/basicmessage/2.0/message";
+
+/// Message type of the edits to a streamed response, sent to clients that support edits
+const CHAT_MESSAGE_EDIT_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-message-edit";
+
+/// Message type of the error replies sent when a message can't be processed
+const CHAT_ERROR_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-error";
+
+/// Filename of the attachment long responses are sent in
+const RESPONSE_ATTACHMENT_FILENAME: &str = "response.txt";
+
+/// Media types that can be passed to a vision model
+const SUPPORTED_IMAGE_TYPES: [&str; 2] = ["image/png", "image/jpeg"];
+
+/// Tool calls made by the model, sent to the remote party to run
+/// Body: `{"calls": [{"id": "...", "name": "get_weather", "arguments": {"city": "Singapore"}}]}`
+///
+/// The remote party runs every tool and replies in the same thread with a chat-tool-result:
+/// `{"results": [{"id": "...", "content": "31°C and sunny"}]}`
+/// Once every call has a result the model continues its response, which may call tools again
+const CHAT_TOOL_CALL_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-tool-call";
+
+/// Results of the model's tool calls, returned by the remote party
+const CHAT_TOOL_RESULT_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-tool-result";
+
+/// Asks for the embedding of some text, only handled by models that support embeddings
+const EMBED_TYPE: &str = "https://affinidi.com/atm/client-actions/embed";
+
+/// Asks which features an agent supports, e.g.
+/// `{"queries": [{"feature-type": "protocol", "match": "https://didcomm.org/*"}]}`
+///
+/// The agent replies with a disclose message listing the features that match any of the queries:
+/// - `protocol` - protocols the agent handles messages of (e.g. https://didcomm.org/trust-ping/2.0)
+/// - `message-type` - message types the agent handles, those that depend on the model (embed, chat-tool-result)
+///   are only listed when the model supports them
+/// - `attachment` - media types of attachments passed to the model, only listed for models that accept images
+///
+/// A match ending in `*` matches any feature starting with the rest of it
+const DISCOVER_FEATURES_QUERIES_TYPE: &str = "https://didcomm.org/discover-features/2.0/queries";
+
+/// Features disclosed in reply to a discover-features query
+const DISCOVER_FEATURES_DISCLOSE_TYPE: &str = "https://didcomm.org/discover-features/2.0/disclose";
+
+/// Names of common languages by their ISO 639-1 code, used in the instruction to respond in a language
+/// Other codes are passed to the model as they are
+const LANGUAGE_NAMES: [(&str, &str); 16] = [
+    ("ar", "Arabic"),
+    ("de", "German"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fr", "French"),
+    ("hi", "Hindi"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("ms", "Malay"),
+    ("nl", "Dutch"),
+    ("pt", "Portuguese"),
+    ("ru", "Russian"),
+    ("th", "Thai"),
+    ("zh", "Chinese"),
+];
+
+/// Reason a message couldn't be processed, sent as the `code` of a chat-error
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChatErrorCode {
+    /// The message type isn't handled by this agent
+    UnknownMessageType,
+    /// The message body couldn't be parsed
+    InvalidBody,
+    /// No agent is configured for the DID the message was sent to
+    NoAgent,
+}
+
+/// Body of an embed request, either a single text or a batch of texts
+#[derive(Deserialize)]
+struct EmbedRequest {
+    input: EmbedInput,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmbedInput {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<EmbedInput> for EmbeddingsInput {
+    fn from(input: EmbedInput) -> Self {
+        match input {
+            EmbedInput::Single(text) => EmbeddingsInput::Single(text),
+            EmbedInput::Multiple(texts) => EmbeddingsInput::Multiple(texts),
+        }
+    }
+}
+
+/// Body of a DIDComm basic message
+#[derive(Deserialize)]
+struct BasicMessage {
+    content: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -41,27 +243,126 @@ struct ChatEffect {
     pub effect: String,
 }
 
+/// Emoji reaction to a message
+#[derive(Deserialize, Serialize)]
+struct ChatReaction {
+    pub reaction: String,
+}
+
+/// Body of a chat-tool-result
+#[derive(Deserialize)]
+struct ToolResults {
+    results: Vec<ToolResult>,
+}
+
+#[derive(Deserialize)]
+struct ToolResult {
+    /// Id of the tool call this is the result of
+    id: String,
+    /// Output of the tool, passed to the model as text
+    content: serde_json::Value,
+}
+
+impl ToolResult {
+    fn text(&self) -> String {
+        match &self.content {
+            serde_json::Value::String(text) => text.clone(),
+            content => content.to_string(),
+        }
+    }
+}
+
+/// Why a prompt is sent to the model
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PromptKind {
+    /// A new prompt from the remote party
+    New,
+    /// The last prompt is replayed, the temperature is raised for a different response
+    Regenerate,
+    /// The remote party returned the results of the model's tool calls, the model continues its response
+    ToolResults,
+}
+
+/// Settings used to generate a response, taken from the model serving the prompt
+struct GenerationSettings {
+    /// Address of the backend host, generations are limited per host
+    host: String,
+    /// Client for the model's Ollama service, used for requests other than chat (e.g. embeddings)
+    ollama: Ollama,
+    model_name: String,
+    options: Option<OllamaOptions>,
+    supports_images: bool,
+    retries: u32,
+    retry_backoff: Duration,
+    keep_alive: Option<KeepAlive>,
+    system_prompt: Option<String>,
+    attachment_threshold: Option<usize>,
+    typing_interval: Duration,
+    presence_interval: Duration,
+    /// Request the response as JSON
+    json_format: bool,
+    /// Sent while waiting for a generation slot, nothing is sent if empty
+    queued_response: String,
+    /// Sent if no generation slot became free in time
+    busy_response: String,
+    backend: Box<dyn ChatBackend>,
+}
+
+/// Settings of the model answering a prompt: generation settings, history length, flush period and flush size
+fn prompt_settings(model: &OllamaModel) -> (GenerationSettings, usize, Duration, usize) {
+    (
+        GenerationSettings::from(model),
+        model.max_history,
+        Duration::from_millis(model.flush_interval_ms),
+        model.flush_chars,
+    )
+}
+
+impl From<&OllamaModel> for GenerationSettings {
+    fn from(model: &OllamaModel) -> Self {
+        Self {
+            host: model.backend_address(),
+            ollama: model.ollama_client(),
+            model_name: model.name.clone(),
+            options: model.options.clone(),
+            supports_images: model.supports_images,
+            retries: model.ollama_retries,
+            retry_backoff: Duration::from_millis(model.ollama_retry_backoff_ms),
+            // Validated when the config is loaded
+            keep_alive: model
+                .keep_alive
+                .as_deref()
+                .and_then(|k| parse_keep_alive(k).ok()),
+            system_prompt: model.system_prompt.clone(),
+            attachment_threshold: model.attachment_threshold_chars,
+            typing_interval: Duration::from_millis(model.typing_interval_ms),
+            presence_interval: Duration::from_millis(model.presence_interval_ms),
+            json_format: model.json_format,
+            queued_response: model
+                .queued_response
+                .clone()
+                .unwrap_or_else(|| QUEUED_RESPONSE.to_string()),
+            busy_response: model
+                .busy_response
+                .clone()
+                .unwrap_or_else(|| BUSY_RESPONSE.to_string()),
+            backend: backends::for_model(model),
+        }
+    }
+}
+
 /// Processes a received message
 /// Doesn't return anything
-pub(crate) async fn handle_message<T>(
-    atm: &ATM,
+pub(crate) async fn handle_message(
+    atm: &dyn MessageTransport,
     profile: &Arc<ATMProfile>,
-    model: &Arc<Mutex<T>>,
+    model: &Arc<Mutex<OllamaModel>>,
     model_name: &str,
     message: &Message,
-) -> Result<()>
-where
-    T: ChannelState,
-{
-    let Ok(msg_type) = MessageType::from_str(&message.type_) else {
-        println!(
-            "{}",
-            style(format!("Unknown message type: {:?}", message)).red()
-        );
-        return Err(anyhow::anyhow!("Unknown message type"));
-    };
-
+    shared_state: &SharedStateRef,
+) -> Result<()> {
     let Some(from_did) = message.from.clone() else {
+        // Anonymous messages can't be replied to, so they are only logged
         println!("{}", style("No 'from' field in message").red());
         println!(
             "{}",
@@ -70,301 +371,2063 @@ where
         return Err(anyhow::anyhow!("No 'from' field in message"));
     };
 
-    match msg_type {
-        MessageType::MessagePickupStatusResponse => {
-            match serde_json::from_value::<MessagePickupStatusReply>(message.body.clone()) {
-                Ok(status) => {
-                    info!(
-                        "STATUS-RESPONSE: queued messages ({}), live_streaming?({})",
-                        status.message_count, status.live_delivery
-                    );
-                }
-                Err(e) => {
-                    println!(
-                        "{}",
-                        style(format!("Error parsing message body: {:?}", e)).red()
-                    );
-                    return Err(anyhow::anyhow!("Error parsing message body"));
-                }
-            }
-        }
-        MessageType::Other(_type) => match _type.as_str() {
-            "https://affinidi.com/atm/client-actions/connection-setup" => {
-                info!(
-                    "{}: Received Connection Setup Request: from({})",
-                    profile.inner.alias, from_did
-                );
-                let didcomm_agent = {
-                    let lock = model.lock().await;
-
-                    let did_agent = lock
-                        .get_model()
-                        .unwrap()
-                        .dids
-                        .iter()
-                        .find(|d| d.did == profile.inner.did)
-                        .unwrap();
+    metrics::record_message_received(model_name);
+    if let Some(state) = model.lock().await.get_channel_state_mut(&digest(&from_did)) {
+        state.touch();
+    }
 
-                    did_agent.clone()
-                };
-                let new_did =
-                    send_connection_response(atm, profile, message, &didcomm_agent).await?;
-                {
-                    let mut lock = model.lock().await;
-                    let from_did_hash = digest(from_did);
-                    lock.remove_channel_state(&from_did_hash);
-                    let new_did_hash = digest(&new_did);
-                    lock.insert_channel_state(
-                        &new_did_hash,
-                        ChatChannelState {
-                            remote_did: new_did.clone(),
-                            remote_did_hash: new_did_hash.clone(),
-                            ..Default::default()
-                        },
-                    );
-                }
-                let _ = send_message(atm, profile, &didcomm_agent.greeting, &new_did, model).await;
-            }
-            "https://affinidi.com/atm/client-actions/chat-presence" => {
-                // Send a presence response back
-                let _ = handle_presence(atm, profile, &from_did).await;
-            }
-            "https://affinidi.com/atm/client-actions/chat-effect" => {
-                // Special handling for balloons and confetti
-                handle_chat_effect(atm, profile, model, message).await;
-            }
-            "https://affinidi.com/atm/client-actions/chat-message" => {
-                let _ = ack_message(atm, profile, message).await;
-                match serde_json::from_value::<ChatMessage>(message.body.clone()) {
-                    Ok(chat_message) => {
-                        println!(
-                            "{}",
-                            style(format!(
-                                "Model ({}): incoming prompt: {:?}",
-                                model_name, chat_message.text
-                            ))
-                            .green()
-                        );
-                        if message.attachments.is_some() {
-                            warn!("Attachments are not supported");
-                            let _ = send_message(
-                                atm,
-                                profile,
-                                "Unfortunately I can't handle attachments yet.. Hopefully one day I will be able to!",
-                                &from_did,
-                                model,
-                            )
-                            .await;
-                            return Ok(());
-                        }
-                        if chat_message.text.starts_with("/") {
-                            let _ =
-                                handle_command(atm, profile, &chat_message, model, &from_did).await;
-                        } else {
-                            let _ =
-                                handle_prompt(atm, profile, &chat_message, model, &from_did).await;
-                        }
-                    }
-                    Err(e) => {
-                        println!(
-                            "{}",
-                            style(format!("Error parsing chat message: {:?}", e)).red()
-                        );
-                        return Err(anyhow::anyhow!("Error parsing chat message"));
-                    }
-                }
-            }
-            "https://affinidi.com/atm/client-actions/chat-alias-profile-hash" => {
-                // Ignore this
-            }
-            "https://affinidi.com/atm/client-actions/chat-delivered" => {
-                // Ignore this, it is the other client acknowledging receipt of a message
-            }
-            "https://affinidi.com/atm/client-actions/chat-activity" => {
-                // Ignore this, other client is typing
-            }
-            _ => {
-                println!(
-                    "{}\n{}",
-                    style(format!("Unknown Message Type: {} received!", _type)).red(),
-                    style(format!("Message: {:?}", message)).cyan()
-                );
-            }
-        },
-        _ => {
-            println!("Received message: {:?}", message);
-        }
+    let context = MessageContext {
+        atm,
+        profile,
+        model,
+        model_name,
+        message,
+        from_did: &from_did,
+        shared_state,
+    };
+    if let Some(handler) = shared_state.message_handlers.find(&message.type_) {
+        return handler.handle(context).await;
     }
-    Ok(())
-}
 
-pub(crate) async fn handle_chat_effect<T>(
-    atm: &ATM,
-    profile: &Arc<ATMProfile>,
-    model: &Arc<Mutex<T>>,
-    message: &Message,
-) where
-    T: ChannelState,
-{
-    match serde_json::from_value::<ChatEffect>(message.body.clone()) {
-        Ok(chat_effect) => {
+    match MessageType::from_str(&message.type_) {
+        Ok(MessageType::Other(_type)) => {
             println!(
-                "{}",
-                style(format!(
-                    "Model ({}): incoming effect: {:?}",
-                    profile.inner.alias, chat_effect.effect
-                ))
-                .green()
+                "{}\n{}",
+                style(format!("Unknown Message Type: {} received!", _type)).red(),
+                style(format!("Message: {:?}", message)).cyan()
             );
-            let prompt = if chat_effect.effect == "balloons" {
-                "I give you a balloon".to_string()
-            } else if chat_effect.effect == "confetti" {
-                "Let's celebrate".to_string()
-            } else {
-                "I don't know what to do with this".to_string()
-            };
-            let _ = handle_prompt(
+            let _ = send_error(
                 atm,
                 profile,
-                &ChatMessage { text: prompt },
-                model,
-                message.from.as_ref().unwrap(),
+                message,
+                &from_did,
+                ChatErrorCode::UnknownMessageType,
+                &format!("Unknown message type ({})", _type),
+                shared_state,
             )
             .await;
         }
-        Err(e) => {
-            println!(
-                "{}",
-                style(format!("Error parsing chat message: {:?}", e)).red()
-            );
+        _ => {
+            println!("Received message: {:?}", message);
         }
     }
+    Ok(())
 }
 
-/// Handles a command message
-async fn handle_command<T>(
-    atm: &ATM,
-    profile: &Arc<ATMProfile>,
-    chat_message: &ChatMessage,
-    model: &Arc<Mutex<T>>,
-    remote_did: &str,
-) -> Result<()>
-where
-    T: ChannelState,
-{
-    let response = if chat_message.text.to_lowercase() == "/help" {
-        r#"Help:
-          /help - Display this help message
-          /think - Status of the think tokens being displayed
-          /think on|off - Turn think tokens on or off
-          /dids - Display the DID's for this chat
-        "#
-        .to_string()
-    } else if chat_message.text.to_lowercase() == "/dids" {
-        format!(
-            "DIDs:\nAgent: {}\nClient: {}",
-            profile.inner.did, remote_did
-        )
-    } else {
-        format!(
-            "ERROR: unknown command: {}\nUse /help to show commands",
-            chat_message.text
-        )
-    };
+/// A built-in message handler, handles the listed message types
+struct BuiltinHandler {
+    message_types: &'static [&'static str],
+    handle: for<'a> fn(MessageContext<'a>) -> BoxFuture<'a, Result<()>>,
+}
 
-    let _ = send_message(atm, profile, &response, remote_did, model).await;
+impl MessageHandler for BuiltinHandler {
+    fn can_handle(&self, message_type: &str) -> bool {
+        self.message_types.contains(&message_type)
+    }
 
-    Ok(())
+    fn handle<'a>(&'a self, context: MessageContext<'a>) -> BoxFuture<'a, Result<()>> {
+        (self.handle)(context)
+    }
+
+    fn message_types(&self) -> &[&str] {
+        self.message_types
+    }
 }
 
-/// Handles a prompt message
-async fn handle_prompt<T>(
-    atm: &ATM,
-    profile: &Arc<ATMProfile>,
-    chat_message: &ChatMessage,
-    model: &Arc<Mutex<T>>,
-    to_did: &str,
-) -> Result<()>
-where
-    T: ChannelState,
-{
-    let (ollama_host, ollama_port, model_name) = {
-        let lock = model.lock().await;
+/// Handlers for the message types the bridge supports, registered by default
+pub(crate) fn builtin_handlers() -> Vec<Box<dyn MessageHandler>> {
+    let handlers = [
+        BuiltinHandler {
+            message_types: &["https://didcomm.org/trust-ping/2.0/ping"],
+            handle: handle_trust_ping,
+        },
+        BuiltinHandler {
+            message_types: &["https://didcomm.org/messagepickup/3.0/status"],
+            handle: handle_pickup_status,
+        },
+        BuiltinHandler {
+            message_types: &["https://affinidi.com/atm/client-actions/connection-setup"],
+            handle: handle_connection_setup,
+        },
+        BuiltinHandler {
+            message_types: &["https://affinidi.com/atm/client-actions/chat-presence"],
+            handle: |context| {
+                Box::pin(async move {
+                    // Send a presence response back
+                    let _ = handle_presence(context.atm, context.profile, context.from_did).await;
+                    Ok(())
+                })
+            },
+        },
+        BuiltinHandler {
+            message_types: &["https://affinidi.com/atm/client-actions/chat-effect"],
+            handle: handle_chat_effect_message,
+        },
+        BuiltinHandler {
+            message_types: &["https://affinidi.com/atm/client-actions/chat-reaction"],
+            handle: handle_chat_reaction_message,
+        },
+        BuiltinHandler {
+            message_types: &["https://affinidi.com/atm/client-actions/chat-message"],
+            handle: handle_chat_message_type,
+        },
+        BuiltinHandler {
+            message_types: &[BASIC_MESSAGE_TYPE],
+            handle: handle_basic_message,
+        },
+        BuiltinHandler {
+            message_types: &[EMBED_TYPE],
+            handle: handle_embed_message,
+        },
+        BuiltinHandler {
+            message_types: &[CHAT_TOOL_RESULT_TYPE],
+            handle: handle_tool_result_message,
+        },
+        BuiltinHandler {
+            message_types: &[DISCOVER_FEATURES_QUERIES_TYPE],
+            handle: handle_discover_features,
+        },
+        BuiltinHandler {
+            // alias-profile-hash is ignored, delivered is the other client acknowledging receipt of a message
+            // and activity is the other client typing
+            message_types: &[
+                "https://affinidi.com/atm/client-actions/chat-alias-profile-hash",
+                "https://affinidi.com/atm/client-actions/chat-delivered",
+                "https://affinidi.com/atm/client-actions/chat-activity",
+            ],
+            handle: |_| Box::pin(async { Ok(()) }),
+        },
+        BuiltinHandler {
+            message_types: &[CHAT_ERROR_TYPE],
+            handle: |context| {
+                Box::pin(async move {
+                    // Never reply to errors, two agents could otherwise trade errors forever
+                    warn!(
+                        "Received chat error from ({}): {}",
+                        context.from_did, context.message.body
+                    );
+                    Ok(())
+                })
+            },
+        },
+    ];
 
-        let model = lock.get_model().unwrap();
+    handlers
+        .into_iter()
+        .map(|handler| Box::new(handler) as Box<dyn MessageHandler>)
+        .collect()
+}
 
-        (
-            model.ollama_host.clone(),
-            model.ollama_port,
-            model.name.clone(),
-        )
-    };
+/// Generic DIDComm clients ping the agent to check it is reachable
+fn handle_trust_ping(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        if context
+            .message
+            .body
+            .get("response_requested")
+            .and_then(|requested| requested.as_bool())
+            .unwrap_or(true)
+        {
+            let _ = send_ping_response(
+                context.atm,
+                context.profile,
+                context.message,
+                context.from_did,
+            )
+            .await;
+        }
+        Ok(())
+    })
+}
 
-    // Instantiate Ollama
-    let ollama = Ollama::new(&ollama_host, ollama_port);
+/// Discloses the features matching a discover-features query, so clients can adapt to the agent
+/// (e.g. not sending images to a model without vision)
+fn handle_discover_features(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let queries = context
+            .message
+            .body
+            .get("queries")
+            .and_then(|queries| queries.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let features = {
+            let model = context.model.lock().await;
+            agent_features(
+                &model,
+                &context.shared_state.message_handlers.message_types(),
+            )
+        };
 
-    let mut stream = ollama
-        .generate_stream(GenerationRequest::new(
-            model_name.clone(),
-            chat_message.text.clone(),
-        ))
-        .await
-        .unwrap();
+        let disclosures = features
+            .into_iter()
+            .filter(|(feature_type, id)| {
+                queries.iter().any(|query| {
+                    query["feature-type"].as_str() == Some(feature_type)
+                        && query["match"]
+                            .as_str()
+                            .is_some_and(|pattern| feature_matches(pattern, id))
+                })
+            })
+            .map(|(feature_type, id)| serde_json::json!({ "feature-type": feature_type, "id": id }))
+            .collect::<Vec<_>>();
 
-    let mut stdout = stdout();
-    stdout.write_all(b"\n> ").await?;
-    stdout.flush().await?;
+        let msg = Message::build(
+            uuid::Uuid::new_v4().to_string(),
+            DISCOVER_FEATURES_DISCLOSE_TYPE.to_string(),
+            serde_json::json!({ "disclosures": disclosures }),
+        )
+        .thid(context.message.id.clone())
+        .created_time(now_secs())
+        .from(context.profile.inner.did.clone())
+        .to(context.from_did.to_string())
+        .finalize();
 
-    let mut think_flag = false;
-    let mut output = String::new();
+        deliver(context.atm, context.profile, &msg, context.from_did).await
+    })
+}
 
-    let timeout: tokio::time::Sleep = tokio::time::sleep(Duration::from_secs(30));
-    let mut typing_interval = tokio::time::interval_at(
-        Instant::now() + Duration::from_secs(3),
-        Duration::from_secs(3),
+/// Features (feature type, id) of a model's agent, see DISCOVER_FEATURES_QUERIES_TYPE
+fn agent_features(model: &OllamaModel, message_types: &[&str]) -> Vec<(&'static str, String)> {
+    let message_types = message_types
+        .iter()
+        .filter(|message_type| match **message_type {
+            EMBED_TYPE => model.supports_embeddings,
+            CHAT_TOOL_RESULT_TYPE => !model.tools.is_empty(),
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    let mut features: Vec<(&'static str, String)> = Vec::new();
+    // A message type is its protocol's URI followed by the message name
+    for (protocol, _) in message_types
+        .iter()
+        .filter_map(|message_type| message_type.rsplit_once('/'))
+    {
+        if !features.iter().any(|(_, id)| id == protocol) {
+            features.push(("protocol", protocol.to_string()));
+        }
+    }
+    features.extend(
+        message_types
+            .iter()
+            .map(|message_type| ("message-type", message_type.to_string())),
     );
-    tokio::pin!(timeout);
+    if model.supports_images {
+        features.extend(
+            SUPPORTED_IMAGE_TYPES
+                .iter()
+                .map(|media_type| ("attachment", media_type.to_string())),
+        );
+    }
+    features
+}
 
-    let _ = i_am_thinking(atm, profile, model, to_did).await;
-    loop {
-        select! {
-            _ = &mut timeout => {
-                warn!("AI Response timed out");
-                let _ = send_message(atm, profile, "Timeout: I'm sorry, I'm taking too long to respond", to_did, model).await;
-                break;
+/// Whether a feature id matches a discover-features query, a trailing `*` matches anything
+fn feature_matches(pattern: &str, id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => id.starts_with(prefix),
+        None => pattern == id,
+    }
+}
+
+fn handle_pickup_status(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        match serde_json::from_value::<MessagePickupStatusReply>(context.message.body.clone()) {
+            Ok(status) => {
+                info!(
+                    "STATUS-RESPONSE: queued messages ({}), live_streaming?({})",
+                    status.message_count, status.live_delivery
+                );
+                Ok(())
             }
-            _ = typing_interval.tick() => {
-                let _ = i_am_thinking(atm, profile, model, to_did).await;
-                let _ = handle_presence(atm, profile, to_did).await;
+            Err(e) => {
+                println!(
+                    "{}",
+                    style(format!("Error parsing message body: {:?}", e)).red()
+                );
+                Err(anyhow::anyhow!("Error parsing message body"))
             }
-            token = stream.next() => {
-                match token {
-                    Some(Ok(res)) => {
-                        for ele in res {
-                            //stdout.write_all(ele.response.as_bytes()).await?;
-                            if !think_flag {
-                                if ele.response == "\n\n" {
-                                    continue;
-                                } else if ele.response == ".\n\n" {
-                                    output.push_str(&ele.response);
-                                    let _ = send_message(atm, profile, &output, to_did, model).await;
-                                    output.clear();
-
-                                    continue;
-                                }
-                                //println!("{:?}", ele);
-                                output.push_str(&ele.response);
-                            }
-                            if ele.response.contains("</think>") {
-                                think_flag = false;
-                            }
+        }
+    })
+}
 
-                            stdout.flush().await?;
-                        }
-                    }
-                    Some(Err(err)) => {
-                        error!("Error: {:?}", err);
-                        break;
+fn handle_connection_setup(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        info!(
+            "{}: Received Connection Setup Request: from({})",
+            profile.inner.alias, from_did
+        );
+        check_connection_setup(atm, profile, message, from_did, shared_state).await?;
+        let didcomm_agent = {
+            let lock = model.lock().await;
+
+            let Some(did_agent) = lock
+                .get_model()
+                .and_then(|model| model.dids.iter().find(|d| d.did == profile.inner.did))
+            else {
+                warn!(
+                    "Model ({}): No agent configured for DID ({}), ignoring connection setup",
+                    model_name, profile.inner.did
+                );
+                drop(lock);
+                let _ = send_error(
+                    atm,
+                    profile,
+                    message,
+                    from_did,
+                    ChatErrorCode::NoAgent,
+                    "This DID isn't configured to accept connections",
+                    shared_state,
+                )
+                .await;
+                return Err(anyhow::anyhow!(
+                    "No agent configured for DID ({})",
+                    profile.inner.did
+                ));
+            };
+
+            // Agents named after the model are presented by the model's alias
+            let mut did_agent = did_agent.clone();
+            if let Some(model) = lock.get_model().filter(|m| m.name == did_agent.name) {
+                did_agent.name = model.display_name().to_string();
+            }
+            did_agent
+        };
+        let new_did = send_connection_response(atm, profile, message, &didcomm_agent).await?;
+        // Clients can send their locale, responses are in that language until /lang changes it
+        let language = message
+            .body
+            .get("locale")
+            .and_then(|locale| locale.as_str())
+            .and_then(parse_language);
+        {
+            let mut lock = model.lock().await;
+            let from_did_hash = digest(from_did);
+            lock.remove_channel_state(&from_did_hash);
+            let new_did_hash = digest(&new_did);
+            lock.insert_channel_state(
+                &new_did_hash,
+                ChatChannelState {
+                    remote_did: new_did.clone(),
+                    remote_did_hash: new_did_hash.clone(),
+                    agent_did: Some(profile.inner.did.clone()),
+                    last_seen: now_secs(),
+                    language,
+                    ..Default::default()
+                },
+            );
+        }
+        let greeting = didcomm_agent.render_greeting(model_name);
+        let _ = send_message(atm, profile, &greeting, &new_did, model).await;
+        Ok(())
+    })
+}
+
+/// Checks a connection-setup message can be responded to, sending the peer a chat-error if it can't
+pub(crate) async fn check_connection_setup(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    message: &Message,
+    from_did: &str,
+    shared_state: &SharedStateRef,
+) -> Result<()> {
+    if let Err(e) = channel_did(message) {
+        warn!(
+            "Malformed connection setup from ({}): {}: {:?}",
+            from_did, e, message
+        );
+        let _ = send_error(
+            atm,
+            profile,
+            message,
+            from_did,
+            ChatErrorCode::InvalidBody,
+            &format!("Couldn't set up the connection, {}", e),
+            shared_state,
+        )
+        .await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn handle_chat_effect_message(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        if !is_permitted(model, profile, from_did).await {
+            let _ = send_message(atm, profile, NOT_PERMITTED_RESPONSE, from_did, model).await;
+            return Ok(());
+        }
+        // Special handling for balloons and confetti
+        handle_chat_effect(
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        )
+        .await;
+        Ok(())
+    })
+}
+
+fn handle_chat_reaction_message(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        let _ = ack_message(atm, profile, message).await;
+        let respond = model.lock().await.respond_to_reactions;
+        if respond && is_permitted(model, profile, from_did).await {
+            handle_chat_reaction(
+                atm,
+                profile,
+                model,
+                model_name,
+                message,
+                from_did,
+                shared_state,
+            )
+            .await;
+        }
+        Ok(())
+    })
+}
+
+fn handle_chat_message_type(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        let _ = ack_message(atm, profile, message).await;
+        match serde_json::from_value::<ChatMessage>(message.body.clone()) {
+            Ok(chat_message) => {
+                // Clients advertise edit support with each message, it changes if the client is swapped
+                if let Some(supports_edits) = message
+                    .body
+                    .get("supportsEdits")
+                    .and_then(|supports| supports.as_bool())
+                    && let Some(state) = model.lock().await.get_channel_state_mut(&digest(from_did))
+                {
+                    state.supports_edits = supports_edits;
+                }
+                let chat_message = ChatMessage {
+                    message_id: Some(message.id.clone()),
+                    thid: Some(reply_thid(message)),
+                    ..chat_message
+                };
+                handle_chat_message(
+                    atm,
+                    profile,
+                    model,
+                    model_name,
+                    message,
+                    chat_message,
+                    shared_state,
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => {
+                println!(
+                    "{}",
+                    style(format!("Error parsing chat message: {:?}", e)).red()
+                );
+                let _ = send_error(
+                    atm,
+                    profile,
+                    message,
+                    from_did,
+                    ChatErrorCode::InvalidBody,
+                    &format!("Couldn't parse chat message: {}", e),
+                    shared_state,
+                )
+                .await;
+                Err(anyhow::anyhow!("Error parsing chat message"))
+            }
+        }
+    })
+}
+
+fn handle_basic_message(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        match serde_json::from_value::<BasicMessage>(message.body.clone()) {
+            Ok(basic_message) => {
+                // Responses on this channel are sent as basic messages from now on
+                if let Some(state) = model.lock().await.get_channel_state_mut(&digest(from_did)) {
+                    state.basic_message = true;
+                }
+                let chat_message = ChatMessage {
+                    text: basic_message.content,
+                    images: Vec::new(),
+                    message_id: None,
+                    thid: Some(reply_thid(message)),
+                };
+                handle_chat_message(
+                    atm,
+                    profile,
+                    model,
+                    model_name,
+                    message,
+                    chat_message,
+                    shared_state,
+                )
+                .await;
+                Ok(())
+            }
+            Err(e) => {
+                println!(
+                    "{}",
+                    style(format!("Error parsing basic message: {:?}", e)).red()
+                );
+                Err(anyhow::anyhow!("Error parsing basic message"))
+            }
+        }
+    })
+}
+
+fn handle_embed_message(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            ..
+        } = context;
+        if !is_permitted(model, profile, from_did).await {
+            warn!(
+                "DID ({}) is not permitted to request embeddings from this agent",
+                from_did
+            );
+            let _ = send_embed_response(
+                atm,
+                profile,
+                message,
+                from_did,
+                serde_json::json!({ "error": NOT_PERMITTED_RESPONSE }),
+            )
+            .await;
+            return Ok(());
+        }
+        let body = match handle_embed_request(model, message).await {
+            Ok((model_name, embeddings)) => {
+                serde_json::json!({ "model": model_name, "embeddings": embeddings })
+            }
+            Err(e) => {
+                warn!("Model ({}): embed request failed: {}", model_name, e);
+                metrics::record_error(model_name);
+                serde_json::json!({ "error": e.to_string() })
+            }
+        };
+        let _ = send_embed_response(atm, profile, message, from_did, body).await;
+        Ok(())
+    })
+}
+
+fn handle_tool_result_message(context: MessageContext<'_>) -> BoxFuture<'_, Result<()>> {
+    Box::pin(async move {
+        let MessageContext {
+            atm,
+            profile,
+            model,
+            model_name,
+            message,
+            from_did,
+            shared_state,
+        } = context;
+        let _ = ack_message(atm, profile, message).await;
+        if !is_permitted(model, profile, from_did).await {
+            let _ = send_message(atm, profile, NOT_PERMITTED_RESPONSE, from_did, model).await;
+            return Ok(());
+        }
+
+        let recorded = match serde_json::from_value::<ToolResults>(message.body.clone()) {
+            Ok(tool_results) => {
+                let mut lock = model.lock().await;
+                let max_history = lock.max_history;
+                match lock.get_channel_state_mut(&digest(from_did)) {
+                    Some(state) => record_tool_results(state, tool_results.results, max_history),
+                    None => Err("No tool calls are waiting for results".to_string()),
+                }
+            }
+            Err(e) => Err(format!("Couldn't parse tool results: {}", e)),
+        };
+        if let Err(reason) = recorded {
+            warn!(
+                "Model ({}): rejected tool results from DID ({}): {}",
+                model_name, from_did, reason
+            );
+            let _ = send_error(
+                atm,
+                profile,
+                message,
+                from_did,
+                ChatErrorCode::InvalidBody,
+                &reason,
+                shared_state,
+            )
+            .await;
+            return Ok(());
+        }
+
+        let chat_message = ChatMessage {
+            text: String::new(),
+            images: Vec::new(),
+            message_id: Some(message.id.clone()),
+            thid: Some(reply_thid(message)),
+        };
+        let _ = handle_prompt(
+            atm,
+            profile,
+            &chat_message,
+            model,
+            from_did,
+            shared_state,
+            PromptKind::ToolResults,
+        )
+        .await;
+        Ok(())
+    })
+}
+
+/// Records the pending tool calls and their results in the conversation history
+/// Every pending call needs a result, results are passed to the model in the order the calls were made
+fn record_tool_results(
+    state: &mut ChatChannelState,
+    mut results: Vec<ToolResult>,
+    max_history: usize,
+) -> Result<(), String> {
+    if state.pending_tool_calls.is_empty() {
+        return Err("No tool calls are waiting for results".to_string());
+    }
+    let mut ordered = Vec::new();
+    for call in &state.pending_tool_calls {
+        let Some(position) = results.iter().position(|result| result.id == call.id) else {
+            return Err(format!(
+                "Missing the result of tool call ({}) to {}",
+                call.id, call.name
+            ));
+        };
+        ordered.push(results.swap_remove(position));
+    }
+
+    let calls = std::mem::take(&mut state.pending_tool_calls);
+    state.push_history(
+        Role::ToolCalls,
+        &serde_json::to_string(&calls).unwrap_or_default(),
+        max_history,
+    );
+    for result in ordered {
+        state.push_history(Role::Tool, &result.text(), max_history);
+    }
+    Ok(())
+}
+
+/// Handles a chat prompt or command, received as a chat-message or a DIDComm basic message
+async fn handle_chat_message<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    model_name: &str,
+    message: &Message,
+    mut chat_message: ChatMessage,
+    shared_state: &SharedStateRef,
+) where
+    T: ChannelState,
+{
+    let Some(from_did) = message.from.as_deref() else {
+        return;
+    };
+    if is_expired(message) {
+        warn!(
+            "Model ({}): dropping expired message ({}) from DID ({})",
+            model_name, message.id, from_did
+        );
+        return;
+    }
+    if !is_permitted(model, profile, from_did).await {
+        warn!(
+            "DID ({}) is not permitted to chat with this agent",
+            from_did
+        );
+        let _ = send_message(atm, profile, NOT_PERMITTED_RESPONSE, from_did, model).await;
+        return;
+    }
+
+    {
+        let mut lock = model.lock().await;
+        if let Some(state) = lock.get_channel_state_mut(&digest(from_did)) {
+            state.messages_processed += 1;
+        }
+    }
+    println!(
+        "{}",
+        style(format!(
+            "Model ({}): incoming prompt: {:?}",
+            model_name, chat_message.text
+        ))
+        .green()
+    );
+    match extract_images(message) {
+        Ok(images) => chat_message.images = images,
+        Err(reason) => {
+            warn!("Rejected attachment: {}", reason);
+            let _ = send_message(atm, profile, &reason, from_did, model).await;
+            return;
+        }
+    }
+    let agent = agent_for(model, profile).await;
+    if let Some(command) = agent.parse_command(&chat_message.text) {
+        let _ = handle_command(
+            atm,
+            profile,
+            &command,
+            &agent,
+            model,
+            from_did,
+            shared_state,
+        )
+        .await;
+    } else if check_prompt(
+        atm,
+        profile,
+        model,
+        message,
+        from_did,
+        &mut chat_message.text,
+        shared_state,
+    )
+    .await
+    {
+        let _ = handle_prompt(
+            atm,
+            profile,
+            &chat_message,
+            model,
+            from_did,
+            shared_state,
+            PromptKind::New,
+        )
+        .await;
+    }
+}
+
+/// Whether the message's expires_time has passed, expired messages are dropped without a response
+pub(crate) fn is_expired(message: &Message) -> bool {
+    message
+        .expires_time
+        .is_some_and(|expires_time| now_secs() > expires_time)
+}
+
+/// Checks a prompt can be answered, telling the remote party why if it can't
+/// Stale prompts and prompts over the rate limit are refused, as are prompts that are too long unless the
+/// model allows them to be truncated. Logged in the agent's span, which names the model
+pub(crate) async fn check_prompt<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    message: &Message,
+    from_did: &str,
+    text: &mut String,
+    shared_state: &SharedStateRef,
+) -> bool
+where
+    T: ChannelState,
+{
+    let limits = prompt_limits(model, shared_state).await;
+    if let Some(age) = stale_message_age(&limits, message) {
+        warn!(
+            "Not answering prompt from DID ({}) received {}s after it was sent",
+            from_did, age
+        );
+        if limits.notify_stale_messages {
+            let _ = send_message(atm, profile, STALE_RESPONSE, from_did, model).await;
+        }
+        return false;
+    }
+    if !acquire_rate_limit(model, &limits, from_did).await {
+        warn!("DID ({}) is sending prompts too quickly", from_did);
+        let _ = send_message(atm, profile, THROTTLED_RESPONSE, from_did, model).await;
+        return false;
+    }
+    match fit_prompt_length(&limits, text) {
+        Ok(None) => true,
+        Ok(Some(warning)) => {
+            warn!("Truncated prompt from DID ({})", from_did);
+            let _ = send_message(atm, profile, &warning, from_did, model).await;
+            true
+        }
+        Err(reason) => {
+            warn!("Rejected prompt from DID ({})", from_did);
+            let _ = send_message(atm, profile, &reason, from_did, model).await;
+            false
+        }
+    }
+}
+
+/// Limits a model puts on the prompts it answers
+#[derive(Clone, Copy, Default)]
+struct PromptLimits {
+    max_message_age_secs: Option<u64>,
+    notify_stale_messages: bool,
+    rate_limit_per_minute: Option<u32>,
+    max_prompt_chars: Option<usize>,
+    truncate_long_prompts: bool,
+}
+
+impl From<&OllamaModel> for PromptLimits {
+    fn from(model: &OllamaModel) -> Self {
+        PromptLimits {
+            max_message_age_secs: model.max_message_age_secs,
+            notify_stale_messages: model.notify_stale_messages,
+            rate_limit_per_minute: model.rate_limit_per_minute,
+            max_prompt_chars: model.max_prompt_chars,
+            truncate_long_prompts: model.truncate_long_prompts,
+        }
+    }
+}
+
+/// The limits on prompts answered on this channel state
+/// The concierge answers with its default model, so that model's limits apply
+async fn prompt_limits<T>(model: &Arc<Mutex<T>>, shared_state: &SharedStateRef) -> PromptLimits
+where
+    T: ChannelState,
+{
+    // Released before the concierge is locked, the channel state may be the concierge's
+    let own_limits = model.lock().await.get_model().map(PromptLimits::from);
+    if let Some(limits) = own_limits {
+        return limits;
+    }
+    let default_model = shared_state.concierge.lock().await.default_model.clone();
+    let target = match default_model {
+        Some(name) => shared_state.models.lock().await.get(&name).cloned(),
+        None => None,
+    };
+    match target {
+        Some(target) => PromptLimits::from(&*target.lock().await),
+        None => PromptLimits::default(),
+    }
+}
+
+/// Whether a received message asks the model for a response (a prompt, /regenerate, tool results or an effect or
+/// reaction that is answered), rather than being another command or client action
+/// Reactions are only answered if the model responds to reactions
+/// Prompts on a channel are answered in order, everything else is handled at once so that /stop can interrupt them
+pub(crate) fn is_prompt(
+    message: &Message,
+    agent: &DIDCommAgent,
+    respond_to_reactions: bool,
+) -> bool {
+    let text = match message.type_.as_str() {
+        "https://affinidi.com/atm/client-actions/chat-message" => message.body.get("text"),
+        BASIC_MESSAGE_TYPE => message.body.get("content"),
+        CHAT_TOOL_RESULT_TYPE | "https://affinidi.com/atm/client-actions/chat-effect" => {
+            return true;
+        }
+        "https://affinidi.com/atm/client-actions/chat-reaction" => return respond_to_reactions,
+        _ => return false,
+    };
+    let Some(text) = text.and_then(|text| text.as_str()) else {
+        return false;
+    };
+    // /regenerate rewinds the last prompt, so it waits for the prompt to be answered
+    agent.parse_command(text).is_none_or(|command| {
+        command
+            .split_whitespace()
+            .next()
+            .is_some_and(|command| command.eq_ignore_ascii_case("/regenerate"))
+    })
+}
+
+/// Applies the model's max_prompt_chars to a prompt, truncating it if the model allows that
+/// # Returns
+/// * `Ok(None)` - The prompt fits
+/// * `Ok(Some(warning))` - The prompt was truncated, the warning is for the remote party
+/// * `Err(reason)` - The prompt is too long, the reason is for the remote party
+fn fit_prompt_length(limits: &PromptLimits, text: &mut String) -> Result<Option<String>, String> {
+    let length = text.chars().count();
+    let Some(max_prompt_chars) = limits.max_prompt_chars.filter(|max| length > *max) else {
+        return Ok(None);
+    };
+
+    if !limits.truncate_long_prompts {
+        return Err(format!(
+            "Your message is too long ({} characters), please keep it to {} characters or fewer.",
+            length, max_prompt_chars
+        ));
+    }
+    *text = text.chars().take(max_prompt_chars).collect();
+    Ok(Some(format!(
+        "Your message is too long ({} characters), only the first {} characters were used.",
+        length, max_prompt_chars
+    )))
+}
+
+/// Returns the age of the message (seconds) if it is older than the model's max_message_age_secs
+/// Messages without a created_time are never considered stale
+fn stale_message_age(limits: &PromptLimits, message: &Message) -> Option<u64> {
+    let max_age = limits.max_message_age_secs?;
+    let age = now_secs().saturating_sub(message.created_time?);
+    (age > max_age).then_some(age)
+}
+
+/// Tells the sender that their message couldn't be processed
+/// Messages from mediators aren't replied to, they aren't chat clients
+pub(crate) async fn send_error(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    request: &Message,
+    to_did: &str,
+    code: ChatErrorCode,
+    reason: &str,
+    shared_state: &SharedStateRef,
+) -> Result<()> {
+    if shared_state.mediator_dids.iter().any(|did| did == to_did) {
+        return Ok(());
+    }
+
+    let msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        CHAT_ERROR_TYPE.to_string(),
+        serde_json::json!({ "code": code, "reason": reason }),
+    )
+    .thid(request.id.clone())
+    .created_time(now_secs())
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string())
+    .finalize();
+
+    deliver(atm, profile, &msg, to_did).await
+}
+
+/// Generates embeddings for an embed request using the model's Ollama instance
+/// Returns the name of the model and an embedding for each input text
+async fn handle_embed_request<T>(
+    model: &Arc<Mutex<T>>,
+    message: &Message,
+) -> Result<(String, Vec<Vec<f32>>)>
+where
+    T: ChannelState,
+{
+    let settings = {
+        let lock = model.lock().await;
+        match lock.get_model() {
+            Some(model) if !model.backend.is_ollama() => {
+                anyhow::bail!(
+                    "Model ({}) embeddings require the Ollama backend",
+                    model.name
+                )
+            }
+            Some(model) if model.supports_embeddings => GenerationSettings::from(model),
+            Some(model) => anyhow::bail!("Model ({}) doesn't support embeddings", model.name),
+            None => anyhow::bail!("This agent doesn't support embeddings"),
+        }
+    };
+
+    let request = serde_json::from_value::<EmbedRequest>(message.body.clone())
+        .map_err(|e| anyhow::anyhow!("Invalid embed request: {}", e))?;
+
+    let mut request =
+        GenerateEmbeddingsRequest::new(settings.model_name.clone(), request.input.into());
+    if let Some(options) = &settings.options {
+        request = request.options(options.to_generation_options());
+    }
+    if let Some(keep_alive) = settings.keep_alive {
+        request = request.keep_alive(keep_alive);
+    }
+
+    let response = settings
+        .ollama
+        .generate_embeddings(request)
+        .await
+        .map_err(|e| anyhow::anyhow!("Ollama couldn't generate embeddings: {}", e))?;
+
+    Ok((settings.model_name, response.embeddings))
+}
+
+/// Sends the result of an embed request back to the requester, threaded to the request
+async fn send_embed_response(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    request: &Message,
+    to_did: &str,
+    body: serde_json::Value,
+) -> Result<()> {
+    let msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        "https://affinidi.com/atm/client-actions/embed-response".to_string(),
+        body,
+    )
+    .thid(request.id.clone())
+    .created_time(now_secs())
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string())
+    .finalize();
+
+    deliver(atm, profile, &msg, to_did).await
+}
+
+/// Takes a token from the channel's rate limiter if the model has a rate limit
+/// Returns false if the prompt should be throttled
+async fn acquire_rate_limit<T>(
+    model: &Arc<Mutex<T>>,
+    limits: &PromptLimits,
+    remote_did: &str,
+) -> bool
+where
+    T: ChannelState,
+{
+    let Some(per_minute) = limits.rate_limit_per_minute else {
+        return true;
+    };
+    match model
+        .lock()
+        .await
+        .get_channel_state_mut(&digest(remote_did))
+    {
+        Some(state) => state.rate_limiter.try_acquire(per_minute),
+        None => true,
+    }
+}
+
+/// Checks the remote DID is permitted to chat with the agent this profile belongs to
+async fn is_permitted<T>(model: &Arc<Mutex<T>>, profile: &Arc<ATMProfile>, remote_did: &str) -> bool
+where
+    T: ChannelState,
+{
+    let lock = model.lock().await;
+    lock.get_model()
+        .and_then(|model| model.dids.iter().find(|d| d.did == profile.inner.did))
+        .is_none_or(|agent| agent.is_allowed(remote_did))
+}
+
+/// The agent configuration for the profile, defaults if the profile doesn't belong to the model
+async fn agent_for<T>(model: &Arc<Mutex<T>>, profile: &Arc<ATMProfile>) -> DIDCommAgent
+where
+    T: ChannelState,
+{
+    let lock = model.lock().await;
+    lock.get_model()
+        .and_then(|model| model.dids.iter().find(|d| d.did == profile.inner.did))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Extracts base64 encoded images from the message attachments
+/// Returns a friendly reason that can be sent to the remote party if an attachment is rejected
+fn extract_images(message: &Message) -> Result<Vec<String>, String> {
+    let Some(attachments) = &message.attachments else {
+        return Ok(Vec::new());
+    };
+
+    let mut images = Vec::new();
+    for attachment in attachments {
+        let media_type = attachment.media_type.as_deref().unwrap_or_default();
+        let image_type = media_type
+            .strip_suffix(compression::GZIP_MEDIA_TYPE_SUFFIX)
+            .unwrap_or(media_type);
+        if !SUPPORTED_IMAGE_TYPES.contains(&image_type) {
+            return Err(format!(
+                "Sorry, I can only handle PNG and JPEG images (received: {})",
+                if media_type.is_empty() {
+                    "unknown"
+                } else {
+                    media_type
+                }
+            ));
+        }
+
+        let AttachmentData::Base64 { value } = &attachment.data else {
+            return Err("Sorry, images must be sent as base64 attachments".to_string());
+        };
+
+        let Ok(bytes) = BASE64_STANDARD
+            .decode(&value.base64)
+            .or_else(|_| BASE64_URL_SAFE_NO_PAD.decode(&value.base64))
+        else {
+            return Err("Sorry, I couldn't decode the attached image".to_string());
+        };
+        let Ok((bytes, _)) = compression::decompress(bytes, media_type, MAX_IMAGE_SIZE) else {
+            return Err(format!(
+                "Sorry, I couldn't decompress the attached image. The maximum size is {} MB",
+                MAX_IMAGE_SIZE / (1024 * 1024)
+            ));
+        };
+
+        if bytes.len() > MAX_IMAGE_SIZE {
+            return Err(format!(
+                "Sorry, that image is too large ({} MB). The maximum size is {} MB",
+                bytes.len() / (1024 * 1024),
+                MAX_IMAGE_SIZE / (1024 * 1024)
+            ));
+        }
+
+        // Ollama expects standard base64 encoding
+        images.push(BASE64_STANDARD.encode(bytes));
+    }
+
+    Ok(images)
+}
+
+/// Turns an emoji reaction into a prompt, so the model can respond to it
+/// Reactions start a generation, so they go through the same checks as a prompt
+async fn handle_chat_reaction<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    model_name: &str,
+    message: &Message,
+    from_did: &str,
+    shared_state: &SharedStateRef,
+) where
+    T: ChannelState,
+{
+    match serde_json::from_value::<ChatReaction>(message.body.clone()) {
+        Ok(chat_reaction) => {
+            println!(
+                "{}",
+                style(format!(
+                    "Model ({}): incoming reaction: {:?}",
+                    model_name, chat_reaction.reaction
+                ))
+                .green()
+            );
+            let text = format!("I reacted to your message with {}", chat_reaction.reaction);
+            answer_client_action(atm, profile, model, message, from_did, text, shared_state).await;
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                style(format!("Error parsing chat reaction: {:?}", e)).red()
+            );
+        }
+    }
+}
+
+/// Turns a chat effect (balloons, confetti) into a prompt, so the model can respond to it
+/// Effects start a generation, so they go through the same checks as a prompt
+pub(crate) async fn handle_chat_effect<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    model_name: &str,
+    message: &Message,
+    from_did: &str,
+    shared_state: &SharedStateRef,
+) where
+    T: ChannelState,
+{
+    match serde_json::from_value::<ChatEffect>(message.body.clone()) {
+        Ok(chat_effect) => {
+            println!(
+                "{}",
+                style(format!(
+                    "Model ({}): incoming effect: {:?}",
+                    model_name, chat_effect.effect
+                ))
+                .green()
+            );
+            let text = if chat_effect.effect == "balloons" {
+                "I give you a balloon".to_string()
+            } else if chat_effect.effect == "confetti" {
+                "Let's celebrate".to_string()
+            } else {
+                "I don't know what to do with this".to_string()
+            };
+            answer_client_action(atm, profile, model, message, from_did, text, shared_state).await;
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                style(format!("Error parsing chat message: {:?}", e)).red()
+            );
+        }
+    }
+}
+
+/// Answers the prompt a client action (a reaction or effect) was turned into, if it passes the prompt checks
+async fn answer_client_action<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    message: &Message,
+    from_did: &str,
+    mut text: String,
+    shared_state: &SharedStateRef,
+) where
+    T: ChannelState,
+{
+    if is_expired(message) {
+        warn!(
+            "Dropping expired message ({}) from DID ({})",
+            message.id, from_did
+        );
+        return;
+    }
+    if !check_prompt(
+        atm,
+        profile,
+        model,
+        message,
+        from_did,
+        &mut text,
+        shared_state,
+    )
+    .await
+    {
+        return;
+    }
+    let chat_message = ChatMessage {
+        text,
+        images: Vec::new(),
+        message_id: None,
+        thid: None,
+    };
+    if let Err(e) = handle_prompt(
+        atm,
+        profile,
+        &chat_message,
+        model,
+        from_did,
+        shared_state,
+        PromptKind::New,
+    )
+    .await
+    {
+        warn!(
+            "Couldn't answer ({}) from DID ({}): {}",
+            message.type_, from_did, e
+        );
+    }
+}
+
+/// Handles a command message
+async fn handle_command<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    agent: &DIDCommAgent,
+    model: &Arc<Mutex<T>>,
+    remote_did: &str,
+    shared_state: &SharedStateRef,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    let text = text.trim();
+    let (command, argument) = match text.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command.to_lowercase(), argument.trim()),
+        None => (text.to_lowercase(), ""),
+    };
+
+    let response = match command.as_str() {
+        "/help" => agent.render_commands(
+            r#"Help:
+          /help - Display this help message
+          /think - Status of the think tokens being displayed
+          /think on|off - Turn think tokens on or off, they are sent as a separate reasoning message
+          /json - Status of JSON responses
+          /json on|off - Ask the model to respond with JSON
+          /temp - Temperature used for responses
+          /temp <0.0-2.0> - Set the temperature, higher is more creative
+          /stop-seq - List the stop sequences added to this chat
+          /stop-seq add <sequence> - Stop responses when the model produces the sequence
+          /stop-seq clear - Remove the stop sequences added to this chat
+          /lang - Language responses are requested in
+          /lang <code> - Ask for responses in a language (e.g. fr, pt-BR)
+          /lang auto - Let the model choose the language
+          /dids - Display the DID's for this chat
+          /clear - Clear the conversation history
+          /regenerate - Answer the last prompt again
+          /model - List the models you can chat with
+          /model <name> - Switch this chat to a different model
+          /status - Display the status of this chat
+          /last - Display the statistics of the last response
+          /stop - Stop the response that is being generated
+        "#,
+        ),
+        "/dids" => format!(
+            "DIDs:\nAgent: {}\nClient: {}",
+            profile.inner.did, remote_did
+        ),
+        "/clear" => {
+            let mut lock = model.lock().await;
+            if let Some(state) = lock.get_channel_state_mut(&digest(remote_did)) {
+                state.history.clear();
+                state.clear_last_prompt();
+            }
+            "Conversation history cleared".to_string()
+        }
+        "/regenerate" => {
+            let has_prompt = {
+                let mut lock = model.lock().await;
+                lock.get_channel_state_mut(&digest(remote_did))
+                    .is_some_and(|state| state.last_prompt.is_some())
+            };
+            // Throttling is checked first, so a throttled regenerate leaves the history untouched
+            if !has_prompt {
+                "Nothing to regenerate".to_string()
+            } else if !acquire_rate_limit(
+                model,
+                &prompt_limits(model, shared_state).await,
+                remote_did,
+            )
+            .await
+            {
+                THROTTLED_RESPONSE.to_string()
+            } else {
+                let last_prompt = {
+                    let mut lock = model.lock().await;
+                    lock.get_channel_state_mut(&digest(remote_did))
+                        .and_then(|state| state.rewind_last_prompt())
+                };
+                match last_prompt {
+                    Some(last_prompt) => {
+                        // Answered in the original prompt's thread, with its images
+                        let chat_message = ChatMessage {
+                            text: last_prompt.text,
+                            images: last_prompt.images,
+                            message_id: None,
+                            thid: last_prompt.thid,
+                        };
+                        return handle_prompt(
+                            atm,
+                            profile,
+                            &chat_message,
+                            model,
+                            remote_did,
+                            shared_state,
+                            PromptKind::Regenerate,
+                        )
+                        .await;
+                    }
+                    None => "Nothing to regenerate".to_string(),
+                }
+            }
+        }
+        "/think" => {
+            let mut lock = model.lock().await;
+            match lock.get_channel_state_mut(&digest(remote_did)) {
+                Some(state) => match argument.to_lowercase().as_str() {
+                    "" => format!(
+                        "Think tokens are {}",
+                        if state.show_thinking { "on" } else { "off" }
+                    ),
+                    "on" => {
+                        state.show_thinking = true;
+                        "Think tokens are now on".to_string()
+                    }
+                    "off" => {
+                        state.show_thinking = false;
+                        "Think tokens are now off".to_string()
+                    }
+                    _ => format!(
+                        "ERROR: unknown /think option: {}\nUse /think on|off",
+                        argument
+                    ),
+                },
+                None => "ERROR: No chat channel found".to_string(),
+            }
+        }
+        "/json" => {
+            let mut lock = model.lock().await;
+            let default = lock.get_model().is_some_and(|m| m.json_format);
+            match lock.get_channel_state_mut(&digest(remote_did)) {
+                Some(state) => match argument.to_lowercase().as_str() {
+                    "" => format!(
+                        "JSON responses are {}",
+                        if state.json_format.unwrap_or(default) {
+                            "on"
+                        } else {
+                            "off"
+                        }
+                    ),
+                    "on" => {
+                        state.json_format = Some(true);
+                        "JSON responses are now on".to_string()
+                    }
+                    "off" => {
+                        state.json_format = Some(false);
+                        "JSON responses are now off".to_string()
+                    }
+                    _ => format!(
+                        "ERROR: unknown /json option: {}\nUse /json on|off",
+                        argument
+                    ),
+                },
+                None => "ERROR: No chat channel found".to_string(),
+            }
+        }
+        "/temp" => {
+            let mut lock = model.lock().await;
+            let default = lock
+                .get_model()
+                .and_then(|m| m.options.as_ref())
+                .and_then(|options| options.temperature)
+                .unwrap_or(DEFAULT_TEMPERATURE);
+            match lock.get_channel_state_mut(&digest(remote_did)) {
+                Some(state) if argument.is_empty() => match state.temperature {
+                    Some(temperature) => format!("Temperature is {}", temperature),
+                    None => format!("Temperature is {} (model default)", default),
+                },
+                Some(state) => match argument.parse::<f32>() {
+                    Ok(temperature) if (0.0..=2.0).contains(&temperature) => {
+                        state.temperature = Some(temperature);
+                        format!("Temperature is now {}", temperature)
+                    }
+                    _ => format!(
+                        "ERROR: invalid temperature: {}\nUse /temp <0.0-2.0>",
+                        argument
+                    ),
+                },
+                None => "ERROR: No chat channel found".to_string(),
+            }
+        }
+        "/lang" => {
+            let mut lock = model.lock().await;
+            match lock.get_channel_state_mut(&digest(remote_did)) {
+                Some(state) if argument.is_empty() => match &state.language {
+                    Some(language) => {
+                        format!("Responses are requested in {}", language_name(language))
+                    }
+                    None => "No language is set, the model chooses the language".to_string(),
+                },
+                Some(state) if argument == "auto" => {
+                    state.language = None;
+                    "Language cleared, the model chooses the language".to_string()
+                }
+                Some(state) => match parse_language(argument) {
+                    Some(language) => {
+                        let response = format!(
+                            "Responses are now requested in {}",
+                            language_name(&language)
+                        );
+                        state.language = Some(language);
+                        response
+                    }
+                    None => format!(
+                        "ERROR: invalid language code: {}\nUse /lang <code> (e.g. fr, pt-BR) or /lang auto",
+                        argument
+                    ),
+                },
+                None => "ERROR: No chat channel found".to_string(),
+            }
+        }
+        "/stop-seq" => {
+            let mut lock = model.lock().await;
+            let (action, sequence) = argument.split_once(' ').unwrap_or((argument, ""));
+            match lock.get_channel_state_mut(&digest(remote_did)) {
+                Some(state) => match action {
+                    "" if state.stop_sequences.is_empty() => {
+                        "No stop sequences have been added".to_string()
+                    }
+                    "" => format!("Stop sequences: {:?}", state.stop_sequences),
+                    "add" if !sequence.is_empty() => {
+                        state.stop_sequences.push(sequence.to_string());
+                        format!("Added stop sequence {:?}", sequence)
+                    }
+                    "clear" => {
+                        state.stop_sequences.clear();
+                        "Stop sequences cleared".to_string()
+                    }
+                    _ => format!(
+                        "ERROR: unknown /stop-seq option: {}\nUse /stop-seq add <sequence>|clear",
+                        argument
+                    ),
+                },
+                None => "ERROR: No chat channel found".to_string(),
+            }
+        }
+        "/model" => handle_model_command(argument, model, remote_did, shared_state).await,
+        "/status" => handle_status_command(profile, model, remote_did, shared_state).await,
+        "/last" => handle_last_command(model, remote_did).await,
+        "/stop" => stop_generation(model, remote_did).await,
+        _ => agent.render_unknown_command(&format!("{}{}", agent.command_prefix(), &text[1..])),
+    };
+
+    let _ = send_message(atm, profile, &response, remote_did, model).await;
+
+    Ok(())
+}
+
+/// Stops the response being generated on the remote DID's channel
+/// Returns the response to send to the remote party
+pub(crate) async fn stop_generation<T>(model: &Arc<Mutex<T>>, remote_did: &str) -> String
+where
+    T: ChannelState,
+{
+    let mut lock = model.lock().await;
+    match lock
+        .get_channel_state_mut(&digest(remote_did))
+        .and_then(|state| state.generation.take())
+    {
+        Some(generation) => {
+            generation.notify_one();
+            "Stopped".to_string()
+        }
+        None => "Nothing to stop".to_string(),
+    }
+}
+
+/// Lists the available models, or switches this chat channel to the requested model
+/// Returns the response to send to the remote party
+async fn handle_model_command<T>(
+    model_name: &str,
+    model: &Arc<Mutex<T>>,
+    remote_did: &str,
+    shared_state: &SharedStateRef,
+) -> String
+where
+    T: ChannelState,
+{
+    // (name, alias)
+    let mut available = Vec::new();
+    {
+        let models = { shared_state.models.lock().await.clone() };
+        for (name, model) in models {
+            available.push((name, model.lock().await.alias.clone()));
+        }
+    }
+    available.sort();
+
+    let mut lock = model.lock().await;
+    let own_model = lock.get_model().map(|m| m.name.clone());
+    let Some(state) = lock.get_channel_state_mut(&digest(remote_did)) else {
+        return "ERROR: No chat channel found".to_string();
+    };
+    let current = state
+        .active_model
+        .clone()
+        .or(own_model.clone())
+        .unwrap_or_default();
+
+    if model_name.is_empty() {
+        let models = available
+            .iter()
+            .map(|(name, alias)| {
+                let alias = alias
+                    .as_ref()
+                    .map(|alias| format!(" ({})", alias))
+                    .unwrap_or_default();
+                if *name == current {
+                    format!("  {}{} (active)", name, alias)
+                } else {
+                    format!("  {}{}", name, alias)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        format!("Available models:\n{}", models)
+    } else if let Some((name, alias)) = available.iter().find(|(name, alias)| {
+        name == model_name
+            || alias
+                .as_deref()
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(model_name))
+    }) {
+        if own_model.as_deref() == Some(name.as_str()) {
+            state.active_model = None;
+        } else {
+            state.active_model = Some(name.clone());
+        }
+        format!(
+            "Now chatting with model: {}",
+            alias.as_deref().unwrap_or(name)
+        )
+    } else {
+        format!(
+            "ERROR: unknown model: {}\nAvailable models: {}",
+            model_name,
+            available
+                .iter()
+                .map(|(name, alias)| alias.clone().unwrap_or(name.clone()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Reports the model serving this chat channel and the channel counters
+/// Returns the response to send to the remote party
+async fn handle_status_command<T>(
+    profile: &Arc<ATMProfile>,
+    model: &Arc<Mutex<T>>,
+    remote_did: &str,
+    shared_state: &SharedStateRef,
+) -> String
+where
+    T: ChannelState,
+{
+    let (own_model, active_model, messages_processed, seq_no, activity_seq_no) = {
+        let lock = model.lock().await;
+        let own_model = lock
+            .get_model()
+            .map(|m| (m.name.clone(), m.backend_address()));
+        let Some(state) = lock.get_channel_state(&digest(remote_did)) else {
+            return "ERROR: No chat channel found".to_string();
+        };
+        (
+            own_model,
+            state.active_model.clone(),
+            state.messages_processed,
+            state.seq_no,
+            state.activity_seq_no,
+        )
+    };
+
+    // Prompts are routed to the model selected via /model if there is one
+    let serving_model = match active_model {
+        Some(name) => {
+            let active = { shared_state.models.lock().await.get(&name).cloned() };
+            match active {
+                Some(active) => {
+                    let lock = active.lock().await;
+                    Some((lock.name.clone(), lock.backend_address()))
+                }
+                None => None,
+            }
+        }
+        None => own_model,
+    };
+
+    let (model_name, backend) =
+        serving_model.unwrap_or_else(|| ("unknown".to_string(), "unknown".to_string()));
+
+    format!(
+        "Status:\nModel: {}\nBackend: {}\nAgent DID: {}\nMessages processed: {}\nseq_no: {}\nactivity_seq_no: {}",
+        model_name, backend, profile.inner.did, messages_processed, seq_no, activity_seq_no
+    )
+}
+
+/// Reports the statistics of the last response on this chat channel
+/// Returns the response to send to the remote party
+async fn handle_last_command<T>(model: &Arc<Mutex<T>>, remote_did: &str) -> String
+where
+    T: ChannelState,
+{
+    let lock = model.lock().await;
+    let Some(state) = lock.get_channel_state(&digest(remote_did)) else {
+        return "ERROR: No chat channel found".to_string();
+    };
+    let Some(stats) = &state.last_generation else {
+        return "No response has been generated on this chat yet".to_string();
+    };
+
+    let mut response = format!("Last response:\nModel: {}", stats.model_name);
+    if let Some(eval_count) = stats.eval_count {
+        response.push_str(&format!("\nTokens: {}", eval_count));
+    }
+    if let Some(eval_duration) = stats.eval_duration {
+        response.push_str(&format!(
+            "\nGeneration time: {:.2}s",
+            eval_duration.as_secs_f64()
+        ));
+        if let Some(eval_count) = stats.eval_count.filter(|_| !eval_duration.is_zero()) {
+            response.push_str(&format!(
+                " ({:.1} tokens/s)",
+                eval_count as f64 / eval_duration.as_secs_f64()
+            ));
+        }
+    }
+    response.push_str(&format!(
+        "\nTotal time: {:.2}s",
+        stats.total_duration.as_secs_f64()
+    ));
+    response
+}
+
+/// Removes reasoning from a streamed token, `thinking` tracks whether a <think> block is open across tokens
+/// Only text between <think> and </think> is removed, so models that never emit the tags are passed through
+/// unchanged, and text sharing a token with a tag is kept
+fn strip_thinking(content: &str, thinking: &mut bool) -> String {
+    split_thinking(content, thinking).0
+}
+
+/// Splits a streamed token into its answer and reasoning text, the tags themselves are dropped
+/// `thinking` tracks whether a <think> block is open across tokens
+fn split_thinking(content: &str, thinking: &mut bool) -> (String, String) {
+    const THINK_START: &str = "<think>";
+    const THINK_END: &str = "</think>";
+
+    let mut visible = String::new();
+    let mut reasoning = String::new();
+    let mut rest = content;
+    loop {
+        if *thinking {
+            let Some(end) = rest.find(THINK_END) else {
+                reasoning.push_str(rest);
+                return (visible, reasoning);
+            };
+            reasoning.push_str(&rest[..end]);
+            *thinking = false;
+            rest = &rest[end + THINK_END.len()..];
+        } else {
+            let Some(start) = rest.find(THINK_START) else {
+                visible.push_str(rest);
+                return (visible, reasoning);
+            };
+            visible.push_str(&rest[..start]);
+            *thinking = true;
+            rest = &rest[start + THINK_START.len()..];
+        }
+    }
+}
+
+/// Handles a prompt message
+/// `kind` - why the prompt is sent, tool results continue the last response instead of adding a prompt
+pub(crate) async fn handle_prompt<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    chat_message: &ChatMessage,
+    model: &Arc<Mutex<T>>,
+    to_did: &str,
+    shared_state: &SharedStateRef,
+    kind: PromptKind,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    let (
+        own_settings,
+        active_model,
+        show_thinking,
+        json_format,
+        temperature,
+        stop_sequences,
+        language,
+    ) = {
+        let lock = model.lock().await;
+
+        let Some(state) = lock.get_channel_state(&digest(to_did)) else {
+            return Err(anyhow::anyhow!("No channel state for {}", to_did));
+        };
+
+        (
+            lock.get_model().map(prompt_settings),
+            state.active_model.clone(),
+            state.show_thinking,
+            state.json_format,
+            state.temperature,
+            state.stop_sequences.clone(),
+            state.language.clone(),
+        )
+    };
+    let (mut settings, max_history, flush_period, flush_chars) = match own_settings {
+        Some(own_settings) => own_settings,
+        // The concierge answers with its default model
+        None => {
+            let default_model = shared_state.concierge.lock().await.default_model.clone();
+            let target = match &default_model {
+                Some(name) => shared_state.models.lock().await.get(name).cloned(),
+                None => None,
+            };
+            let Some(target) = target else {
+                return Err(anyhow::anyhow!(
+                    "Default model ({}) doesn't exist",
+                    default_model.unwrap_or_default()
+                ));
+            };
+            // The concierge can't receive tool results
+            let mut target = target.lock().await.clone();
+            target.tools.clear();
+            prompt_settings(&target)
+        }
+    };
+
+    // Route to the model selected for this channel via /model
+    if let Some(active_model) = active_model.filter(|m| *m != settings.model_name) {
+        let target = { shared_state.models.lock().await.get(&active_model).cloned() };
+        match target {
+            Some(target) => settings = GenerationSettings::from(&*target.lock().await),
+            None => warn!(
+                "Channel model ({}) no longer exists, using ({})",
+                active_model, settings.model_name
+            ),
+        }
+    }
+
+    if let Some(json_format) = json_format {
+        settings.json_format = json_format;
+    }
+    if let Some(temperature) = temperature {
+        settings
+            .options
+            .get_or_insert_with(OllamaOptions::default)
+            .temperature = Some(temperature);
+    }
+    if !stop_sequences.is_empty() {
+        settings
+            .options
+            .get_or_insert_with(OllamaOptions::default)
+            .stop
+            .get_or_insert_with(Vec::new)
+            .extend(stop_sequences);
+    }
+    // Reasoning would make the response invalid JSON
+    let show_thinking = show_thinking && !settings.json_format;
+
+    if kind == PromptKind::Regenerate {
+        settings
+            .options
+            .get_or_insert_with(OllamaOptions::default)
+            .raise_temperature();
+    }
+
+    if !chat_message.images.is_empty() && !settings.supports_images {
+        warn!("Model ({}) doesn't support images", settings.model_name);
+        let _ = send_message(
+            atm,
+            profile,
+            "Unfortunately I can't handle attachments yet.. Hopefully one day I will be able to!",
+            to_did,
+            model,
+        )
+        .await;
+        return Ok(());
+    }
+
+    // Held until the response has finished, limits concurrent generations on the backend host
+    let queued = async {
+        info!(
+            "Model ({}): no generation slot free on ({}), prompt from DID ({}) is queued",
+            settings.model_name, settings.host, to_did
+        );
+        if !settings.queued_response.is_empty() {
+            let _ = send_message(atm, profile, &settings.queued_response, to_did, model).await;
+        }
+    };
+    let _slot = match shared_state
+        .acquire_generation_slot(&settings.host, queued)
+        .await
+    {
+        Ok(slot) => slot,
+        Err(e) => {
+            warn!("Model ({}): {}", settings.model_name, e);
+            let _ = send_message(atm, profile, &settings.busy_response, to_did, model).await;
+            return Ok(());
+        }
+    };
+
+    let generation = Arc::new(Notify::new());
+    let messages = {
+        let mut lock = model.lock().await;
+        let Some(state) = lock.get_channel_state_mut(&digest(to_did)) else {
+            return Err(anyhow::anyhow!("No channel state for {}", to_did));
+        };
+        state.generation = Some(generation.clone());
+        state.streaming_message_id = None;
+        state.reply_thid = chat_message.thid.clone();
+
+        // Record the prompt and replay the conversation so far as context
+        // Tool results were recorded when they arrived
+        if kind != PromptKind::ToolResults {
+            // A new prompt abandons tool calls that are still waiting for results
+            state.pending_tool_calls.clear();
+            state.push_history(Role::User, &chat_message.text, max_history);
+            state.set_last_prompt(
+                &chat_message.text,
+                &chat_message.images,
+                chat_message.thid.as_deref(),
+            );
+        }
+        let mut messages = settings
+            .system_prompt
+            .iter()
+            .map(|system_prompt| OllamaChatMessage::system(system_prompt.clone()))
+            .chain(language.iter().map(|language| {
+                OllamaChatMessage::system(format!(
+                    "Respond in {}, whatever language the user writes in.",
+                    language_name(language)
+                ))
+            }))
+            .chain(
+                state
+                    .history
+                    .iter()
+                    .map(|(role, text)| history_message(*role, text)),
+            )
+            .collect::<Vec<_>>();
+
+        // Images are only sent with the prompt they were attached to
+        if let Some(prompt) = messages
+            .last_mut()
+            .filter(|_| !chat_message.images.is_empty())
+        {
+            prompt.images = Some(chat_message.images.iter().map(Image::from_base64).collect());
+        }
+
+        messages
+    };
+
+    // JSON responses are generated again if they don't parse
+    let retry_messages = settings.json_format.then(|| messages.clone());
+
+    metrics::record_prompt(&settings.model_name);
+    let started = Instant::now();
+    let mut stream = match start_chat_stream(messages, &settings).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            clear_generation(model, to_did, &generation).await;
+            metrics::record_error(&settings.model_name);
+            error!(
+                "Model ({}): backend is unavailable: {}",
+                settings.model_name, e
+            );
+            let _ = send_message(atm, profile, failure_response(&e), to_did, model).await;
+            return Err(anyhow::anyhow!("Backend is unavailable: {}", e));
+        }
+    };
+
+    let mut stdout = stdout();
+    stdout.write_all(b"\n> ").await?;
+    stdout.flush().await?;
+
+    let mut think_flag = false;
+    // Reasoning shown to the remote party is sent as its own message once its <think> block closes
+    let mut reasoning = String::new();
+    let mut stream_error = None;
+    let mut stopped = false;
+    let mut first_token = true;
+    let mut output = String::new();
+    // Set once the response passes the attachment threshold, the rest is buffered and sent as an attachment
+    let mut attaching = false;
+    // JSON responses are buffered so they can be checked before they are sent
+    let buffering = settings.json_format;
+    // Everything sent to the remote party, kept for the conversation history
+    let mut response = String::new();
+
+    let timeout: tokio::time::Sleep = tokio::time::sleep(Duration::from_secs(30));
+    let mut typing_interval = tokio::time::interval_at(
+        Instant::now() + settings.typing_interval,
+        settings.typing_interval,
+    );
+    // Presence is sent less often than typing to limit the traffic to the mediator
+    let mut presence_interval = tokio::time::interval_at(
+        Instant::now() + settings.presence_interval,
+        settings.presence_interval,
+    );
+    // Tokens are batched so the mediator isn't flooded with tiny messages
+    let mut flush_interval = tokio::time::interval_at(Instant::now() + flush_period, flush_period);
+    tokio::pin!(timeout);
+
+    let _ = i_am_thinking(atm, profile, model, to_did, true, settings.typing_interval).await;
+    loop {
+        select! {
+            _ = generation.notified() => {
+                info!("Generation stopped by remote party");
+                stopped = true;
+                break;
+            }
+            _ = &mut timeout => {
+                warn!("AI Response timed out");
+                metrics::record_error(&settings.model_name);
+                let _ = send_message(atm, profile, "Timeout: I'm sorry, I'm taking too long to respond", to_did, model).await;
+                break;
+            }
+            _ = typing_interval.tick() => {
+                let _ = i_am_thinking(atm, profile, model, to_did, true, settings.typing_interval).await;
+            }
+            _ = presence_interval.tick() => {
+                let _ = handle_presence(atm, profile, to_did).await;
+            }
+            _ = flush_interval.tick() => {
+                if !attaching && !buffering && !output.trim().is_empty() {
+                    let text = take_complete_words(&mut output);
+                    response.push_str(&text);
+                    let _ = send_response_part(atm, profile, &text, &response, to_did, model).await;
+                }
+            }
+            token = stream.next() => {
+                match token {
+                    Some(Ok(content)) => {
+                        if first_token {
+                            first_token = false;
+                            metrics::observe_time_to_first_token(&settings.model_name, started.elapsed());
+                        }
+                        // Reasoning is hidden from the remote party unless they asked for it
+                        let content = if show_thinking {
+                            let (content, thought) = split_thinking(&content, &mut think_flag);
+                            reasoning.push_str(&thought);
+                            if !think_flag && !reasoning.is_empty() {
+                                if !reasoning.trim().is_empty() {
+                                    let _ = send_reasoning(atm, profile, reasoning.trim(), to_did, model).await;
+                                }
+                                reasoning.clear();
+                            }
+                            content
+                        } else {
+                            strip_thinking(&content, &mut think_flag)
+                        };
+
+                        if output.is_empty() && content.trim().is_empty() {
+                            // Don't start a message with blank lines
+                            continue;
+                        }
+                        output.push_str(&content);
+                        if settings
+                            .attachment_threshold
+                            .is_some_and(|threshold| response.chars().count() + output.chars().count() > threshold)
+                        {
+                            attaching = true;
+                        }
+
+                        if !attaching && !buffering && output.chars().count() >= flush_chars {
+                            let text = take_complete_words(&mut output);
+                            response.push_str(&text);
+                            let _ = send_response_part(atm, profile, &text, &response, to_did, model).await;
+                            flush_interval.reset();
+                        }
+
+                        stdout.flush().await?;
+                    }
+                    Some(Err(err)) => {
+                        error!("Model ({}): response stream failed: {:?}", settings.model_name, err);
+                        metrics::record_error(&settings.model_name);
+                        stream_error = Some(err);
+                        break;
                     }
                     None => {
                         break;
@@ -374,81 +2437,742 @@ where
         }
     }
 
-    let _ = send_message(atm, profile, &output, to_did, model).await;
+    // The response has finished, been stopped, timed out or failed
+    let _ = i_am_thinking(atm, profile, model, to_did, false, settings.typing_interval).await;
+    clear_generation(model, to_did, &generation).await;
+    metrics::observe_generation_time(&settings.model_name, started.elapsed());
+
+    if let Some(messages) =
+        retry_messages.filter(|_| !stopped && stream_error.is_none() && !output.trim().is_empty())
+    {
+        output = ensure_json(output, messages, &settings).await;
+    }
+
+    // Reasoning cut off by a timeout or failure is still sent
+    if !stopped && !reasoning.trim().is_empty() {
+        let _ = send_reasoning(atm, profile, reasoning.trim(), to_did, model).await;
+    }
+
+    // Always flush whatever remains in the buffer, unless the remote party stopped the generation
+    if !stopped && !output.trim().is_empty() {
+        response.push_str(&output);
+        if attaching {
+            let summary = format!(
+                "The rest of my answer is too long for a message, the full answer ({} characters) is attached as {}",
+                response.chars().count(),
+                RESPONSE_ATTACHMENT_FILENAME
+            );
+            let compress = {
+                model.lock().await.get_model().is_some_and(|model| {
+                    model
+                        .dids
+                        .iter()
+                        .any(|agent| agent.did == profile.inner.did && agent.compress_attachments())
+                })
+            };
+            let _ = send_message_with_attachment(
+                atm,
+                profile,
+                &summary,
+                response_attachment(&response, compress),
+                to_did,
+                model,
+            )
+            .await;
+        } else {
+            let _ = send_response_part(atm, profile, &output, &response, to_did, model).await;
+        }
+    }
+    if let Some(err) = &stream_error {
+        let _ = send_message(atm, profile, failure_response(err), to_did, model).await;
+    }
+
+    settings.backend.finish().await;
     println!("{}", style("AI Responded...").cyan());
 
-    Ok(())
+    // The remote party runs the tools, their results continue the response
+    let tool_calls = if stopped || stream_error.is_some() {
+        Vec::new()
+    } else {
+        settings
+            .backend
+            .tool_calls()
+            .into_iter()
+            .map(|call| ToolCallRequest {
+                id: uuid::Uuid::new_v4().to_string(),
+                name: call.name,
+                arguments: call.arguments,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Backends that don't report statistics only have the time taken
+    let stats = stream_error.is_none().then(|| {
+        settings.backend.stats().unwrap_or_else(|| GenerationStats {
+            model_name: settings.model_name.clone(),
+            eval_count: None,
+            eval_duration: None,
+            total_duration: started.elapsed(),
+        })
+    });
+
+    if let Some(audit_log) = &shared_state.audit_log
+        && (!response.trim().is_empty() || !tool_calls.is_empty())
+    {
+        audit_log.record(AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            agent_did: profile.inner.did.clone(),
+            remote_did_hash: digest(to_did),
+            model: settings.model_name.clone(),
+            kind: match kind {
+                PromptKind::New => "new",
+                PromptKind::Regenerate => "regenerate",
+                PromptKind::ToolResults => "tool_results",
+            }
+            .to_string(),
+            prompt: chat_message.text.clone(),
+            response: response.clone(),
+            tool_calls: tool_calls.clone(),
+        });
+    }
+
+    let basic_message = {
+        let mut lock = model.lock().await;
+        let max_history = lock.get_model().map_or(max_history, |m| m.max_history);
+        let Some(state) = lock.get_channel_state_mut(&digest(to_did)) else {
+            return Ok(());
+        };
+        state.streaming_message_id = None;
+        state.reply_thid = None;
+        if tool_calls.is_empty() || !response.trim().is_empty() {
+            state.push_history(Role::Assistant, &response, max_history);
+        }
+        state.pending_tool_calls = tool_calls.clone();
+        if stats.is_some() {
+            state.last_generation = stats;
+        }
+        state.basic_message
+    };
+
+    if !tool_calls.is_empty() {
+        info!(
+            "Model ({}): sending {} tool call(s) to DID ({})",
+            settings.model_name,
+            tool_calls.len(),
+            to_did
+        );
+        let _ = deliver_tool_calls(
+            atm,
+            profile,
+            &tool_calls,
+            chat_message.thid.as_deref(),
+            to_did,
+        )
+        .await;
+    }
+
+    // Only prompts that got an answer count as processed
+    if let Some(message_id) = &chat_message.message_id
+        && stream_error.is_none()
+        && (!response.trim().is_empty() || !tool_calls.is_empty())
+        && !basic_message
+    {
+        let _ = send_receipt(atm, profile, CHAT_PROCESSED_TYPE, message_id, to_did).await;
+    }
+
+    Ok(())
+}
+
+/// Normalises a language code (e.g. FR, pt_br), None if it doesn't look like a language code
+/// Codes are a 2-3 letter language optionally followed by region or script subtags (e.g. pt-BR, zh-Hant)
+fn parse_language(code: &str) -> Option<String> {
+    let code = code.trim().replace('_', "-");
+    let mut subtags = code.split('-');
+    let language = subtags
+        .next()
+        .filter(|l| (2..=3).contains(&l.len()) && l.chars().all(|c| c.is_ascii_alphabetic()))?
+        .to_ascii_lowercase();
+    let mut normalised = language;
+    for subtag in subtags {
+        if !(2..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        normalised.push('-');
+        normalised.push_str(&match subtag.len() {
+            2 => subtag.to_ascii_uppercase(),
+            _ => subtag.to_string(),
+        });
+    }
+    Some(normalised)
+}
+
+/// Name of the language a code refers to, with the code (e.g. Portuguese (pt-BR))
+fn language_name(code: &str) -> String {
+    let primary = code.split('-').next().unwrap_or(code);
+    match LANGUAGE_NAMES.iter().find(|(c, _)| *c == primary) {
+        Some((_, name)) => format!("{} ({})", name, code),
+        None => format!("the language with code {}", code),
+    }
+}
+
+/// Converts a turn of the conversation history to a message for the model
+fn history_message(role: Role, text: &str) -> OllamaChatMessage {
+    match role {
+        Role::User => OllamaChatMessage::user(text.to_string()),
+        Role::Assistant => OllamaChatMessage::assistant(text.to_string()),
+        Role::ToolCalls => {
+            let mut message = OllamaChatMessage::assistant(String::new());
+            message.tool_calls = serde_json::from_str::<Vec<ToolCallRequest>>(text)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|call| {
+                    serde_json::from_value::<OllamaToolCall>(serde_json::json!({
+                        "function": { "name": call.name, "arguments": call.arguments },
+                    }))
+                    .ok()
+                })
+                .collect();
+            message
+        }
+        Role::Tool => OllamaChatMessage::tool(text.to_string()),
+    }
+}
+
+/// Clears the channel's generation handle if it still belongs to this generation
+async fn clear_generation<T>(model: &Arc<Mutex<T>>, to_did: &str, generation: &Arc<Notify>)
+where
+    T: ChannelState,
+{
+    let mut lock = model.lock().await;
+    if let Some(state) = lock.get_channel_state_mut(&digest(to_did)).filter(|state| {
+        state
+            .generation
+            .as_ref()
+            .is_some_and(|g| Arc::ptr_eq(g, generation))
+    }) {
+        state.generation = None;
+    }
+}
+
+/// Starts streaming a chat response from Ollama
+/// Failed attempts are retried with an exponential backoff, up to the configured number of retries
+async fn start_chat_stream(
+    messages: Vec<OllamaChatMessage>,
+    settings: &GenerationSettings,
+) -> Result<TokenStream> {
+    let mut attempt = 0;
+    loop {
+        match settings
+            .backend
+            .generate_stream(
+                messages.clone(),
+                settings.options.as_ref(),
+                settings.json_format,
+            )
+            .await
+        {
+            Ok(stream) => return Ok(stream),
+            Err(e)
+                if attempt < settings.retries
+                    && e.downcast_ref::<BackendError>()
+                        .is_none_or(|e| e.is_retryable()) =>
+            {
+                let delay = settings
+                    .retry_backoff
+                    .saturating_mul(2_u32.saturating_pow(attempt));
+                attempt += 1;
+                warn!(
+                    "Model ({}): request failed: {}. Retry ({}/{}) in {}ms",
+                    settings.model_name,
+                    e,
+                    attempt,
+                    settings.retries,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Checks a JSON response parses, generating it once more if it doesn't
+/// If it still isn't valid JSON the original response is returned with a warning
+async fn ensure_json(
+    output: String,
+    messages: Vec<OllamaChatMessage>,
+    settings: &GenerationSettings,
+) -> String {
+    if is_json(&output) {
+        return output;
+    }
+
+    warn!(
+        "Model ({}): response isn't valid JSON, retrying",
+        settings.model_name
+    );
+    let retry = async {
+        let mut stream = start_chat_stream(messages, settings).await?;
+        let mut think_flag = false;
+        let mut retry = String::new();
+        while let Some(token) = stream.next().await {
+            retry.push_str(&strip_thinking(&token?, &mut think_flag));
+        }
+        Ok::<String, anyhow::Error>(retry)
+    };
+    match tokio::time::timeout(JSON_RETRY_TIMEOUT, retry).await {
+        Ok(Ok(retry)) if is_json(&retry) => retry,
+        _ => {
+            warn!(
+                "Model ({}): response still isn't valid JSON, sending it as is",
+                settings.model_name
+            );
+            format!("{}\n{}", INVALID_JSON_WARNING, output)
+        }
+    }
+}
+
+/// Generates a greeting for a new connection with the model, the concierge's greeting_model
+/// Errors if the model fails, runs out of time or doesn't write anything, the static greeting is sent instead
+pub(crate) async fn generate_greeting(
+    model: &Arc<Mutex<OllamaModel>>,
+    system_prompt: &str,
+    shared_state: &SharedStateRef,
+) -> Result<String> {
+    let mut settings = GenerationSettings::from(&*model.lock().await);
+    settings.json_format = false;
+    let messages = vec![
+        OllamaChatMessage::system(system_prompt.to_string()),
+        OllamaChatMessage::user("Hello".to_string()),
+    ];
+
+    let generate = async {
+        let _slot = shared_state
+            .acquire_generation_slot(&settings.host, async {})
+            .await?;
+        let mut stream = start_chat_stream(messages, &settings).await?;
+        let mut think_flag = false;
+        let mut greeting = String::new();
+        while let Some(token) = stream.next().await {
+            greeting.push_str(&strip_thinking(&token?, &mut think_flag));
+        }
+        settings.backend.finish().await;
+        Ok::<String, anyhow::Error>(greeting)
+    };
+    let greeting = tokio::time::timeout(GREETING_TIMEOUT, generate)
+        .await
+        .map_err(|_| anyhow::anyhow!("no greeting within {}s", GREETING_TIMEOUT.as_secs()))??;
+
+    match greeting.trim() {
+        "" => Err(anyhow::anyhow!("the model wrote an empty greeting")),
+        greeting => Ok(greeting.to_string()),
+    }
+}
+
+fn is_json(text: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(text.trim()).is_ok()
+}
+
+/// Response telling the remote party why their response couldn't be generated
+fn failure_response(error: &anyhow::Error) -> &'static str {
+    match error.downcast_ref::<BackendError>() {
+        Some(BackendError::ModelNotFound(_)) => MODEL_NOT_FOUND_RESPONSE,
+        Some(BackendError::OutOfMemory(_)) => OUT_OF_MEMORY_RESPONSE,
+        Some(BackendError::ConnectionRefused(_)) => CONNECTION_REFUSED_RESPONSE,
+        _ => MODEL_UNAVAILABLE_RESPONSE,
+    }
+}
+
+/// Takes the buffered text up to the last word boundary, leaving any partial word in the buffer
+/// If there is no word boundary the whole buffer is taken
+fn take_complete_words(output: &mut String) -> String {
+    match output.rfind([' ', '\n']) {
+        Some(index) => {
+            let remainder = output.split_off(index + 1);
+            std::mem::replace(output, remainder)
+        }
+        None => std::mem::take(output),
+    }
+}
+
+/// Sends a chat message to a DID that has connected to the agent, for notifications and alerts pushed by code
+/// embedding the bridge
+/// The message is sent the way the agent's responses are: as a basic message if the remote party chats with
+/// basic messages, otherwise as a chat-message with the channel's next seqNo
+///
+/// `channel_state` is the model (or concierge) the remote party connected to, `profile` the ATM profile of the
+/// agent DID they connected to. The DID's secrets need to be in the TDK's secrets resolver before the ATM client is
+/// created (see `activate::get_secrets`):
+///
+/// ```no_run
+/// # use affinidi_messaging_sdk::{ATM, config::ATMConfig};
+/// # use affinidi_tdk::{common::TDKSharedState, secrets_resolver::SecretsResolver};
+/// # use didcomm_ai_bridge::{
+/// #     activate::get_secrets, agents::state_management::SharedState, chat_messages::send_chat,
+/// #     didcomm_messages::websocket::new_profile,
+/// # };
+/// # async fn notify(remote_did: &str) -> anyhow::Result<()> {
+/// let config = SharedState::load("config.json")?;
+/// let model = config.models.lock().await.get("llama3.2").cloned().unwrap();
+/// let agent_did = model.lock().await.dids[0].did.clone();
+///
+/// let tdk = TDKSharedState::default().await;
+/// tdk.secrets_resolver.insert_vec(&get_secrets(&agent_did)?).await;
+/// let atm = ATM::new(ATMConfig::builder().build()?, tdk).await?;
+/// let profile = new_profile(&atm, "llama3.2", &agent_did, &config.mediator_dids).await?;
+/// let profile = atm.profile_add(&profile, false).await?;
+///
+/// send_chat(&atm, &profile, remote_did, "Your report is ready", &model).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+/// Fails if the DID has no chat channel with the agent, or the message couldn't be delivered
+pub async fn send_chat<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    to_did: &str,
+    text: &str,
+    channel_state: &Arc<Mutex<T>>,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    if channel_state
+        .lock()
+        .await
+        .get_channel_state(&digest(to_did))
+        .is_none()
+    {
+        anyhow::bail!("No chat channel with {}", to_did);
+    }
+    send_message(atm, profile, text, to_did, channel_state).await
+}
+
+pub async fn send_message<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    to_did: &str,
+    channel_state: &Arc<Mutex<T>>,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    send_chat_message(atm, profile, text, None, None, to_did, channel_state).await
+}
+
+/// Sends a chat message with an attachment
+/// Clients that don't support attachments only show the text
+pub async fn send_message_with_attachment<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    attachment: Attachment,
+    to_did: &str,
+    channel_state: &Arc<Mutex<T>>,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    send_chat_message(
+        atm,
+        profile,
+        text,
+        Some(attachment),
+        None,
+        to_did,
+        channel_state,
+    )
+    .await
+}
+
+/// Sends the model's reasoning as a chat message tagged "reasoning", so clients can show it collapsed
+async fn send_reasoning<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    to_did: &str,
+    channel_state: &Arc<Mutex<T>>,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    send_chat_message(
+        atm,
+        profile,
+        text,
+        None,
+        Some(REASONING_TAG),
+        to_did,
+        channel_state,
+    )
+    .await
+}
+
+async fn send_chat_message<T>(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    attachment: Option<Attachment>,
+    tag: Option<&str>,
+    to_did: &str,
+    channel_state: &Arc<Mutex<T>>,
+) -> Result<()>
+where
+    T: ChannelState,
+{
+    let (seq_no, basic_message, thid, metrics_label) = {
+        let mut channel_state = channel_state.lock().await;
+        let metrics_label = channel_state
+            .get_model()
+            .map(|m| m.name.clone())
+            .unwrap_or_else(|| "concierge".to_string());
+        let state = channel_state.channel_state_or_recreate(to_did, &profile.inner.did);
+        let seq_no = state.seq_no;
+        state.seq_no += 1;
+
+        (
+            seq_no,
+            state.basic_message,
+            state.reply_thid.clone(),
+            metrics_label,
+        )
+    };
+    let result = if basic_message {
+        deliver_basic_message(atm, profile, text, attachment, to_did, thid.as_deref()).await
+    } else {
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let header = ChatMessageHeader {
+            id: &message_id,
+            thid: thid.as_deref(),
+            seq_no,
+            tag,
+        };
+        deliver_chat_message(atm, profile, &header, text, attachment, to_did).await
+    };
+    match &result {
+        Ok(_) => metrics::record_message_sent(&metrics_label),
+        Err(_) => metrics::record_error(&metrics_label),
+    }
+    result
 }
 
-pub async fn send_message<T>(
-    atm: &ATM,
+/// Sends part of a streamed response
+/// Clients that support edits get a single message that is edited to the full `response` so far,
+/// other clients get each part as a new message
+async fn send_response_part<T>(
+    atm: &dyn MessageTransport,
     profile: &Arc<ATMProfile>,
-    text: &str,
+    part: &str,
+    response: &str,
     to_did: &str,
     channel_state: &Arc<Mutex<T>>,
 ) -> Result<()>
 where
     T: ChannelState,
 {
-    let seq_no = {
-        let mut channel_state = channel_state.lock().await;
-        let state = channel_state
+    let (message_id, seq_no, thid, metrics_label) = {
+        let mut lock = channel_state.lock().await;
+        let metrics_label = lock
+            .get_model()
+            .map(|m| m.name.clone())
+            .unwrap_or_else(|| "concierge".to_string());
+        let Some(state) = lock
             .get_channel_state_mut(&digest(to_did))
-            .unwrap();
-        let seq_no = state.seq_no;
-        state.seq_no += 1;
+            .filter(|state| state.supports_edits && !state.basic_message)
+        else {
+            drop(lock);
+            return send_message(atm, profile, part, to_did, channel_state).await;
+        };
 
-        seq_no
+        // The first part starts the message that is edited from now on
+        let seq_no = state.streaming_message_id.is_none().then(|| {
+            let seq_no = state.seq_no;
+            state.seq_no += 1;
+            seq_no
+        });
+        let message_id = state
+            .streaming_message_id
+            .get_or_insert_with(|| uuid::Uuid::new_v4().to_string())
+            .clone();
+
+        (message_id, seq_no, state.reply_thid.clone(), metrics_label)
     };
-    let id = uuid::Uuid::new_v4().to_string();
-    let msg = Message::build(
-        id.clone(),
-        "https://affinidi.com/atm/client-actions/chat-message".to_string(),
-        serde_json::json!({ "text": text, "seqNo": seq_no }),
+    let result = match seq_no {
+        Some(seq_no) => {
+            let header = ChatMessageHeader {
+                id: &message_id,
+                thid: thid.as_deref(),
+                seq_no,
+                tag: None,
+            };
+            deliver_chat_message(atm, profile, &header, part, None, to_did).await
+        }
+        None => deliver_chat_message_edit(atm, profile, &message_id, response, to_did).await,
+    };
+    match &result {
+        Ok(_) => metrics::record_message_sent(&metrics_label),
+        Err(_) => metrics::record_error(&metrics_label),
+    }
+    result
+}
+
+/// Text file attachment holding a long response
+/// `compress` - gzip the text if that makes it smaller
+fn response_attachment(text: &str, compress: bool) -> Attachment {
+    let (data, media_type) = if compress {
+        compression::compress(text.as_bytes(), "text/plain")
+    } else {
+        (text.as_bytes().to_vec(), "text/plain".to_string())
+    };
+    Attachment::base64(BASE64_STANDARD.encode(&data))
+        .id(uuid::Uuid::new_v4().to_string())
+        .description("Full response".into())
+        .filename(RESPONSE_ATTACHMENT_FILENAME.into())
+        .media_type(media_type)
+        .byte_count(data.len() as u64)
+        .finalize()
+}
+
+/// Packs and sends a chat message to the remote party
+/// Sends text as a DIDComm basic message, for generic DIDComm clients
+async fn deliver_basic_message(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    text: &str,
+    attachment: Option<Attachment>,
+    to_did: &str,
+    thid: Option<&str>,
+) -> Result<()> {
+    let mut msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        BASIC_MESSAGE_TYPE.to_string(),
+        serde_json::json!({ "content": text }),
     )
-    .created_time(
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            // added to avoid MP chat sorting "issues"
-            + 2,
+    .created_time(now_secs())
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string());
+    if let Some(thid) = thid {
+        msg = msg.thid(thid.to_string());
+    }
+    if let Some(attachment) = attachment {
+        msg = msg.attachment(attachment);
+    }
+    let msg = msg.finalize();
+
+    deliver(atm, profile, &msg, to_did).await
+}
+
+/// Thread a reply to the message is sent in, the message's own thread if it has one
+fn reply_thid(message: &Message) -> String {
+    message.thid.clone().unwrap_or_else(|| message.id.clone())
+}
+
+/// Replies to a DIDComm trust ping
+async fn send_ping_response(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    ping: &Message,
+    to_did: &str,
+) -> Result<()> {
+    let msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        "https://didcomm.org/trust-ping/2.0/ping-response".to_string(),
+        serde_json::json!({}),
     )
+    .thid(ping.id.clone())
+    .created_time(now_secs())
     .from(profile.inner.did.clone())
     .to(to_did.to_string())
     .finalize();
 
-    let packed = atm
-        .pack_encrypted(
-            &msg,
-            to_did,
-            Some(&profile.inner.did),
-            Some(&profile.inner.did),
-        )
-        .await?;
+    deliver(atm, profile, &msg, to_did).await
+}
 
-    if packed.1.messaging_service.is_none() {
-        let _ = atm
-            .forward_and_send_message(
-                profile,
-                &packed.0,
-                None,
-                profile.dids()?.1,
-                to_did,
-                None,
-                None,
-                false,
-            )
-            .await?;
-    } else {
-        let _ = atm
-            .send_message(profile, &packed.0, &id, false, false)
-            .await?;
+async fn deliver_chat_message(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    header: &ChatMessageHeader<'_>,
+    text: &str,
+    attachment: Option<Attachment>,
+    to_did: &str,
+) -> Result<()> {
+    let mut body = serde_json::json!({ "text": text, "seqNo": header.seq_no });
+    if let Some(tag) = header.tag {
+        body["tag"] = serde_json::json!(tag);
     }
-    Ok(())
+    let mut msg = Message::build(
+        header.id.to_string(),
+        "https://affinidi.com/atm/client-actions/chat-message".to_string(),
+        body,
+    )
+    // added to avoid MP chat sorting "issues"
+    .created_time(now_secs() + 2)
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string());
+    if let Some(thid) = header.thid {
+        msg = msg.thid(thid.to_string());
+    }
+    if let Some(attachment) = attachment {
+        msg = msg.attachment(attachment);
+    }
+    let msg = msg.finalize();
+
+    deliver(atm, profile, &msg, to_did).await
+}
+
+/// Asks the remote party to run the tools the model called
+async fn deliver_tool_calls(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    calls: &[ToolCallRequest],
+    thid: Option<&str>,
+    to_did: &str,
+) -> Result<()> {
+    let mut msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        CHAT_TOOL_CALL_TYPE.to_string(),
+        serde_json::json!({ "calls": calls }),
+    )
+    .created_time(now_secs())
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string());
+    if let Some(thid) = thid {
+        msg = msg.thid(thid.to_string());
+    }
+    let msg = msg.finalize();
+
+    deliver(atm, profile, &msg, to_did).await
+}
+
+/// Replaces the text of a chat message sent earlier
+async fn deliver_chat_message_edit(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    message_id: &str,
+    text: &str,
+    to_did: &str,
+) -> Result<()> {
+    let msg = Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        CHAT_MESSAGE_EDIT_TYPE.to_string(),
+        serde_json::json!({ "messageId": message_id, "text": text }),
+    )
+    .created_time(now_secs())
+    .from(profile.inner.did.clone())
+    .to(to_did.to_string())
+    .finalize();
+
+    deliver(atm, profile, &msg, to_did).await
 }
 
-async fn ack_message(atm: &ATM, profile: &Arc<ATMProfile>, message: &Message) -> Result<()> {
+async fn ack_message(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    message: &Message,
+) -> Result<()> {
     let Some(from_did) = message.from.clone() else {
         println!("{}", style("No 'from' field in message").red());
         println!(
@@ -458,121 +3182,638 @@ async fn ack_message(atm: &ATM, profile: &Arc<ATMProfile>, message: &Message) ->
         return Err(anyhow::anyhow!("No 'from' field in message"));
     };
 
-    let id = uuid::Uuid::new_v4().to_string();
+    send_receipt(atm, profile, CHAT_DELIVERED_TYPE, &message.id, &from_did).await
+}
+
+/// Sends a receipt (chat-delivered, chat-processed) for a message to its sender
+async fn send_receipt(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    receipt_type: &str,
+    message_id: &str,
+    to_did: &str,
+) -> Result<()> {
     let new_msg = Message::build(
-        id.clone(),
-        "https://affinidi.com/atm/client-actions/chat-delivered".to_string(),
-        serde_json::json!({ "messages": vec![message.id.to_string()] }),
-    )
-    .created_time(
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
+        uuid::Uuid::new_v4().to_string(),
+        receipt_type.to_string(),
+        serde_json::json!({ "messages": vec![message_id.to_string()] }),
     )
+    .created_time(now_secs())
     .from(profile.inner.did.clone())
-    .to(from_did.to_string())
+    .to(to_did.to_string())
     .finalize();
 
-    let packed = atm
-        .pack_encrypted(
-            &new_msg,
-            &from_did,
-            Some(&profile.inner.did),
-            Some(&profile.inner.did),
-        )
-        .await?;
-
-    if packed.1.messaging_service.is_none() {
-        let _ = atm
-            .forward_and_send_message(
-                profile,
-                &packed.0,
-                None,
-                profile.dids()?.1,
-                &from_did,
-                None,
-                None,
-                false,
-            )
-            .await?;
-    } else {
-        let _ = atm
-            .send_message(profile, &packed.0, &id, false, false)
-            .await?;
-    }
-    Ok(())
+    deliver(atm, profile, &new_msg, to_did).await
 }
 
+/// Sends a chat-activity message so the remote party sees the agent typing
+/// `typing` false tells the remote party the agent has stopped typing, so the indicator is cleared straight away
+/// `typing_interval` is the time until the next indicator is sent, the indicator expires a little after that
 async fn i_am_thinking<T>(
-    atm: &ATM,
+    atm: &dyn MessageTransport,
     profile: &Arc<ATMProfile>,
     channel_state: &Arc<Mutex<T>>,
     to_did: &str,
+    typing: bool,
+    typing_interval: Duration,
 ) -> Result<()>
 where
     T: ChannelState,
 {
     let activity_seq_no = {
         let mut channel_state = channel_state.lock().await;
-        let state = channel_state
-            .get_channel_state_mut(&digest(to_did))
-            .unwrap();
+        let state = channel_state.channel_state_or_recreate(to_did, &profile.inner.did);
+        if state.basic_message {
+            // Basic message clients don't understand typing indicators
+            return Ok(());
+        }
         let activity_seq_no = state.activity_seq_no;
         state.activity_seq_no += 1;
 
         activity_seq_no
     };
-    let id = uuid::Uuid::new_v4().to_string();
     let new_msg = Message::build(
-        id.clone(),
+        uuid::Uuid::new_v4().to_string(),
         "https://affinidi.com/atm/client-actions/chat-activity".to_string(),
-        serde_json::json!({ "activitySeqNo": activity_seq_no }),
-    )
-    .created_time(
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
+        if typing {
+            serde_json::json!({ "activitySeqNo": activity_seq_no })
+        } else {
+            serde_json::json!({ "activitySeqNo": activity_seq_no, "typing": false })
+        },
     )
+    .created_time(now_secs())
+    // Typing indicators are pointless once they are stale
+    .expires_time(now_secs() + (typing_interval + TYPING_EXPIRY_MARGIN).as_secs())
     .from(profile.inner.did.clone())
     .to(to_did.to_string())
     .finalize();
 
-    println!("{}", style("Typing...").cyan());
+    if typing {
+        println!("{}", style("Typing...").cyan());
+    }
 
-    let packed = atm
-        .pack_encrypted(
-            &new_msg,
-            to_did,
-            Some(&profile.inner.did),
-            Some(&profile.inner.did),
+    deliver(atm, profile, &new_msg, to_did).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::state_management::SharedState,
+        test_support::{
+            AGENT_DID, FailingBackend, MODEL_NAME, MockTransport, REMOTE_DID, message_from_remote,
+            test_model, test_profile,
+        },
+    };
+    use serde_json::json;
+
+    const CHAT_MESSAGE_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-message";
+
+    async fn receive(
+        atm: &MockTransport,
+        model: &Arc<Mutex<OllamaModel>>,
+        shared_state: &SharedStateRef,
+        message: &Message,
+    ) -> Result<()> {
+        let profile = test_profile(AGENT_DID).await;
+        handle_message(atm, &profile, model, MODEL_NAME, message, shared_state).await
+    }
+
+    async fn command(atm: &MockTransport, model: &Arc<Mutex<OllamaModel>>, text: &str) -> String {
+        let profile = test_profile(AGENT_DID).await;
+        let shared_state = Arc::new(SharedState::default());
+        let agent = model.lock().await.dids[0].clone();
+        handle_command(
+            atm,
+            &profile,
+            text,
+            &agent,
+            model,
+            REMOTE_DID,
+            &shared_state,
         )
-        .await?;
+        .await
+        .unwrap();
+        atm.chat_texts().pop().unwrap_or_default()
+    }
 
-    if packed.1.messaging_service.is_none() {
-        let _ = atm
-            .forward_and_send_message(
-                profile,
-                &packed.0,
-                None,
-                profile.dids()?.1,
-                to_did,
-                Some(
-                    SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
+    #[tokio::test]
+    async fn trust_ping_is_answered() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let ping = message_from_remote("https://didcomm.org/trust-ping/2.0/ping", json!({}));
+
+        receive(&atm, &model, &Arc::new(SharedState::default()), &ping)
+            .await
+            .unwrap();
+
+        let responses = atm.sent_of_type("https://didcomm.org/trust-ping/2.0/ping-response");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].thid.as_deref(), Some(ping.id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn unknown_message_type_is_answered_with_a_chat_error() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let message = message_from_remote("https://example.com/unknown", json!({}));
+
+        receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+            .await
+            .unwrap();
+
+        assert_eq!(atm.sent_of_type(CHAT_ERROR_TYPE).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn anonymous_message_is_rejected() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let mut message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hello" }));
+        message.from = None;
+
+        assert!(
+            receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+                .await
+                .is_err()
+        );
+        assert!(atm.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chat_message_is_answered_by_the_model() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&["Hello ", "there"])));
+        let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hi" }));
+
+        receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+            .await
+            .unwrap();
+
+        assert_eq!(atm.chat_texts(), vec!["Hello there".to_string()]);
+        assert!(atm.sent().iter().all(|sent| sent.to_did == REMOTE_DID));
+        let lock = model.lock().await;
+        let state = lock.get_channel_state(&digest(REMOTE_DID)).unwrap();
+        assert_eq!(
+            state.history,
+            vec![
+                (Role::User, "hi".to_string()),
+                (Role::Assistant, "Hello there".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn prompts_over_the_rate_limit_are_throttled() {
+        const RATE_LIMIT: u32 = 3;
+        let atm = MockTransport::new();
+        let mut test_model = test_model(&["Answer"]);
+        test_model.rate_limit_per_minute = Some(RATE_LIMIT);
+        let model = Arc::new(Mutex::new(test_model));
+        let shared_state = Arc::new(SharedState::default());
+
+        for _ in 0..=RATE_LIMIT {
+            let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hi" }));
+            receive(&atm, &model, &shared_state, &message)
+                .await
+                .unwrap();
+        }
+
+        let mut expected = vec!["Answer".to_string(); RATE_LIMIT as usize];
+        expected.push(THROTTLED_RESPONSE.to_string());
+        assert_eq!(atm.chat_texts(), expected);
+    }
+
+    #[tokio::test]
+    async fn reactions_and_effects_are_rate_limited_like_prompts() {
+        let atm = MockTransport::new();
+        let mut test_model = test_model(&["Thanks"]);
+        test_model.respond_to_reactions = true;
+        test_model.rate_limit_per_minute = Some(1);
+        let model = Arc::new(Mutex::new(test_model));
+        let shared_state = Arc::new(SharedState::default());
+
+        let reaction = message_from_remote(
+            "https://affinidi.com/atm/client-actions/chat-reaction",
+            json!({ "reaction": "👍" }),
+        );
+        receive(&atm, &model, &shared_state, &reaction)
+            .await
+            .unwrap();
+        let effect = message_from_remote(
+            "https://affinidi.com/atm/client-actions/chat-effect",
+            json!({ "effect": "balloons" }),
+        );
+        receive(&atm, &model, &shared_state, &effect).await.unwrap();
+
+        assert_eq!(
+            atm.chat_texts(),
+            vec!["Thanks".to_string(), THROTTLED_RESPONSE.to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn concierge_prompts_have_the_default_models_limits() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+        let mut default_model = test_model(&[]);
+        default_model.rate_limit_per_minute = Some(2);
+        default_model.max_prompt_chars = Some(5);
+        let shared_state = Arc::new(SharedState::default());
+        shared_state
+            .models
+            .lock()
+            .await
+            .insert(MODEL_NAME.to_string(), Arc::new(Mutex::new(default_model)));
+        let concierge = shared_state.concierge.clone();
+        {
+            let mut lock = concierge.lock().await;
+            lock.default_model = Some(MODEL_NAME.to_string());
+            lock.insert_channel_state(
+                &digest(REMOTE_DID),
+                test_model(&[])
+                    .channel_state
+                    .remove(&digest(REMOTE_DID))
+                    .unwrap(),
+            );
+        }
+        let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({}));
+        let check = |text: &str| {
+            let mut text = text.to_string();
+            let (atm, profile, concierge, message, shared_state) =
+                (&atm, &profile, &concierge, &message, &shared_state);
+            async move {
+                check_prompt(
+                    atm,
+                    profile,
+                    concierge,
+                    message,
+                    REMOTE_DID,
+                    &mut text,
+                    shared_state,
+                )
+                .await
+            }
+        };
+
+        assert!(check("hi").await);
+        assert!(!check("too long").await);
+        assert!(!check("hi").await);
+        let responses = atm.chat_texts();
+        assert!(responses[0].starts_with("Your message is too long"));
+        assert_eq!(responses[1], THROTTLED_RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn reasoning_model_answer_has_its_thinking_removed() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[
+            "<think>Let me",
+            " think</think>The ",
+            "answer",
+        ])));
+        let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hi" }));
+
+        receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+            .await
+            .unwrap();
+
+        assert_eq!(atm.chat_texts(), vec!["The answer".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn plain_model_answer_is_not_muted() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&["No ", "think ", "tags"])));
+        let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hi" }));
+
+        receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+            .await
+            .unwrap();
+
+        assert_eq!(atm.chat_texts(), vec!["No think tags".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn history_is_kept_per_channel() {
+        const OTHER_DID: &str = "did:example:other";
+        let atm = MockTransport::new();
+        let mut test_model = test_model(&[]);
+        test_model.channel_state.insert(
+            digest(OTHER_DID),
+            ChatChannelState {
+                remote_did: OTHER_DID.to_string(),
+                remote_did_hash: digest(OTHER_DID),
+                ..Default::default()
+            },
+        );
+        let model = Arc::new(Mutex::new(test_model));
+        let shared_state = Arc::new(SharedState::default());
+
+        for text in ["first", "second"] {
+            let message = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": text }));
+            receive(&atm, &model, &shared_state, &message)
+                .await
+                .unwrap();
+        }
+
+        let lock = model.lock().await;
+        let history = &lock.get_channel_state(&digest(REMOTE_DID)).unwrap().history;
+        assert_eq!(
+            history
+                .iter()
+                .map(|(_, text)| text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["first", "first", "second", "second"]
+        );
+        assert!(
+            lock.get_channel_state(&digest(OTHER_DID))
+                .unwrap()
+                .history
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_setup_without_channel_did_is_rejected() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let message = message_from_remote(
+            "https://affinidi.com/atm/client-actions/connection-setup",
+            json!({}),
+        );
+
+        assert!(
+            receive(&atm, &model, &Arc::new(SharedState::default()), &message)
+                .await
+                .is_err()
+        );
+        let errors = atm.sent_of_type(CHAT_ERROR_TYPE);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].body.to_string().contains("channel_did"));
+        assert_eq!(atm.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reply_recreates_a_channel_removed_while_answering() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        // e.g. a connection reset between receiving the prompt and replying
+        model.lock().await.remove_channel_state(&digest(REMOTE_DID));
+
+        i_am_thinking(
+            &atm,
+            &profile,
+            &model,
+            REMOTE_DID,
+            true,
+            Duration::from_secs(3),
+        )
+        .await
+        .unwrap();
+        send_message(&atm, &profile, "reply", REMOTE_DID, &model)
+            .await
+            .unwrap();
+
+        assert_eq!(atm.chat_texts(), vec!["reply".to_string()]);
+        let lock = model.lock().await;
+        let state = lock.get_channel_state(&digest(REMOTE_DID)).unwrap();
+        assert_eq!(state.agent_did.as_deref(), Some(AGENT_DID));
+        assert_eq!(state.seq_no, 1);
+    }
+
+    #[tokio::test]
+    async fn typing_indicator_outlasts_the_typing_interval() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let typing_interval = Duration::from_secs(30);
+
+        i_am_thinking(&atm, &profile, &model, REMOTE_DID, true, typing_interval)
+            .await
+            .unwrap();
+
+        let activity =
+            &atm.sent_of_type("https://affinidi.com/atm/client-actions/chat-activity")[0];
+        let valid_for = activity.expires_time.unwrap() - activity.created_time.unwrap();
+        assert!(valid_for > typing_interval.as_secs());
+    }
+
+    #[tokio::test]
+    async fn help_command_lists_the_commands() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+
+        let help = command(&atm, &model, "/help").await;
+
+        assert!(help.contains("/clear"));
+        assert!(help.contains("/regenerate"));
+    }
+
+    #[tokio::test]
+    async fn clear_command_clears_the_history() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        {
+            let mut lock = model.lock().await;
+            let state = lock.get_channel_state_mut(&digest(REMOTE_DID)).unwrap();
+            state.push_history(Role::User, "hi", 20);
+            state.set_last_prompt("hi", &[], None);
+        }
+
+        assert_eq!(
+            command(&atm, &model, "/clear").await,
+            "Conversation history cleared"
+        );
+        let lock = model.lock().await;
+        let state = lock.get_channel_state(&digest(REMOTE_DID)).unwrap();
+        assert!(state.history.is_empty());
+        assert!(state.last_prompt.is_none());
+    }
+
+    #[tokio::test]
+    async fn regenerated_answer_is_sent_in_the_prompts_thread() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&["Answer"])));
+        let prompt = message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": "hi" }));
+        receive(&atm, &model, &Arc::new(SharedState::default()), &prompt)
+            .await
+            .unwrap();
+
+        assert_eq!(command(&atm, &model, "/regenerate").await, "Answer");
+
+        let answers = atm.sent_of_type(CHAT_MESSAGE_TYPE);
+        assert_eq!(answers.len(), 2);
+        assert!(
+            answers
+                .iter()
+                .all(|answer| answer.thid.as_deref() == Some(prompt.id.as_str()))
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_command_is_reported() {
+        let atm = MockTransport::new();
+        let model = Arc::new(Mutex::new(test_model(&[])));
+
+        let response = command(&atm, &model, "/frobnicate").await;
+
+        assert!(response.contains("unknown command: /frobnicate"));
+    }
+
+    #[test]
+    fn commands_are_not_queued_as_prompts() {
+        let agent = DIDCommAgent {
+            command_prefix: Some("!".to_string()),
+            ..Default::default()
+        };
+        let chat = |text: &str| message_from_remote(CHAT_MESSAGE_TYPE, json!({ "text": text }));
+
+        assert!(is_prompt(&chat("hello"), &agent, false));
+        assert!(is_prompt(&chat("/stop"), &agent, false));
+        assert!(!is_prompt(&chat("!stop"), &agent, false));
+        assert!(is_prompt(&chat("!regenerate"), &agent, false));
+        assert!(is_prompt(
+            &message_from_remote(BASIC_MESSAGE_TYPE, json!({ "content": "hello" })),
+            &agent,
+            false
+        ));
+        assert!(is_prompt(
+            &message_from_remote(CHAT_TOOL_RESULT_TYPE, json!({})),
+            &agent,
+            false
+        ));
+        assert!(!is_prompt(
+            &message_from_remote(
+                "https://affinidi.com/atm/client-actions/chat-presence",
+                json!({})
+            ),
+            &agent,
+            false
+        ));
+
+        // Effects are always answered, reactions only if the model responds to them
+        let effect = message_from_remote(
+            "https://affinidi.com/atm/client-actions/chat-effect",
+            json!({ "effect": "balloons" }),
+        );
+        assert!(is_prompt(&effect, &agent, false));
+        let reaction = message_from_remote(
+            "https://affinidi.com/atm/client-actions/chat-reaction",
+            json!({ "reaction": "👍" }),
+        );
+        assert!(!is_prompt(&reaction, &agent, false));
+        assert!(is_prompt(&reaction, &agent, true));
+    }
+
+    /// Generation settings of the test model, using a backend whose first `failures` requests fail
+    fn failing_settings(
+        failures: u32,
+        retries: u32,
+    ) -> (GenerationSettings, Arc<std::sync::Mutex<u32>>) {
+        let mut model = test_model(&[]);
+        model.ollama_retries = retries;
+        model.ollama_retry_backoff_ms = 1;
+        let backend = FailingBackend::new(failures, &["Recovered"]);
+        let attempts = backend.attempts();
+        let settings = GenerationSettings {
+            backend: Box::new(backend),
+            ..GenerationSettings::from(&model)
+        };
+        (settings, attempts)
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_retried() {
+        let (settings, attempts) = failing_settings(2, 2);
+
+        let stream = start_chat_stream(Vec::new(), &settings).await.unwrap();
+
+        let tokens: Vec<String> = stream.map(|token| token.unwrap()).collect().await;
+        assert_eq!(tokens, vec!["Recovered"]);
+        assert_eq!(*attempts.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn requests_fail_once_the_retries_are_used_up() {
+        let (settings, attempts) = failing_settings(2, 1);
+
+        assert!(start_chat_stream(Vec::new(), &settings).await.is_err());
+        assert_eq!(*attempts.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn prompts_up_to_max_prompt_chars_are_rejected_or_truncated_beyond() {
+        for truncate_long_prompts in [false, true] {
+            let limits = PromptLimits {
+                max_prompt_chars: Some(5),
+                truncate_long_prompts,
+                ..Default::default()
+            };
+
+            let mut text = "12345".to_string();
+            assert_eq!(fit_prompt_length(&limits, &mut text), Ok(None));
+            assert_eq!(text, "12345");
+
+            let mut text = "123456".to_string();
+            let fitted = fit_prompt_length(&limits, &mut text);
+            if truncate_long_prompts {
+                assert!(
+                    fitted
                         .unwrap()
-                        .as_secs()
-                        + 10,
-                ),
-                None,
-                false,
-            )
-            .await?;
-    } else {
-        let _ = atm
-            .send_message(profile, &packed.0, &id, false, false)
-            .await?;
+                        .unwrap()
+                        .contains("only the first 5 characters")
+                );
+                assert_eq!(text, "12345");
+            } else {
+                assert!(fitted.unwrap_err().contains("keep it to 5 characters"));
+                assert_eq!(text, "123456");
+            }
+        }
+    }
+
+    #[test]
+    fn prompts_are_truncated_on_a_char_boundary() {
+        let limits = PromptLimits {
+            max_prompt_chars: Some(3),
+            truncate_long_prompts: true,
+            ..Default::default()
+        };
+
+        // Each of these is more than one byte, so the limit is counted in chars rather than bytes
+        let mut text = "héé".to_string();
+        assert_eq!(fit_prompt_length(&limits, &mut text), Ok(None));
+
+        let mut text = "日本語です".to_string();
+        assert!(fit_prompt_length(&limits, &mut text).unwrap().is_some());
+        assert_eq!(text, "日本語");
+    }
+
+    #[test]
+    fn thinking_is_split_from_the_answer_across_tokens() {
+        let mut thinking = false;
+        let tokens = ["<think>Let me", " think</think>The ", "answer"];
+        let (mut visible, mut reasoning) = (String::new(), String::new());
+        for token in tokens {
+            let (text, thought) = split_thinking(token, &mut thinking);
+            visible.push_str(&text);
+            reasoning.push_str(&thought);
+        }
+
+        assert_eq!(visible, "The answer");
+        assert_eq!(reasoning, "Let me think");
+        assert!(!thinking);
+    }
+
+    #[test]
+    fn text_without_think_tags_is_passed_through() {
+        let mut thinking = false;
+        let visible: String = ["Plain ", "answer"]
+            .iter()
+            .map(|token| strip_thinking(token, &mut thinking))
+            .collect();
+
+        assert_eq!(visible, "Plain answer");
     }
-    Ok(())
 }
@@ -0,0 +1,94 @@
+/*!
+ * Watches the configuration file and reloads it when it changes
+ *
+ * A new configuration is validated before it is handed to the concierge, which applies it
+ * without tearing down live connections. Invalid configurations are logged and ignored, as are
+ * the bridge's own saves.
+ */
+
+use crate::{
+    agents::{
+        concierge::concierge_handler::ConciergeMessage,
+        state_management::{SharedState, SharedStateRef},
+    },
+    termination::Interrupted,
+};
+use anyhow::{Context, Result};
+use notify::{Event, RecursiveMode, Watcher};
+use std::{fs, path::Path, time::Duration};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+};
+use tracing::{info, warn};
+
+/// Changes within this period are treated as one, editors often write a file several times when saving
+const DEBOUNCE_PERIOD: Duration = Duration::from_millis(500);
+
+/// Watches the configuration file until interrupted
+/// Each settled change is validated and sent to the concierge to apply
+pub async fn watch(
+    config_file: String,
+    shared_state: SharedStateRef,
+    to_concierge: mpsc::UnboundedSender<ConciergeMessage>,
+    mut interrupt_rx: broadcast::Receiver<Interrupted>,
+) -> Result<()> {
+    let path = fs::canonicalize(&config_file).context(format!(
+        "Couldn't find configuration file ({})",
+        config_file
+    ))?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            let _ = events_tx.send(event);
+        }
+    })
+    .context("Couldn't create configuration file watcher")?;
+    // The directory is watched as editors often replace the file rather than writing to it
+    watcher
+        .watch(directory, RecursiveMode::NonRecursive)
+        .context(format!(
+            "Couldn't watch configuration file ({})",
+            config_file
+        ))?;
+    info!("Watching configuration file ({}) for changes", config_file);
+
+    loop {
+        select! {
+            Some(event) = events_rx.recv() => {
+                if event.kind.is_access()
+                    || !event.paths.iter().any(|p| p.file_name() == path.file_name())
+                {
+                    continue;
+                }
+
+                // Wait for the file to settle before reading it
+                while let Ok(Some(_)) = tokio::time::timeout(DEBOUNCE_PERIOD, events_rx.recv()).await {}
+
+                // The bridge saves the file itself (e.g. sequence numbers), there is nothing to reload
+                if fs::read(&config_file).is_ok_and(|contents| shared_state.is_own_write(&contents)) {
+                    continue;
+                }
+
+                match SharedState::load(&config_file) {
+                    Ok(config) => {
+                        info!("Configuration file ({}) changed, reloading", config_file);
+                        to_concierge.send(ConciergeMessage::Reload { config: Box::new(config) })?;
+                    }
+                    Err(e) => warn!(
+                        "Configuration file ({}) changed but is invalid, keeping the current configuration: {:#}",
+                        config_file, e
+                    ),
+                }
+            }
+            _ = interrupt_rx.recv() => {
+                info!("Configuration watcher shutting down");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
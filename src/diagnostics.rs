@@ -0,0 +1,75 @@
+/*!
+ * Diagnostic summary of the bridge's environment, for bug reports
+ *
+ * Reports as much as it can even when the configuration can't be loaded. Secrets, passphrases and API keys
+ * are never printed.
+ */
+
+use console::style;
+use didcomm_ai_bridge::{agents::state_management::SharedState, secrets::SecretsConfig};
+use std::{env, fs};
+
+/// Prints the diagnostic summary
+pub(crate) async fn run(config_file: &str, keyring_service: Option<&str>) {
+    println!("{}", style("Diagnostics").bold());
+    println!("  Version: {}", env!("CARGO_PKG_VERSION"));
+    println!("  OS: {} ({})", env::consts::OS, env::consts::ARCH);
+
+    let config_path = fs::canonicalize(config_file)
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| format!("{} (not found)", config_file));
+    println!("  Configuration file: {}", config_path);
+
+    // The configured secrets backend is only known if the configuration loads
+    let secrets = match SharedState::load(config_file) {
+        Ok(config) => {
+            println!("  Configured models: {}", config.models.lock().await.len());
+            println!("  Mediator DIDs:");
+            for mediator_did in &config.mediator_dids {
+                println!("    {}", mediator_did);
+            }
+            Some(config.secrets.clone())
+        }
+        Err(e) => {
+            println!(
+                "  {}",
+                style(format!("Couldn't load the configuration: {:#}", e)).red()
+            );
+            None
+        }
+    };
+
+    match secrets
+        .unwrap_or_default()
+        .with_keyring_service(keyring_service)
+        .resolve()
+    {
+        Ok(secrets) => {
+            let backend = match &secrets {
+                SecretsConfig::Keyring { service } => format!("keyring ({})", service),
+                SecretsConfig::File { path } => format!("file ({})", path),
+            };
+            match secrets.probe() {
+                Ok(_) => println!("  Secrets backend: {}", backend),
+                Err(e) => println!(
+                    "  Secrets backend: {} {}",
+                    backend,
+                    style(format!("unusable ({})", e)).yellow()
+                ),
+            }
+        }
+        Err(e) => println!(
+            "  {}",
+            style(format!("Invalid secrets backend: {:#}", e)).red()
+        ),
+    }
+
+    let keyring = SecretsConfig::default().with_keyring_service(keyring_service);
+    match keyring.probe() {
+        Ok(_) => println!("  Keyring: available"),
+        Err(e) => println!(
+            "  Keyring: {}",
+            style(format!("unavailable ({})", e)).yellow()
+        ),
+    }
+}
@@ -1,21 +1,116 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
 use affinidi_messaging_sdk::{
     ATM,
     messages::{DeleteMessageRequest, FetchDeletePolicy, Folder, fetch::FetchOptions},
     profiles::ATMProfile,
 };
 use anyhow::Result;
-use tracing::info;
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Delay before the second batch of messages is cleared, doubles with each batch
+const CLEAR_INITIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest delay between batches of messages being cleared
+const CLEAR_MAX_DELAY: Duration = Duration::from_millis(500);
+
+/// Most batches of messages cleared from a folder, stops the loop if deleting doesn't empty it
+/// Kept low enough to finish within the time allowed to connect a profile
+const CLEAR_MAX_BATCHES: u32 = 25;
+
+/// Default number of messages fetched in each batch when the inbox is cleared
+pub const DEFAULT_FETCH_BATCH_LIMIT: usize = 10;
+
+/// What happens to the messages queued on the mediator when an agent connects
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePolicy {
+    /// Messages are deleted as they are fetched
+    #[default]
+    Optimistic,
+    /// Messages are left on the mediator so they can be inspected, for diagnosing lost messages
+    DoNotDelete,
+}
+
+/// How the messages queued on the mediator are cleared when an agent connects
+#[derive(Clone, Copy, Debug)]
+pub struct FetchSettings {
+    pub delete_policy: DeletePolicy,
+    /// Number of messages fetched in each batch
+    pub batch_limit: usize,
+}
+
+/// Outcome of clearing a folder
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClearedMessages {
+    /// Number of messages deleted
+    pub cleared: usize,
+    /// Stopped after CLEAR_MAX_BATCHES, messages may remain
+    pub capped: bool,
+}
+
+/// Backs off between batches so a large backlog doesn't hammer the mediator
+/// Half to one and a half times the delay, doubling the delay for the next batch
+async fn clear_backoff(delay: &mut Duration) {
+    let jitter = delay.mul_f64(OsRng.next_u32() as f64 / u32::MAX as f64);
+    tokio::time::sleep(*delay / 2 + jitter).await;
+    *delay = (*delay * 2).min(CLEAR_MAX_DELAY);
+}
+
+pub async fn clear_inbound_messages(
+    atm: &ATM,
+    profile: &Arc<ATMProfile>,
+    settings: FetchSettings,
+) -> Result<ClearedMessages> {
+    if settings.delete_policy == DeletePolicy::DoNotDelete {
+        let response = atm
+            .fetch_messages(
+                profile,
+                &FetchOptions {
+                    limit: settings.batch_limit,
+                    delete_policy: FetchDeletePolicy::DoNotDelete,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        info!(
+            "{}: {}: Left ({}) messages in INBOX, delete policy is do_not_delete",
+            profile.inner.did,
+            profile.inner.alias,
+            response.success.len()
+        );
+        for message in &response.success {
+            info!(
+                "{}: queued message ({}) received at ({}), {} bytes",
+                profile.inner.did, message.msg_id, message.timestamp, message.size
+            );
+        }
+        return Ok(ClearedMessages::default());
+    }
 
-pub async fn clear_inbound_messages(atm: &ATM, profile: &Arc<ATMProfile>) -> Result<()> {
     // Clear out the inbox queue in case old questions have been queued up
-    let mut deleted = 0;
-    loop {
+    let mut result = ClearedMessages::default();
+    let mut delay = CLEAR_INITIAL_DELAY;
+    for batch in 0..=CLEAR_MAX_BATCHES {
+        if batch == CLEAR_MAX_BATCHES {
+            result.capped = true;
+            break;
+        }
+        if batch > 0 {
+            clear_backoff(&mut delay).await;
+        }
+
         let response = atm
             .fetch_messages(
                 profile,
                 &FetchOptions {
+                    limit: settings.batch_limit,
                     delete_policy: FetchDeletePolicy::Optimistic,
                     ..Default::default()
                 },
@@ -25,22 +120,45 @@ pub async fn clear_inbound_messages(atm: &ATM, profile: &Arc<ATMProfile>) -> Res
         if response.success.is_empty() {
             break;
         } else {
-            deleted += response.success.len();
+            result.cleared += response.success.len();
         }
     }
 
     info!(
         "{}: {}: Cleared ({}) messages from INBOX",
-        profile.inner.did, profile.inner.alias, deleted
+        profile.inner.did, profile.inner.alias, result.cleared
     );
+    if result.capped {
+        warn!(
+            "{}: {}: Stopped clearing INBOX after ({}) batches, messages may remain",
+            profile.inner.did, profile.inner.alias, CLEAR_MAX_BATCHES
+        );
+    }
 
-    Ok(())
+    Ok(result)
 }
 
-pub async fn clear_outbound_messages(atm: &ATM, profile: &Arc<ATMProfile>) -> Result<()> {
+pub async fn clear_outbound_messages(
+    atm: &ATM,
+    profile: &Arc<ATMProfile>,
+    settings: FetchSettings,
+) -> Result<ClearedMessages> {
+    if settings.delete_policy == DeletePolicy::DoNotDelete {
+        return Ok(ClearedMessages::default());
+    }
+
     // Clear out the outbox queue in case old questions have been queued up
-    let mut deleted = 0;
-    loop {
+    let mut result = ClearedMessages::default();
+    let mut delay = CLEAR_INITIAL_DELAY;
+    for batch in 0..=CLEAR_MAX_BATCHES {
+        if batch == CLEAR_MAX_BATCHES {
+            result.capped = true;
+            break;
+        }
+        if batch > 0 {
+            clear_backoff(&mut delay).await;
+        }
+
         let response = atm.list_messages(profile, Folder::Outbox).await?;
 
         let mut request = DeleteMessageRequest::default();
@@ -50,15 +168,21 @@ pub async fn clear_outbound_messages(atm: &ATM, profile: &Arc<ATMProfile>) -> Re
             for message in &response {
                 request.message_ids.push(message.msg_id.clone());
             }
-            deleted += response.len();
+            result.cleared += response.len();
             let _ = atm.delete_messages_direct(profile, &request).await?;
         }
     }
 
     info!(
         "{}: {}: Cleared ({}) messages from OUTBOX",
-        profile.inner.did, profile.inner.alias, deleted
+        profile.inner.did, profile.inner.alias, result.cleared
     );
+    if result.capped {
+        warn!(
+            "{}: {}: Stopped clearing OUTBOX after ({}) batches, messages may remain",
+            profile.inner.did, profile.inner.alias, CLEAR_MAX_BATCHES
+        );
+    }
 
-    Ok(())
+    Ok(result)
 }
@@ -0,0 +1,44 @@
+/*!
+ * Gzip compression of attachment data
+ *
+ * Compressed attachments keep their base64 encoding, and have `+gzip` added to their media type
+ * (e.g. `text/plain+gzip`) so that the receiver knows to decompress them.
+ */
+
+use anyhow::{Result, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use std::io::{Read, Write};
+
+/// Suffix added to the media type of gzip compressed attachments
+pub const GZIP_MEDIA_TYPE_SUFFIX: &str = "+gzip";
+
+/// Compresses attachment data, returning the data to attach and its media type
+/// The data is returned unchanged if compression doesn't make it smaller
+pub fn compress(data: &[u8], media_type: &str) -> (Vec<u8>, String) {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    match encoder.write_all(data).and_then(|_| encoder.finish()) {
+        Ok(compressed) if compressed.len() < data.len() => (
+            compressed,
+            format!("{}{}", media_type, GZIP_MEDIA_TYPE_SUFFIX),
+        ),
+        _ => (data.to_vec(), media_type.to_string()),
+    }
+}
+
+/// Decompresses attachment data if its media type has the gzip suffix
+/// Returns the data and its media type without the suffix
+/// `max_size` - largest decompressed size accepted, guards against highly compressed payloads
+pub fn decompress(data: Vec<u8>, media_type: &str, max_size: usize) -> Result<(Vec<u8>, String)> {
+    let Some(media_type) = media_type.strip_suffix(GZIP_MEDIA_TYPE_SUFFIX) else {
+        return Ok((data, media_type.to_string()));
+    };
+
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data.as_slice())
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_size {
+        bail!("decompressed attachment is larger than {} bytes", max_size);
+    }
+    Ok((decompressed, media_type.to_string()))
+}
@@ -2,20 +2,156 @@ use affinidi_messaging_didcomm::Message;
 use affinidi_messaging_sdk::{ATM, profiles::ATMProfile};
 use anyhow::Result;
 use chrono::Local;
+use futures::future::BoxFuture;
 use serde_json::json;
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
 
 pub mod clear_messages;
+pub mod compression;
 pub mod oob_connection;
+pub mod websocket;
 
-pub async fn handle_presence(atm: &ATM, profile: &Arc<ATMProfile>, to_did: &str) -> Result<()> {
+/// Number of times sending a message to the mediator is retried
+pub(crate) const DELIVERY_RETRIES: u32 = 3;
+/// Delay before the first delivery retry, doubled on each further attempt
+const DELIVERY_BACKOFF: Duration = Duration::from_millis(250);
+/// Longest delay between delivery retries
+const DELIVERY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Packs and sends DIDComm messages, implemented by ATM
+/// Agents only send messages through this, so message handling can be exercised without a mediator
+pub trait MessageTransport: Send + Sync {
+    /// Packs a message from the profile's DID to `to_did`
+    /// Returns the packed message and whether it must be forwarded via the mediator, which is the case unless
+    /// the recipient has its own messaging service
+    fn pack_encrypted<'a>(
+        &'a self,
+        profile: &'a Arc<ATMProfile>,
+        message: &'a Message,
+        to_did: &'a str,
+    ) -> BoxFuture<'a, Result<(String, bool)>>;
+
+    /// Sends a packed message, forwarding it via the profile's mediator if `forward` is set
+    /// The message's expires_time (if set) is also used as the expiry of the forwarded message
+    fn send<'a>(
+        &'a self,
+        profile: &'a Arc<ATMProfile>,
+        packed: &'a str,
+        message: &'a Message,
+        to_did: &'a str,
+        forward: bool,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+impl MessageTransport for ATM {
+    fn pack_encrypted<'a>(
+        &'a self,
+        profile: &'a Arc<ATMProfile>,
+        message: &'a Message,
+        to_did: &'a str,
+    ) -> BoxFuture<'a, Result<(String, bool)>> {
+        Box::pin(async move {
+            let (packed, metadata) = ATM::pack_encrypted(
+                self,
+                message,
+                to_did,
+                Some(&profile.inner.did),
+                Some(&profile.inner.did),
+            )
+            .await?;
+            Ok((packed, metadata.messaging_service.is_none()))
+        })
+    }
+
+    fn send<'a>(
+        &'a self,
+        profile: &'a Arc<ATMProfile>,
+        packed: &'a str,
+        message: &'a Message,
+        to_did: &'a str,
+        forward: bool,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if forward {
+                self.forward_and_send_message(
+                    profile,
+                    packed,
+                    None,
+                    profile.dids()?.1,
+                    to_did,
+                    message.expires_time,
+                    None,
+                    false,
+                )
+                .await?;
+            } else {
+                self.send_message(profile, packed, &message.id, false, false)
+                    .await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Packs and sends a message to `to_did`
+/// The message is forwarded via the mediator unless the recipient has its own messaging service.
+/// Sending is retried with a capped exponential backoff, failures are logged and returned.
+pub async fn deliver(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    message: &Message,
+    to_did: &str,
+) -> Result<()> {
+    let (packed, forward) = atm.pack_encrypted(profile, message, to_did).await?;
+
+    let mut attempt = 0;
+    loop {
+        let sent = atm.send(profile, &packed, message, to_did, forward).await;
+
+        match sent {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < DELIVERY_RETRIES => {
+                let delay = DELIVERY_BACKOFF
+                    .saturating_mul(2_u32.saturating_pow(attempt))
+                    .min(DELIVERY_MAX_BACKOFF);
+                attempt += 1;
+                warn!(
+                    "Couldn't send message ({}) to {}: {}. Retry ({}/{}) in {}ms",
+                    message.id,
+                    to_did,
+                    e,
+                    attempt,
+                    DELIVERY_RETRIES,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't send message ({}) to {}: {}. Giving up",
+                    message.id, to_did, e
+                );
+                return Err(e);
+            }
+        }
+    }
+}
+
+pub async fn handle_presence(
+    atm: &dyn MessageTransport,
+    profile: &Arc<ATMProfile>,
+    to_did: &str,
+) -> Result<()> {
     // Create the response message
     // presence timestamp = 2025-02-05T04:59:09.190394Z
     //                      2025-02-05T14:33:37.816332+08:00
     let dt = Local::now();
-    let id = uuid::Uuid::new_v4().to_string();
     let new_message = Message::build(
-        id.clone(),
+        uuid::Uuid::new_v4().to_string(),
         "https://affinidi.com/atm/client-actions/chat-presence".to_string(),
         json!({"presence": dt.to_rfc3339()}),
     )
@@ -29,32 +165,62 @@ pub async fn handle_presence(atm: &ATM, profile: &Arc<ATMProfile>, to_did: &str)
     )
     .finalize();
 
-    let packed = atm
-        .pack_encrypted(
-            &new_message,
-            to_did,
-            Some(&profile.inner.did),
-            Some(&profile.inner.did),
-        )
-        .await?;
-
-    if packed.1.messaging_service.is_none() {
-        let _ = atm
-            .forward_and_send_message(
-                profile,
-                &packed.0,
-                None,
-                profile.dids()?.1,
-                to_did,
-                None,
-                None,
-                false,
-            )
-            .await?;
-    } else {
-        let _ = atm
-            .send_message(profile, &packed.0, &id, false, false)
-            .await?;
+    deliver(atm, profile, &new_message, to_did).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AGENT_DID, MockTransport, REMOTE_DID, test_profile};
+
+    const PRESENCE_TYPE: &str = "https://affinidi.com/atm/client-actions/chat-presence";
+
+    #[tokio::test]
+    async fn presence_is_forwarded_via_the_mediator() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+
+        handle_presence(&atm, &profile, REMOTE_DID).await.unwrap();
+
+        let sent = atm.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].message.type_, PRESENCE_TYPE);
+        assert_eq!(sent[0].to_did, REMOTE_DID);
+        assert!(sent[0].forwarded);
+    }
+
+    #[tokio::test]
+    async fn presence_is_sent_directly_to_a_recipient_with_a_messaging_service() {
+        let atm = MockTransport::direct();
+        let profile = test_profile(AGENT_DID).await;
+
+        handle_presence(&atm, &profile, REMOTE_DID).await.unwrap();
+
+        let sent = atm.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(!sent[0].forwarded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_retries_a_failed_send() {
+        let atm = MockTransport::failing(1);
+        let profile = test_profile(AGENT_DID).await;
+        let message = Message::build("1".into(), PRESENCE_TYPE.into(), json!({})).finalize();
+
+        deliver(&atm, &profile, &message, REMOTE_DID).await.unwrap();
+
+        assert_eq!(atm.attempts(), 2);
+        assert_eq!(atm.sent().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_gives_up_after_the_retries() {
+        let atm = MockTransport::failing(DELIVERY_RETRIES + 1);
+        let profile = test_profile(AGENT_DID).await;
+        let message = Message::build("1".into(), PRESENCE_TYPE.into(), json!({})).finalize();
+
+        assert!(deliver(&atm, &profile, &message, REMOTE_DID).await.is_err());
+        assert_eq!(atm.attempts(), DELIVERY_RETRIES + 1);
+        assert!(atm.sent().is_empty());
     }
-    Ok(())
 }
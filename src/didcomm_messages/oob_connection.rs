@@ -2,22 +2,31 @@
  * DIDComm Out Of Band (OOB) Discovery and Connection handling
  */
 
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use affinidi_messaging_didcomm::{Attachment, Message};
 use affinidi_messaging_sdk::{ATM, profiles::ATMProfile, protocols::Protocols};
-use anyhow::Result;
+use anyhow::{Result, bail};
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use qrcode::{QrCode, render::unicode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::{info, warn};
 use uuid::Uuid;
 
+use super::{MessageTransport, compression::compress, deliver};
 use crate::agents::state_management::DIDCommAgent;
 
+/// Media type of the vCard attached to connection responses
+const VCARD_MEDIA_TYPE: &str = "text/x-vcard";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Name {
     pub given: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub surname: Option<String>,
 }
 
@@ -36,7 +45,9 @@ pub enum VcardTypes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VCard {
     pub n: Name,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<VcardType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tel: Option<VcardType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub photo: Option<String>,
@@ -49,49 +60,101 @@ pub struct VCard {
     pub x_meetingplace_verification_id: Option<String>,
 }
 
-// Reads a file and returns a BAS64 encoded String
-fn _read_file(path: &str) -> String {
-    let file = std::fs::read(path).unwrap();
-    BASE64_URL_SAFE_NO_PAD.encode(file)
+/// How long an OOB invitation can be used for, the mediator removes it after this
+pub const INVITATION_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Creates an OOB invitation for the agent using this profile, returning the invitation URL
+/// The invitation is from the agent's DID and is hosted by the profile's mediator, which clients use to reach
+/// the agent. Each call creates a new invitation, earlier invitations stay valid until they expire
+pub async fn create_invitation(atm: &ATM, profile: &Arc<ATMProfile>) -> Result<String> {
+    let Some(mediator_url) = profile.get_mediator_rest_endpoint() else {
+        bail!(
+            "Agent ({}) has no mediator REST endpoint to host the invitation",
+            profile.inner.did
+        );
+    };
+
+    let oob_id = Protocols::default()
+        .oob_discovery
+        .create_invite(atm, profile, Some(INVITATION_EXPIRY))
+        .await?;
+
+    Ok([&mediator_url, "/oob?_oobid=", &oob_id].concat())
+}
+
+/// Renders an invitation URL as a QR code for the terminal
+pub fn invitation_qr_code(url: &str) -> Result<String> {
+    let code = QrCode::new(url)?;
+    // Inverted so the code scans on dark terminal backgrounds
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// DID the remote party created for the new channel, sent in the body of a connection-setup message
+pub fn channel_did(message: &Message) -> Result<String> {
+    match message
+        .body
+        .get("channel_did")
+        .and_then(|channel_did| channel_did.as_str())
+    {
+        Some(channel_did) => Ok(channel_did.to_string()),
+        None => bail!("connection-setup message has no channel_did"),
+    }
 }
 
 pub async fn send_connection_response(
-    atm: &ATM,
+    atm: &dyn MessageTransport,
     profile: &Arc<ATMProfile>,
     message: &Message,
     didcomm_agent: &DIDCommAgent,
 ) -> Result<String> {
     // Get the new DID
-    let new_did = message
-        .body
-        .get("channel_did")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_string();
+    let new_did = channel_did(message)?;
+    let (Some(from_did), Some(thid), Some(pthid)) = (&message.from, &message.thid, &message.pthid)
+    else {
+        bail!("connection-setup message needs from, thid and pthid");
+    };
 
-    let photo = _read_file(&didcomm_agent.image);
+    // A missing image shouldn't stop connections being made, the vCard is sent without a photo
+    let photo = match didcomm_agent.image_data() {
+        Ok(photo) => Some(BASE64_URL_SAFE_NO_PAD.encode(photo)),
+        Err(e) => {
+            warn!(
+                "Agent ({}): sending the vCard without a photo: {:#}",
+                didcomm_agent.name, e
+            );
+            None
+        }
+    };
 
     let vcard = VCard {
         n: Name {
             given: Some(didcomm_agent.name.clone()),
-            surname: Some(String::new()),
+            surname: didcomm_agent.vcard_surname.clone(),
         },
-        email: Some(VcardType {
-            r#type: VcardTypes::Work(String::new()),
+        email: didcomm_agent.vcard_email.clone().map(|email| VcardType {
+            r#type: VcardTypes::Work(email),
         }),
-        tel: Some(VcardType {
-            r#type: VcardTypes::Cell(String::new()),
+        tel: didcomm_agent.vcard_tel.clone().map(|tel| VcardType {
+            r#type: VcardTypes::Cell(tel),
         }),
-        photo: Some(photo),
+        photo,
         x_meetingplace_contact_attributes: didcomm_agent.x_meetingplace_contact_attributes,
         x_meetingplace_verification_id: didcomm_agent.x_meetingplace_verification_id.clone(),
     };
     let vcard = serde_json::to_string(&vcard).unwrap();
+    let (vcard, media_type) = if didcomm_agent.compress_attachments() {
+        compress(vcard.as_bytes(), VCARD_MEDIA_TYPE)
+    } else {
+        (vcard.into_bytes(), VCARD_MEDIA_TYPE.to_string())
+    };
     let attachment = Attachment::base64(BASE64_URL_SAFE_NO_PAD.encode(vcard))
         .id(Uuid::new_v4().into())
         .description("Affinidi Concierge vCard Info".into())
-        .media_type("text/x-vcard".into())
+        .media_type(media_type)
         .format("https://affinidi.com/atm/client-attachment/contact-card".into())
         .finalize();
 
@@ -102,9 +165,9 @@ pub async fn send_connection_response(
         json!({"channel_did": profile.inner.did.clone()}),
     )
     .from(profile.inner.did.clone())
-    .pthid(message.pthid.clone().unwrap())
-    .thid(message.thid.clone().unwrap())
-    .to(message.from.clone().unwrap())
+    .pthid(pthid.clone())
+    .thid(thid.clone())
+    .to(from_did.clone())
     .attachment(attachment)
     .created_time(
         SystemTime::now()
@@ -114,42 +177,112 @@ pub async fn send_connection_response(
     )
     .finalize();
 
-    let packed = atm
-        .pack_encrypted(
-            &new_message,
-            message.from.as_ref().unwrap(),
-            Some(&profile.inner.did),
-            Some(&profile.inner.did),
+    // Forwarded via the mediator unless the peer has its own messaging service, failures are already logged
+    if deliver(atm, profile, &new_message, from_did).await.is_ok() {
+        info!("Connection Response Sent");
+    }
+
+    Ok(new_did)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        didcomm_messages::DELIVERY_RETRIES,
+        test_support::{AGENT_DID, MockTransport, REMOTE_DID, test_profile},
+    };
+
+    const CONNECTION_ACCEPTED_TYPE: &str =
+        "https://affinidi.com/atm/client-actions/connection-accepted";
+    const CHANNEL_DID: &str = "did:example:channel";
+
+    fn connection_setup() -> Message {
+        Message::build(
+            Uuid::new_v4().to_string(),
+            "https://affinidi.com/atm/client-actions/connection-setup".to_string(),
+            json!({ "channel_did": CHANNEL_DID }),
         )
-        .await;
-
-    let protocols = Protocols::default();
-    let forwarded = protocols
-        .routing
-        .forward_message(
-            atm,
-            profile,
-            packed.unwrap().0.as_str(),
-            profile.dids().unwrap().1,
-            message.from.as_ref().unwrap(),
-            None,
-            None,
+        .from(REMOTE_DID.to_string())
+        .to(AGENT_DID.to_string())
+        .thid("thid".to_string())
+        .pthid("pthid".to_string())
+        .finalize()
+    }
+
+    #[tokio::test]
+    async fn connection_response_is_forwarded_via_the_mediator() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+
+        let new_did = send_connection_response(
+            &atm,
+            &profile,
+            &connection_setup(),
+            &DIDCommAgent::default(),
         )
-        .await;
-
-    match atm
-        .send_message(
-            profile,
-            &forwarded.as_ref().unwrap().1,
-            &forwarded.as_ref().unwrap().0,
-            false,
-            true,
+        .await
+        .unwrap();
+
+        assert_eq!(new_did, CHANNEL_DID);
+        let sent = atm.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].message.type_, CONNECTION_ACCEPTED_TYPE);
+        assert_eq!(sent[0].to_did, REMOTE_DID);
+        assert!(sent[0].forwarded);
+    }
+
+    #[tokio::test]
+    async fn connection_response_is_sent_directly_to_a_peer_with_a_messaging_service() {
+        let atm = MockTransport::direct();
+        let profile = test_profile(AGENT_DID).await;
+
+        send_connection_response(
+            &atm,
+            &profile,
+            &connection_setup(),
+            &DIDCommAgent::default(),
         )
         .await
-    {
-        Ok(_) => info!("Connection Response Sent"),
-        Err(e) => warn!("Error Sending Connection Response: {:#?}", e),
+        .unwrap();
+
+        let sent = atm.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(!sent[0].forwarded);
     }
 
-    Ok(new_did)
+    #[tokio::test]
+    async fn connection_setup_without_a_thread_is_an_error() {
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+        let mut message = connection_setup();
+        message.thid = None;
+
+        assert!(
+            send_connection_response(&atm, &profile, &message, &DIDCommAgent::default())
+                .await
+                .is_err()
+        );
+        assert!(atm.sent().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn undelivered_connection_response_is_retried_without_panicking() {
+        let atm = MockTransport::failing(DELIVERY_RETRIES + 1);
+        let profile = test_profile(AGENT_DID).await;
+
+        let new_did = send_connection_response(
+            &atm,
+            &profile,
+            &connection_setup(),
+            &DIDCommAgent::default(),
+        )
+        .await
+        .unwrap();
+
+        // Delivery failures are logged rather than returned, the channel is still set up
+        assert_eq!(new_did, CHANNEL_DID);
+        assert_eq!(atm.attempts(), DELIVERY_RETRIES + 1);
+        assert!(atm.sent().is_empty());
+    }
 }
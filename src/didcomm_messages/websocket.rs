@@ -0,0 +1,277 @@
+/*!
+ * Supervision of the mediator websocket connections
+ *
+ * Each profile gets its own direct channel so that a dropped connection can be detected, messages from
+ * all profiles are forwarded onto a single event channel for the agent to process.
+ *
+ * Where more than one mediator is configured, a profile that can't connect to its mediator fails over to
+ * the next mediator in priority order.
+ */
+
+use super::clear_messages::{FetchSettings, clear_inbound_messages, clear_outbound_messages};
+use affinidi_messaging_didcomm::{Message, UnpackMetadata};
+use affinidi_messaging_sdk::{ATM, profiles::ATMProfile};
+use anyhow::{Result, anyhow};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::warn;
+
+/// Initial delay before reconnecting a dropped websocket
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between reconnect attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// How long a single reconnect attempt may take before it is abandoned
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Events received from the mediator for a profile
+pub enum ProfileEvent {
+    /// A message was received
+    Message(Box<(Message, UnpackMetadata)>),
+    /// The websocket for the profile (DID) was closed
+    Disconnected(String),
+}
+
+/// Enables the websocket for a profile and forwards its messages onto the events channel
+/// A `ProfileEvent::Disconnected` is sent when the websocket closes the direct channel
+pub async fn connect_profile(
+    atm: &ATM,
+    profile: &Arc<ATMProfile>,
+    events: mpsc::Sender<ProfileEvent>,
+) -> Result<()> {
+    atm.profile_enable_websocket(profile).await?;
+
+    let (direct_tx, mut direct_rx) = mpsc::channel::<Box<(Message, UnpackMetadata)>>(32);
+    profile.enable_direct_channel(direct_tx).await?;
+
+    let did = profile.inner.did.clone();
+    tokio::spawn(async move {
+        while let Some(data) = direct_rx.recv().await {
+            if events.send(ProfileEvent::Message(data)).await.is_err() {
+                // Agent has exited
+                return;
+            }
+        }
+        let _ = events.send(ProfileEvent::Disconnected(did)).await;
+    });
+
+    Ok(())
+}
+
+/// Mediator DID that a profile is using
+fn profile_mediator(profile: &ATMProfile) -> Option<String> {
+    profile
+        .inner
+        .mediator
+        .as_ref()
+        .as_ref()
+        .map(|mediator| mediator.did.clone())
+}
+
+/// Creates a profile on the first mediator that can be resolved, in priority order
+pub async fn new_profile(
+    atm: &ATM,
+    alias: &str,
+    did: &str,
+    mediator_dids: &[String],
+) -> Result<ATMProfile> {
+    let mut last_error = anyhow!("No mediators configured");
+    for mediator_did in mediator_dids {
+        match ATMProfile::new(
+            atm,
+            Some(alias.to_string()),
+            did.to_string(),
+            Some(mediator_did.clone()),
+        )
+        .await
+        {
+            Ok(profile) => return Ok(profile),
+            Err(e) => {
+                warn!("{}: Couldn't use mediator ({}): {}", did, mediator_did, e);
+                last_error = e.into();
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Creates a new profile with the same alias, DID and mediator as an existing profile
+/// A new profile is required to re-activate a websocket as the SDK still considers the old profile connected
+pub async fn renew_profile(atm: &ATM, profile: &ATMProfile) -> Result<ATMProfile> {
+    Ok(ATMProfile::new(
+        atm,
+        Some(profile.inner.alias.clone()),
+        profile.inner.did.clone(),
+        profile_mediator(profile),
+    )
+    .await?)
+}
+
+/// Adds a profile to ATM and enables its websocket
+/// When `clear` is set, queued messages are cleared with those settings before live streaming starts
+async fn add_and_connect(
+    atm: &ATM,
+    profile: &ATMProfile,
+    clear: Option<FetchSettings>,
+    events: mpsc::Sender<ProfileEvent>,
+) -> Result<Arc<ATMProfile>> {
+    let activate = async {
+        let _ = atm.profile_remove(&profile.inner.alias).await;
+        let new_profile = atm.profile_add(profile, false).await?;
+        if let Some(settings) = clear {
+            let _ = clear_inbound_messages(atm, &new_profile, settings).await;
+            let _ = clear_outbound_messages(atm, &new_profile, settings).await;
+        }
+        connect_profile(atm, &new_profile, events).await?;
+        Ok(new_profile)
+    };
+
+    tokio::time::timeout(RECONNECT_TIMEOUT, activate)
+        .await
+        .map_err(|_| anyhow!("Timed out connecting to mediator"))?
+}
+
+/// Creates a profile on the given mediator, adds it to ATM and enables its websocket
+async fn activate_on_mediator(
+    atm: &ATM,
+    profile: &ATMProfile,
+    mediator_did: &str,
+    clear: Option<FetchSettings>,
+    events: mpsc::Sender<ProfileEvent>,
+) -> Result<Arc<ATMProfile>> {
+    let new_profile = ATMProfile::new(
+        atm,
+        Some(profile.inner.alias.clone()),
+        profile.inner.did.clone(),
+        Some(mediator_did.to_string()),
+    )
+    .await?;
+    add_and_connect(atm, &new_profile, clear, events)
+        .await
+        .map_err(|e| anyhow!("Mediator ({}): {}", mediator_did, e))
+}
+
+/// Activates the profile on its mediator and enables its websocket
+/// When `clear` is set, queued messages are cleared with those settings first, otherwise they are delivered over
+/// the websocket
+/// If that fails, each of the other mediators is tried in priority order
+pub async fn activate_profile(
+    atm: &ATM,
+    profile: &ATMProfile,
+    mediator_dids: &[String],
+    clear: Option<FetchSettings>,
+    events: mpsc::Sender<ProfileEvent>,
+) -> Result<Arc<ATMProfile>> {
+    let mut last_error = match add_and_connect(atm, profile, clear, events.clone()).await {
+        Ok(profile) => return Ok(profile),
+        Err(e) => e,
+    };
+
+    let current = profile_mediator(profile);
+    for mediator_did in mediator_dids
+        .iter()
+        .filter(|did| current.as_ref() != Some(*did))
+    {
+        warn!(
+            "{}: Mediator failed ({}), failing over to ({})",
+            profile.inner.did, last_error, mediator_did
+        );
+        match activate_on_mediator(atm, profile, mediator_did, clear, events.clone()).await {
+            Ok(profile) => return Ok(profile),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Replaces a disconnected profile with a new one and re-enables the websocket
+/// The profile's current mediator is tried first, then the other mediators in priority order
+pub async fn reconnect_profile(
+    atm: &ATM,
+    profile: &Arc<ATMProfile>,
+    mediator_dids: &[String],
+    events: mpsc::Sender<ProfileEvent>,
+) -> Result<Arc<ATMProfile>> {
+    let current = profile_mediator(profile);
+    let mediators = current.iter().chain(
+        mediator_dids
+            .iter()
+            .filter(|did| current.as_ref() != Some(*did)),
+    );
+
+    let mut last_error = anyhow!("No mediators configured");
+    for mediator_did in mediators {
+        match activate_on_mediator(atm, profile, mediator_did, None, events.clone()).await {
+            Ok(profile) => return Ok(profile),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Tracks profiles (by DID) that are waiting to be reconnected, backing off exponentially
+#[derive(Default)]
+pub struct ReconnectSchedule {
+    /// DID -> (failed attempts, time of next attempt)
+    pending: HashMap<String, (u32, Instant)>,
+}
+
+impl ReconnectSchedule {
+    /// Schedules a reconnect for a DID whose websocket has dropped
+    pub fn schedule(&mut self, did: &str) {
+        self.pending
+            .entry(did.to_string())
+            .or_insert((0, Instant::now() + RECONNECT_INITIAL_DELAY));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When the next reconnect attempt is due
+    pub fn next_attempt(&self) -> Instant {
+        self.pending
+            .values()
+            .map(|(_, at)| *at)
+            .min()
+            .unwrap_or_else(|| Instant::now() + RECONNECT_MAX_DELAY)
+    }
+
+    /// DIDs that are due to be reconnected
+    pub fn due(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.pending
+            .iter()
+            .filter(|(_, (_, at))| *at <= now)
+            .map(|(did, _)| did.clone())
+            .collect()
+    }
+
+    /// Reconnect succeeded, stop tracking the DID
+    pub fn succeeded(&mut self, did: &str) {
+        self.pending.remove(did);
+    }
+
+    /// Reconnect failed, backs off before the next attempt
+    /// Returns the number of consecutive failed attempts for the DID
+    pub fn failed(&mut self, did: &str, error: &anyhow::Error) -> u32 {
+        let (attempts, at) = self
+            .pending
+            .entry(did.to_string())
+            .or_insert((0, Instant::now()));
+        *attempts += 1;
+        let delay = RECONNECT_INITIAL_DELAY
+            .saturating_mul(2_u32.saturating_pow(*attempts))
+            .min(RECONNECT_MAX_DELAY);
+        *at = Instant::now() + delay;
+        warn!(
+            "{}: Websocket reconnect attempt ({}) failed: {}. Retrying in {}s",
+            did,
+            attempts,
+            error,
+            delay.as_secs()
+        );
+        *attempts
+    }
+}
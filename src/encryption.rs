@@ -0,0 +1,56 @@
+/*!
+ * Passphrase based encryption used for data stored at rest
+ *
+ * Layout of encrypted data: salt (16 bytes) | nonce (12 bytes) | ciphertext
+ * The key is derived from the passphrase using PBKDF2-HMAC-SHA256 and data is encrypted with AES-256-GCM
+ */
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng, rand_core::RngCore},
+};
+use anyhow::{Result, anyhow, bail};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+/// Derives the AES-256-GCM cipher from the passphrase and salt
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    Aes256Gcm::new_from_slice(&key).map_err(|e| anyhow!("Couldn't create cipher: {}", e))
+}
+
+/// Encrypts the plaintext using a key derived from the passphrase
+pub fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Couldn't encrypt data"))?;
+
+    let mut data = Vec::with_capacity(SALT_LENGTH + NONCE_LENGTH + ciphertext.len());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+/// Decrypts data created by `encrypt`
+/// Fails if the passphrase is wrong or the data has been tampered with
+pub fn decrypt(passphrase: &str, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < SALT_LENGTH + NONCE_LENGTH {
+        bail!("Encrypted data is too short");
+    }
+    let (salt, rest) = data.split_at(SALT_LENGTH);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+
+    cipher(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Couldn't decrypt data, is the passphrase correct?"))
+}
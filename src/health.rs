@@ -0,0 +1,101 @@
+/*!
+ * Health check endpoints for container orchestration
+ *
+ * When `health_port` is set in the config:
+ * - `/healthz` returns 200 while the process is running (liveness)
+ * - `/readyz` returns 200 once the concierge and at least one model agent are connected to the mediator
+ */
+
+use crate::{agents::state_management::SharedStateRef, http, termination::Interrupted};
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use tokio::{select, sync::broadcast};
+use tracing::info;
+
+/// Runtime readiness of the agents, updated by the agents as they connect and disconnect
+#[derive(Default)]
+pub struct Readiness {
+    /// Concierge websocket is connected
+    concierge: AtomicBool,
+    /// Models whose agents are running with all websockets connected
+    models: Mutex<HashSet<String>>,
+}
+
+impl Readiness {
+    /// Marks the concierge as connected or disconnected
+    pub fn set_concierge(&self, ready: bool) {
+        self.concierge.store(ready, Ordering::Relaxed);
+    }
+
+    /// Marks a model agent as connected or disconnected
+    pub fn set_model(&self, model_name: &str, ready: bool) {
+        if let Ok(mut models) = self.models.lock() {
+            if ready {
+                models.insert(model_name.to_string());
+            } else {
+                models.remove(model_name);
+            }
+        }
+    }
+
+    /// Ready once the concierge and at least one model agent are connected
+    pub fn is_ready(&self) -> bool {
+        self.concierge.load(Ordering::Relaxed)
+            && self.models.lock().is_ok_and(|models| !models.is_empty())
+    }
+
+    /// Describes the readiness of each agent
+    fn describe(&self) -> String {
+        let mut models = self
+            .models
+            .lock()
+            .map(|models| models.iter().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        models.sort();
+
+        format!(
+            "concierge: {}\nmodels ready: {}\n",
+            if self.concierge.load(Ordering::Relaxed) {
+                "connected"
+            } else {
+                "disconnected"
+            },
+            models.join(", ")
+        )
+    }
+}
+
+/// Serves the health check endpoints until the bridge is interrupted
+pub async fn serve(
+    port: u16,
+    shared_state: SharedStateRef,
+    mut interrupt_rx: broadcast::Receiver<Interrupted>,
+) -> Result<()> {
+    let server = http::serve("Health", port, move |path| match path {
+        "/healthz" => ("200 OK", "ok\n".to_string()),
+        "/readyz" => {
+            let readiness = &shared_state.readiness;
+            let status = if readiness.is_ready() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, readiness.describe())
+        }
+        _ => http::not_found(),
+    });
+
+    select! {
+        result = server => result,
+        _ = interrupt_rx.recv() => {
+            info!("Health endpoint shutting down");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,73 @@
+/*!
+ * Minimal HTTP server for the operational endpoints (metrics and health checks)
+ *
+ * Only the request path is looked at, every response is plain text and the connection is closed.
+ */
+
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+/// HTTP status line and body of a response
+pub(crate) type Response = (&'static str, String);
+
+/// Response for any path that isn't served
+pub(crate) fn not_found() -> Response {
+    ("404 Not Found", "Not Found\n".to_string())
+}
+
+/// Serves requests on the given port until the future is dropped
+/// The path of each request is passed to the handler
+pub(crate) async fn serve<F>(name: &str, port: u16, handler: F) -> Result<()>
+where
+    F: Fn(&str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.context(format!(
+        "Couldn't bind {} endpoint to port ({})",
+        name, port
+    ))?;
+    info!("{} endpoint listening on http://0.0.0.0:{}", name, port);
+
+    let handler = Arc::new(handler);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                let name = name.to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, handler.as_ref()).await {
+                        warn!("{} request failed: {}", name, e);
+                    }
+                });
+            }
+            Err(e) => warn!("{} endpoint couldn't accept connection: {}", name, e),
+        }
+    }
+}
+
+/// Responds to a single HTTP request
+async fn respond<F>(mut stream: TcpStream, handler: &F) -> Result<()>
+where
+    F: Fn(&str) -> Response,
+{
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = handler(path);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
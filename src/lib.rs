@@ -1,56 +1,224 @@
 use affinidi_tdk::secrets_resolver::secrets::{Secret, SecretMaterial, SecretType};
-use anyhow::{Context, Result};
+use agents::state_management::SharedState;
+use anyhow::{Context, Result, bail};
 use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
 use console::style;
 use did_peer::{
     DIDPeer, DIDPeerCreateKeys, DIDPeerKeys, DIDPeerService, PeerServiceEndPoint,
     PeerServiceEndPointLong,
 };
-use keyring::Entry;
+use secrets::secret_store;
 use ssi::{JWK, jwk::Params};
+use std::{fs, io::Write, path::Path, str::FromStr};
+use tracing::debug;
 
 pub mod activate;
+pub mod agent_logs;
 pub mod agents;
+pub mod audit_log;
+pub mod backends;
 pub mod chat_messages;
+pub mod config_watcher;
 pub mod didcomm_messages;
+pub mod encryption;
+pub mod health;
+mod http;
+pub mod message_handlers;
+pub mod metrics;
+pub mod secrets;
 pub mod termination;
-
-const DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME: &str = "didcomm-ai-bridge";
+#[cfg(test)]
+mod test_support;
 
 pub enum DIDMethods {
     Key,
     Peer,
+    Jwk,
+}
+
+impl FromStr for DIDMethods {
+    type Err = anyhow::Error;
+
+    /// Parses a DID method name, with or without the did: prefix (e.g. key or did:key)
+    fn from_str(method: &str) -> Result<Self> {
+        match method.trim().trim_start_matches("did:") {
+            "key" => Ok(DIDMethods::Key),
+            "peer" => Ok(DIDMethods::Peer),
+            "jwk" => Ok(DIDMethods::Jwk),
+            _ => bail!(
+                "unknown DID method ({}), must be one of key, peer or jwk",
+                method
+            ),
+        }
+    }
+}
+
+impl DIDMethods {
+    /// DID method used by an existing DID
+    pub fn from_did(did: &str) -> DIDMethods {
+        if did.starts_with("did:key") {
+            DIDMethods::Key
+        } else if did.starts_with("did:jwk") {
+            DIDMethods::Jwk
+        } else {
+            DIDMethods::Peer
+        }
+    }
 }
 
-pub fn create_did(method: &DIDMethods, mediator_did: &str) -> Result<String> {
+/// Creates a new DID, storing its secrets in the secret store
+/// `routing_keys` - added to the mediator service of did:peer DIDs, ignored by other methods
+pub fn create_did(
+    method: &DIDMethods,
+    mediator_did: &str,
+    routing_keys: &[String],
+) -> Result<String> {
     match method {
         DIDMethods::Key => _create_did_key(),
-        DIDMethods::Peer => _create_did_peer(mediator_did),
+        DIDMethods::Peer => _create_did_peer(mediator_did, routing_keys),
+        DIDMethods::Jwk => _create_did_jwk(),
     }
 }
 
-// Fetches the secret from keyring
+/// Rotates the keys for the agent using the given DID
+/// New keys always result in a new DID, the agent is updated in place keeping its name/greeting/image.
+/// The old DID's secret is kept, remove it with delete_did_secret once the configuration using the new DID is saved.
+/// Returns (old DID, new DID) so that connected clients can be told about the change
+pub async fn rotate_keys(
+    shared_state: &SharedState,
+    did: &str,
+    method: &DIDMethods,
+) -> Result<(String, String)> {
+    let new_did = {
+        let mut concierge = shared_state.concierge.lock().await;
+        if concierge.agent.did == did {
+            let new_did = create_did(
+                method,
+                shared_state.service_mediator_did(),
+                &shared_state.routing_keys,
+            )?;
+            concierge.agent.did = new_did.clone();
+            Some(new_did)
+        } else {
+            None
+        }
+    };
+
+    let new_did = match new_did {
+        Some(new_did) => new_did,
+        None => {
+            let models = { shared_state.models.lock().await.clone() };
+            let mut new_did = None;
+            for model in models.values() {
+                let mut model = model.lock().await;
+                if let Some(agent) = model.dids.iter_mut().find(|agent| agent.did == did) {
+                    let did = create_did(
+                        method,
+                        shared_state.service_mediator_did(),
+                        &shared_state.routing_keys,
+                    )?;
+                    agent.did = did.clone();
+                    new_did = Some(did);
+                    break;
+                }
+            }
+            match new_did {
+                Some(new_did) => new_did,
+                None => bail!("No agent found using DID ({})", did),
+            }
+        }
+    };
+
+    Ok((did.to_string(), new_did))
+}
+
+// Fetches the secret from the secret store
 pub fn get_did_secret(did: &str) -> Result<Vec<u8>> {
-    let entry = Entry::new(DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME, did)?;
-    match entry.get_secret() {
+    match secret_store().get_secret(did) {
         Ok(secret) => Ok(secret),
         Err(e) => {
             println!(
                 "{}",
                 style(format!("ERROR: Couldn't get secret for {}: {}", did, e)).red()
             );
-            return Err(e.into());
+            Err(e)
         }
     }
 }
 
-// Deletes the secret from keyring
+// Deletes the secret from the secret store
 pub fn delete_did_secret(did: &str) -> Result<()> {
-    let event = Entry::new(DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME, did).unwrap();
-    let _ = event.delete_credential();
+    let _ = secret_store().delete_secret(did);
     Ok(())
 }
 
+/// Replaces a file so that readers, and a crash part way through, only ever see the old or the new contents
+/// The contents are written and synced to a temporary file in the same directory, which is then renamed over the file.
+/// `mode` sets the file permissions, otherwise those of the file being replaced are kept.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .context(format!("{} isn't a file path", path.display()))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let written = (|| -> Result<()> {
+        let mut file = fs::File::create(&tmp_path)?;
+        match (mode, fs::metadata(path)) {
+            #[cfg(unix)]
+            (Some(mode), _) => {
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
+            (_, Ok(existing)) => file.set_permissions(existing.permissions())?,
+            _ => {}
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        // Sync the directory so the rename itself survives a crash
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    })();
+
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    written.context(format!("Couldn't write {}", path.display()))
+}
+
+/// Describes secrets for logging without any private key material
+/// Only the key id, and the public `kty`/`crv`/`x`/`y` components of a JWK are included, never `d`
+fn redact_secrets(secrets: &[Secret]) -> String {
+    secrets
+        .iter()
+        .map(|secret| match &secret.secret_material {
+            SecretMaterial::JWK { private_key_jwk } => {
+                let public = ["kty", "crv", "x", "y"]
+                    .iter()
+                    .filter_map(|k| private_key_jwk.get(k).map(|v| format!("{}={}", k, v)))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                format!("{} ({})", secret.id, public)
+            }
+            _ => format!("{} (redacted)", secret.id),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
 /// Creates a DID Key to use as the DIDComm agent for a Ollama Model
 fn _create_did_key() -> Result<String> {
     let secp256k1_key = JWK::generate_secp256k1();
@@ -75,8 +243,9 @@ fn _create_did_key() -> Result<String> {
         });
     }
 
-    let entry = Entry::new(DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME, &did_key)?;
-    entry.set_secret(
+    debug!("Created {}: {}", did_key, redact_secrets(&secrets));
+    secret_store().set_secret(
+        &did_key,
         BASE64_STANDARD_NO_PAD
             .encode(serde_json::to_string(&secrets).unwrap().as_bytes())
             .as_bytes(),
@@ -86,7 +255,7 @@ fn _create_did_key() -> Result<String> {
 }
 
 /// Creates a DID Peer to use as the DIDComm agent for a LLM
-fn _create_did_peer(mediator_did: &str) -> Result<String> {
+fn _create_did_peer(mediator_did: &str, routing_keys: &[String]) -> Result<String> {
     let e_secp256k1_key = JWK::generate_secp256k1();
     let v_ed25519_key = JWK::generate_ed25519().unwrap();
 
@@ -112,7 +281,7 @@ fn _create_did_peer(mediator_did: &str) -> Result<String> {
         service_end_point: PeerServiceEndPoint::Long(PeerServiceEndPointLong {
             uri: mediator_did.into(),
             accept: vec!["didcomm/v2".into()],
-            routing_keys: vec![],
+            routing_keys: routing_keys.to_vec(),
         }),
         id: None,
     }];
@@ -121,7 +290,7 @@ fn _create_did_peer(mediator_did: &str) -> Result<String> {
     let (did_peer, _) =
         DIDPeer::create_peer_did(&keys, Some(&services)).context("Failed to create did:peer")?;
 
-    // Save the private keys to secure storage
+    // Save the private keys to the secret store
 
     let mut secrets = Vec::new();
     if let Params::OKP(map) = v_ed25519_key.params {
@@ -148,8 +317,9 @@ fn _create_did_peer(mediator_did: &str) -> Result<String> {
         });
     }
 
-    let entry = Entry::new(DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME, &did_peer)?;
-    entry.set_secret(
+    debug!("Created {}: {}", did_peer, redact_secrets(&secrets));
+    secret_store().set_secret(
+        &did_peer,
         BASE64_STANDARD_NO_PAD
             .encode(serde_json::to_string(&secrets).unwrap().as_bytes())
             .as_bytes(),
@@ -157,3 +327,129 @@ fn _create_did_peer(mediator_did: &str) -> Result<String> {
 
     Ok(did_peer)
 }
+
+/// Creates a DID JWK to use as the DIDComm agent for a LLM
+/// The P-256 key is used for both signing and key agreement
+fn _create_did_jwk() -> Result<String> {
+    let p256_key = JWK::generate_p256();
+    let did_jwk = ssi::dids::DIDJWK::generate(&p256_key).to_string();
+
+    let mut secrets = Vec::new();
+    if let Params::EC(map) = p256_key.params {
+        secrets.push(Secret {
+            // did:jwk documents have a single verification method with the fragment #0
+            id: [&did_jwk, "#0"].concat(),
+            type_: SecretType::JsonWebKey2020,
+            secret_material: SecretMaterial::JWK {
+                private_key_jwk: serde_json::json!({
+                     "crv": map.curve,
+                     "kty": "EC",
+                     "x": String::from(map.x_coordinate.clone().unwrap()),
+                     "y": String::from(map.y_coordinate.clone().unwrap()),
+                     "d": String::from(map.ecc_private_key.clone().unwrap())
+                }),
+            },
+        });
+    }
+
+    debug!("Created {}: {}", did_jwk, redact_secrets(&secrets));
+    secret_store().set_secret(
+        &did_jwk,
+        BASE64_STANDARD_NO_PAD
+            .encode(serde_json::to_string(&secrets).unwrap().as_bytes())
+            .as_bytes(),
+    )?;
+
+    Ok(did_jwk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{file_names, temp_dir, use_memory_secret_store};
+    use affinidi_tdk::common::TDKSharedState;
+
+    #[test]
+    fn write_atomic_replaces_the_file() {
+        let dir = temp_dir();
+        let path = dir.join("config.json");
+        fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new", None).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_names(&dir), vec!["config.json"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_keeps_or_sets_the_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let path = dir.join("config.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        write_atomic(&path, b"new", None).unwrap();
+        assert_eq!(mode(&path), 0o640);
+
+        write_atomic(&path, b"newer", Some(0o600)).unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn write_atomic_leaves_the_file_alone_on_failure() {
+        let dir = temp_dir();
+        let path = dir.join("config.json");
+        fs::write(&path, "old").unwrap();
+        // A directory can't be renamed over with a file
+        fs::create_dir(dir.join("taken")).unwrap();
+
+        assert!(write_atomic(&dir.join("taken"), b"new", None).is_err());
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(file_names(&dir), vec!["config.json", "taken"]);
+    }
+
+    #[tokio::test]
+    async fn did_peer_service_has_the_routing_keys() {
+        use_memory_secret_store();
+        let routing_keys = vec!["did:example:mediator#key-1".to_string()];
+
+        let did = _create_did_peer("did:example:mediator", &routing_keys).unwrap();
+
+        let resolved = TDKSharedState::default()
+            .await
+            .did_resolver
+            .resolve(&did)
+            .await
+            .unwrap();
+        let doc = serde_json::to_value(&resolved.doc).unwrap();
+        let endpoint = &doc["service"][0]["serviceEndpoint"];
+        assert_eq!(endpoint["uri"], "did:example:mediator");
+        assert_eq!(endpoint["routing_keys"], serde_json::json!(routing_keys));
+        assert!(secret_store().get_secret(&did).is_ok());
+    }
+
+    #[test]
+    fn redacted_secrets_have_no_private_key_material() {
+        use_memory_secret_store();
+        let did = _create_did_key().unwrap();
+        let stored = BASE64_STANDARD_NO_PAD
+            .decode(secret_store().get_secret(&did).unwrap())
+            .unwrap();
+        let secrets: Vec<Secret> = serde_json::from_slice(&stored).unwrap();
+        let SecretMaterial::JWK { private_key_jwk } = &secrets[0].secret_material else {
+            panic!("expected a JWK secret");
+        };
+        let private_key = private_key_jwk["d"].as_str().unwrap();
+
+        let redacted = redact_secrets(&secrets);
+
+        assert!(!redacted.contains(private_key));
+        assert!(!redacted.contains("d="));
+        assert!(redacted.contains(&secrets[0].id));
+        assert!(redacted.contains(private_key_jwk["x"].as_str().unwrap()));
+    }
+}
@@ -5,28 +5,45 @@
 
 use std::{collections::HashMap, process, sync::Arc};
 
-use affinidi_messaging_sdk::{ATM, config::ATMConfig, profiles::ATMProfile};
+use affinidi_messaging_sdk::{ATM, config::ATMConfig};
 use affinidi_tdk::{
     common::{TDKSharedState, environments::TDKEnvironments},
     secrets_resolver::SecretsResolver,
 };
-use anyhow::Result;
+use anyhow::{Result, bail};
 use clap::Parser;
 use console::style;
+use dialoguer::{Password, theme::ColorfulTheme};
 use didcomm_ai_bridge::{
-    activate::get_secrets,
+    DIDMethods,
+    activate::{get_model_secrets, get_secrets},
+    agent_logs::AgentLogs,
     agents::{
         concierge::concierge_handler::{Concierge, ConciergeMessage},
-        state_management::SharedState,
+        state_management::{Config, SharedState, persist_sequence_numbers},
+    },
+    audit_log::AuditLog,
+    config_watcher, delete_did_secret,
+    didcomm_messages::{
+        clear_messages::DeletePolicy,
+        oob_connection::{INVITATION_EXPIRY, create_invitation, invitation_qr_code},
+        websocket::new_profile,
+    },
+    health, metrics, rotate_keys,
+    secrets::{
+        SecretsConfig, export_secrets, import_secrets, init_secret_store, is_secret_missing,
     },
     termination::{Interrupted, create_termination},
 };
-use setup_wizard::run_setup_wizard;
-use std::env;
+use schemars::schema_for;
+use setup_wizard::{generate_config, run_setup_wizard};
+use std::{env, path::Path, str::FromStr};
 use tokio::{sync::mpsc, try_join};
-use tracing::info;
-use tracing_subscriber::filter;
+use tracing::{Instrument, error, info, info_span};
+use tracing_subscriber::{EnvFilter, Layer, filter::LevelFilter, fmt, layer::SubscriberExt};
 
+mod diagnostics;
+mod self_test;
 mod setup_wizard;
 
 /// DIDComm agent for your Ollama models
@@ -52,17 +69,110 @@ struct Args {
     /// Path to the environments file (defaults to environments.json)
     #[arg(short, long)]
     path_environments: Option<String>,
+
+    /// Rotate the keys of the agent using this DID, then exit
+    #[arg(long, value_name = "DID")]
+    rotate_keys: Option<String>,
+
+    /// Export the secrets of all configured agents to an encrypted file, then exit
+    #[arg(long, value_name = "FILE")]
+    export_secrets: Option<String>,
+
+    /// Import agent secrets from an encrypted file created by --export-secrets, then exit
+    #[arg(long, value_name = "FILE")]
+    import_secrets: Option<String>,
+
+    /// List the configured models and their DIDs, then exit
+    #[arg(long)]
+    list_models: bool,
+
+    /// Check the secrets, models and mediator are all working, then exit (non-zero on any failure)
+    #[arg(long)]
+    self_test: bool,
+
+    /// Print an invitation URL and QR code to connect to a model's agent (or "concierge"), then exit
+    #[arg(long, value_name = "MODEL")]
+    print_invite: Option<String>,
+
+    /// Print the version, OS, configuration and secrets backend details for bug reports, then exit
+    #[arg(long)]
+    diagnostics: bool,
+
+    /// Print a JSON Schema of the configuration file for validation and editor autocompletion, then exit
+    #[arg(long)]
+    config_schema: bool,
+
+    /// Also write each model's and the concierge's logs to their own file (<name>.log) in this directory
+    #[arg(long, value_name = "DIR", env = "AI_BRIDGE_LOG_DIR")]
+    log_dir: Option<String>,
+
+    /// What happens to messages queued on the mediator when agents connect, overrides the configuration file
+    /// do-not-delete leaves them on the mediator so they can be inspected
+    #[arg(long, value_enum, value_name = "POLICY")]
+    fetch_delete_policy: Option<DeletePolicy>,
+
+    /// Keyring service name to store secrets under, overrides the configuration file
+    #[arg(long, value_name = "NAME")]
+    keyring_service: Option<String>,
+
+    /// Create the configuration file without prompts (for CI and scripted provisioning), then exit
+    #[arg(long)]
+    generate_config: bool,
+
+    /// Mediator DIDs for --generate-config, comma separated in priority order
+    #[arg(
+        long,
+        value_name = "DIDS",
+        env = "AI_BRIDGE_MEDIATOR_DIDS",
+        value_delimiter = ','
+    )]
+    mediator_dids: Vec<String>,
+
+    /// DID method for --generate-config (key, peer or jwk)
+    #[arg(
+        long,
+        value_name = "METHOD",
+        env = "AI_BRIDGE_DID_METHOD",
+        default_value = "key"
+    )]
+    did_method: String,
+
+    /// Ollama service address for --generate-config, comma separated addresses are used for failover
+    #[arg(
+        long,
+        value_name = "URL",
+        env = "AI_BRIDGE_OLLAMA_ADDRESS",
+        default_value = "http://localhost:11434"
+    )]
+    ollama_address: String,
+
+    /// Ollama API key for --generate-config, sent as a bearer token
+    #[arg(long, value_name = "KEY", env = "AI_BRIDGE_OLLAMA_API_KEY")]
+    ollama_api_key: Option<String>,
+
+    /// Models for --generate-config, comma separated (e.g. llama3.2:latest,deepseek-r1:8b)
+    #[arg(
+        long,
+        value_name = "MODELS",
+        env = "AI_BRIDGE_MODELS",
+        value_delimiter = ','
+    )]
+    models: Vec<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    // Each agent's info and above also goes to its own file, whatever RUST_LOG is set to
+    let agent_logs = match &args.log_dir {
+        Some(log_dir) => Some(AgentLogs::new(log_dir)?.with_filter(LevelFilter::INFO)),
+        None => None,
+    };
     // construct a subscriber that prints formatted traces to stdout
-    let subscriber = tracing_subscriber::fmt()
-        // Use a more compact, abbreviated log format
-        .with_env_filter(filter::EnvFilter::from_default_env())
-        .finish();
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(agent_logs);
     // use that subscriber to process traces emitted after this point
     tracing::subscriber::set_global_default(subscriber).expect("Logging failed, exiting...");
 
@@ -72,14 +182,97 @@ async fn main() -> Result<()> {
         "config.json".to_string()
     };
 
+    if args.config_schema {
+        println!("{}", serde_json::to_string_pretty(&schema_for!(Config))?);
+        process::exit(0);
+    }
+
+    if args.diagnostics {
+        diagnostics::run(&config_file, args.keyring_service.as_deref()).await;
+        process::exit(0);
+    }
+
+    if args.generate_config {
+        if Path::new(&config_file).exists() {
+            println!(
+                "{}",
+                style(format!(
+                    "ERROR: Configuration file ({}) already exists, it won't be overwritten",
+                    config_file
+                ))
+                .red()
+            );
+            process::exit(1);
+        }
+        let secrets = SecretsConfig::default()
+            .resolve()?
+            .with_keyring_service(args.keyring_service.as_deref());
+        init_secret_store(&secrets)?;
+        let trimmed = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<String>>()
+        };
+        let config = generate_config(
+            secrets,
+            trimmed(&args.mediator_dids),
+            &DIDMethods::from_str(&args.did_method)?,
+            &args.ollama_address,
+            args.ollama_api_key
+                .clone()
+                .filter(|api_key| !api_key.is_empty()),
+            &trimmed(&args.models),
+        )
+        .await?;
+        config.save(&config_file).await?;
+        println!("Created configuration file ({})", config_file);
+        process::exit(0);
+    }
+
     let config = match SharedState::load(&config_file) {
-        Ok(config) => Arc::new(config),
+        Ok(mut config) => {
+            config.fetch_delete_policy_override = args.fetch_delete_policy;
+            if let Some(path) = &config.audit_log_path {
+                config.audit_log = Some(AuditLog::start(path, config.audit_log_max_bytes())?);
+                info!(
+                    "Recording prompts and responses to the audit log ({})",
+                    path
+                );
+            }
+            Arc::new(config)
+        }
         Err(e) => {
             if e.to_string()
                 .starts_with("Couldn't open configuration file")
             {
                 println!("{}", style("ERROR: No configuration file found.").red());
-                let config = run_setup_wizard().await?;
+                let secrets = SecretsConfig::default()
+                    .resolve()?
+                    .with_keyring_service(args.keyring_service.as_deref());
+                init_secret_store(&secrets)?;
+                // Mediators can still be configured without a messaging client, they just aren't checked
+                let messaging = match messaging_setup(
+                    args.environment.as_deref(),
+                    args.path_environments.as_deref(),
+                )
+                .await
+                {
+                    Ok(messaging) => Some(messaging),
+                    Err(e) => {
+                        println!(
+                            "{}",
+                            style(format!(
+                                "WARNING: Mediator reachability can't be checked: {}",
+                                e
+                            ))
+                            .yellow()
+                        );
+                        None
+                    }
+                };
+                let config = run_setup_wizard(secrets, messaging).await?;
                 config.save(&config_file).await?;
                 println!("New config created, please update it, if needed, and re-run the app");
                 process::exit(0);
@@ -91,59 +284,114 @@ async fn main() -> Result<()> {
         }
     };
 
-    let environment_name = if let Some(environment_name) = &args.environment {
-        environment_name.to_string()
-    } else if let Ok(environment_name) = env::var("TDK_ENVIRONMENT") {
-        environment_name
-    } else {
-        "default".to_string()
-    };
+    if args.list_models {
+        list_models(&config).await;
+        process::exit(0);
+    }
 
-    // Instantiate TDK
-    let tdk = TDKSharedState::default().await;
+    init_secret_store(
+        &config
+            .secrets
+            .clone()
+            .with_keyring_service(args.keyring_service.as_deref()),
+    )?;
 
-    let mut environment =
-        TDKEnvironments::fetch_from_file(args.path_environments.as_deref(), &environment_name)?;
-    println!("Using Environment: {}", environment_name);
+    if let Some(path) = &args.export_secrets {
+        let passphrase = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Passphrase to encrypt the exported secrets")
+            .with_confirmation("Confirm passphrase", "Passphrases don't match")
+            .interact()?;
+        let count = export_secrets(&config.dids().await, path, &passphrase)?;
+        println!("Exported ({}) secrets to {}", count, path);
+        process::exit(0);
+    }
 
-    let mut atm_config = ATMConfig::builder();
-    atm_config = atm_config.with_ssl_certificates(&mut environment.ssl_certificates);
+    if let Some(path) = &args.import_secrets {
+        let passphrase = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt("Passphrase of the exported secrets")
+            .interact()?;
+        let count = import_secrets(path, &passphrase)?;
+        println!("Imported ({}) secrets from {}", count, path);
+        process::exit(0);
+    }
+
+    if let Some(did) = &args.rotate_keys {
+        let (old_did, new_did) = rotate_keys(&config, did, &DIDMethods::from_did(did)).await?;
+        config.save(&config_file).await?;
+        // Only removed once the configuration no longer uses the old DID
+        delete_did_secret(&old_did)?;
+        println!(
+            "Rotated keys for agent:\n  Old DID: {}\n  New DID: {}",
+            old_did, new_did
+        );
+        println!(
+            "{}",
+            style("Clients connected to the old DID will need to reconnect using the new DID")
+                .yellow()
+        );
+        process::exit(0);
+    }
+
+    if let Some(port) = config.metrics_port {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(port).await {
+                println!("{}", style(format!("ERROR: {}", e)).red());
+            }
+        });
+    }
+
+    let (tdk, atm_config) = messaging_setup(
+        args.environment.as_deref(),
+        args.path_environments.as_deref(),
+    )
+    .await?;
+
+    if args.self_test {
+        let passed = self_test::run(&config, atm_config, tdk).await?;
+        process::exit(if passed { 0 } else { 1 });
+    }
 
     let mut additional_secrets = Vec::new();
     let concierge_did = config.concierge.lock().await.agent.did.clone();
-    let concierge_secret = get_secrets(&concierge_did)?.first().unwrap().to_owned();
-    additional_secrets.push(concierge_secret);
+    // Without its secret the concierge can't be reached, so there is nothing to start
+    additional_secrets.extend(get_secrets(&concierge_did).map_err(|e| {
+        if is_secret_missing(&e) {
+            e.context(format!(
+                "The concierge's secret ({}) is missing from the secret store. Restore it with --import-secrets, or move the configuration file aside and re-run the app to set up a new concierge",
+                concierge_did
+            ))
+        } else {
+            e
+        }
+    })?);
 
     let mut model_names = Vec::new();
-    {
-        for (model_name, model) in config.models.lock().await.iter() {
-            model_names.push(model_name.to_string());
-
-            let did = model.lock().await.dids.first().unwrap().to_owned();
-            let model_secret = get_secrets(&did.did)?.first().unwrap().to_owned();
-            additional_secrets.push(model_secret);
-        }
+    for (model_name, model_secrets) in get_model_secrets(&config).await {
+        model_names.push(model_name);
+        additional_secrets.extend(model_secrets);
     }
     println!("additional_secrets: {}", additional_secrets.len());
     tdk.secrets_resolver.insert_vec(&additional_secrets).await;
 
     // Create a new ATM Client
-    let atm = ATM::new(atm_config.build()?, tdk).await?;
+    let atm = ATM::new(atm_config, tdk).await?;
+
+    if let Some(name) = &args.print_invite {
+        print_invite(&atm, &config, name).await?;
+        process::exit(0);
+    }
 
     let mut model_profiles = HashMap::new();
     {
         for (model_name, model) in config.models.lock().await.iter() {
+            if !model_names.contains(model_name) {
+                continue;
+            }
             let mut model_atm_profiles = Vec::new();
-            for did in model.lock().await.dids.to_owned() {
-                model_atm_profiles.push(
-                    ATMProfile::new(
-                        &atm,
-                        Some(did.name),
-                        did.did.to_string(),
-                        Some(config.mediator_did.to_string()),
-                    )
-                    .await?,
-                );
+            let dids = model.lock().await.dids.clone();
+            for did in dids {
+                model_atm_profiles
+                    .push(new_profile(&atm, &did.name, &did.did, &config.mediator_dids).await?);
             }
             model_profiles.insert(model_name.to_owned(), model_atm_profiles);
         }
@@ -151,41 +399,190 @@ async fn main() -> Result<()> {
 
     let (to_concierge, from_main) = mpsc::unbounded_channel::<ConciergeMessage>();
     let (terminator, mut interrupt_rx) = create_termination();
-    let (concierge, _) = Concierge::new(atm.clone(), config.clone(), from_main);
+    let (concierge, _) = Concierge::new(atm.clone(), config.clone(), &config_file, from_main);
 
     let concierge_profile = {
-        ATMProfile::new(
+        new_profile(
             &atm,
-            Some("Affinidi Concierge".into()),
-            concierge_did.to_string(),
-            Some(config.mediator_did.to_string()),
+            "Affinidi Concierge",
+            &concierge_did,
+            &config.mediator_dids,
         )
         .await?
     };
 
-    let concierge_handle = concierge.run(
-        concierge_profile,
-        model_profiles,
-        terminator,
+    if let Some(port) = config.health_port {
+        let config = config.clone();
+        let interrupt_rx = interrupt_rx.resubscribe();
+        tokio::spawn(async move {
+            if let Err(e) = health::serve(port, config, interrupt_rx).await {
+                println!("{}", style(format!("ERROR: {}", e)).red());
+            }
+        });
+    }
+
+    {
+        let config_file = config_file.clone();
+        let config = config.clone();
+        let to_concierge = to_concierge.clone();
+        let interrupt_rx = interrupt_rx.resubscribe();
+        tokio::spawn(async move {
+            if let Err(e) =
+                config_watcher::watch(config_file, config, to_concierge, interrupt_rx).await
+            {
+                println!("{}", style(format!("ERROR: {}", e)).red());
+            }
+        });
+    }
+
+    tokio::spawn(persist_sequence_numbers(
+        config.clone(),
+        config_file.clone(),
         interrupt_rx.resubscribe(),
-    );
+    ));
+
+    let concierge_handle = concierge
+        .run(
+            concierge_profile,
+            model_profiles,
+            terminator,
+            interrupt_rx.resubscribe(),
+        )
+        .instrument(info_span!("agent", name = "concierge"));
 
     for model_name in model_names {
         to_concierge.send(ConciergeMessage::StartModel { model_name })?;
     }
 
     try_join!(concierge_handle)?;
+    atm.graceful_shutdown().await;
 
     match interrupt_rx.recv().await {
         Ok(reason) => match reason {
             Interrupted::UserInt => info!("exited per user request"),
             Interrupted::OsSigInt => info!("exited because of an os sig int"),
-            Interrupted::SystemError => info!("exited because of a system error"),
+            Interrupted::SystemError => {
+                error!("exited because of a system error");
+                process::exit(1);
+            }
         },
         _ => {
             println!("exited because of an unexpected error");
+            process::exit(1);
         }
     }
 
     Ok(())
 }
+
+/// Instantiates the TDK and the messaging client configuration for the selected environment
+/// * `environment` - TDK environment name, TDK_ENVIRONMENT or "default" is used if not set
+/// * `path_environments` - Environments file, the TDK's default file is used if not set
+async fn messaging_setup(
+    environment: Option<&str>,
+    path_environments: Option<&str>,
+) -> Result<(TDKSharedState, ATMConfig)> {
+    let environment_name = if let Some(environment_name) = environment {
+        environment_name.to_string()
+    } else if let Ok(environment_name) = env::var("TDK_ENVIRONMENT") {
+        environment_name
+    } else {
+        "default".to_string()
+    };
+
+    // Instantiate TDK
+    let tdk = TDKSharedState::default().await;
+
+    let mut environment = TDKEnvironments::fetch_from_file(path_environments, &environment_name)?;
+    println!("Using Environment: {}", environment_name);
+
+    let atm_config = ATMConfig::builder()
+        .with_ssl_certificates(&mut environment.ssl_certificates)
+        .build()?;
+
+    Ok((tdk, atm_config))
+}
+
+/// Prints an invitation to connect to a model's agent, or the concierge, with its QR code
+async fn print_invite(atm: &ATM, config: &SharedState, name: &str) -> Result<()> {
+    let (alias, did) = if name == "concierge" {
+        let agent = { config.concierge.lock().await.agent.clone() };
+        (agent.name, agent.did)
+    } else {
+        let model = { config.models.lock().await.get(name).cloned() };
+        let Some(model) = model else {
+            bail!("Unknown model ({})", name);
+        };
+        match config.next_agent(&*model.lock().await) {
+            Some(agent) => (agent.name, agent.did),
+            None => bail!("Model ({}) has no agent DIDs", name),
+        }
+    };
+
+    let profile = new_profile(atm, &alias, &did, &config.mediator_dids).await?;
+    let profile = atm.profile_add(&profile, false).await?;
+    let url = create_invitation(atm, &profile).await?;
+
+    println!("{}", invitation_qr_code(&url)?);
+    println!(
+        "Invitation to connect to {} ({}), valid for {} hours:\n{}",
+        name,
+        did,
+        INVITATION_EXPIRY.as_secs() / 3600,
+        url
+    );
+    atm.graceful_shutdown().await;
+    Ok(())
+}
+
+/// Prints each configured model with the service it uses and its DIDs
+async fn list_models(config: &SharedState) {
+    let models = { config.models.lock().await.clone() };
+    if models.is_empty() {
+        println!("{}", style("No models configured").yellow());
+        return;
+    }
+
+    let mut rows = Vec::new();
+    for model in models.values() {
+        let model = model.lock().await;
+        rows.push((
+            model.name.clone(),
+            model.backend_address(),
+            model
+                .dids
+                .iter()
+                .map(|agent| agent.did.clone())
+                .collect::<Vec<String>>(),
+        ));
+    }
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let name_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0).max(5);
+    let address_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0).max(7);
+    println!(
+        "{}",
+        style(format!(
+            "{:name_width$}  {:address_width$}  DIDs",
+            "Model", "Address"
+        ))
+        .bold()
+    );
+    for (name, address, dids) in rows {
+        let mut dids = dids.iter();
+        println!(
+            "{}  {:address_width$}  {}",
+            style(format!("{:name_width$}", name)).green(),
+            address,
+            style(dids.next().map(|did| did.as_str()).unwrap_or("-")).cyan()
+        );
+        for did in dids {
+            println!(
+                "{:name_width$}  {:address_width$}  {}",
+                "",
+                "",
+                style(did).cyan()
+            );
+        }
+    }
+}
@@ -0,0 +1,169 @@
+/*!
+ * Routing of messages received by model agents to their handlers
+ *
+ * Each handler declares the message types it handles. Handlers registered by an integrator are checked
+ * before the built-in handlers (chat messages, presence, effects, connection setup, ...), so they can add new
+ * message types or replace how a built-in type is handled.
+ *
+ * ```ignore
+ * let mut config = SharedState::load("config.json")?;
+ * config.message_handlers.register(MyHandler);
+ * ```
+ */
+
+use crate::{
+    agents::state_management::{OllamaModel, SharedStateRef},
+    didcomm_messages::MessageTransport,
+};
+use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_sdk::profiles::ATMProfile;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A received message and the agent it was sent to
+#[derive(Clone, Copy)]
+pub struct MessageContext<'a> {
+    pub atm: &'a dyn MessageTransport,
+    /// Profile of the agent DID the message was sent to
+    pub profile: &'a Arc<ATMProfile>,
+    pub model: &'a Arc<Mutex<OllamaModel>>,
+    pub model_name: &'a str,
+    pub message: &'a Message,
+    /// Sender of the message, anonymous messages aren't passed to handlers
+    pub from_did: &'a str,
+    pub shared_state: &'a SharedStateRef,
+}
+
+/// Handles one or more message types
+pub trait MessageHandler: Send + Sync {
+    /// Whether this handler processes messages of this type (e.g. https://didcomm.org/basicmessage/2.0/message)
+    fn can_handle(&self, message_type: &str) -> bool;
+
+    /// Processes the message
+    fn handle<'a>(&'a self, context: MessageContext<'a>) -> BoxFuture<'a, Result<()>>;
+
+    /// Message types this handler processes, disclosed to clients that query the agent's features
+    /// Handlers that don't list their message types aren't disclosed
+    fn message_types(&self) -> &[&str] {
+        &[]
+    }
+}
+
+/// Message handlers in the order they are checked, the first that can handle a message type processes it
+pub struct MessageHandlers {
+    handlers: Vec<Box<dyn MessageHandler>>,
+}
+
+impl MessageHandlers {
+    /// Adds a handler, checked before the handlers already registered
+    pub fn register(&mut self, handler: impl MessageHandler + 'static) {
+        self.handlers.insert(0, Box::new(handler));
+    }
+
+    /// Message types listed by the registered handlers, each type once
+    pub fn message_types(&self) -> Vec<&str> {
+        let mut message_types = Vec::new();
+        for message_type in self
+            .handlers
+            .iter()
+            .flat_map(|handler| handler.message_types())
+        {
+            if !message_types.contains(message_type) {
+                message_types.push(*message_type);
+            }
+        }
+        message_types
+    }
+
+    /// The handler for a message type, if there is one
+    pub fn find(&self, message_type: &str) -> Option<&dyn MessageHandler> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.can_handle(message_type))
+            .map(|handler| handler.as_ref())
+    }
+}
+
+/// The built-in handlers
+impl Default for MessageHandlers {
+    fn default() -> Self {
+        Self {
+            handlers: crate::chat_messages::builtin_handlers(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::state_management::SharedState,
+        chat_messages::handle_message,
+        test_support::{
+            AGENT_DID, MODEL_NAME, MockTransport, REMOTE_DID, message_from_remote, test_model,
+            test_profile,
+        },
+    };
+    use serde_json::json;
+    use std::sync::Mutex as StdMutex;
+
+    const CUSTOM_TYPE: &str = "https://example.com/custom/1.0/message";
+
+    /// Records the id and sender of each message it handles
+    #[derive(Default)]
+    struct RecordingHandler {
+        handled: Arc<StdMutex<Vec<(String, String)>>>,
+    }
+
+    impl MessageHandler for RecordingHandler {
+        fn can_handle(&self, message_type: &str) -> bool {
+            message_type == CUSTOM_TYPE
+        }
+
+        fn handle<'a>(&'a self, context: MessageContext<'a>) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                self.handled
+                    .lock()
+                    .unwrap()
+                    .push((context.message.id.clone(), context.from_did.to_string()));
+                Ok(())
+            })
+        }
+
+        fn message_types(&self) -> &[&str] {
+            &[CUSTOM_TYPE]
+        }
+    }
+
+    #[tokio::test]
+    async fn registered_handler_receives_its_message_type() {
+        let handler = RecordingHandler::default();
+        let handled = handler.handled.clone();
+        let mut shared_state = SharedState::default();
+        shared_state.message_handlers.register(handler);
+        let shared_state = Arc::new(shared_state);
+        let atm = MockTransport::new();
+        let profile = test_profile(AGENT_DID).await;
+        let model = Arc::new(Mutex::new(test_model(&[])));
+        let message = message_from_remote(CUSTOM_TYPE, json!({}));
+
+        handle_message(&atm, &profile, &model, MODEL_NAME, &message, &shared_state)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *handled.lock().unwrap(),
+            vec![(message.id.clone(), REMOTE_DID.to_string())]
+        );
+        // Handled by the custom handler rather than answered as an unknown type
+        assert!(atm.sent().is_empty());
+        assert!(
+            shared_state
+                .message_handlers
+                .message_types()
+                .contains(&CUSTOM_TYPE)
+        );
+    }
+}
@@ -0,0 +1,205 @@
+/*!
+ * Metrics for prompts, messages and generation latency
+ *
+ * Metrics are recorded per model (the concierge is recorded as `concierge`) and can be scraped in the
+ * Prometheus text format from `http://<host>:<metrics_port>/metrics` when `metrics_port` is set in the config.
+ */
+
+use crate::http;
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+/// Prefix for all metric names
+const METRIC_PREFIX: &str = "didcomm_ai_bridge";
+
+/// Upper bounds of the latency histogram buckets (seconds)
+const LATENCY_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+static METRICS: LazyLock<Mutex<HashMap<String, ModelMetrics>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Metrics recorded for a single model
+#[derive(Default)]
+struct ModelMetrics {
+    messages_received: u64,
+    prompts_received: u64,
+    messages_sent: u64,
+    errors: u64,
+    time_to_first_token: Histogram,
+    generation_time: Histogram,
+}
+
+/// Cumulative histogram using the LATENCY_BUCKETS
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Name, help text and value of a counter
+type CounterDefinition = (&'static str, &'static str, fn(&ModelMetrics) -> u64);
+/// Name, help text and value of a histogram
+type HistogramDefinition = (&'static str, &'static str, fn(&ModelMetrics) -> &Histogram);
+
+/// Applies an update to the metrics of a model
+fn update(model: &str, f: impl FnOnce(&mut ModelMetrics)) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        f(metrics.entry(model.to_string()).or_default());
+    }
+}
+
+/// A DIDComm message was received by the model's agent
+pub fn record_message_received(model: &str) {
+    update(model, |m| m.messages_received += 1);
+}
+
+/// A prompt was received and sent to the model
+pub fn record_prompt(model: &str) {
+    update(model, |m| m.prompts_received += 1);
+}
+
+/// A chat message was sent to a remote party
+pub fn record_message_sent(model: &str) {
+    update(model, |m| m.messages_sent += 1);
+}
+
+/// An error occurred while generating or sending a response
+pub fn record_error(model: &str) {
+    update(model, |m| m.errors += 1);
+}
+
+/// Time from sending the prompt to receiving the first token from the model
+pub fn observe_time_to_first_token(model: &str, duration: Duration) {
+    update(model, |m| m.time_to_first_token.observe(duration));
+}
+
+/// Time from sending the prompt to the response being complete
+pub fn observe_generation_time(model: &str, duration: Duration) {
+    update(model, |m| m.generation_time.observe(duration));
+}
+
+/// Escapes a label value for the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders all metrics in the Prometheus text format
+pub fn render() -> String {
+    let Ok(metrics) = METRICS.lock() else {
+        return String::new();
+    };
+    let mut models = metrics.iter().collect::<Vec<_>>();
+    models.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut output = String::new();
+    let counters: [CounterDefinition; 4] = [
+        (
+            "messages_received_total",
+            "DIDComm messages received",
+            |m| m.messages_received,
+        ),
+        ("prompts_received_total", "Prompts sent to the model", |m| {
+            m.prompts_received
+        }),
+        (
+            "messages_sent_total",
+            "Chat messages sent to remote parties",
+            |m| m.messages_sent,
+        ),
+        (
+            "errors_total",
+            "Errors generating or sending responses",
+            |m| m.errors,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(output, "# HELP {}_{} {}", METRIC_PREFIX, name, help);
+        let _ = writeln!(output, "# TYPE {}_{} counter", METRIC_PREFIX, name);
+        for (model, m) in &models {
+            let _ = writeln!(
+                output,
+                "{}_{}{{model=\"{}\"}} {}",
+                METRIC_PREFIX,
+                name,
+                escape_label(model),
+                value(m)
+            );
+        }
+    }
+
+    let histograms: [HistogramDefinition; 2] = [
+        (
+            "time_to_first_token_seconds",
+            "Time from sending the prompt to the first token",
+            |m| &m.time_to_first_token,
+        ),
+        (
+            "generation_seconds",
+            "Time from sending the prompt to the response being complete",
+            |m| &m.generation_time,
+        ),
+    ];
+    for (name, help, histogram) in histograms {
+        let _ = writeln!(output, "# HELP {}_{} {}", METRIC_PREFIX, name, help);
+        let _ = writeln!(output, "# TYPE {}_{} histogram", METRIC_PREFIX, name);
+        for (model, m) in &models {
+            let model = escape_label(model);
+            let histogram = histogram(m);
+            for (count, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(
+                    output,
+                    "{}_{}_bucket{{model=\"{}\",le=\"{}\"}} {}",
+                    METRIC_PREFIX, name, model, le, count
+                );
+            }
+            let _ = writeln!(
+                output,
+                "{}_{}_bucket{{model=\"{}\",le=\"+Inf\"}} {}",
+                METRIC_PREFIX, name, model, histogram.count
+            );
+            let _ = writeln!(
+                output,
+                "{}_{}_sum{{model=\"{}\"}} {}",
+                METRIC_PREFIX, name, model, histogram.sum
+            );
+            let _ = writeln!(
+                output,
+                "{}_{}_count{{model=\"{}\"}} {}",
+                METRIC_PREFIX, name, model, histogram.count
+            );
+        }
+    }
+
+    output
+}
+
+/// Serves the metrics over HTTP on the given port until the process exits
+pub async fn serve(port: u16) -> Result<()> {
+    http::serve("Metrics", port, |path| match path {
+        "/metrics" => ("200 OK", render()),
+        _ => http::not_found(),
+    })
+    .await
+}
@@ -0,0 +1,397 @@
+/*!
+ * Storage of DID secrets
+ *
+ * Secrets are stored in the OS keyring by default. Where no keyring is available (headless servers,
+ * containers) an encrypted file can be used instead.
+ *
+ * The backend is selected from the configuration, and can be overridden with environment variables:
+ * - DIDCOMM_AI_BRIDGE_SECRETS_BACKEND: `keyring` or `file`
+ * - DIDCOMM_AI_BRIDGE_SECRETS_FILE: path of the secrets file (file backend)
+ * - DIDCOMM_AI_BRIDGE_SECRETS_PASSPHRASE: passphrase used to encrypt the secrets file (file backend)
+ *
+ * Keyring secrets are stored under a service name (`didcomm-ai-bridge` by default). Give each bridge running on
+ * the same machine its own service name so that their secrets don't collide.
+ */
+
+use crate::{
+    encryption::{decrypt, encrypt},
+    write_atomic,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use keyring::Entry;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+const DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME: &str = "didcomm-ai-bridge";
+const SECRETS_BACKEND_ENV: &str = "DIDCOMM_AI_BRIDGE_SECRETS_BACKEND";
+const SECRETS_FILE_ENV: &str = "DIDCOMM_AI_BRIDGE_SECRETS_FILE";
+const SECRETS_PASSPHRASE_ENV: &str = "DIDCOMM_AI_BRIDGE_SECRETS_PASSPHRASE";
+const DEFAULT_SECRETS_FILE: &str = "secrets.enc";
+/// Keyring entry looked up to check the keyring is available
+const KEYRING_PROBE_USER: &str = "didcomm-ai-bridge-probe";
+
+static SECRET_STORE: OnceLock<Box<dyn SecretStore>> = OnceLock::new();
+
+/// Backend used to store DID secrets
+pub trait SecretStore: Send + Sync {
+    /// Fetches the secret for a DID
+    fn get_secret(&self, did: &str) -> Result<Vec<u8>>;
+    /// Stores the secret for a DID, replacing any existing secret
+    fn set_secret(&self, did: &str, secret: &[u8]) -> Result<()>;
+    /// Deletes the secret for a DID
+    fn delete_secret(&self, did: &str) -> Result<()>;
+}
+
+/// Which secrets backend to use, stored in config.json
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum SecretsConfig {
+    /// OS native keyring
+    Keyring {
+        /// Keyring service name the secrets are stored under
+        #[serde(default = "default_keyring_service")]
+        service: String,
+    },
+    /// Passphrase encrypted file
+    File { path: String },
+}
+
+impl Default for SecretsConfig {
+    fn default() -> Self {
+        SecretsConfig::Keyring {
+            service: default_keyring_service(),
+        }
+    }
+}
+
+fn default_keyring_service() -> String {
+    DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME.to_string()
+}
+
+impl SecretsConfig {
+    /// Overrides the keyring service name, has no effect on other backends
+    pub fn with_keyring_service(self, service: Option<&str>) -> SecretsConfig {
+        match (self, service) {
+            (SecretsConfig::Keyring { .. }, Some(service)) => SecretsConfig::Keyring {
+                service: service.to_string(),
+            },
+            (config, _) => config,
+        }
+    }
+
+    /// Applies any environment variable overrides to the configured backend
+    pub fn resolve(&self) -> Result<SecretsConfig> {
+        let backend = match env::var(SECRETS_BACKEND_ENV) {
+            Ok(backend) => backend.to_lowercase(),
+            Err(_) => return Ok(self.clone()),
+        };
+
+        match backend.as_str() {
+            "keyring" => match self {
+                SecretsConfig::Keyring { .. } => Ok(self.clone()),
+                SecretsConfig::File { .. } => Ok(SecretsConfig::default()),
+            },
+            "file" => {
+                let path = match (env::var(SECRETS_FILE_ENV), self) {
+                    (Ok(path), _) => path,
+                    (Err(_), SecretsConfig::File { path }) => path.clone(),
+                    (Err(_), SecretsConfig::Keyring { .. }) => DEFAULT_SECRETS_FILE.to_string(),
+                };
+                Ok(SecretsConfig::File { path })
+            }
+            _ => bail!(
+                "Unknown secrets backend ({}) in {}, must be keyring or file",
+                backend,
+                SECRETS_BACKEND_ENV
+            ),
+        }
+    }
+
+    /// Checks the backend can be used without reading or writing any secrets
+    /// The keyring is checked by looking up an entry that doesn't exist
+    pub fn probe(&self) -> Result<()> {
+        match self {
+            SecretsConfig::Keyring { service } => {
+                match Entry::new(service, KEYRING_PROBE_USER)?.get_secret() {
+                    Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            SecretsConfig::File { .. } => self.build().map(|_| ()),
+        }
+    }
+
+    /// Creates the secret store for this backend
+    fn build(&self) -> Result<Box<dyn SecretStore>> {
+        match self {
+            SecretsConfig::Keyring { service } => Ok(Box::new(KeyringSecretStore::new(service))),
+            SecretsConfig::File { path } => {
+                let passphrase = env::var(SECRETS_PASSPHRASE_ENV).context(format!(
+                    "The file secrets backend requires {} to be set",
+                    SECRETS_PASSPHRASE_ENV
+                ))?;
+                Ok(Box::new(FileSecretStore::new(path, &passphrase)))
+            }
+        }
+    }
+}
+
+/// Initialises the secret store used for all secret reads and writes
+/// Must be called before any secrets are accessed, otherwise the keyring is used
+pub fn init_secret_store(config: &SecretsConfig) -> Result<()> {
+    set_secret_store(config.resolve()?.build()?)
+}
+
+/// Sets the secret store used for all secret reads and writes, it can only be set once
+pub(crate) fn set_secret_store(store: Box<dyn SecretStore>) -> Result<()> {
+    SECRET_STORE
+        .set(store)
+        .map_err(|_| anyhow!("Secret store has already been initialised"))
+}
+
+/// No secret is stored for the DID, e.g. it was deleted from the keyring or the keyring was wiped
+#[derive(Debug)]
+pub struct SecretNotFound(pub String);
+
+impl std::fmt::Display for SecretNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No secret found for {}", self.0)
+    }
+}
+
+impl std::error::Error for SecretNotFound {}
+
+/// Whether the error is caused by the secret for a DID not being in the secret store
+pub fn is_secret_missing(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.downcast_ref::<SecretNotFound>().is_some())
+}
+
+/// Whether the error is caused by the secret store being unavailable (e.g. the keyring daemon has stopped),
+/// rather than a problem with a particular secret
+pub fn is_store_unavailable(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<keyring::Error>(),
+            Some(keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_))
+        )
+    })
+}
+
+/// Returns the active secret store
+pub(crate) fn secret_store() -> &'static dyn SecretStore {
+    SECRET_STORE
+        .get_or_init(|| Box::new(KeyringSecretStore::default()))
+        .as_ref()
+}
+
+/// Exports the secrets for the given DIDs to a passphrase encrypted file
+/// The file uses the same format as the file backend (DID -> secret), so it can be imported or used as a
+/// secrets file directly
+/// Returns the number of secrets exported
+pub fn export_secrets(dids: &[String], path: &str, passphrase: &str) -> Result<usize> {
+    let mut secrets = HashMap::new();
+    for did in dids {
+        let secret = secret_store()
+            .get_secret(did)
+            .context(format!("Couldn't get secret for {}", did))?;
+        secrets.insert(did.to_string(), BASE64_STANDARD_NO_PAD.encode(secret));
+    }
+
+    FileSecretStore::new(path, passphrase).store(&secrets)?;
+    Ok(secrets.len())
+}
+
+/// Imports every secret in an exported file into the active secret store
+/// Existing secrets for the same DIDs are replaced
+/// Returns the number of secrets imported
+pub fn import_secrets(path: &str, passphrase: &str) -> Result<usize> {
+    let file = FileSecretStore::new(path, passphrase);
+    if !file.path.exists() {
+        bail!("Secrets export file ({}) doesn't exist", path);
+    }
+
+    let secrets = file.load()?;
+    for (did, secret) in &secrets {
+        secret_store()
+            .set_secret(did, &BASE64_STANDARD_NO_PAD.decode(secret)?)
+            .context(format!("Couldn't store secret for {}", did))?;
+    }
+    Ok(secrets.len())
+}
+
+/// Stores secrets in the OS native keyring
+pub struct KeyringSecretStore {
+    service: String,
+}
+
+impl KeyringSecretStore {
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+        }
+    }
+}
+
+impl Default for KeyringSecretStore {
+    fn default() -> Self {
+        Self::new(DIDCOMM_AI_BRIDGE_KEYRING_SERVICE_NAME)
+    }
+}
+
+impl SecretStore for KeyringSecretStore {
+    fn get_secret(&self, did: &str) -> Result<Vec<u8>> {
+        match Entry::new(&self.service, did)?.get_secret() {
+            Ok(secret) => Ok(secret),
+            Err(keyring::Error::NoEntry) => Err(SecretNotFound(did.to_string()).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_secret(&self, did: &str, secret: &[u8]) -> Result<()> {
+        Ok(Entry::new(&self.service, did)?.set_secret(secret)?)
+    }
+
+    fn delete_secret(&self, did: &str) -> Result<()> {
+        Ok(Entry::new(&self.service, did)?.delete_credential()?)
+    }
+}
+
+/// Stores secrets in a file encrypted with a passphrase derived key
+pub struct FileSecretStore {
+    path: PathBuf,
+    passphrase: String,
+    /// Serialises read-modify-write cycles on the file
+    lock: Mutex<()>,
+}
+
+impl FileSecretStore {
+    pub fn new(path: &str, passphrase: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            passphrase: passphrase.to_string(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Reads all secrets from the file, DID -> base64 encoded secret
+    fn load(&self) -> Result<HashMap<String, String>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let data = fs::read(&self.path).context(format!(
+            "Couldn't read secrets file ({})",
+            self.path.display()
+        ))?;
+        let contents = decrypt(&self.passphrase, &data).context(format!(
+            "Couldn't decrypt secrets file ({})",
+            self.path.display()
+        ))?;
+        serde_json::from_slice(&contents).context(format!(
+            "Couldn't parse secrets file ({})",
+            self.path.display()
+        ))
+    }
+
+    /// Writes all secrets to the file
+    fn store(&self, secrets: &HashMap<String, String>) -> Result<()> {
+        let data = encrypt(&self.passphrase, &serde_json::to_vec(secrets)?)?;
+        // Replaced atomically so an interrupted write can't lose every secret
+        write_atomic(&self.path, &data, Some(0o600)).context(format!(
+            "Couldn't write secrets file ({})",
+            self.path.display()
+        ))
+    }
+}
+
+impl SecretStore for FileSecretStore {
+    fn get_secret(&self, did: &str) -> Result<Vec<u8>> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Secrets file lock poisoned"))?;
+        let secrets = self.load()?;
+        let Some(secret) = secrets.get(did) else {
+            return Err(SecretNotFound(did.to_string()).into());
+        };
+        Ok(BASE64_STANDARD_NO_PAD.decode(secret)?)
+    }
+
+    fn set_secret(&self, did: &str, secret: &[u8]) -> Result<()> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Secrets file lock poisoned"))?;
+        let mut secrets = self.load()?;
+        secrets.insert(did.to_string(), BASE64_STANDARD_NO_PAD.encode(secret));
+        self.store(&secrets)
+    }
+
+    fn delete_secret(&self, did: &str) -> Result<()> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Secrets file lock poisoned"))?;
+        let mut secrets = self.load()?;
+        if secrets.remove(did).is_some() {
+            self.store(&secrets)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{file_names, temp_dir};
+
+    #[test]
+    fn file_store_round_trips_secrets() {
+        let dir = temp_dir();
+        let path = dir.join("secrets.enc");
+        let store = FileSecretStore::new(path.to_str().unwrap(), "passphrase");
+
+        store.set_secret("did:example:one", b"one").unwrap();
+        store.set_secret("did:example:two", b"two").unwrap();
+        store.delete_secret("did:example:one").unwrap();
+
+        assert_eq!(store.get_secret("did:example:two").unwrap(), b"two");
+        assert!(store.get_secret("did:example:one").is_err());
+        assert_eq!(file_names(&dir), vec!["secrets.enc"]);
+    }
+
+    #[test]
+    fn file_store_needs_the_passphrase() {
+        let dir = temp_dir();
+        let path = dir.join("secrets.enc");
+        FileSecretStore::new(path.to_str().unwrap(), "passphrase")
+            .set_secret("did:example:one", b"one")
+            .unwrap();
+
+        let store = FileSecretStore::new(path.to_str().unwrap(), "wrong");
+        assert!(store.get_secret("did:example:one").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_store_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir();
+        let path = dir.join("secrets.enc");
+        FileSecretStore::new(path.to_str().unwrap(), "passphrase")
+            .set_secret("did:example:one", b"one")
+            .unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+    }
+}
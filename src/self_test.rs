@@ -0,0 +1,183 @@
+/*!
+ * Self test of the bridge's plumbing
+ *
+ * Checks, in order, that:
+ * - the secrets of every agent DID can be read from the secret store
+ * - each model's backend answers a trivial prompt
+ * - the mediator accepts a message to each agent's own DID and returns it
+ *
+ * Each check is printed as it completes, followed by a summary.
+ */
+
+use affinidi_messaging_sdk::{
+    ATM, config::ATMConfig, messages::GetMessagesRequest, protocols::Protocols,
+};
+use affinidi_tdk::{common::TDKSharedState, secrets_resolver::SecretsResolver};
+use anyhow::{Result, anyhow, bail};
+use console::style;
+use didcomm_ai_bridge::{
+    activate::get_secrets,
+    agents::state_management::{OllamaModel, SharedState},
+    backends,
+    didcomm_messages::websocket::new_profile,
+};
+use ollama_rs::generation::chat::ChatMessage;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+/// Prompt sent to each model, any response is a pass
+const SELF_TEST_PROMPT: &str = "Reply with the single word: OK";
+
+/// Time a model has to start responding, models may need loading into memory first
+const MODEL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Attempts to fetch the message sent to the agent's own DID, and the delay between them
+const FETCH_ATTEMPTS: u32 = 5;
+const FETCH_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs every check, printing a pass/fail line for each
+/// Returns true if every check passed
+pub(crate) async fn run(
+    config: &SharedState,
+    atm_config: ATMConfig,
+    tdk: TDKSharedState,
+) -> Result<bool> {
+    println!("{}", style("Running self test").green());
+    let mut failures = 0;
+
+    // Agent DIDs by name, the concierge first
+    let mut agents = vec![(
+        "concierge".to_string(),
+        config.concierge.lock().await.agent.did.clone(),
+    )];
+    let models = { config.models.lock().await.clone() };
+    let mut model_names = models.keys().cloned().collect::<Vec<String>>();
+    model_names.sort();
+    for model_name in &model_names {
+        for agent in &models[model_name].lock().await.dids {
+            agents.push((
+                format!("{} ({})", model_name, agent.name),
+                agent.did.clone(),
+            ));
+        }
+    }
+
+    println!("{}", style("Secrets").bold());
+    let mut reachable = Vec::new();
+    for (name, did) in agents {
+        let result = get_secrets(&did);
+        if let Ok(secrets) = &result {
+            tdk.secrets_resolver.insert_vec(secrets).await;
+            reachable.push((name.clone(), did.clone()));
+        }
+        failures += report(&format!("{}: {}", name, did), result.map(|_| ()));
+    }
+
+    println!("{}", style("Models").bold());
+    for model_name in &model_names {
+        let model = { models[model_name].lock().await.clone() };
+        let result = check_model(&model).await;
+        failures += report(
+            &format!("{} at {}", model_name, model.backend_address()),
+            result,
+        );
+    }
+
+    println!("{}", style("Mediator").bold());
+    match ATM::new(atm_config, tdk).await {
+        Ok(atm) => {
+            for (name, did) in reachable {
+                let result = check_round_trip(&atm, config, &name, &did).await;
+                failures += report(&format!("{}: message to own DID", name), result);
+            }
+        }
+        Err(e) => failures += report("Messaging client", Err(e.into())),
+    }
+
+    println!();
+    if failures == 0 {
+        println!("{}", style("Self test passed").green());
+    } else {
+        println!(
+            "{}",
+            style(format!("Self test failed: ({}) checks failed", failures)).red()
+        );
+    }
+    Ok(failures == 0)
+}
+
+/// Prints the result of a check
+/// Returns the number of failures (0 or 1)
+fn report(check: &str, result: Result<()>) -> usize {
+    match result {
+        Ok(_) => {
+            println!("  {} {}", style("PASS").green(), check);
+            0
+        }
+        Err(e) => {
+            println!("  {} {}: {}", style("FAIL").red(), check, e);
+            1
+        }
+    }
+}
+
+/// Sends a trivial prompt to the model's backend, passing once the first token arrives
+async fn check_model(model: &OllamaModel) -> Result<()> {
+    let backend = backends::for_model(model);
+    let check = async {
+        let mut stream = backend
+            .generate_stream(
+                vec![ChatMessage::user(SELF_TEST_PROMPT.to_string())],
+                model.options.as_ref(),
+                false,
+            )
+            .await?;
+        match stream.next().await {
+            Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => Err(e),
+            None => Err(anyhow!("the response was empty")),
+        }
+    };
+
+    tokio::time::timeout(MODEL_TIMEOUT, check)
+        .await
+        .map_err(|_| anyhow!("no response within {}s", MODEL_TIMEOUT.as_secs()))?
+}
+
+/// Sends a trust ping from the agent to its own DID through the mediator, then fetches it back from the inbox
+async fn check_round_trip(atm: &ATM, config: &SharedState, name: &str, did: &str) -> Result<()> {
+    let profile = new_profile(atm, name, did, &config.mediator_dids).await?;
+    let profile = atm.profile_add(&profile, false).await?;
+
+    let ping = Protocols::default()
+        .trust_ping
+        .send_ping(atm, &profile, did, true, false, false)
+        .await?;
+
+    for _ in 0..FETCH_ATTEMPTS {
+        let response = atm
+            .get_messages(
+                &profile,
+                &GetMessagesRequest {
+                    message_ids: vec![ping.message_hash.clone()],
+                    delete: true,
+                },
+            )
+            .await?;
+        if response
+            .success
+            .iter()
+            .any(|message| message.msg_id == ping.message_hash)
+        {
+            let _ = atm.profile_remove(&profile.inner.alias).await;
+            return Ok(());
+        }
+        tokio::time::sleep(FETCH_DELAY).await;
+    }
+
+    let _ = atm.profile_remove(&profile.inner.alias).await;
+    bail!(
+        "the message wasn't delivered back within {}s",
+        (FETCH_DELAY * FETCH_ATTEMPTS).as_secs()
+    )
+}
@@ -1,25 +1,141 @@
-use anyhow::{Result, anyhow};
+use affinidi_messaging_sdk::{ATM, config::ATMConfig, profiles::ATMProfile};
+use affinidi_tdk::common::TDKSharedState;
+use anyhow::{Result, anyhow, bail};
 use console::style;
-use dialoguer::{Input, MultiSelect, Select, theme::ColorfulTheme};
+use dialoguer::{
+    Confirm, Editor, Input, MultiSelect, Password, Select, Sort, theme::ColorfulTheme,
+};
+use didcomm_ai_bridge::backends::ollama;
 use didcomm_ai_bridge::{
     DIDMethods,
-    agents::state_management::{ConciergeState, DIDCommAgent, OllamaModel, SharedState},
+    agents::state_management::{
+        ConciergeState, DIDCommAgent, OllamaModel, OllamaOptions, SharedState,
+    },
     create_did,
+    secrets::SecretsConfig,
 };
-use ollama_rs::Ollama;
-use regex::Regex;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-pub(crate) async fn run_setup_wizard() -> Result<SharedState> {
+/// Ollama service the selected models are served by
+struct OllamaService {
+    /// Includes the scheme, e.g. https://ollama.example.com
+    host: String,
+    port: u16,
+    /// Sent as a bearer token if set
+    api_key: Option<String>,
+    /// Tried in order when the service above can't be reached
+    fallback_hosts: Vec<String>,
+}
+
+/// Runs the setup wizard, creating a new configuration
+/// * `secrets` - Secrets backend the new agents' secrets are stored in
+/// * `messaging` - Used to check the selected mediators are reachable, the check is skipped if not set
+pub(crate) async fn run_setup_wizard(
+    secrets: SecretsConfig,
+    messaging: Option<(TDKSharedState, ATMConfig)>,
+) -> Result<SharedState> {
     println!();
     println!("{}", style("Running setup wizard").green());
-    let mediator_did = get_mediator_did()?;
+    let atm = match messaging {
+        Some((tdk, atm_config)) => match ATM::new(atm_config, tdk).await {
+            Ok(atm) => Some(atm),
+            Err(e) => {
+                println!(
+                    "{}",
+                    style(format!(
+                        "WARNING: Mediator reachability can't be checked: {}",
+                        e
+                    ))
+                    .yellow()
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let mediator_dids = loop {
+        let mediator_dids = get_mediator_dids()?;
+        match &atm {
+            Some(atm) if !check_mediators(atm, &mediator_dids).await? => continue,
+            _ => break mediator_dids,
+        }
+    };
+    if let Some(atm) = atm {
+        atm.graceful_shutdown().await;
+    }
     let did_method = get_did_method()?;
-    let mut shared_state = SharedState {
+    let routing_keys = match did_method {
+        DIDMethods::Peer => get_routing_keys()?,
+        _ => Vec::new(),
+    };
+    let vcard = get_vcard_details()?;
+    let mut shared_state = new_config(secrets, mediator_dids, &did_method, routing_keys, vcard)?;
+
+    add_new_model(&mut shared_state, &did_method).await?;
+
+    Ok(shared_state)
+}
+
+/// Creates a configuration without prompting, for CI and scripted provisioning
+/// * `ollama_address` - Ollama service for every model, e.g. http://localhost:11434
+///   Further comma separated addresses are used when the ones before can't be reached
+/// * `ollama_api_key` - Sent to the Ollama service as a bearer token if set
+/// * `model_names` - Models as they are known to Ollama (e.g. llama3.2:latest)
+pub(crate) async fn generate_config(
+    secrets: SecretsConfig,
+    mediator_dids: Vec<String>,
+    did_method: &DIDMethods,
+    ollama_address: &str,
+    ollama_api_key: Option<String>,
+    model_names: &[String],
+) -> Result<SharedState> {
+    if mediator_dids.is_empty() {
+        return Err(anyhow!("at least one mediator DID is required"));
+    }
+    if model_names.is_empty() {
+        return Err(anyhow!("at least one model is required"));
+    }
+    let (host, port, fallback_hosts) = parse_ollama_addresses(ollama_address)?;
+
+    let shared_state = new_config(
+        secrets,
+        mediator_dids,
+        did_method,
+        Vec::new(),
+        (None, None, None),
+    )?;
+    for model_name in model_names {
+        let mut model = OllamaModel::new(
+            host.clone(),
+            port,
+            shared_state.service_mediator_did(),
+            &shared_state.routing_keys,
+            model_name,
+            did_method,
+        )?;
+        model.ollama_api_key = ollama_api_key.clone();
+        model.ollama_fallback_hosts = fallback_hosts.clone();
+        shared_state.add_model(model_name, model).await;
+    }
+
+    Ok(shared_state)
+}
+
+/// Creates a configuration with a new concierge agent and no models
+/// * `vcard` - (surname, email, tel) shown on the concierge's vCard
+fn new_config(
+    secrets: SecretsConfig,
+    mediator_dids: Vec<String>,
+    did_method: &DIDMethods,
+    routing_keys: Vec<String>,
+    vcard: (Option<String>, Option<String>, Option<String>),
+) -> Result<SharedState> {
+    let (vcard_surname, vcard_email, vcard_tel) = vcard;
+    Ok(SharedState {
         concierge: Arc::new(Mutex::new(ConciergeState {
             agent: DIDCommAgent {
-                did: create_did(&did_method, &mediator_did)?,
+                did: create_did(did_method, &mediator_dids[0], &routing_keys)?,
                 image: "ollama.png".to_string(),
                 name: "AI Concierge".to_string(),
                 greeting:
@@ -27,42 +143,163 @@ pub(crate) async fn run_setup_wizard() -> Result<SharedState> {
                         .to_string(),
                 x_meetingplace_contact_attributes: 8,
                 x_meetingplace_verification_id: None,
+                allowed_dids: None,
+                vcard_surname,
+                vcard_email,
+                vcard_tel,
+                command_prefix: None,
+                unknown_command_response: None,
+                weight: None,
+                compress_attachments: None,
             },
             ..Default::default()
         })),
-        mediator_did,
+        mediator_dids,
+        routing_keys,
+        secrets,
         ..Default::default()
-    };
-
-    add_new_model(&mut shared_state, &did_method).await?;
-
-    Ok(shared_state)
+    })
 }
 
 pub(crate) async fn add_new_model(
     shared_state: &mut SharedState,
     did_method: &DIDMethods,
 ) -> Result<()> {
-    let (address, port) = get_ollama_address()?;
-    add_ollama_models(&address, port, shared_state, did_method).await?;
+    let service = get_ollama_service()?;
+    let options = get_ollama_options()?;
+    let system_prompt = get_system_prompt()?;
+    add_ollama_models(&service, shared_state, did_method, options, system_prompt).await?;
 
     Ok(())
 }
 
-fn get_mediator_did() -> Result<String> {
+/// Select the mediators to use, in priority order
+/// The first mediator is the primary, the others are used for failover
+fn get_mediator_dids() -> Result<Vec<String>> {
     let mediators = [
         "did:web:mediator-nlb.storm.ws:mediator:v1:.well-known",
         "did:web:internal-atn-mediator.dev.euw1.affinidi.io:.well-known",
         "did:web:internal-atn-mediator.dev.apse1.affinidi.io:.well-known",
     ];
-    let selected = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Mediator DID")
-        .default(1)
-        .items(&mediators)
-        .interact()
-        .unwrap();
+    let selected = loop {
+        let selected = MultiSelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Mediator DIDs (space to select, enter to confirm)")
+            .items(&mediators)
+            .defaults(&[false, true, false])
+            .interact()
+            .unwrap();
+        if !selected.is_empty() {
+            break selected;
+        }
+        println!("{}", style("At least one mediator must be selected").red());
+    };
+
+    let mut mediator_dids = selected
+        .iter()
+        .map(|i| mediators[*i].to_string())
+        .collect::<Vec<String>>();
 
-    Ok(mediators[selected].to_string())
+    if mediator_dids.len() > 1 {
+        let order = Sort::with_theme(&ColorfulTheme::default())
+            .with_prompt("Order mediators by priority (space to move, enter to confirm)")
+            .items(&mediator_dids)
+            .interact()
+            .unwrap();
+        mediator_dids = order.iter().map(|i| mediator_dids[*i].clone()).collect();
+    }
+
+    Ok(mediator_dids)
+}
+
+/// Checks each mediator is reachable, asking whether to proceed anyway if one isn't
+/// # Returns
+/// * `Ok(false)` - A mediator is unreachable and the mediators should be selected again
+async fn check_mediators(atm: &ATM, mediator_dids: &[String]) -> Result<bool> {
+    let mut reachable = true;
+    for mediator_did in mediator_dids {
+        println!("Checking mediator ({})...", mediator_did);
+        if let Err(e) = check_mediator(atm, mediator_did).await {
+            println!(
+                "{}",
+                style(format!(
+                    "WARNING: Mediator ({}) isn't reachable: {:#}",
+                    mediator_did, e
+                ))
+                .yellow()
+            );
+            reachable = false;
+        }
+    }
+    if reachable {
+        return Ok(true);
+    }
+
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Proceed with these mediators anyway?")
+        .default(false)
+        .interact()?)
+}
+
+/// Resolves the mediator DID and requests the mediator's well-known DID from its REST endpoint
+/// No agent DID exists yet, so this only checks the mediator answers, not that agents can authenticate
+async fn check_mediator(atm: &ATM, mediator_did: &str) -> Result<()> {
+    atm.get_tdk()
+        .did_resolver
+        .resolve(mediator_did)
+        .await
+        .map_err(|e| anyhow!("couldn't resolve the mediator DID: {}", e))?;
+
+    // The profile is never authenticated, it is only used to look up the mediator's endpoints
+    let profile = ATMProfile::new(
+        atm,
+        None,
+        mediator_did.to_string(),
+        Some(mediator_did.to_string()),
+    )
+    .await?;
+    let Some(rest_endpoint) = profile.get_mediator_rest_endpoint() else {
+        bail!("the mediator DID has no REST endpoint");
+    };
+
+    let response = atm
+        .get_tdk()
+        .client
+        .get([&rest_endpoint, "/.well-known/did"].concat())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("{} responded with ({})", rest_endpoint, response.status());
+    }
+
+    Ok(())
+}
+
+/// Get the optional contact details shown on the concierge's vCard
+/// Leave a field empty to skip it
+/// # Returns
+/// * `Ok((surname, email, tel))`
+fn get_vcard_details() -> Result<(Option<String>, Option<String>, Option<String>)> {
+    println!(
+        "{}",
+        style("Contact details shown to connecting clients (leave empty to skip)").cyan()
+    );
+
+    Ok((
+        get_optional_input("vCard surname")?,
+        get_optional_input("vCard email")?,
+        get_optional_input("vCard phone number")?,
+    ))
+}
+
+/// Prompts for a value that may be left empty
+fn get_optional_input(prompt: &str) -> Result<Option<String>> {
+    let value: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .allow_empty(true)
+        .interact_text()?;
+    let value = value.trim();
+
+    Ok((!value.is_empty()).then(|| value.to_string()))
 }
 
 /// Select the DID method to use for generating keys
@@ -72,76 +309,238 @@ fn get_did_method() -> Result<DIDMethods> {
             "DID Method to use for generating keys (NOTE: did:peer is not supported by MPX)",
         )
         .default(0)
-        .items(&["did:key", "did:peer"])
+        .items(&["did:key", "did:peer", "did:jwk"])
         .interact()
         .unwrap();
 
-    if selected == 0 {
-        Ok(DIDMethods::Key)
-    } else {
-        Ok(DIDMethods::Peer)
+    match selected {
+        0 => Ok(DIDMethods::Key),
+        1 => Ok(DIDMethods::Peer),
+        _ => Ok(DIDMethods::Jwk),
     }
 }
 
-/// Get the Ollama address from the user
-/// http://localhost:11434
+/// Get the routing keys for the mediator service of did:peer DIDs
+/// Only needed when the mediator requires them, leave empty to skip
+fn get_routing_keys() -> Result<Vec<String>> {
+    let routing_keys: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Mediator routing keys (comma separated DID URLs, leave empty for none)")
+        .allow_empty(true)
+        .validate_with(|input: &String| -> Result<(), &str> {
+            if input
+                .split(',')
+                .map(|key| key.trim())
+                .filter(|key| !key.is_empty())
+                .all(|key| key.starts_with("did:"))
+            {
+                Ok(())
+            } else {
+                Err("Routing keys must be DID URLs (e.g. did:key:z6Mk...#z6Mk...)")
+            }
+        })
+        .interact_text()?;
+
+    Ok(routing_keys
+        .split(',')
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .map(|key| key.to_string())
+        .collect())
+}
+
+/// Get the Ollama address, and an API key for services behind an authenticating proxy, from the user
+/// http://localhost:11434 or https://ollama.example.com:443
+/// Several comma separated addresses can be given, the others are used when the first can't be reached
 /// # Returns
-/// * `Ok((String, u16))` - The address and port of the Ollama service
-fn get_ollama_address() -> Result<(String, u16)> {
-    let ollama_address_re = Regex::new(r"^(http:\/\/[^:]*):(\d+)$").unwrap();
-    let validate_re = ollama_address_re.clone();
+/// * `Ok(OllamaService)` - The address, port and API key of the Ollama service
+fn get_ollama_service() -> Result<OllamaService> {
     let ollama_address: String = Input::with_theme(&ColorfulTheme::default())
-        .with_prompt("Ollama Service Address")
+        .with_prompt("Ollama Service Address (comma separated for failover)")
         .default("http://localhost:11434".into())
-        .validate_with({
-            move |input: &String| -> Result<(), &str> {
-                match validate_re.captures(input) {
-                    None => Err("This is not a valid address; must look similar to http://localhost:11434"),
-                    Some(caps) => {
-                        if caps.len() == 3 {
-                        Ok(())
-                        } else {
-                        Err("This is not a valid address; must look similar to http://localhost:11434")
-                }
+        .validate_with(|input: &String| -> Result<(), String> {
+            parse_ollama_addresses(input)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+        .interact_text()
+        .unwrap();
+    let (host, port, fallback_hosts) = parse_ollama_addresses(&ollama_address)?;
+
+    let api_key = Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("Ollama API key (sent as a bearer token, leave empty for none)")
+        .allow_empty_password(true)
+        .interact()?;
+
+    Ok(OllamaService {
+        host,
+        port,
+        api_key: Some(api_key).filter(|api_key| !api_key.is_empty()),
+        fallback_hosts,
+    })
+}
+
+/// Splits comma separated Ollama addresses into the host (including the scheme) and port of the first
+/// and the addresses of the others, which are tried in order when the ones before can't be reached
+fn parse_ollama_addresses(ollama_addresses: &str) -> Result<(String, u16, Vec<String>)> {
+    let mut addresses = ollama_addresses
+        .split(',')
+        .map(|address| address.trim())
+        .filter(|address| !address.is_empty());
+    let Some(primary) = addresses.next() else {
+        bail!("At least one address is required (e.g. http://localhost:11434)");
+    };
+    let (host, port) = ollama::parse_address(primary)?;
+    let fallback_hosts = addresses
+        .map(|address| ollama::parse_address(address).map(|_| address.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((host, port, fallback_hosts))
+}
+
+/// Get the system prompt (persona) to apply to the selected models
+/// The prompt is written in the user's editor so that it can span multiple lines
+/// # Returns
+/// * `Ok(None)` - No system prompt, the model's default behaviour is used
+fn get_system_prompt() -> Result<Option<String>> {
+    if !Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Set a system prompt (persona) for the selected models?")
+        .default(false)
+        .interact()?
+    {
+        return Ok(None);
+    }
+
+    let system_prompt = Editor::new()
+        .edit("You are a helpful assistant.")?
+        .map(|prompt| prompt.trim().to_string())
+        .filter(|prompt| !prompt.is_empty());
+    if system_prompt.is_none() {
+        println!("{}", style("No system prompt saved").yellow());
+    }
+
+    Ok(system_prompt)
+}
+
+/// Get the generation options to apply to the selected models
+/// Defaults offered match the Ollama defaults
+/// # Returns
+/// * `Ok(None)` - Use the Ollama defaults
+fn get_ollama_options() -> Result<Option<OllamaOptions>> {
+    let customise = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(
+            "Customise generation options (temperature, top_p, top_k, num_ctx, repeat_penalty)?",
+        )
+        .default(false)
+        .interact()
+        .unwrap();
+
+    if !customise {
+        return Ok(None);
+    }
+
+    let temperature: f32 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Temperature (0.0 - 2.0)")
+        .default(0.8)
+        .validate_with(|input: &f32| -> Result<(), &str> {
+            if (0.0..=2.0).contains(input) {
+                Ok(())
+            } else {
+                Err("Temperature must be between 0.0 and 2.0")
             }
-        }
-        }})
+        })
         .interact_text()
         .unwrap();
 
-    match ollama_address_re.captures(&ollama_address) {
-        None => Err(anyhow::anyhow!(
-            "This is not a valid address; must look similar to http://localhost:11434"
-        )),
-        Some(caps) => {
-            if caps.len() != 3 {
-                return Err(anyhow::anyhow!(
-                    "This is not a valid address; must look similar to http://localhost:11434"
-                ));
+    let top_p: f32 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("top_p (0.0 - 1.0)")
+        .default(0.9)
+        .validate_with(|input: &f32| -> Result<(), &str> {
+            if (0.0..=1.0).contains(input) {
+                Ok(())
+            } else {
+                Err("top_p must be between 0.0 and 1.0")
             }
-            let port = caps.get(2).unwrap().as_str().parse::<u16>()?;
-            Ok((caps.get(1).unwrap().as_str().to_string(), port))
-        }
-    }
+        })
+        .interact_text()
+        .unwrap();
+
+    let top_k: u32 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("top_k")
+        .default(40)
+        .interact_text()
+        .unwrap();
+
+    let num_ctx: u64 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Context window size (num_ctx)")
+        .default(2048)
+        .interact_text()
+        .unwrap();
+
+    let repeat_penalty: f32 = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Repeat penalty")
+        .default(1.1)
+        .interact_text()
+        .unwrap();
+
+    let options = OllamaOptions {
+        temperature: Some(temperature),
+        top_p: Some(top_p),
+        top_k: Some(top_k),
+        num_ctx: Some(num_ctx),
+        repeat_penalty: Some(repeat_penalty),
+        stop: None,
+    };
+    options.validate()?;
+
+    Ok(Some(options))
 }
 
 /// Creates a list of Ollama models that you can select to enable
-pub async fn add_ollama_models(
-    host: &str,
-    port: u16,
+async fn add_ollama_models(
+    service: &OllamaService,
     config: &mut SharedState,
     did_method: &DIDMethods,
+    options: Option<OllamaOptions>,
+    system_prompt: Option<String>,
 ) -> Result<()> {
-    let ollama = Ollama::new(host.to_string(), port);
+    let ollama = ollama::client(&service.host, service.port, service.api_key.as_deref());
 
     println!();
-    let multi_select = ollama
-        .list_local_models()
-        .await
-        .map_err(|e| anyhow!(format!("list_local_models() failed: {}", e.to_string())))?
-        .iter()
-        .map(|m| m.name.clone())
-        .collect::<Vec<String>>();
+    let multi_select = match ollama.list_local_models().await {
+        Ok(models) => models
+            .iter()
+            .map(|m| m.name.clone())
+            .collect::<Vec<String>>(),
+        Err(e) => {
+            println!(
+                "{}",
+                style(format!(
+                    "Couldn't list models from Ollama ({}:{}): {}",
+                    service.host, service.port, e
+                ))
+                .red()
+            );
+            if !Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Ollama unreachable - enter models manually?")
+                .default(true)
+                .interact()?
+            {
+                return Err(anyhow!(format!("list_local_models() failed: {}", e)));
+            }
+
+            for model_name in get_model_names()? {
+                add_ollama_model(
+                    service,
+                    config,
+                    &model_name,
+                    did_method,
+                    &options,
+                    &system_prompt,
+                )
+                .await?;
+            }
+            return Ok(());
+        }
+    };
 
     let mut defaults: Vec<bool> = Vec::new();
     {
@@ -160,18 +559,15 @@ pub async fn add_ollama_models(
         .unwrap();
 
     for s in &selected {
-        config
-            .add_model(
-                &multi_select[*s],
-                OllamaModel::new(
-                    host.to_string(),
-                    port,
-                    &config.mediator_did,
-                    &multi_select[*s],
-                    did_method,
-                )?,
-            )
-            .await;
+        add_ollama_model(
+            service,
+            config,
+            &multi_select[*s],
+            did_method,
+            &options,
+            &system_prompt,
+        )
+        .await?;
     }
 
     // Check for what we removed
@@ -185,3 +581,51 @@ pub async fn add_ollama_models(
 
     Ok(())
 }
+
+/// Creates a model and adds it to the configuration
+async fn add_ollama_model(
+    service: &OllamaService,
+    config: &mut SharedState,
+    model_name: &str,
+    did_method: &DIDMethods,
+    options: &Option<OllamaOptions>,
+    system_prompt: &Option<String>,
+) -> Result<()> {
+    let mut model = OllamaModel::new(
+        service.host.clone(),
+        service.port,
+        config.service_mediator_did(),
+        &config.routing_keys,
+        model_name,
+        did_method,
+    )?;
+    model.ollama_api_key = service.api_key.clone();
+    model.ollama_fallback_hosts = service.fallback_hosts.clone();
+    model.options = options.clone();
+    model.system_prompt = system_prompt.clone();
+    config.add_model(model_name, model).await;
+
+    Ok(())
+}
+
+/// Get the model names from the user when they can't be listed from Ollama
+/// # Returns
+/// * `Ok(Vec<String>)` - Model names as they are known to Ollama (e.g. llama3.2:latest)
+fn get_model_names() -> Result<Vec<String>> {
+    let model_names: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Models to enable (comma separated, e.g. llama3.2:latest, deepseek-r1:8b)")
+        .validate_with(|input: &String| -> Result<(), &str> {
+            if input.split(',').any(|name| !name.trim().is_empty()) {
+                Ok(())
+            } else {
+                Err("At least one model name is required")
+            }
+        })
+        .interact_text()?;
+
+    Ok(model_names
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect())
+}
@@ -0,0 +1,297 @@
+/*!
+ * Test doubles shared by the unit tests
+ *
+ * `MockTransport` records the messages agents send instead of packing them for a mediator, and test models use
+ * the scripted backend, so message handling runs without a mediator or a model server.
+ */
+
+use crate::{
+    agents::state_management::{
+        ChatChannelState, DIDCommAgent, OllamaModel, OllamaOptions, now_secs,
+    },
+    backends::{ChatBackend, TokenStream, scripted::ScriptedBackend},
+    didcomm_messages::MessageTransport,
+    secrets::{SecretNotFound, SecretStore, set_secret_store},
+};
+use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_sdk::{ATM, config::ATMConfig, profiles::ATMProfile};
+use affinidi_tdk::common::TDKSharedState;
+use anyhow::{Result, bail};
+use futures::future::BoxFuture;
+use ollama_rs::generation::chat::ChatMessage;
+use serde_json::json;
+use sha256::digest;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, Once},
+};
+
+/// DID of the agent the test model answers on
+pub const AGENT_DID: &str = "did:example:agent";
+/// DID of the remote party chatting with the test model
+pub const REMOTE_DID: &str = "did:example:remote";
+/// Name of the test model
+pub const MODEL_NAME: &str = "test-model";
+
+/// A message handed to the transport
+#[derive(Clone)]
+pub struct SentMessage {
+    pub to_did: String,
+    pub message: Message,
+    /// Sent via the mediator rather than to the recipient's own messaging service
+    pub forwarded: bool,
+}
+
+/// Records sent messages, optionally failing the first sends
+#[derive(Default)]
+pub struct MockTransport {
+    sent: Mutex<Vec<SentMessage>>,
+    /// Number of sends still to fail
+    failures: Mutex<u32>,
+    /// Number of times a send was attempted, including failures
+    attempts: Mutex<u32>,
+    /// Recipients have their own messaging service, so messages aren't forwarded
+    direct: bool,
+}
+
+impl MockTransport {
+    /// Transport to recipients reached via the mediator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transport to recipients with their own messaging service
+    pub fn direct() -> Self {
+        Self {
+            direct: true,
+            ..Default::default()
+        }
+    }
+
+    /// Transport where the first `failures` sends fail
+    pub fn failing(failures: u32) -> Self {
+        Self {
+            failures: Mutex::new(failures),
+            ..Default::default()
+        }
+    }
+
+    /// Messages sent so far, in the order they were sent
+    pub fn sent(&self) -> Vec<SentMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Messages of a type sent so far
+    pub fn sent_of_type(&self, message_type: &str) -> Vec<Message> {
+        self.sent()
+            .into_iter()
+            .filter(|sent| sent.message.type_ == message_type)
+            .map(|sent| sent.message)
+            .collect()
+    }
+
+    /// Text of the chat messages sent so far
+    pub fn chat_texts(&self) -> Vec<String> {
+        self.sent_of_type("https://affinidi.com/atm/client-actions/chat-message")
+            .iter()
+            .filter_map(|message| message.body.get("text")?.as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// Number of sends attempted, including those that failed
+    pub fn attempts(&self) -> u32 {
+        *self.attempts.lock().unwrap()
+    }
+}
+
+impl MessageTransport for MockTransport {
+    fn pack_encrypted<'a>(
+        &'a self,
+        _profile: &'a Arc<ATMProfile>,
+        message: &'a Message,
+        _to_did: &'a str,
+    ) -> BoxFuture<'a, Result<(String, bool)>> {
+        Box::pin(async move { Ok((serde_json::to_string(message)?, !self.direct)) })
+    }
+
+    fn send<'a>(
+        &'a self,
+        _profile: &'a Arc<ATMProfile>,
+        packed: &'a str,
+        _message: &'a Message,
+        to_did: &'a str,
+        forward: bool,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            *self.attempts.lock().unwrap() += 1;
+            {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    bail!("mediator unavailable");
+                }
+            }
+            self.sent.lock().unwrap().push(SentMessage {
+                to_did: to_did.to_string(),
+                message: serde_json::from_str(packed)?,
+                forwarded: forward,
+            });
+            Ok(())
+        })
+    }
+}
+
+/// Backend whose first requests fail, then streams its tokens like the scripted backend
+pub struct FailingBackend {
+    /// Number of requests still to fail
+    failures: Mutex<u32>,
+    /// Number of requests made, including failures, shared so it can be read once the backend is in use
+    attempts: Arc<Mutex<u32>>,
+    scripted: ScriptedBackend,
+}
+
+impl FailingBackend {
+    /// Backend where the first `failures` requests fail, then every response streams `tokens`
+    pub fn new(failures: u32, tokens: &[&str]) -> Self {
+        let tokens: Vec<String> = tokens.iter().map(|token| token.to_string()).collect();
+        Self {
+            failures: Mutex::new(failures),
+            attempts: Arc::default(),
+            scripted: ScriptedBackend::new(&tokens),
+        }
+    }
+
+    /// Number of requests made, including those that failed
+    pub fn attempts(&self) -> Arc<Mutex<u32>> {
+        self.attempts.clone()
+    }
+}
+
+impl ChatBackend for FailingBackend {
+    fn generate_stream<'a>(
+        &'a self,
+        messages: Vec<ChatMessage>,
+        options: Option<&'a OllamaOptions>,
+        json: bool,
+    ) -> BoxFuture<'a, Result<TokenStream>> {
+        *self.attempts.lock().unwrap() += 1;
+        {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Box::pin(async { bail!("backend unavailable") });
+            }
+        }
+        self.scripted.generate_stream(messages, options, json)
+    }
+}
+
+/// Profile of an agent DID with no mediator, messages are sent through a `MockTransport`
+pub async fn test_profile(did: &str) -> Arc<ATMProfile> {
+    let atm = ATM::new(
+        ATMConfig::builder().build().unwrap(),
+        TDKSharedState::default().await,
+    )
+    .await
+    .unwrap();
+    Arc::new(
+        ATMProfile::new(&atm, None, did.to_string(), None)
+            .await
+            .unwrap(),
+    )
+}
+
+/// Model answering on AGENT_DID with a channel to REMOTE_DID
+/// Every response streams `tokens`, the prompt is echoed back if there are none
+pub fn test_model(tokens: &[&str]) -> OllamaModel {
+    let mut model: OllamaModel = serde_json::from_value(json!({
+        "name": MODEL_NAME,
+        "ollama_host": "http://localhost",
+        "ollama_port": 11434,
+        "backend": { "type": "scripted", "tokens": tokens },
+        "dids": [],
+        "channel_state": {},
+    }))
+    .unwrap();
+    model.dids.push(DIDCommAgent {
+        did: AGENT_DID.to_string(),
+        name: MODEL_NAME.to_string(),
+        ..Default::default()
+    });
+    model.channel_state.insert(
+        digest(REMOTE_DID),
+        ChatChannelState {
+            remote_did: REMOTE_DID.to_string(),
+            remote_did_hash: digest(REMOTE_DID),
+            agent_did: Some(AGENT_DID.to_string()),
+            last_seen: now_secs(),
+            ..Default::default()
+        },
+    );
+    model
+}
+
+/// A message of `message_type` from REMOTE_DID to AGENT_DID
+pub fn message_from_remote(message_type: &str, body: serde_json::Value) -> Message {
+    Message::build(
+        uuid::Uuid::new_v4().to_string(),
+        message_type.to_string(),
+        body,
+    )
+    .from(REMOTE_DID.to_string())
+    .to(AGENT_DID.to_string())
+    .finalize()
+}
+
+/// A new empty directory under the system temp directory
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("didcomm-ai-bridge-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Names of the files in a directory
+pub fn file_names(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Keeps secrets in memory, so tests don't touch the OS keyring
+#[derive(Default)]
+struct MemorySecretStore {
+    secrets: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl SecretStore for MemorySecretStore {
+    fn get_secret(&self, did: &str) -> Result<Vec<u8>> {
+        match self.secrets.lock().unwrap().get(did) {
+            Some(secret) => Ok(secret.clone()),
+            None => Err(SecretNotFound(did.to_string()).into()),
+        }
+    }
+
+    fn set_secret(&self, did: &str, secret: &[u8]) -> Result<()> {
+        self.secrets
+            .lock()
+            .unwrap()
+            .insert(did.to_string(), secret.to_vec());
+        Ok(())
+    }
+
+    fn delete_secret(&self, did: &str) -> Result<()> {
+        self.secrets.lock().unwrap().remove(did);
+        Ok(())
+    }
+}
+
+/// Stores secrets in memory for the rest of the test run, call before any secrets are read or written
+/// The store is shared by all tests, so each test should use its own DIDs
+pub fn use_memory_secret_store() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| set_secret_store(Box::new(MemorySecretStore::default())).unwrap());
+}
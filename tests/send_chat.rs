@@ -0,0 +1,114 @@
+//! Sends chat messages through the public API with a transport that records them instead of using a mediator
+
+use std::sync::{Arc, Mutex};
+
+use affinidi_messaging_didcomm::Message;
+use affinidi_messaging_sdk::{ATM, config::ATMConfig, profiles::ATMProfile};
+use affinidi_tdk::common::TDKSharedState;
+use anyhow::Result;
+use didcomm_ai_bridge::{
+    agents::state_management::{ChannelState, ChatChannelState, SharedState},
+    chat_messages::send_chat,
+    didcomm_messages::MessageTransport,
+};
+use futures::future::BoxFuture;
+use sha256::digest;
+
+const AGENT_DID: &str = "did:example:agent";
+const REMOTE_DID: &str = "did:example:remote";
+
+/// Transport that records the messages sent and the DIDs they were sent to
+#[derive(Default)]
+struct RecordingTransport {
+    sent: Mutex<Vec<(String, Message)>>,
+}
+
+impl MessageTransport for RecordingTransport {
+    fn pack_encrypted<'a>(
+        &'a self,
+        _profile: &'a Arc<ATMProfile>,
+        message: &'a Message,
+        _to_did: &'a str,
+    ) -> BoxFuture<'a, Result<(String, bool)>> {
+        Box::pin(async move { Ok((serde_json::to_string(message)?, true)) })
+    }
+
+    fn send<'a>(
+        &'a self,
+        _profile: &'a Arc<ATMProfile>,
+        _packed: &'a str,
+        message: &'a Message,
+        to_did: &'a str,
+        _forward: bool,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((to_did.to_string(), message.clone()));
+            Ok(())
+        })
+    }
+}
+
+async fn profile() -> Arc<ATMProfile> {
+    let atm = ATM::new(
+        ATMConfig::builder().build().unwrap(),
+        TDKSharedState::default().await,
+    )
+    .await
+    .unwrap();
+    Arc::new(
+        ATMProfile::new(&atm, None, AGENT_DID.to_string(), None)
+            .await
+            .unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn message_is_sent_to_a_did_with_a_channel() {
+    let transport = RecordingTransport::default();
+    let shared_state = SharedState::default();
+    shared_state.concierge.lock().await.insert_channel_state(
+        &digest(REMOTE_DID),
+        ChatChannelState {
+            remote_did: REMOTE_DID.to_string(),
+            remote_did_hash: digest(REMOTE_DID),
+            ..Default::default()
+        },
+    );
+
+    send_chat(
+        &transport,
+        &profile().await,
+        REMOTE_DID,
+        "Your report is ready",
+        &shared_state.concierge,
+    )
+    .await
+    .unwrap();
+
+    let sent = transport.sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    let (to_did, message) = &sent[0];
+    assert_eq!(to_did, REMOTE_DID);
+    assert_eq!(message.body["text"], "Your report is ready");
+}
+
+#[tokio::test]
+async fn sending_to_a_did_without_a_channel_fails() {
+    let transport = RecordingTransport::default();
+    let shared_state = SharedState::default();
+
+    let result = send_chat(
+        &transport,
+        &profile().await,
+        REMOTE_DID,
+        "Your report is ready",
+        &shared_state.concierge,
+    )
+    .await;
+
+    assert!(result.is_err());
+    assert!(transport.sent.lock().unwrap().is_empty());
+}